@@ -0,0 +1,103 @@
+//! Telling shop ads and spam apart from messages that merely contain links.
+
+use crate::ReplaceReport;
+
+lazy_static! {
+  /// Affiliate links of JD union, taobao and AliExpress, and their params.
+  static ref AFFILIATE_REGEX: regex::Regex = regex::Regex::new(
+    r"(?i)(\b(union-click\.jd\.com|u\.jd\.com|s\.click\.taobao\.com|m\.tb\.cn|s\.click\.aliexpress\.com|a\.aliexpress\.com)/|[?&](aff_fcid|aff_platform|aff_trace_key|unionId|union_id)=)"
+  )
+  .unwrap();
+  /// Params paying whoever shared the link.
+  static ref AFFILIATE_PARAM_REGEX: regex::Regex = regex::Regex::new(
+    r"[?&](aff_fcid|aff_platform|aff_trace_key|aff_id|affiliate|unionId|union_id|tag|ascsubtag|clickid)="
+  )
+  .unwrap();
+  /// Shorteners that hide where spam leads to.
+  static ref SPAM_SHORTENER_REGEX: regex::Regex = regex::Regex::new(
+    r"(?i)\b(bit\.ly|tinyurl\.com|is\.gd|v\.gd|cutt\.ly|shorturl\.at|rebrand\.ly|t\.ly|ow\.ly|adf\.ly|shorte\.st|bc\.vc|ouo\.io)/"
+  )
+  .unwrap();
+  static ref LINK_REGEX: regex::Regex = regex::Regex::new(r#"https?://[^\s<>"]+"#).unwrap();
+  /// Taobao share passwords, like `￥AbC1dEf2gH3￥`.
+  static ref TAOBAO_PASSWORD_REGEX: regex::Regex =
+    regex::Regex::new(r"[￥$€₤₳¢¤₴][0-9a-zA-Z]{8,14}[￥$€₤₳¢¤₴]").unwrap();
+}
+
+/// Replacers whose links are always shopping links.
+const SHOP_SITES: &[&str] = &["jd", "amazon"];
+
+/// Whether `text`, cleaned as in `report`, promotes shops with affiliate
+/// links, taobao passwords or product links.
+pub fn is_shop_ad(text: &str, report: &ReplaceReport) -> bool {
+  AFFILIATE_REGEX.is_match(text)
+    || TAOBAO_PASSWORD_REGEX.is_match(text)
+    || report
+      .replacements
+      .iter()
+      .any(|replacement| SHOP_SITES.contains(&replacement.site.as_str()))
+}
+
+/// What makes a message look like spam, counted in its text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpamSignals {
+  pub links: usize,
+  pub affiliate_params: usize,
+  pub spam_shorteners: usize,
+}
+
+pub fn spam_signals(text: &str) -> SpamSignals {
+  SpamSignals {
+    links: LINK_REGEX.find_iter(text).count(),
+    affiliate_params: AFFILIATE_PARAM_REGEX.find_iter(text).count(),
+    spam_shorteners: SPAM_SHORTENER_REGEX.find_iter(text).count(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{is_shop_ad, spam_signals, SpamSignals};
+  use crate::ReplaceReport;
+
+  #[test]
+  fn shop_ads() {
+    let report = ReplaceReport::default();
+    assert!(is_shop_ad("好价 https://u.jd.com/AbCdEf", &report));
+    assert!(is_shop_ad(
+      "https://s.click.aliexpress.com/e/_DlXYZ",
+      &report
+    ));
+    assert!(is_shop_ad(
+      "https://www.aliexpress.com/item/1005.html?aff_fcid=abc",
+      &report
+    ));
+    assert!(is_shop_ad("复制 ￥AbC1dEf2gH3￥ 打开淘宝", &report));
+    assert!(!is_shop_ad(
+      "https://www.bilibili.com/video/BV1se4y177g9/",
+      &report
+    ));
+
+    // cleaned by the jd replacer
+    #[cfg(feature = "cn-ecommerce")]
+    {
+      let text = "https://item.m.jd.com/product/100026923531.html?utm_source=iosapp";
+      assert!(is_shop_ad(text, &crate::clean_offline(text).1));
+    }
+  }
+
+  #[test]
+  fn signals() {
+    assert_eq!(
+      SpamSignals {
+        links: 3,
+        affiliate_params: 2,
+        spam_shorteners: 1,
+      },
+      spam_signals(
+        "https://bit.ly/3abc https://www.amazon.com/dp/B00?tag=spam-20 \
+         https://www.aliexpress.com/item/1.html?aff_fcid=x"
+      )
+    );
+    assert_eq!(SpamSignals::default(), spam_signals("no links here"));
+  }
+}
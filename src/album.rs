@@ -0,0 +1,175 @@
+//! Albums, buffered until all of their messages arrived so they're reposted
+//! whole.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use frankenstein::{
+  InputMediaAudio, InputMediaDocument, InputMediaPhoto, InputMediaVideo, Media, Message, ParseMode,
+};
+
+/// Most UTF-16 units in the caption of a message.
+pub(crate) const CAPTION_LIMIT: usize = 1024;
+
+/// Messages of albums still coming in, by chat and `media_group_id`.
+#[derive(Debug, Default)]
+pub(crate) struct Albums {
+  pending: Mutex<HashMap<(i64, String), Vec<Message>>>,
+}
+
+impl Albums {
+  /// Buffers `msg` with the rest of its album, returns whether it's the first
+  /// one, whose task handles the album.
+  pub fn add(&self, msg: Message) -> bool {
+    let key = (msg.chat.id, msg.media_group_id.clone().unwrap_or_default());
+    let mut pending = self.pending.lock().unwrap();
+    let album = pending.entry(key).or_default();
+    album.push(msg);
+    album.len() == 1
+  }
+
+  /// The buffered messages of album `group` in `chat`, in order.
+  pub fn take(&self, chat: i64, group: &str) -> Vec<Message> {
+    let mut album = self
+      .pending
+      .lock()
+      .unwrap()
+      .remove(&(chat, group.to_string()))
+      .unwrap_or_default();
+    album.sort_by_key(|msg| msg.message_id);
+    album
+  }
+}
+
+/// `msg` as an item of an album, with a `caption` in `parse_mode`. `None`
+/// for media albums can't hold.
+fn media(msg: &Message, caption: Option<String>, parse_mode: ParseMode) -> Option<Media> {
+  let parse_mode = caption.as_ref().map(|_| parse_mode);
+  let media = if let Some(photo) = msg.photo.as_ref().and_then(|sizes| sizes.last()) {
+    let mut media = InputMediaPhoto::builder()
+      .media(photo.file_id.clone())
+      .build();
+    (media.caption, media.parse_mode) = (caption, parse_mode);
+    media.has_spoiler = msg.has_media_spoiler;
+    Media::Photo(media)
+  } else if let Some(video) = &msg.video {
+    let mut media = InputMediaVideo::builder()
+      .media(video.file_id.clone())
+      .build();
+    (media.caption, media.parse_mode) = (caption, parse_mode);
+    media.has_spoiler = msg.has_media_spoiler;
+    Media::Video(media)
+  } else if let Some(document) = &msg.document {
+    let mut media = InputMediaDocument::builder()
+      .media(document.file_id.clone())
+      .build();
+    (media.caption, media.parse_mode) = (caption, parse_mode);
+    Media::Document(media)
+  } else if let Some(audio) = &msg.audio {
+    let mut media = InputMediaAudio::builder()
+      .media(audio.file_id.clone())
+      .build();
+    (media.caption, media.parse_mode) = (caption, parse_mode);
+    Media::Audio(media)
+  } else {
+    return None;
+  };
+  Some(media)
+}
+
+/// Items to send `album` again with `caption`, in `parse_mode`, on message
+/// `captioned`, `None` if the caption is too long or some message can't be
+/// sent again.
+pub(crate) fn media_group(
+  album: &[Message],
+  captioned: i32,
+  caption: &str,
+  parse_mode: ParseMode,
+) -> Option<Vec<Media>> {
+  if caption.encode_utf16().count() > CAPTION_LIMIT {
+    return None;
+  }
+  album
+    .iter()
+    .map(|msg| {
+      media(
+        msg,
+        (msg.message_id == captioned).then(|| caption.to_string()),
+        parse_mode,
+      )
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use frankenstein::{Chat, ChatType, Media, Message, ParseMode, PhotoSize};
+
+  use super::{media_group, Albums};
+
+  fn photo(message_id: i32, file_id: &str) -> Message {
+    let chat = Chat::builder()
+      .id(-1001)
+      .type_field(ChatType::Supergroup)
+      .build();
+    let size = |file_id: &str| {
+      PhotoSize::builder()
+        .file_id(file_id)
+        .file_unique_id(file_id)
+        .width(90u32)
+        .height(90u32)
+        .build()
+    };
+    let mut msg = Message::builder()
+      .message_id(message_id)
+      .date(0u64)
+      .chat(chat)
+      .build();
+    msg.media_group_id = Some("album".to_string());
+    msg.photo = Some(vec![size("thumb"), size(file_id)]);
+    msg
+  }
+
+  #[test]
+  fn buffers_albums() {
+    let albums = Albums::default();
+    assert!(albums.add(photo(2, "b")));
+    assert!(!albums.add(photo(1, "a")));
+    let album = albums.take(-1001, "album");
+    assert_eq!(
+      vec![1, 2],
+      album.iter().map(|msg| msg.message_id).collect::<Vec<_>>()
+    );
+    assert!(albums.take(-1001, "album").is_empty());
+  }
+
+  #[test]
+  fn caption_on_one_item() {
+    let album = [photo(1, "a"), photo(2, "b")];
+    let media = media_group(&album, 2, "cleaned", ParseMode::Html).unwrap();
+    let items: Vec<_> = media
+      .iter()
+      .map(|media| match media {
+        Media::Photo(photo) => (photo.media.clone(), photo.caption.clone()),
+        _ => unreachable!(),
+      })
+      .collect();
+    assert_eq!(
+      vec![
+        ("a".to_string().into(), None),
+        ("b".to_string().into(), Some("cleaned".to_string())),
+      ],
+      items
+    );
+    assert_eq!(
+      None,
+      media_group(&album, 2, &"x".repeat(1025), ParseMode::Html)
+    );
+
+    let mut text = photo(3, "c");
+    text.photo = None;
+    assert_eq!(
+      None,
+      media_group(&[photo(1, "a"), text], 1, "cleaned", ParseMode::Html)
+    );
+  }
+}
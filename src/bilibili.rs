@@ -0,0 +1,181 @@
+//! Ids in bilibili links, and canonical links built from them.
+
+use std::fmt::Display;
+
+use reqwest::Url;
+
+const TABLE: &[u8; 58] = b"FcwAPNKTMug3GV5Lj7EJnHpWsx4tb8haYeviqBz6rkCy12mUSDQX9RdoZf";
+const XOR_CODE: u64 = 23442827791579;
+const MASK_CODE: u64 = 2251799813685247;
+const MAX_AID: u64 = 1 << 51;
+
+/// A video, as an av number or a `BV...` id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VideoId {
+  Av(u64),
+  Bv(String),
+}
+
+impl VideoId {
+  /// The av number, converting from the bv id if needed.
+  pub fn av(&self) -> Option<u64> {
+    match self {
+      VideoId::Av(aid) => Some(*aid),
+      VideoId::Bv(bvid) => bv_to_av(bvid),
+    }
+  }
+
+  /// The bv id, converting from the av number if needed.
+  pub fn bv(&self) -> String {
+    match self {
+      VideoId::Av(aid) => av_to_bv(*aid),
+      VideoId::Bv(bvid) => bvid.clone(),
+    }
+  }
+}
+
+/// What a bilibili link points to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BiliId {
+  Video(VideoId),
+  /// An article, the number of `cv...`.
+  Article(u64),
+  /// A dynamic post, also shown under `/opus/`.
+  Dynamic(u64),
+}
+
+impl BiliId {
+  /// Parses the id of a video, article or dynamic link, with or without the
+  /// scheme, `None` for anything else.
+  pub fn parse(url: &str) -> Option<Self> {
+    let url = Url::parse(url)
+      .ok()
+      .filter(|url| url.has_host())
+      .or_else(|| Url::parse(&format!("https://{url}")).ok())?;
+    let host = url.host_str()?;
+    let mut segments = url.path_segments()?.filter(|segment| !segment.is_empty());
+    let first = segments.next()?;
+    let second = segments.next();
+    match (host, first, second) {
+      ("t.bilibili.com", id, None) => id.parse().ok().map(BiliId::Dynamic),
+      ("www.bilibili.com" | "m.bilibili.com" | "bilibili.com", first, Some(second)) => {
+        match first {
+          "video" => parse_video(second).map(BiliId::Video),
+          "read" if second == "mobile" => segments.next()?.parse().ok().map(BiliId::Article),
+          "read" => second.strip_prefix("cv")?.parse().ok().map(BiliId::Article),
+          "opus" | "dynamic" => second.parse().ok().map(BiliId::Dynamic),
+          _ => None,
+        }
+      },
+      _ => None,
+    }
+  }
+
+  /// The desktop link without any params.
+  pub fn canonical_url(&self) -> String {
+    match self {
+      BiliId::Video(VideoId::Av(aid)) => format!("https://www.bilibili.com/video/av{aid}/"),
+      BiliId::Video(VideoId::Bv(bvid)) => format!("https://www.bilibili.com/video/{bvid}/"),
+      BiliId::Article(cvid) => format!("https://www.bilibili.com/read/cv{cvid}"),
+      BiliId::Dynamic(id) => format!("https://t.bilibili.com/{id}"),
+    }
+  }
+}
+
+impl Display for BiliId {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      BiliId::Video(VideoId::Av(aid)) => write!(f, "av{aid}"),
+      BiliId::Video(VideoId::Bv(bvid)) => f.write_str(bvid),
+      BiliId::Article(cvid) => write!(f, "cv{cvid}"),
+      BiliId::Dynamic(id) => write!(f, "dynamic {id}"),
+    }
+  }
+}
+
+fn parse_video(id: &str) -> Option<VideoId> {
+  if let Some(aid) = id.strip_prefix("av").or_else(|| id.strip_prefix("AV")) {
+    return aid.parse().ok().map(VideoId::Av);
+  }
+  bv_to_av(id)?;
+  Some(VideoId::Bv(format!("BV{}", &id[2..])))
+}
+
+/// Converts an av number to its bv id.
+pub fn av_to_bv(aid: u64) -> String {
+  let mut bytes = *b"BV1000000000";
+  let mut tmp = (MAX_AID | aid) ^ XOR_CODE;
+  let mut i = bytes.len() - 1;
+  while tmp > 0 {
+    bytes[i] = TABLE[(tmp % 58) as usize];
+    tmp /= 58;
+    i -= 1;
+  }
+  bytes.swap(3, 9);
+  bytes.swap(4, 7);
+  String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+/// Converts a bv id, like `BV17x411w7KC`, to its av number.
+pub fn bv_to_av(bvid: &str) -> Option<u64> {
+  let mut bytes: [u8; 12] = bvid.as_bytes().try_into().ok()?;
+  if !bytes[..2].eq_ignore_ascii_case(b"BV") || bytes[2] != b'1' {
+    return None;
+  }
+  bytes.swap(3, 9);
+  bytes.swap(4, 7);
+  let mut tmp: u64 = 0;
+  for byte in &bytes[3..] {
+    let digit = TABLE.iter().position(|c| c == byte)? as u64;
+    tmp = tmp.checked_mul(58)?.checked_add(digit)?;
+  }
+  Some((tmp & MASK_CODE) ^ XOR_CODE)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn av_bv() {
+    assert_eq!("BV17x411w7KC", av_to_bv(170001));
+    assert_eq!(Some(170001), bv_to_av("BV17x411w7KC"));
+    assert_eq!(None, bv_to_av("BV17x411w7K"));
+    assert_eq!(None, bv_to_av("BV17x411w7K0"));
+  }
+
+  #[test]
+  fn parse() {
+    let video = BiliId::parse("https://m.bilibili.com/video/BV1se4y177g9/?t=100").unwrap();
+    assert_eq!(
+      BiliId::Video(VideoId::Bv("BV1se4y177g9".to_string())),
+      video
+    );
+    assert_eq!(
+      "https://www.bilibili.com/video/BV1se4y177g9/",
+      video.canonical_url()
+    );
+    assert_eq!(
+      Some(BiliId::Video(VideoId::Av(170001))),
+      BiliId::parse("bilibili.com/video/av170001")
+    );
+    assert_eq!(
+      Some(BiliId::Article(22363418)),
+      BiliId::parse("https://www.bilibili.com/read/mobile/22363418?share_source=x")
+    );
+    assert_eq!(
+      Some(BiliId::Article(22363418)),
+      BiliId::parse("https://www.bilibili.com/read/cv22363418")
+    );
+    assert_eq!(
+      Some(BiliId::Dynamic(785145600816119832)),
+      BiliId::parse("https://www.bilibili.com/opus/785145600816119832")
+    );
+    assert_eq!(
+      Some(BiliId::Dynamic(785145600816119832)),
+      BiliId::parse("https://t.bilibili.com/785145600816119832?share_medium=android")
+    );
+    assert_eq!(None, BiliId::parse("https://space.bilibili.com/21967971"));
+    assert_eq!(None, BiliId::parse("https://example.com/video/av1"));
+  }
+}
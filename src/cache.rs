@@ -0,0 +1,272 @@
+//! Bounded in-memory caches, evicting the least recently used entries to
+//! stay within a budget of entries and bytes.
+
+use std::{
+  collections::{BTreeMap, HashMap},
+  hash::Hash,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex, RwLock,
+  },
+  time::{Duration, Instant},
+};
+
+use bytesize::ByteSize;
+
+/// Most entries and bytes a cache may hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheLimits {
+  pub max_entries: usize,
+  pub max_bytes: ByteSize,
+}
+
+/// Counters of a cache, as shown in `/stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+  pub name: &'static str,
+  pub entries: usize,
+  pub bytes: u64,
+  pub hits: u64,
+  pub misses: u64,
+  pub evictions: u64,
+}
+
+impl CacheStats {
+  /// Share of lookups that hit, `None` before the first one.
+  pub fn hit_rate(&self) -> Option<f64> {
+    let lookups = self.hits + self.misses;
+    (lookups > 0).then(|| self.hits as f64 / lookups as f64)
+  }
+}
+
+#[derive(Debug)]
+struct Entry<V> {
+  value: V,
+  bytes: usize,
+  inserted: Instant,
+  /// Key in `Inner::order`.
+  used: u64,
+}
+
+#[derive(Debug)]
+struct Inner<K, V> {
+  entries: HashMap<K, Entry<V>>,
+  /// Keys by last use, oldest first.
+  order: BTreeMap<u64, K>,
+  bytes: usize,
+  clock: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> Inner<K, V> {
+  fn remove(&mut self, key: &K) -> Option<Entry<V>> {
+    let entry = self.entries.remove(key)?;
+    self.order.remove(&entry.used);
+    self.bytes -= entry.bytes;
+    Some(entry)
+  }
+
+  /// Evicts the least recently used entries until within `limits`, returns
+  /// how many were evicted.
+  fn shrink(&mut self, limits: CacheLimits) -> u64 {
+    let mut evicted = 0;
+    while self.entries.len() > limits.max_entries || self.bytes as u64 > limits.max_bytes.as_u64() {
+      let Some((_, key)) = self.order.pop_first() else {
+        break;
+      };
+      let entry = self.entries.remove(&key).unwrap();
+      self.bytes -= entry.bytes;
+      evicted += 1;
+    }
+    evicted
+  }
+}
+
+/// A cache of `V` by `K`, sized with `weigh` and optionally expiring entries
+/// after `ttl`.
+#[derive(Debug)]
+pub struct Cache<K, V> {
+  name: &'static str,
+  ttl: Option<Duration>,
+  weigh: fn(&K, &V) -> usize,
+  limits: RwLock<CacheLimits>,
+  inner: Mutex<Inner<K, V>>,
+  hits: AtomicU64,
+  misses: AtomicU64,
+  evictions: AtomicU64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Cache<K, V> {
+  pub fn new(
+    name: &'static str,
+    limits: CacheLimits,
+    ttl: Option<Duration>,
+    weigh: fn(&K, &V) -> usize,
+  ) -> Self {
+    Self {
+      name,
+      ttl,
+      weigh,
+      limits: RwLock::new(limits),
+      inner: Mutex::new(Inner {
+        entries: HashMap::new(),
+        order: BTreeMap::new(),
+        bytes: 0,
+        clock: 0,
+      }),
+      hits: AtomicU64::new(0),
+      misses: AtomicU64::new(0),
+      evictions: AtomicU64::new(0),
+    }
+  }
+
+  /// Applies reloaded `[cache]` limits, evicting what no longer fits.
+  pub fn set_limits(&self, limits: CacheLimits) {
+    *self.limits.write().unwrap() = limits;
+    let evicted = self.inner.lock().unwrap().shrink(limits);
+    self.evictions.fetch_add(evicted, Ordering::Relaxed);
+  }
+
+  fn expired(&self, entry: &Entry<V>) -> bool {
+    self.ttl.is_some_and(|ttl| entry.inserted.elapsed() >= ttl)
+  }
+
+  pub fn get(&self, key: &K) -> Option<V> {
+    let mut inner = self.inner.lock().unwrap();
+    let value = match inner.entries.get(key) {
+      Some(entry) if self.expired(entry) => {
+        inner.remove(key);
+        None
+      },
+      Some(_) => {
+        inner.clock += 1;
+        let clock = inner.clock;
+        let entry = inner.entries.get_mut(key).unwrap();
+        let used = std::mem::replace(&mut entry.used, clock);
+        let value = entry.value.clone();
+        inner.order.remove(&used);
+        inner.order.insert(clock, key.clone());
+        Some(value)
+      },
+      None => None,
+    };
+    let counter = if value.is_some() {
+      &self.hits
+    } else {
+      &self.misses
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    value
+  }
+
+  pub fn insert(&self, key: K, value: V) {
+    let limits = *self.limits.read().unwrap();
+    let bytes = (self.weigh)(&key, &value);
+    let mut inner = self.inner.lock().unwrap();
+    inner.remove(&key);
+    inner.clock += 1;
+    let used = inner.clock;
+    inner.order.insert(used, key.clone());
+    inner.entries.insert(
+      key,
+      Entry {
+        value,
+        bytes,
+        inserted: Instant::now(),
+        used,
+      },
+    );
+    inner.bytes += bytes;
+    let evicted = inner.shrink(limits);
+    self.evictions.fetch_add(evicted, Ordering::Relaxed);
+  }
+
+  /// Drops expired entries, returns how many are left.
+  pub fn prune(&self) -> usize {
+    let mut inner = self.inner.lock().unwrap();
+    let expired: Vec<_> = inner
+      .entries
+      .iter()
+      .filter(|(_, entry)| self.expired(entry))
+      .map(|(key, _)| key.clone())
+      .collect();
+    for key in expired {
+      inner.remove(&key);
+    }
+    inner.entries.len()
+  }
+
+  pub fn stats(&self) -> CacheStats {
+    let inner = self.inner.lock().unwrap();
+    CacheStats {
+      name: self.name,
+      entries: inner.entries.len(),
+      bytes: inner.bytes as u64,
+      hits: self.hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+      evictions: self.evictions.load(Ordering::Relaxed),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use bytesize::ByteSize;
+
+  use super::{Cache, CacheLimits};
+
+  fn cache(limits: CacheLimits, ttl: Option<Duration>) -> Cache<String, String> {
+    Cache::new("test", limits, ttl, |key, value| key.len() + value.len())
+  }
+
+  #[test]
+  fn evicts_least_recently_used() {
+    let limits = CacheLimits {
+      max_entries: 2,
+      max_bytes: ByteSize::b(10),
+    };
+    let cache = cache(limits, None);
+    cache.insert("a".to_string(), "1".to_string());
+    cache.insert("b".to_string(), "2".to_string());
+    assert_eq!(Some("1".to_string()), cache.get(&"a".to_string()));
+    cache.insert("c".to_string(), "3".to_string());
+    assert_eq!(None, cache.get(&"b".to_string()));
+    assert_eq!(Some("1".to_string()), cache.get(&"a".to_string()));
+
+    // too many bytes, evicting c as a was used since
+    cache.insert("d".to_string(), "1234567".to_string());
+    assert_eq!(None, cache.get(&"c".to_string()));
+    let stats = cache.stats();
+    assert_eq!(
+      (2, 10, 2, 2, 2),
+      (
+        stats.entries,
+        stats.bytes,
+        stats.hits,
+        stats.misses,
+        stats.evictions
+      )
+    );
+    assert_eq!(Some(0.5), stats.hit_rate());
+
+    cache.set_limits(CacheLimits {
+      max_entries: 0,
+      ..limits
+    });
+    assert_eq!(0, cache.stats().entries);
+  }
+
+  #[test]
+  fn expires() {
+    let limits = CacheLimits {
+      max_entries: 10,
+      max_bytes: ByteSize::kib(1),
+    };
+    let cache = cache(limits, Some(Duration::ZERO));
+    cache.insert("a".to_string(), "1".to_string());
+    assert_eq!(None, cache.get(&"a".to_string()));
+    cache.insert("b".to_string(), "2".to_string());
+    assert_eq!(0, cache.prune());
+  }
+}
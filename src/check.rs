@@ -0,0 +1,187 @@
+//! `--check-config`, validating the config without starting the bot.
+
+use std::{fs, iter, path::Path};
+
+use anyhow::{bail, Context, Result};
+use frankenstein::{AsyncApi, AsyncTelegramApi};
+use fuckburl_bot::{config::Config, plugin, replacer::REPLACERS, resolver};
+use reqwest::{Client, ClientBuilder, Proxy};
+
+/// Client for telegram and embed probes, going through `proxy` if set.
+pub(crate) fn http_client(config: &Config) -> Result<Client> {
+  with_proxy(Client::builder(), config.proxy.as_ref())
+}
+
+/// [`http_client`] for resolving short links, which follows redirects
+/// itself and goes through `resolver.proxy` instead if set.
+pub(crate) fn resolver_client(config: &Config) -> Result<Client> {
+  let cli = if config.resolver.private {
+    resolver::private_client_builder()
+  } else {
+    resolver::client_builder()
+  };
+  with_proxy(
+    cli,
+    config.resolver.proxy.as_ref().or(config.proxy.as_ref()),
+  )
+}
+
+fn with_proxy(mut cli: ClientBuilder, proxy: Option<&String>) -> Result<Client> {
+  if let Some(proxy) = proxy {
+    let proxy =
+      Proxy::all(proxy.clone()).with_context(|| format!("Failed to set \"{proxy}\" as proxy"))?;
+    cli = cli.proxy(proxy);
+  }
+  Ok(cli.build()?)
+}
+
+/// Names in `[[chats]] disable` that are neither a replacer, a custom rule
+/// nor a plugin.
+pub(crate) fn unknown_replacers(config: &Config) -> Vec<String> {
+  let plugins = config
+    .plugins
+    .as_deref()
+    .and_then(|dir| plugin::names(dir).ok())
+    .unwrap_or_default();
+  let mut unknown = Vec::new();
+  for chat in &config.chats {
+    for name in chat.disable.iter() {
+      if !REPLACERS.contains(&name.as_str())
+        && !config.rules.iter().any(|rule| rule.name == *name)
+        && !plugins.contains(name)
+      {
+        unknown.push(format!(
+          "Unknown replacer \"{name}\" disabled in chat {}",
+          chat.id
+        ));
+      }
+    }
+  }
+  unknown
+}
+
+/// Problems of a parsed config the bot would only warn about, or run into
+/// later.
+fn problems(config: &Config) -> Vec<String> {
+  let mut problems = Vec::new();
+  let ids = config
+    .enabled_chats
+    .iter()
+    .map(|id| ("enabled-chats", id))
+    .chain(config.bots.iter().flat_map(|bot| {
+      bot
+        .enabled_chats
+        .iter()
+        .map(|id| ("bots.enabled-chats", id))
+    }))
+    .chain(config.chats.iter().map(|chat| ("chats", &chat.id)))
+    .chain(
+      config
+        .disabled_chats
+        .iter()
+        .map(|id| ("disabled-chats", id)),
+    );
+  for (key, id) in ids {
+    if id.parse::<i64>().is_err() && !id.starts_with('@') {
+      problems.push(format!(
+        "{key}: \"{id}\" is neither a numeric chat id nor an @username"
+      ));
+    }
+  }
+  for chat in &config.chats {
+    if chat.bot > config.bots.len() {
+      problems.push(format!(
+        "chats: bot {} of chat \"{}\" isn't configured, there are {} [[bots]]",
+        chat.bot,
+        chat.id,
+        config.bots.len()
+      ));
+    }
+  }
+  for user in &config.whitelisted_users {
+    if user.parse::<u64>().is_err() && !user.starts_with('@') {
+      problems.push(format!(
+        "whitelisted-users: \"{user}\" is neither a numeric user id nor an @username"
+      ));
+    }
+  }
+  if let Err(err) = http_client(config) {
+    problems.push(format!("{err:#}"));
+  }
+  if config.resolver.proxy.is_some() {
+    if let Err(err) = resolver_client(config) {
+      problems.push(format!("resolver.proxy: {err:#}"));
+    }
+  }
+  if let Some(Err(err)) = config.plugins.as_deref().map(plugin::names) {
+    problems.push(format!("{err:#}"));
+  }
+  problems.extend(unknown_replacers(config));
+  problems
+}
+
+/// Prints every problem of the config at `path`, failing if there's any.
+/// With `online`, also checks the tokens with `getMe`.
+pub(crate) async fn run(path: &Path, online: bool) -> Result<()> {
+  let str = fs::read_to_string(path)
+    .with_context(|| format!("Failed to read config file: {}", path.to_string_lossy()))?;
+  let (config, issues) = Config::from_toml(&str)
+    .with_context(|| format!("Failed to parse config file: {}", path.to_string_lossy()))?;
+  let mut problems: Vec<_> = issues.iter().map(ToString::to_string).collect();
+  problems.extend(self::problems(&config));
+  if online && problems.is_empty() {
+    let cli = http_client(&config)?;
+    let tokens =
+      iter::once(&config.telegram_token).chain(config.bots.iter().map(|bot| &bot.telegram_token));
+    for token in tokens {
+      let api = AsyncApi::builder()
+        .api_url(format!("{}{}", frankenstein::BASE_API_URL, token))
+        .client(cli.clone())
+        .build();
+      match api.get_me().await {
+        Ok(me) => println!(
+          "Token belongs to @{}",
+          me.result.username.unwrap_or_default()
+        ),
+        Err(err) => problems.push(format!("getMe failed: {err}")),
+      }
+    }
+  }
+  for problem in &problems {
+    println!("{}: {problem}", path.to_string_lossy());
+  }
+  if !problems.is_empty() {
+    bail!("{} problems in {}", problems.len(), path.to_string_lossy());
+  }
+  println!("{} is valid", path.to_string_lossy());
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use fuckburl_bot::config::Config;
+
+  use super::problems;
+
+  #[test]
+  fn chat_ids_and_proxy() {
+    let (config, _) = Config::from_toml(
+      "telegram-token = \"123:abc\"\nenabled-chats = [\"-100123\", \"@group\", \"group\"]\n\
+       proxy = \"not a url\"\n\
+       [[chats]]\nid = \"-1\"\nbot = 1\ndisable = [\"tracking\", \"nope\"]\n",
+    )
+    .unwrap();
+    let problems = problems(&config);
+    assert_eq!(4, problems.len(), "{problems:?}");
+    assert_eq!(
+      "enabled-chats: \"group\" is neither a numeric chat id nor an @username",
+      problems[0]
+    );
+    assert_eq!(
+      "chats: bot 1 of chat \"-1\" isn't configured, there are 0 [[bots]]",
+      problems[1]
+    );
+    assert!(problems[2].contains("not a url"), "{}", problems[2]);
+    assert_eq!("Unknown replacer \"nope\" disabled in chat -1", problems[3]);
+  }
+}
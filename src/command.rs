@@ -0,0 +1,322 @@
+//! Commands the owner sends to the bot in a private chat.
+
+use std::{fmt::Write, time::Duration};
+
+use anyhow::{Context, Result};
+use bytesize::ByteSize;
+use frankenstein::{AsyncTelegramApi, ChatType, Message, ParseMode, SendMessageParams};
+use log::info;
+
+use fuckburl_bot::config::Config;
+
+use crate::{db::ReplacementStats, health::now, Bot, START_TIME};
+
+/// Handles `msg` if it's an owner command, returns `false` otherwise.
+pub(crate) async fn handle(bot: &Bot, config: &Config, msg: &Message) -> Result<bool> {
+  if msg.chat.type_field != ChatType::Private
+    || config.owner.is_none()
+    || msg.from.as_ref().map(|user| user.id as i64) != config.owner
+  {
+    return Ok(false);
+  }
+  let Some((command, args)) = msg.text.as_deref().and_then(parse) else {
+    return Ok(false);
+  };
+  info!("Owner command: /{command}");
+  let text = match command {
+    "audit" => audit(bot, args)?,
+    "list_chats" => list_chats(bot, config)?,
+    "enable" => enable(bot, args, true)?,
+    "disable" => enable(bot, args, false)?,
+    "restore" => restore(bot, args)?,
+    "stats" => stats(bot, config, args)?,
+    _ => return Ok(false),
+  };
+  let params = SendMessageParams::builder()
+    .chat_id(msg.chat.id)
+    .text(text)
+    .parse_mode(ParseMode::Html)
+    .build();
+  bot
+    .api
+    .send_message(&params)
+    .await
+    .with_context(|| format!("Failed to reply to /{command}"))?;
+  Ok(true)
+}
+
+/// Handles `/optout` and `/optin` from anyone, in any chat, returns
+/// `false` for other messages.
+pub(crate) async fn handle_opt(bot: &Bot, msg: &Message) -> Result<bool> {
+  let (Some(from), Some(text)) = (&msg.from, msg.text.as_deref()) else {
+    return Ok(false);
+  };
+  let Some(command) = parse_for(text, &bot.username) else {
+    return Ok(false);
+  };
+  let opted_out = match command {
+    "optout" => true,
+    "optin" => false,
+    _ => return Ok(false),
+  };
+  let changed = bot.db.set_opted_out(from.id, opted_out)?;
+  info!(chat_id = msg.chat.id; "User {} sent /{command}", from.id);
+  let text = match (opted_out, changed) {
+    (true, true) => "Your messages won't be cleaned anymore, /optin to undo.",
+    (true, false) => "You opted out already, /optin to have your messages cleaned again.",
+    (false, true) => "Your messages will be cleaned again.",
+    (false, false) => "Your messages are cleaned already.",
+  };
+  let mut params = SendMessageParams::builder()
+    .chat_id(msg.chat.id)
+    .text(text)
+    .reply_to_message_id(msg.message_id)
+    .build();
+  if msg.is_topic_message == Some(true) {
+    params.message_thread_id = msg.message_thread_id;
+  }
+  bot
+    .api
+    .send_message(&params)
+    .await
+    .with_context(|| format!("Failed to reply to /{command}"))?;
+  Ok(true)
+}
+
+/// Name of a command without arguments for the bot `username`, `None` for
+/// other text and commands like `/name@other_bot`.
+fn parse_for<'a>(text: &'a str, username: &str) -> Option<&'a str> {
+  let word = text.strip_prefix('/')?.split_whitespace().next()?;
+  match word.split_once('@') {
+    Some((name, bot)) if bot.eq_ignore_ascii_case(username) => Some(name),
+    Some(_) => None,
+    None => Some(word),
+  }
+}
+
+/// Returns the command name and arguments of `/name@bot args`.
+fn parse(text: &str) -> Option<(&str, &str)> {
+  let text = text.strip_prefix('/')?;
+  let (word, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+  let name = word.split('@').next().unwrap_or(word);
+  if name.is_empty() {
+    return None;
+  }
+  Some((name, args.trim()))
+}
+
+/// Chats, domains and senders listed by `/stats`.
+const TOP: usize = 5;
+
+/// Links cleaned within `window` as recorded in the database.
+fn render_recorded(stats: &ReplacementStats, window: Duration) -> String {
+  let mut text = format!(
+    "Cleaned links in the last {}: {}",
+    humantime::format_duration(window),
+    stats.links
+  );
+  if stats.links == 0 {
+    return text;
+  }
+  text.push_str("\nTop chats:");
+  for (chat, links) in &stats.chats {
+    write!(text, "\n<code>{chat}</code> {links}").unwrap();
+  }
+  text.push_str("\nTop domains:");
+  for (domain, links) in &stats.domains {
+    write!(text, "\n{} {links}", v_htmlescape::escape(domain)).unwrap();
+  }
+  if !stats.offenders.is_empty() {
+    text.push_str("\nTop senders:");
+  }
+  for (id, username, links) in &stats.offenders {
+    match username {
+      Some(username) => write!(text, "\n@{} {links}", v_htmlescape::escape(username)),
+      None => write!(text, "\n<code>{id}</code> {links}"),
+    }
+    .unwrap();
+  }
+  text
+}
+
+/// `/stats`, with `args` the period of recorded links, like `1d`.
+fn stats(bot: &Bot, config: &Config, args: &str) -> Result<String> {
+  let window = if args.is_empty() {
+    config.stats.window
+  } else {
+    match humantime::parse_duration(args) {
+      Ok(window) => window,
+      Err(_) => return Ok("Usage: /stats [period, like 1d or 12h]".to_string()),
+    }
+  };
+  let mut text = bot
+    .stats
+    .render_activity(*START_TIME, now(), bot.health.last_poll());
+  text.push_str("\n\n");
+  text.push_str(&bot.stats.render());
+  let recorded = bot
+    .db
+    .replacement_stats(now().saturating_sub(window.as_secs()), None, TOP)?;
+  text.push_str("\n\n");
+  text.push_str(&render_recorded(&recorded, window));
+  let rejections = bot.resolver.rejections();
+  if !rejections.is_empty() {
+    text.push_str("\n\nRefused by shorteners:");
+    for (host, count) in rejections {
+      write!(
+        text,
+        "\n<code>{}</code> {count}",
+        v_htmlescape::escape(&host)
+      )
+      .unwrap();
+    }
+  }
+  text.push_str("\n\nCaches:");
+  for cache in [bot.resolver.cache().stats(), bot.admins.stats()] {
+    let hit_rate = cache
+      .hit_rate()
+      .map_or_else(|| "-".to_string(), |rate| format!("{:.0}%", rate * 100.0));
+    write!(
+      text,
+      "\n{}: {} entries, {}, {hit_rate} hits, {} evicted",
+      cache.name,
+      cache.entries,
+      ByteSize::b(cache.bytes),
+      cache.evictions
+    )
+    .unwrap();
+  }
+  text.push_str("\n\n");
+  text.push_str(&bot.stats.render_latency());
+  Ok(text)
+}
+
+fn list_chats(bot: &Bot, config: &Config) -> Result<String> {
+  let chats = bot.seen.list();
+  let downgrades = bot.db.downgrades()?;
+  let mut text = if chats.is_empty() {
+    "No chats seen since start.".to_string()
+  } else {
+    "Chats seen since start:\n".to_string()
+  };
+  let enabled_chats = bot.enabled_chats.read().unwrap().clone();
+  for chat in chats {
+    let enabled = if config
+      .chat_in(
+        bot.entry,
+        &enabled_chats,
+        chat.id,
+        bot.username_of(chat.id, chat.username.as_deref()),
+      )
+      .is_some()
+    {
+      "enabled"
+    } else {
+      "disabled"
+    };
+    write!(
+      text,
+      "\n<code>{}</code> {} ({}, {enabled})",
+      chat.id,
+      v_htmlescape::escape(&chat.title),
+      chat.kind
+    )
+    .unwrap();
+  }
+  if !downgrades.is_empty() {
+    text.push_str("\n\nSwitched to reply as deleting failed, /restore to undo:");
+    for downgrade in downgrades {
+      write!(
+        text,
+        "\n<code>{}</code> {}",
+        downgrade.chat_id,
+        v_htmlescape::escape(&downgrade.reason)
+      )
+      .unwrap();
+    }
+  }
+  Ok(text)
+}
+
+/// What the audited chat `args` shared so far.
+fn audit(bot: &Bot, args: &str) -> Result<String> {
+  let Ok(chat_id) = args.parse::<i64>() else {
+    return Ok("Usage: /audit <chat id>".to_string());
+  };
+  let summary = bot.db.audit_summary(chat_id)?;
+  if summary.messages == 0 {
+    return Ok(format!("Nothing audited in {chat_id} yet."));
+  }
+  let mut text = format!(
+    "{} messages in {chat_id} shared {} dirty links:",
+    summary.messages, summary.links
+  );
+  for (site, count) in &summary.sites {
+    write!(text, "\n{} ×{count}", v_htmlescape::escape(site)).unwrap();
+  }
+  Ok(text)
+}
+
+fn restore(bot: &Bot, args: &str) -> Result<String> {
+  let Ok(chat_id) = args.parse::<i64>() else {
+    return Ok("Usage: /restore <chat id>".to_string());
+  };
+  Ok(if bot.db.remove_downgrade(chat_id)? {
+    format!("Chat {chat_id} restored to its configured action.")
+  } else {
+    format!("Chat {chat_id} wasn't switched to reply.")
+  })
+}
+
+/// Adds `args`, a chat id or `@username`, to the enabled chats of the bot,
+/// or removes it.
+fn enable(bot: &Bot, args: &str, enable: bool) -> Result<String> {
+  let command = if enable { "enable" } else { "disable" };
+  if args.is_empty() || (args.parse::<i64>().is_err() && !args.starts_with('@')) {
+    return Ok(format!("Usage: /{command} <chat id or @username>"));
+  }
+  let changed = if enable {
+    bot.db.enable_chat(bot.number(), args)?
+  } else {
+    bot.db.disable_chat(bot.number(), args)?
+  };
+  *bot.enabled_chats.write().unwrap() = bot.db.enabled_chats(bot.number())?;
+  let entry = v_htmlescape::escape(args);
+  let mut text = match (enable, changed) {
+    (true, true) => format!("Enabled {entry}."),
+    (true, false) => format!("{entry} was already enabled."),
+    (false, true) => format!("Disabled {entry}."),
+    (false, false) => format!("{entry} wasn't enabled."),
+  };
+  if !enable && bot.config.load().enable_all {
+    text.push_str(" With enable-all, add it to disabled-chats to stop serving it.");
+  }
+  Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{parse, parse_for};
+
+  #[test]
+  fn parse_command() {
+    assert_eq!(parse("/list_chats"), Some(("list_chats", "")));
+    assert_eq!(
+      parse("/restore@fuckburl_bot  -1001 "),
+      Some(("restore", "-1001"))
+    );
+    assert_eq!(parse("list_chats"), None);
+    assert_eq!(parse("/"), None);
+  }
+
+  #[test]
+  fn parse_for_bot() {
+    assert_eq!(Some("optout"), parse_for("/optout", "fuckburl_bot"));
+    assert_eq!(
+      Some("optin"),
+      parse_for("/optin@FuckBurl_Bot", "fuckburl_bot")
+    );
+    assert_eq!(None, parse_for("/optout@other_bot", "fuckburl_bot"));
+    assert_eq!(None, parse_for("optout", "fuckburl_bot"));
+  }
+}
@@ -0,0 +1,1496 @@
+//! Configuration file of the bot.
+
+use std::{collections::BTreeMap, fmt::Display, net::SocketAddr, path::PathBuf, time::Duration};
+
+use anyhow::{bail, Result};
+use bytesize::ByteSize;
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use fancy_regex::Regex;
+use log::warn;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::{
+  cache::CacheLimits,
+  embed::{Embeds, Service},
+  replacer::{default_tracking_params, CustomAction, CustomRule, ReplaceReport, REPLACERS},
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct Config {
+  pub telegram_token: String,
+  #[serde(default = "Default::default")]
+  pub enabled_chats: Vec<String>,
+  /// Serve every chat not in `disabled-chats`, not only the enabled ones.
+  #[serde(default = "Default::default")]
+  pub enable_all: bool,
+  /// Chats never served, even when enabled or in `[[chats]]`.
+  #[serde(default = "Default::default")]
+  pub disabled_chats: Vec<String>,
+  /// Reply to anyone sending or forwarding messages to the bot privately
+  /// with them cleaned.
+  #[serde(default = "Default::default")]
+  pub private_chats: bool,
+  /// Polled alongside the bot of `telegram-token`.
+  #[serde(default = "Default::default")]
+  pub bots: Vec<BotEntry>,
+  /// From `[[chats]]`, see [`Config::from_toml`].
+  #[serde(skip)]
+  pub chats: Vec<Chat>,
+  /// From `[[rules]]`, see [`Config::from_toml`].
+  #[serde(skip)]
+  pub rules: Vec<CustomRule>,
+  #[serde(default = "Default::default")]
+  pub action: Action,
+  #[serde(default = "default_tracking_params")]
+  pub tracking_params: Vec<String>,
+  pub proxy: Option<String>,
+  pub owner: Option<i64>,
+  /// Count messages that would have been cleaned in chats that aren't
+  /// enabled or where the bot lacks permissions.
+  #[serde(default = "Default::default")]
+  pub report_missed: bool,
+  /// Where state kept across restarts is stored, defaults to `fuckburl.db`
+  /// next to the config file.
+  pub database: Option<PathBuf>,
+  /// Directory of replacers compiled to WASM, see [`crate::plugin`]. Only
+  /// loaded when built with the `plugins` feature.
+  pub plugins: Option<PathBuf>,
+  /// IANA timezone of chats without their own, the server's when unset.
+  pub timezone: Option<Tz>,
+  /// Whether reposts notify chat members, `false` sends them silently.
+  #[serde(default = "default_notifications")]
+  pub notifications: bool,
+  /// Only log what would be sent and deleted.
+  #[serde(default = "Default::default")]
+  pub dry_run: bool,
+  /// Ids or `@usernames` of senders whose messages are left alone.
+  #[serde(default = "Default::default")]
+  pub whitelisted_users: Vec<String>,
+  /// Deprecated millisecond delays, superseded by `timeouts`.
+  time: Option<Time>,
+  #[serde(default = "Default::default")]
+  pub timeouts: Timeouts,
+  #[serde(default = "Default::default")]
+  pub embed: Embed,
+  #[serde(default = "Default::default")]
+  pub template: Template,
+  #[serde(default = "Default::default")]
+  pub logging: Logging,
+  #[serde(default = "Default::default")]
+  pub sentry: Sentry,
+  #[serde(default = "Default::default")]
+  pub tracing: Tracing,
+  #[serde(default = "Default::default")]
+  pub health: Health,
+  #[serde(default = "Default::default")]
+  pub resolver: Resolver,
+  #[serde(default = "Default::default")]
+  pub spam: Spam,
+  #[serde(default = "Default::default")]
+  pub cache: Cache,
+  #[serde(default = "Default::default")]
+  pub updates: Updates,
+  #[serde(default = "Default::default")]
+  pub runtime: Runtime,
+  #[serde(default = "Default::default")]
+  pub retry: Retry,
+  #[serde(default = "Default::default")]
+  pub stats: Stats,
+}
+
+/// `[[chats]]` and `[[rules]]` kept raw, so a bad entry doesn't fail the rest.
+#[derive(Deserialize)]
+struct Entries {
+  #[serde(default = "Default::default")]
+  chats: Vec<Spanned<toml::Value>>,
+  #[serde(default = "Default::default")]
+  rules: Vec<Spanned<toml::Value>>,
+}
+
+/// A malformed `[[chats]]` or `[[rules]]` entry that was skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigIssue {
+  /// e.g. `rules[2]`
+  pub entry: String,
+  /// 1-based line the entry starts at.
+  pub line: usize,
+  pub message: String,
+}
+
+impl Display for ConfigIssue {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "line {}: skipped {}, {}",
+      self.line, self.entry, self.message
+    )
+  }
+}
+
+fn line_of(str: &str, offset: usize) -> usize {
+  str[..offset].matches('\n').count() + 1
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+struct RuleConfig {
+  name: String,
+  pattern: String,
+  replace: Option<String>,
+  keep: Option<Vec<String>>,
+  command: Option<String>,
+  #[serde(default = "Default::default")]
+  args: Vec<String>,
+  #[serde(default = "default_command_timeout", with = "humantime_serde")]
+  timeout: Duration,
+}
+
+fn default_command_timeout() -> Duration {
+  Duration::from_secs(5)
+}
+
+impl RuleConfig {
+  fn compile(self) -> Result<CustomRule, String> {
+    if REPLACERS.contains(&self.name.as_str()) {
+      return Err(format!(
+        "field `name`: \"{}\" is a built-in replacer",
+        self.name
+      ));
+    }
+    let action = match (self.replace, self.keep, self.command) {
+      (Some(template), None, None) => CustomAction::Replace(template),
+      (None, Some(keys), None) => CustomAction::Keep(keys),
+      (None, None, Some(program)) => CustomAction::Command {
+        program,
+        args: self.args,
+        timeout: self.timeout,
+      },
+      _ => {
+        return Err("exactly one of field `replace`, `keep` or `command` is required".to_string())
+      },
+    };
+    let regex = Regex::new(&self.pattern).map_err(|err| format!("field `pattern`: {err}"))?;
+    Ok(CustomRule {
+      name: self.name,
+      regex,
+      action,
+    })
+  }
+}
+
+fn compile_rules(
+  str: &str,
+  entries: Vec<Spanned<toml::Value>>,
+) -> (Vec<CustomRule>, Vec<ConfigIssue>) {
+  let mut rules = Vec::new();
+  let mut issues = Vec::new();
+  for (i, entry) in entries.into_iter().enumerate() {
+    let line = line_of(str, entry.span().start);
+    let rule = entry
+      .into_inner()
+      .try_into::<RuleConfig>()
+      .map_err(|err| err.message().to_string())
+      .and_then(RuleConfig::compile);
+    match rule {
+      Ok(rule) => rules.push(rule),
+      Err(message) => issues.push(ConfigIssue {
+        entry: format!("rules[{i}]"),
+        line,
+        message,
+      }),
+    }
+  }
+  (rules, issues)
+}
+
+/// The cleaning related part of a config file, it may lack everything else
+/// such as `telegram-token`.
+#[derive(Debug)]
+pub struct RulesFile {
+  pub tracking_params: Vec<String>,
+  pub embed: Embed,
+  pub rules: Vec<CustomRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+struct RawRulesFile {
+  #[serde(default = "default_tracking_params")]
+  tracking_params: Vec<String>,
+  #[serde(default = "Default::default")]
+  embed: Embed,
+  #[serde(default = "Default::default")]
+  rules: Vec<Spanned<toml::Value>>,
+}
+
+impl RulesFile {
+  /// Parses like [`Config::from_toml`], other keys are ignored.
+  pub fn from_toml(str: &str) -> Result<(Self, Vec<ConfigIssue>)> {
+    let raw: RawRulesFile = toml::from_str(str)?;
+    let (rules, issues) = compile_rules(str, raw.rules);
+    Ok((
+      Self {
+        tracking_params: raw.tracking_params,
+        embed: raw.embed,
+        rules,
+      },
+      issues,
+    ))
+  }
+
+  pub fn embeds(&self) -> Embeds {
+    Embeds::new([
+      self.embed.twitter.clone(),
+      self.embed.tiktok.clone(),
+      self.embed.instagram.clone(),
+    ])
+  }
+}
+
+fn default_notifications() -> bool {
+  true
+}
+
+impl Config {
+  /// Parses and validates a config file.
+  ///
+  /// Malformed `[[chats]]` and `[[rules]]` entries are skipped and returned as
+  /// issues, any other error fails the whole file.
+  pub fn from_toml(str: &str) -> Result<(Self, Vec<ConfigIssue>)> {
+    let entries: Entries = toml::from_str(str)?;
+    let mut config: Config = toml::from_str(str)?;
+    let mut issues = Vec::new();
+    for (i, entry) in entries.chats.into_iter().enumerate() {
+      let line = line_of(str, entry.span().start);
+      match entry.into_inner().try_into::<Chat>() {
+        Ok(chat) => config.chats.push(chat),
+        Err(err) => issues.push(ConfigIssue {
+          entry: format!("chats[{i}]"),
+          line,
+          message: err.message().to_string(),
+        }),
+      }
+    }
+    let (rules, rule_issues) = compile_rules(str, entries.rules);
+    config.rules = rules;
+    issues.extend(rule_issues);
+    if let Some(time) = config.time.take() {
+      warn!("[time] is deprecated, please move to [timeouts] with values like \"1s\" or \"500ms\"");
+      config.timeouts.fetch_delay = Duration::from_millis(time.fetch_delay);
+      config.timeouts.failed_delay = Duration::from_millis(time.failed_delay);
+    }
+    config.timeouts.validate()?;
+    config.logging.validate()?;
+    if config.retry.attempts == 0 {
+      bail!("retry.attempts must be at least 1, which disables retrying");
+    }
+    if config.health.stale_after <= config.timeouts.long_poll + config.timeouts.fetch_delay {
+      bail!("health.stale-after must be longer than timeouts.long-poll plus timeouts.fetch-delay");
+    }
+    Ok((config, issues))
+  }
+
+  /// Embed mirrors configured in `[embed]`.
+  pub fn embeds(&self) -> Embeds {
+    Embeds::new(self.embed_domains())
+  }
+
+  /// Mirror domains of twitter, tiktok and instagram.
+  pub fn embed_domains(&self) -> [String; 3] {
+    [
+      self.embed.twitter.clone(),
+      self.embed.tiktok.clone(),
+      self.embed.instagram.clone(),
+    ]
+  }
+
+  /// Returns the settings of an enabled chat, `None` if the bot should ignore it.
+  pub fn chat(&self, id: i64) -> Option<ChatSettings<'_>> {
+    self.chat_of(None, id, None)
+  }
+
+  /// `enabled-chats` of `bots[bot]`, or of the bot of `telegram-token` when
+  /// `None`.
+  pub fn enabled_chats_of(&self, bot: Option<usize>) -> &[String] {
+    match bot {
+      None => &self.enabled_chats,
+      Some(bot) => self
+        .bots
+        .get(bot)
+        .map_or(&[][..], |bot| &bot.enabled_chats[..]),
+    }
+  }
+
+  /// `[[chats]]` entries of `bots[bot]`, or of the bot of `telegram-token`
+  /// when `None`.
+  pub fn chats_of(&self, bot: Option<usize>) -> impl Iterator<Item = &Chat> {
+    let number = bot.map_or(0, |i| i + 1);
+    self.chats.iter().filter(move |chat| chat.bot == number)
+  }
+
+  /// [`Config::chat`] for `bots[bot]`, or the bot of `telegram-token` when
+  /// `None`, enabling its `enabled-chats` and `[[chats]]`. `@username`
+  /// entries match chats by `username`.
+  pub fn chat_of(
+    &self,
+    bot: Option<usize>,
+    id: i64,
+    username: Option<&str>,
+  ) -> Option<ChatSettings<'_>> {
+    let enabled_chats: Vec<_> = self
+      .enabled_chats_of(bot)
+      .iter()
+      .cloned()
+      .chain(self.chats_of(bot).map(|chat| chat.id.clone()))
+      .collect();
+    self.chat_in(bot, &enabled_chats, id, username)
+  }
+
+  /// [`Config::chat_of`] with only `enabled_chats` enabled, as when they're
+  /// stored elsewhere. `[[chats]]` then only supply settings.
+  pub fn chat_in(
+    &self,
+    bot: Option<usize>,
+    enabled_chats: &[String],
+    id: i64,
+    username: Option<&str>,
+  ) -> Option<ChatSettings<'_>> {
+    let id = id.to_string();
+    if self
+      .disabled_chats
+      .iter()
+      .any(|entry| is_chat(entry, &id, username))
+    {
+      return None;
+    }
+    if !self.enable_all
+      && !enabled_chats
+        .iter()
+        .any(|entry| is_chat(entry, &id, username))
+    {
+      return None;
+    }
+    if let Some(chat) = self
+      .chats_of(bot)
+      .find(|chat| is_chat(&chat.id, &id, username))
+    {
+      return Some(ChatSettings {
+        action: chat.action.unwrap_or(self.action),
+        trial: chat.trial.as_ref(),
+        disabled: &chat.disable,
+        delete_ads: chat.delete_ads,
+        timezone: chat.timezone.or(self.timezone),
+        notifications: chat.notifications.unwrap_or(self.notifications),
+        summary: chat.summary.unwrap_or(self.stats.summary),
+        template: chat.template.as_ref(),
+      });
+    }
+    Some(ChatSettings {
+      action: self.action,
+      trial: None,
+      disabled: &[],
+      delete_ads: false,
+      timezone: self.timezone,
+      notifications: self.notifications,
+      summary: self.stats.summary,
+      template: None,
+    })
+  }
+
+  /// Whether the user `id` with `username` is in `whitelisted-users`.
+  pub fn is_whitelisted(&self, id: u64, username: Option<&str>) -> bool {
+    let id = id.to_string();
+    self
+      .whitelisted_users
+      .iter()
+      .any(|entry| is_chat(entry, &id, username))
+  }
+}
+
+/// Whether a chat `entry` is the chat `id`, or its username for `@username`.
+fn is_chat(entry: &str, id: &str, username: Option<&str>) -> bool {
+  match entry.strip_prefix('@') {
+    Some(name) => username.is_some_and(|username| username.eq_ignore_ascii_case(name)),
+    None => entry == id,
+  }
+}
+
+/// What to do with a message containing cleanable links.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub enum Action {
+  /// Delete the original message and send the cleaned one.
+  #[default]
+  Repost,
+  /// Reply to the original message with the cleaned one.
+  Reply,
+  /// Reply with just the cleaned links, leaving the original alone.
+  Links,
+  /// Only record the links and what they'd be cleaned to, see `/audit`.
+  Audit,
+  /// [`Action::Reply`] when only params were stripped, [`Action::Repost`]
+  /// otherwise.
+  Auto,
+}
+
+impl Action {
+  /// What `Auto` is for a message cleaned as in `report`.
+  pub fn resolve(self, report: &ReplaceReport) -> Self {
+    match self {
+      Self::Auto if report.only_params_stripped() => Self::Reply,
+      Self::Auto => Self::Repost,
+      action => action,
+    }
+  }
+}
+
+/// `[[bots]]`, another bot with its own token and enabled chats.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct BotEntry {
+  pub telegram_token: String,
+  #[serde(default = "Default::default")]
+  pub enabled_chats: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct Chat {
+  pub id: String,
+  /// Number of the bot the entry is for, 0 for the bot of `telegram-token`
+  /// and 1 for the first of `[[bots]]`.
+  #[serde(default = "Default::default")]
+  pub bot: usize,
+  pub action: Option<Action>,
+  pub trial: Option<Trial>,
+  /// Replacers not applied in this chat.
+  #[serde(default = "Default::default")]
+  pub disable: Vec<String>,
+  /// Delete shop ads forwarded from channels instead of cleaning them.
+  #[serde(default = "Default::default")]
+  pub delete_ads: bool,
+  /// Overrides the global `timezone`.
+  pub timezone: Option<Tz>,
+  /// Overrides the global `notifications`.
+  pub notifications: Option<bool>,
+  /// Overrides `stats.summary`.
+  pub summary: Option<Summary>,
+  pub template: Option<ChatTemplate>,
+}
+
+/// Reply-only period after a chat is enabled.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct Trial {
+  pub since: NaiveDate,
+  pub days: u32,
+}
+
+impl Trial {
+  /// Trials ending past the last representable date never end.
+  pub fn is_active(&self, today: NaiveDate) -> bool {
+    self
+      .since
+      .checked_add_days(chrono::Days::new(self.days.into()))
+      .is_none_or(|end| today < end)
+  }
+}
+
+pub struct ChatSettings<'a> {
+  pub action: Action,
+  pub trial: Option<&'a Trial>,
+  pub disabled: &'a [String],
+  pub delete_ads: bool,
+  /// Of the chat or the global one, `None` for the server's.
+  pub timezone: Option<Tz>,
+  /// Of the chat or the global one.
+  pub notifications: bool,
+  /// Of the chat or `stats.summary`.
+  pub summary: Summary,
+  /// Overrides of `[template]`.
+  pub template: Option<&'a ChatTemplate>,
+}
+
+impl ChatSettings<'_> {
+  /// Action for now, and whether it's forced by the trial.
+  pub fn current_action(&self) -> (Action, bool) {
+    let today = match self.timezone {
+      Some(tz) => Utc::now().with_timezone(&tz).date_naive(),
+      None => Local::now().date_naive(),
+    };
+    match self.trial {
+      // auditing never touches messages, trial or not
+      Some(trial) if trial.is_active(today) && self.action != Action::Audit => {
+        (Action::Reply, true)
+      },
+      _ => (self.action, false),
+    }
+  }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+struct Time {
+  fetch_delay: u64,
+  failed_delay: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct Timeouts {
+  /// Delay between fetching updates.
+  #[serde(with = "humantime_serde")]
+  pub fetch_delay: Duration,
+  /// Delay before fetching again after a failure.
+  #[serde(with = "humantime_serde")]
+  pub failed_delay: Duration,
+  /// How long telegram holds `getUpdates` open waiting for new updates.
+  #[serde(with = "humantime_serde")]
+  pub long_poll: Duration,
+  /// Limit of resolving a short link.
+  #[serde(with = "humantime_serde")]
+  pub resolve: Duration,
+  /// How long in-flight updates may take to finish on shutdown.
+  #[serde(with = "humantime_serde")]
+  pub shutdown_grace: Duration,
+  /// Interval of checking the config file for changes, zero disables reloading.
+  #[serde(with = "humantime_serde")]
+  pub reload_interval: Duration,
+  /// Interval of pruning in-memory state and vacuuming the database, zero
+  /// disables it.
+  #[serde(with = "humantime_serde")]
+  pub maintenance_interval: Duration,
+  /// How long the first message of an album waits for the rest.
+  #[serde(with = "humantime_serde")]
+  pub album_wait: Duration,
+}
+
+impl Default for Timeouts {
+  fn default() -> Self {
+    Self {
+      fetch_delay: Duration::from_secs(1),
+      failed_delay: Duration::from_secs(5),
+      long_poll: Duration::ZERO,
+      resolve: Duration::from_secs(10),
+      shutdown_grace: Duration::from_secs(5),
+      reload_interval: Duration::from_secs(5),
+      maintenance_interval: Duration::from_secs(7 * 24 * 60 * 60),
+      album_wait: Duration::from_secs(1),
+    }
+  }
+}
+
+impl Timeouts {
+  fn validate(&self) -> Result<()> {
+    if self.failed_delay.is_zero() {
+      bail!("timeouts.failed-delay must be longer than 0s, or failed fetching would spin");
+    }
+    if self.resolve.is_zero() {
+      bail!("timeouts.resolve must be longer than 0s, e.g. \"10s\"");
+    }
+    if self.long_poll.subsec_nanos() != 0 {
+      bail!(
+        "timeouts.long-poll must be whole seconds, got \"{}\"",
+        humantime::format_duration(self.long_poll)
+      );
+    }
+    if self.long_poll > MAX_LONG_POLL {
+      bail!(
+        "timeouts.long-poll must be at most {}, got \"{}\"",
+        humantime::format_duration(MAX_LONG_POLL),
+        humantime::format_duration(self.long_poll)
+      );
+    }
+    Ok(())
+  }
+}
+
+/// Longer polls are likely cut by proxies or NAT before telegram answers.
+const MAX_LONG_POLL: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct Embed {
+  #[serde(with = "humantime_serde")]
+  pub probe_interval: Duration,
+  pub twitter: String,
+  pub tiktok: String,
+  pub instagram: String,
+}
+
+impl Default for Embed {
+  fn default() -> Self {
+    Self {
+      probe_interval: Duration::from_secs(300),
+      twitter: Service::Twitter.default_domain().to_string(),
+      tiktok: Service::TikTok.default_domain().to_string(),
+      instagram: Service::Instagram.default_domain().to_string(),
+    }
+  }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct Template {
+  pub domain_summary: bool,
+  /// Markup of cleaned messages and of the templates below.
+  pub parse_mode: Markup,
+  /// First line of reposts, with `{user}`, `{date}` and `{link_count}`.
+  pub header: String,
+  /// Layout of reposts, with the `header` variables, `{header}`, `{text}`
+  /// and `{forward}`.
+  pub repost: String,
+  /// Line of forwarded reposts, with `{source}`, see [`Template::forward`].
+  pub forward: Option<String>,
+  pub link_preview: LinkPreview,
+}
+
+impl Default for Template {
+  fn default() -> Self {
+    Self {
+      domain_summary: true,
+      parse_mode: Markup::default(),
+      header: "Send by {user}:".to_string(),
+      repost: "{header}\n\n{text}\n\n{forward}".to_string(),
+      forward: None,
+      link_preview: LinkPreview::default(),
+    }
+  }
+}
+
+impl Template {
+  /// This template with the overrides of a chat.
+  pub fn for_chat(&self, chat: Option<&ChatTemplate>) -> Self {
+    let mut template = self.clone();
+    if let Some(chat) = chat {
+      let fields = [
+        (&mut template.header, &chat.header),
+        (&mut template.repost, &chat.repost),
+      ];
+      for (field, chat) in fields {
+        if let Some(chat) = chat {
+          field.clone_from(chat);
+        }
+      }
+      if chat.forward.is_some() {
+        template.forward.clone_from(&chat.forward);
+      }
+      template.parse_mode = chat.parse_mode.unwrap_or(template.parse_mode);
+    }
+    template
+  }
+
+  /// The `forward` line, italic in `parse_mode` when unset.
+  pub fn forward(&self) -> &str {
+    match (&self.forward, self.parse_mode) {
+      (Some(forward), _) => forward,
+      (None, Markup::Html) => "<i>forwarded from {source}</i>",
+      (None, Markup::MarkdownV2) => "_forwarded from {source}_",
+    }
+  }
+}
+
+/// How cleaned messages are formatted, see telegram's formatting options.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub enum Markup {
+  #[default]
+  Html,
+  MarkdownV2,
+}
+
+/// `[chats.template]`, overriding parts of `[template]` in a chat.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct ChatTemplate {
+  pub parse_mode: Option<Markup>,
+  pub header: Option<String>,
+  pub repost: Option<String>,
+  pub forward: Option<String>,
+}
+
+/// Which link telegram previews in cleaned messages.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub enum LinkPreview {
+  /// Whichever telegram picks, which may be the sender in the header.
+  #[default]
+  Auto,
+  Disabled,
+  /// The first cleaned link.
+  FirstLink,
+}
+
+/// `[logging]`, log output besides the console.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct Logging {
+  /// Also write logs to this file.
+  pub file: Option<PathBuf>,
+  /// Size the file is rolled over at.
+  pub max_size: ByteSize,
+  /// Rolled files kept as `<file>.0` (newest) to `<file>.<keep - 1>`.
+  pub keep: u32,
+  pub format: LogFormat,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub enum LogFormat {
+  #[default]
+  Text,
+  /// One JSON object per line, with fields like `update_id`, `chat_id` and
+  /// `replacer`.
+  Json,
+}
+
+impl Default for Logging {
+  fn default() -> Self {
+    Self {
+      file: None,
+      max_size: ByteSize::mib(10),
+      keep: 5,
+      format: LogFormat::Text,
+    }
+  }
+}
+
+impl Logging {
+  fn validate(&self) -> Result<()> {
+    if self.max_size.as_u64() == 0 {
+      bail!("logging.max-size must be larger than 0, e.g. \"10 MiB\"");
+    }
+    Ok(())
+  }
+}
+
+/// `[sentry]`, only used when built with the `sentry` feature.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct Sentry {
+  pub dsn: Option<String>,
+  pub environment: Option<String>,
+}
+
+/// `[updates]`, how many updates are processed at once.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct Updates {
+  /// Updates processed concurrently, read at startup.
+  pub concurrency: usize,
+  /// Updates waiting for a free slot, polling pauses beyond this.
+  pub queue: usize,
+}
+
+impl Default for Updates {
+  fn default() -> Self {
+    Self {
+      concurrency: 16,
+      queue: 256,
+    }
+  }
+}
+
+/// `[runtime]`, the async runtime, read at startup.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct Runtime {
+  /// Threads of a multi-threaded runtime, a single thread when unset.
+  pub worker_threads: Option<usize>,
+}
+
+/// `[retry]`, retrying telegram calls that failed for transient reasons.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct Retry {
+  /// Tries of a call in total, 1 disables retrying.
+  pub attempts: u32,
+  /// Delay before the first retry, doubled for each one after.
+  #[serde(with = "humantime_serde")]
+  pub base_delay: Duration,
+  /// Longest delay between tries, also capping telegram's `retry_after`.
+  #[serde(with = "humantime_serde")]
+  pub max_delay: Duration,
+}
+
+impl Default for Retry {
+  fn default() -> Self {
+    Self {
+      attempts: 3,
+      base_delay: Duration::from_millis(500),
+      max_delay: Duration::from_secs(10),
+    }
+  }
+}
+
+/// `[stats]`, the cleaned links recorded in the database for `/stats`.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct Stats {
+  /// Period `/stats` reports on without an argument.
+  #[serde(with = "humantime_serde")]
+  pub window: Duration,
+  /// How long cleaned links are kept, pruned during maintenance.
+  #[serde(with = "humantime_serde")]
+  pub keep: Duration,
+  /// When chats get a summary of the links cleaned in the past week.
+  pub summary: Summary,
+}
+
+impl Default for Stats {
+  fn default() -> Self {
+    Self {
+      window: Duration::from_secs(7 * 24 * 60 * 60),
+      keep: Duration::from_secs(90 * 24 * 60 * 60),
+      summary: Summary::Off,
+    }
+  }
+}
+
+/// A weekly schedule like `"sun 20:00"` in the timezone of the chat, or
+/// `"off"`.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum Summary {
+  #[default]
+  Off,
+  Weekly {
+    day: Weekday,
+    time: NaiveTime,
+  },
+}
+
+impl TryFrom<String> for Summary {
+  type Error = String;
+
+  fn try_from(str: String) -> Result<Self, Self::Error> {
+    if str == "off" {
+      return Ok(Self::Off);
+    }
+    let Some((day, time)) = str.split_once(' ') else {
+      return Err(format!(
+        "expected \"off\" or a day and time like \"sun 20:00\", found \"{str}\""
+      ));
+    };
+    let day = day
+      .parse()
+      .map_err(|_| format!("unknown day of the week \"{day}\""))?;
+    let time = NaiveTime::parse_from_str(time, "%H:%M")
+      .map_err(|_| format!("invalid time \"{time}\", expected one like \"20:00\""))?;
+    Ok(Self::Weekly { day, time })
+  }
+}
+
+impl Display for Summary {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Off => write!(f, "off"),
+      Self::Weekly { day, time } => write!(f, "{day} {}", time.format("%H:%M")),
+    }
+  }
+}
+
+impl Summary {
+  /// Unix time of the latest summary due by `now` in `timezone`, the
+  /// server's when `None`.
+  pub fn last_due(&self, now: DateTime<Utc>, timezone: Option<Tz>) -> Option<u64> {
+    let Self::Weekly { day, time } = *self else {
+      return None;
+    };
+    match timezone {
+      Some(tz) => last_due(now.with_timezone(&tz), day, time),
+      None => last_due(now.with_timezone(&Local), day, time),
+    }
+  }
+}
+
+fn last_due<Z: TimeZone>(now: DateTime<Z>, day: Weekday, time: NaiveTime) -> Option<u64> {
+  let days_back = (7 + now.weekday().num_days_from_monday() - day.num_days_from_monday()) % 7;
+  let mut date = now.date_naive() - chrono::Duration::days(days_back.into());
+  let tz = now.timezone();
+  for _ in 0..2 {
+    // skipped by daylight saving time when `None`, like that week's summary
+    if let Some(due) = tz.from_local_datetime(&date.and_time(time)).earliest() {
+      if due <= now {
+        return Some(due.timestamp() as u64);
+      }
+    }
+    date -= chrono::Duration::days(7);
+  }
+  None
+}
+
+/// `[health]`, the `/healthz` endpoint.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct Health {
+  /// Address to serve on, disabled when unset.
+  pub listen: Option<SocketAddr>,
+  /// Bearer token `/rules` and `/rules/reload` require, which only answer
+  /// requests from this host when unset.
+  pub token: Option<String>,
+  /// Unhealthy once `getUpdates` hasn't succeeded for this long.
+  #[serde(with = "humantime_serde")]
+  pub stale_after: Duration,
+}
+
+impl Default for Health {
+  fn default() -> Self {
+    Self {
+      listen: None,
+      token: None,
+      stale_after: Duration::from_secs(120),
+    }
+  }
+}
+
+/// `[tracing]`, only used when built with the `otel` feature.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct Tracing {
+  /// OTLP/HTTP traces endpoint, spans are exported when set.
+  pub otlp_endpoint: Option<String>,
+  pub service_name: String,
+}
+
+impl Default for Tracing {
+  fn default() -> Self {
+    Self {
+      otlp_endpoint: None,
+      service_name: "fuckburl-bot".to_string(),
+    }
+  }
+}
+
+/// `[resolver]`, resolving short links.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct Resolver {
+  /// Least time between two requests to the same host.
+  #[serde(with = "humantime_serde")]
+  pub host_interval: Duration,
+  /// Redirects followed before giving up on a short link.
+  pub max_redirects: usize,
+  /// Generic shorteners, whose links are all resolved the same way.
+  pub shorteners: Vec<Shortener>,
+  pub headers: ResolverHeaders,
+  /// Never sends cookies or referers and sticks to HTTP/1.1 without reusing
+  /// connections, so shorteners can't tell requests apart as the bot's.
+  pub private: bool,
+  /// Proxy for resolving only, instead of `proxy`, like Tor's
+  /// `socks5h://127.0.0.1:9050`.
+  pub proxy: Option<String>,
+}
+
+impl Default for Resolver {
+  fn default() -> Self {
+    Self {
+      host_interval: Duration::from_millis(200),
+      max_redirects: 5,
+      shorteners: [
+        "bit.ly",
+        "tinyurl.com",
+        "is.gd",
+        "goo.gl",
+        "reurl.cc",
+        "ow.ly",
+        "buff.ly",
+        "t.ly",
+        "cutt.ly",
+        "rebrand.ly",
+      ]
+      .into_iter()
+      .map(|domain| Shortener {
+        domain: domain.to_string(),
+        keep: None,
+      })
+      .collect(),
+      headers: ResolverHeaders::default(),
+      private: false,
+      proxy: None,
+    }
+  }
+}
+
+/// `[resolver.headers]`, headers sent while resolving, like `user-agent =
+/// "..."`, and tables of them by domain, like `[resolver.headers."xhslink.com"]`.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(try_from = "BTreeMap<String, RawHeader>")]
+pub struct ResolverHeaders {
+  global: HeaderMap,
+  /// Headers of a domain and its subdomains, over the global ones.
+  domains: Vec<(String, HeaderMap)>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawHeader {
+  Value(String),
+  Domain(BTreeMap<String, String>),
+}
+
+fn header(name: &str, value: &str) -> Result<(HeaderName, HeaderValue), String> {
+  let name = HeaderName::from_bytes(name.as_bytes())
+    .map_err(|_| format!("invalid header name \"{name}\""))?;
+  let value =
+    HeaderValue::from_str(value).map_err(|_| format!("invalid value of header \"{name}\""))?;
+  Ok((name, value))
+}
+
+impl TryFrom<BTreeMap<String, RawHeader>> for ResolverHeaders {
+  type Error = String;
+
+  fn try_from(raw: BTreeMap<String, RawHeader>) -> Result<Self, Self::Error> {
+    let mut headers = Self::default();
+    for (key, entry) in raw {
+      match entry {
+        RawHeader::Value(value) => {
+          let (name, value) = header(&key, &value)?;
+          headers.global.insert(name, value);
+        },
+        RawHeader::Domain(table) => {
+          let mut map = HeaderMap::new();
+          for (name, value) in table {
+            let (name, value) = header(&name, &value)?;
+            map.insert(name, value);
+          }
+          headers.domains.push((key, map));
+        },
+      }
+    }
+    // the most specific domain is applied last
+    headers.domains.sort_by_key(|(domain, _)| domain.len());
+    Ok(headers)
+  }
+}
+
+impl ResolverHeaders {
+  /// Headers of requests to `host`.
+  pub fn of(&self, host: &str) -> HeaderMap {
+    let mut headers = self.global.clone();
+    for (domain, map) in &self.domains {
+      if host == domain || host.ends_with(&format!(".{domain}")) {
+        headers.extend(map.clone());
+      }
+    }
+    headers
+  }
+}
+
+/// `[[resolver.shorteners]]`, a shortener without a replacer of its own.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct Shortener {
+  /// Links of this host and its `www.` are resolved.
+  pub domain: String,
+  /// Params kept in the query of where links lead, all of them when unset
+  /// and none when empty.
+  pub keep: Option<Vec<String>>,
+}
+
+/// `[cache]`, limits of each in-memory cache, like of resolved short links
+/// and chat admins.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct Cache {
+  pub max_entries: usize,
+  pub max_bytes: ByteSize,
+}
+
+impl Default for Cache {
+  fn default() -> Self {
+    Self {
+      max_entries: 10_000,
+      max_bytes: ByteSize::mib(4),
+    }
+  }
+}
+
+impl Cache {
+  pub fn limits(&self) -> CacheLimits {
+    CacheLimits {
+      max_entries: self.max_entries,
+      max_bytes: self.max_bytes,
+    }
+  }
+}
+
+/// `[spam]`, escalating from cleaning messages that score as spam.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct Spam {
+  /// Ping the chat admins from this score on, disabled when unset.
+  pub report_score: Option<u32>,
+  /// Delete without reposting from this score on, disabled when unset.
+  pub delete_score: Option<u32>,
+  /// Earlier messages with links by the same user within this add to the
+  /// score.
+  #[serde(with = "humantime_serde")]
+  pub repeat_window: Duration,
+}
+
+impl Default for Spam {
+  fn default() -> Self {
+    Self {
+      report_score: None,
+      delete_score: None,
+      repeat_window: Duration::from_secs(600),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TOKEN: &str = "telegram-token = \"123:abc\"\n";
+
+  #[test]
+  fn resolver() {
+    let config = Config::from_toml(TOKEN).unwrap().0;
+    assert_eq!(Duration::from_millis(200), config.resolver.host_interval);
+    let config = Config::from_toml(&format!("{TOKEN}[resolver]\nhost-interval = \"1s\"\n"))
+      .unwrap()
+      .0;
+    assert_eq!(Duration::from_secs(1), config.resolver.host_interval);
+    assert_eq!("bit.ly", config.resolver.shorteners[0].domain);
+    assert!(config.resolver.headers.of("xhslink.com").is_empty());
+
+    let config = Config::from_toml(&format!(
+      "{TOKEN}[[resolver.shorteners]]\ndomain = \"s.id\"\nkeep = [\"id\"]\n"
+    ))
+    .unwrap()
+    .0;
+    assert_eq!(
+      vec![Shortener {
+        domain: "s.id".to_string(),
+        keep: Some(vec!["id".to_string()]),
+      }],
+      config.resolver.shorteners
+    );
+  }
+
+  #[test]
+  fn resolver_headers() {
+    let config = Config::from_toml(&format!(
+      "{TOKEN}[resolver.headers]\nuser-agent = \"bot\"\naccept-language = \"zh-CN\"\n\
+       [resolver.headers.\"xhslink.com\"]\nuser-agent = \"Mozilla/5.0\"\n"
+    ))
+    .unwrap()
+    .0;
+    let headers = config.resolver.headers.of("www.xhslink.com");
+    assert_eq!("Mozilla/5.0", headers["user-agent"]);
+    assert_eq!("zh-CN", headers["accept-language"]);
+    assert_eq!("bot", config.resolver.headers.of("b23.tv")["user-agent"]);
+
+    let err = Config::from_toml(&format!(
+      "{TOKEN}[resolver.headers]\n\"user agent\" = \"bot\"\n"
+    ))
+    .unwrap_err();
+    assert!(
+      format!("{err:#}").contains("invalid header name \"user agent\""),
+      "{err:#}"
+    );
+  }
+
+  #[test]
+  fn trials() {
+    let since = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+    let trial = Trial { since, days: 7 };
+    assert!(trial.is_active(NaiveDate::from_ymd_opt(2024, 1, 7).unwrap()));
+    assert!(!trial.is_active(NaiveDate::from_ymd_opt(2024, 1, 8).unwrap()));
+    let forever = Trial {
+      since,
+      days: 4_000_000_000,
+    };
+    assert!(forever.is_active(since));
+  }
+
+  #[test]
+  fn bots() {
+    let config = Config::from_toml(&format!(
+      "{TOKEN}enabled-chats = [\"-1\"]\n\
+       [[bots]]\ntelegram-token = \"456:def\"\nenabled-chats = [\"-2\"]\n\
+       [[chats]]\nid = \"-3\"\n[[chats]]\nid = \"-4\"\nbot = 1\n"
+    ))
+    .unwrap()
+    .0;
+    assert_eq!("456:def", config.bots[0].telegram_token);
+    assert!(config.chat(-1).is_some());
+    assert!(config.chat(-2).is_none());
+    assert!(config.chat(-3).is_some());
+    assert!(config.chat(-4).is_none());
+    assert!(config.chat_of(Some(0), -1, None).is_none());
+    assert!(config.chat_of(Some(0), -2, None).is_some());
+    assert!(config.chat_of(Some(0), -3, None).is_none());
+    assert!(config.chat_of(Some(0), -4, None).is_some());
+    assert!(config.chat_of(Some(1), -2, None).is_none());
+    // stored enabled chats decide, [[chats]] only add settings
+    assert!(config.chat_in(None, &[], -3, None).is_none());
+    assert!(config
+      .chat_in(None, &["-3".to_string()], -3, None)
+      .is_some());
+  }
+
+  #[test]
+  fn disabled_chats() {
+    let chats = "disabled-chats = [\"-1\", \"@spam\"]\n[[chats]]\nid = \"-1\"\n";
+    let (config, _) =
+      Config::from_toml(&format!("{TOKEN}enabled-chats = [\"-2\"]\n{chats}")).unwrap();
+    assert!(config.chat(-1).is_none());
+    assert!(config.chat(-2).is_some());
+    assert!(config.chat(-3).is_none());
+    let (config, _) = Config::from_toml(&format!("{TOKEN}enable-all = true\n{chats}")).unwrap();
+    assert!(config.chat(-1).is_none());
+    assert!(config.chat_of(None, -2, Some("spam")).is_none());
+    assert!(config.chat(-3).is_some());
+  }
+
+  #[test]
+  fn usernames() {
+    let config = Config::from_toml(&format!(
+      "{TOKEN}enabled-chats = [\"@MyGroup\"]\n[[chats]]\nid = \"@other\"\naction = \"reply\"\n"
+    ))
+    .unwrap()
+    .0;
+    assert!(config.chat_of(None, -1, Some("mygroup")).is_some());
+    assert!(config.chat_of(None, -1, Some("mygroup2")).is_none());
+    assert!(config.chat(-1).is_none());
+    let chat = config.chat_of(None, -2, Some("other")).unwrap();
+    assert_eq!(Action::Reply, chat.action);
+  }
+
+  #[test]
+  fn timezones() {
+    let (config, issues) = Config::from_toml(&format!(
+      "timezone = \"Asia/Shanghai\"\n{TOKEN}enabled-chats = [\"-1\"]\n\
+       [[chats]]\nid = \"-2\"\ntimezone = \"Europe/Berlin\"\n\
+       [[chats]]\nid = \"-3\"\ntimezone = \"Mars/Olympus\"\n"
+    ))
+    .unwrap();
+    assert_eq!(Some(Tz::Asia__Shanghai), config.chat(-1).unwrap().timezone);
+    assert_eq!(Some(Tz::Europe__Berlin), config.chat(-2).unwrap().timezone);
+    assert_eq!("chats[1]", issues[0].entry);
+    assert!(Config::from_toml(&format!("timezone = \"Nowhere\"\n{TOKEN}")).is_err());
+  }
+
+  #[test]
+  fn summaries() {
+    let (config, issues) = Config::from_toml(&format!(
+      "{TOKEN}enabled-chats = [\"-1\"]\n[stats]\nsummary = \"sun 20:00\"\n\
+       [[chats]]\nid = \"-2\"\nsummary = \"off\"\n\
+       [[chats]]\nid = \"-3\"\nsummary = \"funday 20:00\"\n"
+    ))
+    .unwrap();
+    let summary = config.chat(-1).unwrap().summary;
+    assert_eq!("Sun 20:00", summary.to_string());
+    assert_eq!(Summary::Off, config.chat(-2).unwrap().summary);
+    assert_eq!("chats[1]", issues[0].entry);
+
+    let at = |date: &str| date.parse::<DateTime<Utc>>().unwrap();
+    let unix = |date: &str| Some(at(date).timestamp() as u64);
+    let shanghai = Some(Tz::Asia__Shanghai);
+    assert_eq!(
+      unix("2024-01-07T12:00:00Z"),
+      summary.last_due(at("2024-01-10T12:00:00Z"), shanghai)
+    );
+    assert_eq!(
+      unix("2024-01-07T12:00:00Z"),
+      summary.last_due(at("2024-01-07T12:00:00Z"), shanghai)
+    );
+    assert_eq!(
+      unix("2023-12-31T12:00:00Z"),
+      summary.last_due(at("2024-01-07T11:59:00Z"), shanghai)
+    );
+    assert_eq!(
+      None,
+      Summary::Off.last_due(at("2024-01-07T12:00:00Z"), None)
+    );
+  }
+
+  #[test]
+  fn whitelisted_users() {
+    let config = Config::from_toml(&format!(
+      "whitelisted-users = [\"42\", \"@TrustedBot\"]\n{TOKEN}"
+    ))
+    .unwrap()
+    .0;
+    assert!(config.is_whitelisted(42, None));
+    assert!(config.is_whitelisted(7, Some("trustedbot")));
+    assert!(!config.is_whitelisted(7, Some("alice")));
+  }
+
+  #[test]
+  fn notifications() {
+    let chats = "enabled-chats = [\"-1\"]\n[[chats]]\nid = \"-2\"\nnotifications = true\n";
+    let config = Config::from_toml(&format!("{TOKEN}{chats}")).unwrap().0;
+    assert!(config.chat(-1).unwrap().notifications);
+    let config = Config::from_toml(&format!("notifications = false\n{TOKEN}{chats}"))
+      .unwrap()
+      .0;
+    assert!(!config.chat(-1).unwrap().notifications);
+    assert!(config.chat(-2).unwrap().notifications);
+  }
+
+  #[test]
+  fn parse_modes() {
+    let chats = "[[chats]]\nid = \"-2\"\ntemplate = { parse-mode = \"html\" }\n";
+    let config = Config::from_toml(&format!(
+      "{TOKEN}{chats}[template]\nparse-mode = \"markdown-v2\"\n"
+    ))
+    .unwrap()
+    .0;
+    assert_eq!(Markup::MarkdownV2, config.template.parse_mode);
+    assert_eq!("_forwarded from {source}_", config.template.forward());
+    let template = config.template.for_chat(config.chat(-2).unwrap().template);
+    assert_eq!(Markup::Html, template.parse_mode);
+    assert_eq!("<i>forwarded from {source}</i>", template.forward());
+  }
+
+  #[test]
+  fn humantime_timeouts() {
+    let config = Config::from_toml(&format!(
+      "{TOKEN}[timeouts]\nfetch-delay = \"500ms\"\nlong-poll = \"30s\"\n"
+    ))
+    .unwrap()
+    .0;
+    assert_eq!(Duration::from_millis(500), config.timeouts.fetch_delay);
+    assert_eq!(Duration::from_secs(30), config.timeouts.long_poll);
+    assert_eq!(Duration::from_secs(5), config.timeouts.failed_delay);
+  }
+
+  #[test]
+  fn legacy_time() {
+    let config = Config::from_toml(&format!(
+      "{TOKEN}[time]\nfetch-delay = 2000\nfailed-delay = 100\n"
+    ))
+    .unwrap()
+    .0;
+    assert_eq!(Duration::from_secs(2), config.timeouts.fetch_delay);
+    assert_eq!(Duration::from_millis(100), config.timeouts.failed_delay);
+  }
+
+  #[test]
+  fn invalid_timeouts() {
+    let err = Config::from_toml(&format!("{TOKEN}[timeouts]\nlong-poll = \"1.5s\"\n")).unwrap_err();
+    assert!(err.to_string().contains("whole seconds"));
+    assert!(Config::from_toml(&format!("{TOKEN}[timeouts]\nresolve = \"0s\"\n")).is_err());
+    assert!(Config::from_toml(&format!("{TOKEN}[timeouts]\nresolve = 10\n")).is_err());
+  }
+
+  #[test]
+  fn logging() {
+    let config = Config::from_toml(&format!(
+      "{TOKEN}[logging]\nfile = \"bot.log\"\nmax-size = \"1 MiB\"\n"
+    ))
+    .unwrap()
+    .0;
+    assert_eq!(Some(PathBuf::from("bot.log")), config.logging.file);
+    assert_eq!(ByteSize::mib(1), config.logging.max_size);
+    assert_eq!(5, config.logging.keep);
+    assert_eq!(LogFormat::Text, config.logging.format);
+    let config = Config::from_toml(&format!("{TOKEN}[logging]\nformat = \"json\"\n"))
+      .unwrap()
+      .0;
+    assert_eq!(LogFormat::Json, config.logging.format);
+    assert!(Config::from_toml(&format!("{TOKEN}[logging]\nmax-size = 0\n")).is_err());
+  }
+
+  #[test]
+  fn health() {
+    let config = Config::from_toml(&format!("{TOKEN}[health]\nlisten = \"127.0.0.1:8080\"\n"))
+      .unwrap()
+      .0;
+    assert_eq!(
+      Some("127.0.0.1:8080".parse().unwrap()),
+      config.health.listen
+    );
+    assert_eq!(None, config.health.token);
+    assert!(Config::from_toml(&format!(
+      "{TOKEN}[timeouts]\nlong-poll = \"5m\"\n[health]\nstale-after = \"1m\"\n"
+    ))
+    .is_err());
+  }
+
+  #[test]
+  fn skip_bad_entries() {
+    let (config, issues) = Config::from_toml(&format!(
+      r#"{TOKEN}
+[[chats]]
+id = "-1001"
+
+[[chats]]
+action = "reply"
+
+[[rules]]
+name = "good"
+pattern = 'https://example\.com/\S*'
+keep = ["id"]
+
+[[rules]]
+name = "bad"
+pattern = '(unclosed'
+replace = "$0"
+"#
+    ))
+    .unwrap();
+    assert_eq!(1, config.chats.len());
+    assert_eq!(1, config.rules.len());
+    assert_eq!(2, issues.len());
+    assert_eq!("chats[1]", issues[0].entry);
+    assert_eq!(6, issues[0].line);
+    assert!(issues[0].message.contains("`id`"));
+    assert_eq!("rules[1]", issues[1].entry);
+    assert_eq!(14, issues[1].line);
+    assert!(issues[1].message.contains("`pattern`"));
+  }
+
+  #[test]
+  fn rules_file() {
+    let (file, issues) = RulesFile::from_toml(
+      r#"tracking-params = ["from"]
+[[rules]]
+name = "good"
+pattern = 'https://example\.com/\S*'
+keep = ["id"]
+"#,
+    )
+    .unwrap();
+    assert!(issues.is_empty());
+    assert_eq!(vec!["from".to_string()], file.tracking_params);
+    assert_eq!("good", file.rules[0].name);
+  }
+
+  #[test]
+  fn command_rules() {
+    let (file, issues) = RulesFile::from_toml(
+      r#"[[rules]]
+name = "cleaner"
+pattern = 'https://example\.com/\S*'
+command = "/usr/local/bin/my-cleaner"
+timeout = "500ms"
+
+[[rules]]
+name = "both"
+pattern = 'x'
+command = "cat"
+replace = "y"
+"#,
+    )
+    .unwrap();
+    let CustomAction::Command {
+      program, timeout, ..
+    } = &file.rules[0].action
+    else {
+      panic!("{:?}", file.rules[0].action);
+    };
+    assert_eq!("/usr/local/bin/my-cleaner", program);
+    assert_eq!(Duration::from_millis(500), *timeout);
+    assert_eq!(1, issues.len());
+    assert!(issues[0].message.contains("exactly one"));
+  }
+}
@@ -0,0 +1,552 @@
+//! State kept across restarts, in SQLite.
+
+use std::{path::Path, sync::Mutex};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+
+/// Schema changes, `user_version` is the number applied.
+const MIGRATIONS: &[&str] = &[
+  "CREATE TABLE downgrades (
+    chat_id INTEGER PRIMARY KEY,
+    since INTEGER NOT NULL,
+    reason TEXT NOT NULL
+  );",
+  "CREATE TABLE enabled_chats (
+    bot INTEGER NOT NULL,
+    entry TEXT NOT NULL,
+    PRIMARY KEY (bot, entry)
+  );
+  CREATE TABLE seeded_bots (bot INTEGER PRIMARY KEY);",
+  "CREATE TABLE audits (
+    chat_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    date INTEGER NOT NULL,
+    site TEXT NOT NULL,
+    original TEXT NOT NULL,
+    cleaned TEXT NOT NULL
+  );
+  CREATE INDEX audits_chat ON audits (chat_id);",
+  "CREATE TABLE replacements (
+    chat_id INTEGER NOT NULL,
+    user_id INTEGER,
+    username TEXT,
+    domain TEXT NOT NULL,
+    date INTEGER NOT NULL
+  );
+  CREATE INDEX replacements_date ON replacements (date);",
+  "CREATE TABLE summaries (
+    chat_id INTEGER PRIMARY KEY,
+    date INTEGER NOT NULL
+  );",
+  "CREATE TABLE opted_out (user_id INTEGER PRIMARY KEY);",
+  "CREATE TABLE seeded_chats (
+    bot INTEGER NOT NULL,
+    entry TEXT NOT NULL,
+    PRIMARY KEY (bot, entry)
+  );",
+];
+
+/// A chat switched from repost to reply, as the bot can't delete there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Downgrade {
+  pub chat_id: i64,
+  /// Unix time.
+  pub since: i64,
+  pub reason: String,
+}
+
+/// What an audited chat shared, see [`Db::audit_summary`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct AuditSummary {
+  pub messages: u64,
+  pub links: u64,
+  /// Links by site, most first.
+  pub sites: Vec<(String, u64)>,
+}
+
+/// Cleaned links since some time, see [`Db::replacement_stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ReplacementStats {
+  pub links: u64,
+  /// Links by chat, most first.
+  pub chats: Vec<(i64, u64)>,
+  pub domains: Vec<(String, u64)>,
+  /// Links by sender id and their latest username.
+  pub offenders: Vec<(i64, Option<String>, u64)>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Db {
+  conn: Mutex<Connection>,
+}
+
+impl Db {
+  pub fn open(path: &Path) -> Result<Self> {
+    let conn = Connection::open(path)
+      .with_context(|| format!("Failed to open database: {}", path.to_string_lossy()))?;
+    Self::init(conn)
+  }
+
+  #[cfg(test)]
+  pub fn in_memory() -> Result<Self> {
+    Self::init(Connection::open_in_memory()?)
+  }
+
+  fn init(mut conn: Connection) -> Result<Self> {
+    let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    let version = version as usize;
+    let tx = conn.transaction()?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+      tx.execute_batch(migration)
+        .with_context(|| format!("Failed to migrate database to version {}", i + 1))?;
+    }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len().max(version) as i64)?;
+    tx.commit()?;
+    Ok(Self {
+      conn: Mutex::new(conn),
+    })
+  }
+
+  pub fn downgrade(&self, chat_id: i64) -> Result<Option<Downgrade>> {
+    let conn = self.conn.lock().unwrap();
+    Ok(
+      conn
+        .query_row(
+          "SELECT chat_id, since, reason FROM downgrades WHERE chat_id = ?1",
+          [chat_id],
+          |row| {
+            Ok(Downgrade {
+              chat_id: row.get(0)?,
+              since: row.get(1)?,
+              reason: row.get(2)?,
+            })
+          },
+        )
+        .optional()?,
+    )
+  }
+
+  pub fn downgrades(&self) -> Result<Vec<Downgrade>> {
+    let conn = self.conn.lock().unwrap();
+    let mut stmt = conn.prepare("SELECT chat_id, since, reason FROM downgrades ORDER BY since")?;
+    let rows = stmt.query_map([], |row| {
+      Ok(Downgrade {
+        chat_id: row.get(0)?,
+        since: row.get(1)?,
+        reason: row.get(2)?,
+      })
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+  }
+
+  /// Records a downgrade, returns `false` if the chat already was.
+  pub fn add_downgrade(&self, chat_id: i64, since: i64, reason: &str) -> Result<bool> {
+    let conn = self.conn.lock().unwrap();
+    let changed = conn.execute(
+      "INSERT OR IGNORE INTO downgrades (chat_id, since, reason) VALUES (?1, ?2, ?3)",
+      params![chat_id, since, reason],
+    )?;
+    Ok(changed > 0)
+  }
+
+  /// Stores `entries` as the enabled chats of `bot` if they never were, or
+  /// replacing the stored ones with `force`, and enables each of the
+  /// `[[chats]]` ids in `chats` the first time it's seen, so disabling one
+  /// sticks. Returns whether anything was stored.
+  ///
+  /// `bot` is 0 for the bot of `telegram-token`, `i + 1` for `bots[i]`.
+  pub fn seed_enabled_chats(
+    &self,
+    bot: i64,
+    entries: &[String],
+    chats: &[String],
+    force: bool,
+  ) -> Result<bool> {
+    let mut conn = self.conn.lock().unwrap();
+    let tx = conn.transaction()?;
+    let seeded = tx
+      .query_row(
+        "SELECT 1 FROM seeded_bots WHERE bot = ?1",
+        [bot],
+        |_| Ok(()),
+      )
+      .optional()?
+      .is_some();
+    let mut stored = false;
+    if !seeded || force {
+      tx.execute("DELETE FROM enabled_chats WHERE bot = ?1", [bot])?;
+      tx.execute("DELETE FROM seeded_chats WHERE bot = ?1", [bot])?;
+      for entry in entries {
+        tx.execute(
+          "INSERT OR IGNORE INTO enabled_chats (bot, entry) VALUES (?1, ?2)",
+          params![bot, entry],
+        )?;
+      }
+      tx.execute("INSERT OR IGNORE INTO seeded_bots (bot) VALUES (?1)", [bot])?;
+      stored = true;
+    }
+    for entry in chats {
+      let new = tx.execute(
+        "INSERT OR IGNORE INTO seeded_chats (bot, entry) VALUES (?1, ?2)",
+        params![bot, entry],
+      )?;
+      if new > 0 {
+        tx.execute(
+          "INSERT OR IGNORE INTO enabled_chats (bot, entry) VALUES (?1, ?2)",
+          params![bot, entry],
+        )?;
+        stored = true;
+      }
+    }
+    tx.commit()?;
+    Ok(stored)
+  }
+
+  pub fn enabled_chats(&self, bot: i64) -> Result<Vec<String>> {
+    let conn = self.conn.lock().unwrap();
+    let mut stmt = conn.prepare("SELECT entry FROM enabled_chats WHERE bot = ?1 ORDER BY rowid")?;
+    let rows = stmt.query_map([bot], |row| row.get(0))?;
+    Ok(rows.collect::<Result<_, _>>()?)
+  }
+
+  /// Returns `false` if the chat already was enabled.
+  pub fn enable_chat(&self, bot: i64, entry: &str) -> Result<bool> {
+    let conn = self.conn.lock().unwrap();
+    let changed = conn.execute(
+      "INSERT OR IGNORE INTO enabled_chats (bot, entry) VALUES (?1, ?2)",
+      params![bot, entry],
+    )?;
+    Ok(changed > 0)
+  }
+
+  /// Returns `false` if the chat wasn't enabled.
+  pub fn disable_chat(&self, bot: i64, entry: &str) -> Result<bool> {
+    let conn = self.conn.lock().unwrap();
+    let changed = conn.execute(
+      "DELETE FROM enabled_chats WHERE bot = ?1 AND entry = ?2",
+      params![bot, entry],
+    )?;
+    Ok(changed > 0)
+  }
+
+  /// Rebuilds the file to reclaim free pages, returns its size in bytes.
+  pub fn vacuum(&self) -> Result<u64> {
+    let conn = self.conn.lock().unwrap();
+    conn
+      .execute_batch("VACUUM")
+      .context("Failed to vacuum database")?;
+    let pages: i64 = conn.pragma_query_value(None, "page_count", |row| row.get(0))?;
+    let page_size: i64 = conn.pragma_query_value(None, "page_size", |row| row.get(0))?;
+    Ok((pages * page_size) as u64)
+  }
+
+  /// Records the links of message `message_id` in an audited chat, as
+  /// `(site, original, cleaned)`.
+  pub fn add_audit(
+    &self,
+    chat_id: i64,
+    message_id: i32,
+    date: u64,
+    links: &[(&str, &str, &str)],
+  ) -> Result<()> {
+    let mut conn = self.conn.lock().unwrap();
+    let tx = conn.transaction()?;
+    for (site, original, cleaned) in links {
+      tx.execute(
+        "INSERT INTO audits (chat_id, message_id, date, site, original, cleaned)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![chat_id, message_id, date as i64, site, original, cleaned],
+      )?;
+    }
+    tx.commit()?;
+    Ok(())
+  }
+
+  pub fn audit_summary(&self, chat_id: i64) -> Result<AuditSummary> {
+    let conn = self.conn.lock().unwrap();
+    let (messages, links): (i64, i64) = conn.query_row(
+      "SELECT COUNT(DISTINCT message_id), COUNT(*) FROM audits WHERE chat_id = ?1",
+      [chat_id],
+      |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let mut stmt = conn.prepare(
+      "SELECT site, COUNT(*) AS links FROM audits WHERE chat_id = ?1
+       GROUP BY site ORDER BY links DESC, site",
+    )?;
+    let sites = stmt.query_map([chat_id], |row| {
+      Ok((row.get(0)?, row.get::<_, i64>(1)? as u64))
+    })?;
+    Ok(AuditSummary {
+      messages: messages as u64,
+      links: links as u64,
+      sites: sites.collect::<Result<_, _>>()?,
+    })
+  }
+
+  /// Records the links cleaned in a message by `user`, as their domains.
+  pub fn add_replacements(
+    &self,
+    chat_id: i64,
+    user: Option<(u64, Option<&str>)>,
+    date: u64,
+    domains: &[&str],
+  ) -> Result<()> {
+    let mut conn = self.conn.lock().unwrap();
+    let tx = conn.transaction()?;
+    let (user_id, username) = user.unzip();
+    for domain in domains {
+      tx.execute(
+        "INSERT INTO replacements (chat_id, user_id, username, domain, date)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+          chat_id,
+          user_id.map(|id| id as i64),
+          username.flatten(),
+          domain,
+          date as i64
+        ],
+      )?;
+    }
+    tx.commit()?;
+    Ok(())
+  }
+
+  /// Links cleaned since unix time `since`, in `chat` or all of them, with
+  /// the `top` chats, domains and senders.
+  pub fn replacement_stats(
+    &self,
+    since: u64,
+    chat: Option<i64>,
+    top: usize,
+  ) -> Result<ReplacementStats> {
+    let conn = self.conn.lock().unwrap();
+    let params = params![since as i64, top as i64, chat];
+    let links: i64 = conn.query_row(
+      "SELECT COUNT(*) FROM replacements
+       WHERE date >= ?1 AND (?3 IS NULL OR chat_id = ?3)",
+      params,
+      |row| row.get(0),
+    )?;
+    let mut stmt = conn.prepare(
+      "SELECT chat_id, COUNT(*) AS links FROM replacements
+       WHERE date >= ?1 AND (?3 IS NULL OR chat_id = ?3)
+       GROUP BY chat_id ORDER BY links DESC, chat_id LIMIT ?2",
+    )?;
+    let chats = stmt
+      .query_map(params, |row| {
+        Ok((row.get(0)?, row.get::<_, i64>(1)? as u64))
+      })?
+      .collect::<Result<_, _>>()?;
+    let mut stmt = conn.prepare(
+      "SELECT domain, COUNT(*) AS links FROM replacements
+       WHERE date >= ?1 AND (?3 IS NULL OR chat_id = ?3)
+       GROUP BY domain ORDER BY links DESC, domain LIMIT ?2",
+    )?;
+    let domains = stmt
+      .query_map(params, |row| {
+        Ok((row.get(0)?, row.get::<_, i64>(1)? as u64))
+      })?
+      .collect::<Result<_, _>>()?;
+    // the username of the latest row, as sqlite takes bare columns of
+    // aggregates from the row with the max
+    let mut stmt = conn.prepare(
+      "SELECT user_id, username, MAX(date), COUNT(*) AS links FROM replacements
+       WHERE date >= ?1 AND (?3 IS NULL OR chat_id = ?3) AND user_id IS NOT NULL
+       GROUP BY user_id ORDER BY links DESC, user_id LIMIT ?2",
+    )?;
+    let offenders = stmt
+      .query_map(params, |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get::<_, i64>(3)? as u64))
+      })?
+      .collect::<Result<_, _>>()?;
+    Ok(ReplacementStats {
+      links: links as u64,
+      chats,
+      domains,
+      offenders,
+    })
+  }
+
+  /// Unix time of the summary last posted to chat `chat_id`.
+  pub fn last_summary(&self, chat_id: i64) -> Result<Option<u64>> {
+    let conn = self.conn.lock().unwrap();
+    let date: Option<i64> = conn
+      .query_row(
+        "SELECT date FROM summaries WHERE chat_id = ?1",
+        [chat_id],
+        |row| row.get(0),
+      )
+      .optional()?;
+    Ok(date.map(|date| date as u64))
+  }
+
+  /// Records the summary due at unix time `date` as posted to chat
+  /// `chat_id`, returns `false` if it or a later one already was, as by
+  /// another bot in the chat.
+  pub fn claim_summary(&self, chat_id: i64, date: u64) -> Result<bool> {
+    let conn = self.conn.lock().unwrap();
+    let changed = conn.execute(
+      "INSERT INTO summaries (chat_id, date) VALUES (?1, ?2)
+       ON CONFLICT (chat_id) DO UPDATE SET date = excluded.date WHERE date < excluded.date",
+      params![chat_id, date as i64],
+    )?;
+    Ok(changed > 0)
+  }
+
+  /// Forgets links cleaned before unix time `before`, returns how many.
+  pub fn prune_replacements(&self, before: u64) -> Result<usize> {
+    let conn = self.conn.lock().unwrap();
+    Ok(conn.execute("DELETE FROM replacements WHERE date < ?1", [before as i64])?)
+  }
+
+  /// Whether user `user_id` sent `/optout`.
+  pub fn is_opted_out(&self, user_id: u64) -> Result<bool> {
+    let conn = self.conn.lock().unwrap();
+    let found = conn
+      .query_row(
+        "SELECT 1 FROM opted_out WHERE user_id = ?1",
+        [user_id as i64],
+        |_| Ok(()),
+      )
+      .optional()?;
+    Ok(found.is_some())
+  }
+
+  /// Opts user `user_id` out of cleaning, or back in, returns `false` if
+  /// they already were.
+  pub fn set_opted_out(&self, user_id: u64, opted_out: bool) -> Result<bool> {
+    let conn = self.conn.lock().unwrap();
+    let sql = if opted_out {
+      "INSERT OR IGNORE INTO opted_out (user_id) VALUES (?1)"
+    } else {
+      "DELETE FROM opted_out WHERE user_id = ?1"
+    };
+    Ok(conn.execute(sql, [user_id as i64])? > 0)
+  }
+
+  /// Returns `false` if the chat wasn't downgraded.
+  pub fn remove_downgrade(&self, chat_id: i64) -> Result<bool> {
+    let conn = self.conn.lock().unwrap();
+    let changed = conn.execute("DELETE FROM downgrades WHERE chat_id = ?1", [chat_id])?;
+    Ok(changed > 0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{AuditSummary, Db, ReplacementStats};
+
+  #[test]
+  fn downgrades() {
+    let db = Db::in_memory().unwrap();
+    assert_eq!(None, db.downgrade(-1).unwrap());
+    assert!(db.add_downgrade(-1, 10, "not enough rights").unwrap());
+    assert!(!db.add_downgrade(-1, 20, "again").unwrap());
+    let downgrade = db.downgrade(-1).unwrap().unwrap();
+    assert_eq!(10, downgrade.since);
+    assert_eq!(1, db.downgrades().unwrap().len());
+    assert!(db.remove_downgrade(-1).unwrap());
+    assert!(!db.remove_downgrade(-1).unwrap());
+    assert!(db.vacuum().unwrap() > 0);
+  }
+
+  #[test]
+  fn audits() {
+    let db = Db::in_memory().unwrap();
+    db.add_audit(
+      -1,
+      1,
+      10,
+      &[("bilibili", "a?spm=1", "a"), ("twitter", "b?s=1", "b")],
+    )
+    .unwrap();
+    db.add_audit(-1, 2, 20, &[("bilibili", "c?spm=1", "c")])
+      .unwrap();
+    let summary = db.audit_summary(-1).unwrap();
+    assert_eq!(
+      AuditSummary {
+        messages: 2,
+        links: 3,
+        sites: vec![("bilibili".to_string(), 2), ("twitter".to_string(), 1)],
+      },
+      summary
+    );
+    assert_eq!(AuditSummary::default(), db.audit_summary(-2).unwrap());
+  }
+
+  #[test]
+  fn replacements() {
+    let db = Db::in_memory().unwrap();
+    let alice = Some((1, Some("alice")));
+    db.add_replacements(-1, alice, 10, &["b23.tv", "x.com"])
+      .unwrap();
+    db.add_replacements(-1, Some((1, Some("alice2"))), 20, &["b23.tv"])
+      .unwrap();
+    db.add_replacements(-2, Some((2, None)), 30, &["x.com"])
+      .unwrap();
+    db.add_replacements(-2, None, 30, &["x.com"]).unwrap();
+    let stats = db.replacement_stats(0, None, 1).unwrap();
+    assert_eq!(
+      ReplacementStats {
+        links: 5,
+        chats: vec![(-1, 3)],
+        domains: vec![("x.com".to_string(), 3)],
+        offenders: vec![(1, Some("alice2".to_string()), 3)],
+      },
+      stats
+    );
+    assert_eq!(2, db.replacement_stats(30, None, 5).unwrap().links);
+    let chat = db.replacement_stats(0, Some(-2), 5).unwrap();
+    assert_eq!((2, vec![(-2, 2)]), (chat.links, chat.chats));
+    assert_eq!(2, db.prune_replacements(20).unwrap());
+    assert_eq!(3, db.replacement_stats(0, None, 5).unwrap().links);
+  }
+
+  #[test]
+  fn opted_out() {
+    let db = Db::in_memory().unwrap();
+    assert!(!db.is_opted_out(1).unwrap());
+    assert!(db.set_opted_out(1, true).unwrap());
+    assert!(!db.set_opted_out(1, true).unwrap());
+    assert!(db.is_opted_out(1).unwrap());
+    assert!(!db.is_opted_out(2).unwrap());
+    assert!(db.set_opted_out(1, false).unwrap());
+    assert!(!db.is_opted_out(1).unwrap());
+  }
+
+  #[test]
+  fn summaries() {
+    let db = Db::in_memory().unwrap();
+    assert_eq!(None, db.last_summary(-1).unwrap());
+    assert!(db.claim_summary(-1, 10).unwrap());
+    assert!(!db.claim_summary(-1, 10).unwrap());
+    assert!(db.claim_summary(-1, 20).unwrap());
+    assert!(!db.claim_summary(-1, 10).unwrap());
+    assert_eq!(Some(20), db.last_summary(-1).unwrap());
+  }
+
+  #[test]
+  fn enabled_chats() {
+    let db = Db::in_memory().unwrap();
+    let seed = vec!["-1".to_string(), "@group".to_string()];
+    assert!(db.seed_enabled_chats(0, &seed, &[], false).unwrap());
+    assert!(db.enable_chat(0, "-2").unwrap());
+    assert!(!db.enable_chat(0, "-2").unwrap());
+    assert!(db.disable_chat(0, "-1").unwrap());
+    // config is only a seed from now on
+    assert!(!db.seed_enabled_chats(0, &seed, &[], false).unwrap());
+    assert_eq!(vec!["@group", "-2"], db.enabled_chats(0).unwrap());
+    assert!(db.enabled_chats(1).unwrap().is_empty());
+
+    // [[chats]] are enabled once, then stay disabled
+    let chats = vec!["-3".to_string()];
+    assert!(db.seed_enabled_chats(0, &seed, &chats, false).unwrap());
+    assert!(db.disable_chat(0, "-3").unwrap());
+    assert!(!db.seed_enabled_chats(0, &seed, &chats, false).unwrap());
+    assert_eq!(vec!["@group", "-2"], db.enabled_chats(0).unwrap());
+
+    assert!(db.seed_enabled_chats(0, &seed, &chats, true).unwrap());
+    assert_eq!(vec!["-1", "@group", "-3"], db.enabled_chats(0).unwrap());
+  }
+}
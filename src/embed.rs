@@ -0,0 +1,186 @@
+use std::{
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+  },
+  time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use frankenstein::{AsyncApi, AsyncTelegramApi, SendMessageParams};
+use log::{debug, error, info, warn};
+use reqwest::Client;
+
+use crate::config::Config;
+
+/// Services that rewrite links into embed-friendly mirrors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+  Twitter,
+  TikTok,
+  Instagram,
+}
+
+impl Service {
+  const ALL: [Service; 3] = [Service::Twitter, Service::TikTok, Service::Instagram];
+
+  fn name(self) -> &'static str {
+    match self {
+      Service::Twitter => "twitter",
+      Service::TikTok => "tiktok",
+      Service::Instagram => "instagram",
+    }
+  }
+
+  /// Whether the replacer rewriting to the service is built in.
+  fn is_built(self) -> bool {
+    match self {
+      Service::Twitter => cfg!(feature = "twitter"),
+      Service::TikTok => cfg!(feature = "tiktok"),
+      Service::Instagram => cfg!(feature = "instagram"),
+    }
+  }
+
+  /// Mirror used when none is configured.
+  pub fn default_domain(self) -> &'static str {
+    match self {
+      Service::Twitter => "c.vxtwitter.com",
+      Service::TikTok => "vxtiktok.com",
+      Service::Instagram => "ddinstagram.com",
+    }
+  }
+}
+
+/// Configured mirror and reachability of each service, the latter updated by
+/// [`probe_loop`].
+///
+/// Every service starts as healthy, so links are rewritten right away before
+/// the first probe finishes.
+#[derive(Debug)]
+pub struct Embeds {
+  domains: RwLock<[String; 3]>,
+  healthy: [AtomicBool; 3],
+}
+
+impl Default for Embeds {
+  fn default() -> Self {
+    Self::new(Service::ALL.map(|service| service.default_domain().to_string()))
+  }
+}
+
+impl Embeds {
+  /// Creates with mirror domains of twitter, tiktok and instagram, in order.
+  pub fn new(domains: [String; 3]) -> Self {
+    Self {
+      domains: RwLock::new(domains),
+      healthy: [
+        AtomicBool::new(true),
+        AtomicBool::new(true),
+        AtomicBool::new(true),
+      ],
+    }
+  }
+
+  /// Returns the embed domain of `service`, or `None` when it's unreachable
+  /// and the replacer should only strip params.
+  pub fn domain(&self, service: Service) -> Option<String> {
+    if self.is_healthy(service) {
+      Some(self.configured(service))
+    } else {
+      None
+    }
+  }
+
+  fn configured(&self, service: Service) -> String {
+    self.domains.read().unwrap()[service as usize].clone()
+  }
+
+  /// Switches to new mirror domains, changed ones are assumed healthy until
+  /// the next probe.
+  pub fn set_domains(&self, domains: [String; 3]) {
+    let mut current = self.domains.write().unwrap();
+    for service in Service::ALL {
+      if current[service as usize] != domains[service as usize] {
+        self.set_healthy(service, true);
+      }
+    }
+    *current = domains;
+  }
+
+  fn is_healthy(&self, service: Service) -> bool {
+    self.healthy[service as usize].load(Ordering::Acquire)
+  }
+
+  /// Stores the new state, returns `true` if it changed.
+  fn set_healthy(&self, service: Service, healthy: bool) -> bool {
+    self.healthy[service as usize].swap(healthy, Ordering::AcqRel) != healthy
+  }
+}
+
+/// Probes the services whose replacer is built every `embed.probe-interval`
+/// and tells `owner` when one goes down or comes back, both read from the
+/// current `config` each round.
+pub async fn probe_loop(
+  client: Client,
+  embeds: Arc<Embeds>,
+  api: Arc<AsyncApi>,
+  config: Arc<ArcSwap<Config>>,
+) {
+  loop {
+    let current = config.load_full();
+    for service in Service::ALL
+      .into_iter()
+      .filter(|service| service.is_built())
+    {
+      let domain = embeds.configured(service);
+      let healthy = probe(&client, &domain).await;
+      if !embeds.set_healthy(service, healthy) {
+        continue;
+      }
+      let text = if healthy {
+        info!("Embed service {domain} is back");
+        format!(
+          "Embed service {} is reachable again, {} links will be rewritten to it.",
+          domain,
+          service.name()
+        )
+      } else {
+        warn!("Embed service {domain} is unreachable");
+        format!(
+          "Embed service {} is unreachable, only stripping params of {} links for now.",
+          domain,
+          service.name()
+        )
+      };
+      if let Some(owner) = current.owner {
+        let params = SendMessageParams::builder()
+          .chat_id(owner)
+          .text(text)
+          .build();
+        if let Err(err) = api.send_message(&params).await {
+          error!("Failed to notify owner: {err}");
+        }
+      }
+    }
+    tokio::time::sleep(current.embed.probe_interval).await;
+  }
+}
+
+async fn probe(client: &Client, domain: &str) -> bool {
+  let url = format!("https://{domain}/");
+  match client
+    .head(&url)
+    .timeout(Duration::from_secs(10))
+    .send()
+    .await
+  {
+    Ok(resp) => {
+      debug!("Probe {url}: {}", resp.status());
+      !resp.status().is_server_error()
+    },
+    Err(err) => {
+      debug!("Probe {url} failed: {err}");
+      false
+    },
+  }
+}
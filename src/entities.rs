@@ -0,0 +1,141 @@
+//! Leaving links in code alone, as code entities of the message mark them.
+
+use std::ops::Range;
+
+use anyhow::Result;
+use frankenstein::{MessageEntity, MessageEntityType};
+use fuckburl_bot::{replace_all, resolver::Resolver, ReplaceReport, Rules};
+
+/// Byte ranges of `text` in `code` and `pre` entities, in order and not
+/// overlapping.
+pub(crate) fn code_ranges(text: &str, entities: &[MessageEntity]) -> Vec<Range<usize>> {
+  // entity offsets count UTF-16 units
+  let mut units = Vec::with_capacity(text.len() + 1);
+  let mut unit = 0;
+  for (i, c) in text.char_indices() {
+    units.push((unit, i));
+    unit += c.len_utf16();
+  }
+  units.push((unit, text.len()));
+  let byte_of = |unit: usize| {
+    units
+      .iter()
+      .find(|(at, _)| *at >= unit)
+      .map_or(text.len(), |(_, i)| *i)
+  };
+  let mut ranges: Vec<Range<usize>> = entities
+    .iter()
+    .filter(|entity| {
+      matches!(
+        entity.type_field,
+        MessageEntityType::Code | MessageEntityType::Pre
+      )
+    })
+    .map(|entity| {
+      let start = usize::from(entity.offset);
+      byte_of(start)..byte_of(start + usize::from(entity.length))
+    })
+    .collect();
+  ranges.sort_by_key(|range| range.start);
+  let mut merged: Vec<Range<usize>> = Vec::new();
+  for range in ranges {
+    match merged.last_mut() {
+      Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+      _ => merged.push(range),
+    }
+  }
+  merged
+}
+
+/// [`replace_all`] on the parts of `text` outside of `code`.
+pub(crate) async fn replace_outside(
+  text: &str,
+  code: &[Range<usize>],
+  rules: Rules<'_>,
+  resolver: &Resolver,
+) -> Result<(String, ReplaceReport)> {
+  if code.is_empty() {
+    return replace_all(text, rules, resolver).await;
+  }
+  let mut new = String::with_capacity(text.len());
+  let mut report = ReplaceReport::default();
+  let mut start = 0;
+  for range in code {
+    let (replaced, part) = replace_all(&text[start..range.start], rules, resolver).await?;
+    new.push_str(&replaced);
+    new.push_str(&text[range.clone()]);
+    report.extend(part);
+    start = range.end;
+  }
+  let (replaced, part) = replace_all(&text[start..], rules, resolver).await?;
+  new.push_str(&replaced);
+  report.extend(part);
+  Ok((new, report))
+}
+
+#[cfg(test)]
+mod tests {
+  use frankenstein::{MessageEntity, MessageEntityType};
+  use fuckburl_bot::{embed::Embeds, resolver::Resolver, Rules};
+
+  use super::{code_ranges, replace_outside};
+
+  fn entity(type_field: MessageEntityType, offset: u16, length: u16) -> MessageEntity {
+    MessageEntity::builder()
+      .type_field(type_field)
+      .offset(offset)
+      .length(length)
+      .build()
+  }
+
+  #[test]
+  fn ranges() {
+    let text = "链接 `a` b";
+    let entities = [
+      entity(MessageEntityType::Bold, 0, 2),
+      entity(MessageEntityType::Code, 3, 3),
+      entity(MessageEntityType::Pre, 4, 1),
+    ];
+    let ranges = code_ranges(text, &entities);
+    assert_eq!(vec![7..10], ranges);
+    assert_eq!("`a`", &text[ranges[0].clone()]);
+  }
+
+  #[tokio::test]
+  async fn keeps_code() {
+    let text = "https://example.com/a?utm_source=tg curl https://example.com/a?utm_source=tg";
+    let start = text.find("curl").unwrap() as u16;
+    let code = code_ranges(
+      text,
+      &[entity(
+        MessageEntityType::Pre,
+        start,
+        text.len() as u16 - start,
+      )],
+    );
+    let embeds = Embeds::default();
+    let tracking = ["utm_source".to_string()];
+    let rules = Rules {
+      embeds: &embeds,
+      tracking_params: &tracking,
+      custom: &[],
+      disabled: &[],
+      plugins: None,
+      shorteners: &[],
+    };
+    let resolver = Resolver::new(
+      reqwest::Client::new(),
+      std::time::Duration::from_secs(1),
+      std::time::Duration::ZERO,
+      5,
+    );
+    let (replaced, report) = replace_outside(text, &code, rules, &resolver)
+      .await
+      .unwrap();
+    assert_eq!(
+      "https://example.com/a curl https://example.com/a?utm_source=tg",
+      replaced
+    );
+    assert_eq!(1, report.replacements.len());
+  }
+}
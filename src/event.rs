@@ -1,137 +1,1059 @@
-use std::{fmt::Display, sync::Arc};
+use std::{
+  fmt::Display,
+  mem,
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{Context, Ok, Result};
+use chrono::{DateTime, Local};
+use chrono_tz::Tz;
 use frankenstein::{
-  AsyncApi, AsyncTelegramApi, DeleteMessageParams, ParseMode, SendMessageParams, Update,
+  AsyncApi, AsyncTelegramApi, Chat, ChatMember, ChatType, DeleteMessageParams,
+  GetChatAdministratorsParams, Message, ParseMode, SendMediaGroupParams, SendMessageParams, Update,
   UpdateContent, User,
 };
-use log::{debug, info};
+use log::{debug, error, info, warn};
+use reqwest::Url;
+use tracing::{info_span, Instrument};
+
+use fuckburl_bot::{
+  ads::is_shop_ad,
+  cache::{Cache, CacheLimits},
+  clean_offline_with,
+  config::{Action, Config, LinkPreview, Markup, Template},
+  replacer::UNEXPANDED,
+  ReplaceReport, Rules,
+};
 
-use crate::{replacer::replace_all, Config, START_TIME};
+use crate::{
+  album::media_group,
+  command,
+  entities::{code_ranges, replace_outside},
+  retry::with_retry,
+  spam::Escalation,
+  split::{split, MESSAGE_LIMIT},
+  stats::{Missed, Stage},
+  Bot, START_TIME,
+};
 use std::fmt::Write;
 
-fn write_user(text: &mut String, user: &User) {
-  match user.username {
-    Some(ref at) => {
-      write!(text, "@{at}").unwrap();
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M";
+/// Sent in place of a text that cleaning left empty, in italics.
+const NOTHING_LEFT: &str = "(nothing left after cleaning)";
+/// Replied in private chats to messages without dirty links.
+const NOTHING_TO_CLEAN: &str = "<i>(no links to clean)</i>";
+
+/// How long the admins of a chat are reused, so a wave of spam doesn't ask
+/// for them every message.
+const ADMINS_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Human admins of chats, by chat id.
+pub(crate) fn admin_cache(limits: CacheLimits) -> Cache<i64, Vec<User>> {
+  Cache::new("chat admins", limits, Some(ADMINS_TTL), |_, admins| {
+    admins
+      .iter()
+      .map(|admin| {
+        mem::size_of::<User>()
+          + admin.first_name.len()
+          + admin.last_name.as_ref().map_or(0, String::len)
+          + admin.username.as_ref().map_or(0, String::len)
+      })
+      .sum()
+  })
+}
+
+/// How telegram should parse text in `markup`.
+pub(crate) fn parse_mode(markup: Markup) -> ParseMode {
+  match markup {
+    Markup::Html => ParseMode::Html,
+    Markup::MarkdownV2 => ParseMode::MarkdownV2,
+  }
+}
+
+/// `text` escaped for `markup`.
+fn escape(markup: Markup, text: &str) -> String {
+  match markup {
+    Markup::Html => v_htmlescape::escape(text).to_string(),
+    Markup::MarkdownV2 => {
+      let mut escaped = String::with_capacity(text.len());
+      for c in text.chars() {
+        if "_*[]()~`>#+-=|{}.!\\".contains(c) {
+          escaped.push('\\');
+        }
+        escaped.push(c);
+      }
+      escaped
     },
+  }
+}
+
+/// A link to `url` showing `label`, which is escaped already.
+fn link(markup: Markup, url: &str, label: &str) -> String {
+  match markup {
+    Markup::Html => format!(r#"<a href="{}">{label}</a>"#, v_htmlescape::escape(url)),
+    Markup::MarkdownV2 => format!(
+      "[{label}]({})",
+      url.replace('\\', "\\\\").replace(')', "\\)")
+    ),
+  }
+}
+
+/// `text`, escaped already, in italics.
+fn italic(markup: Markup, text: &str) -> String {
+  match markup {
+    Markup::Html => format!("<i>{text}</i>"),
+    Markup::MarkdownV2 => format!("_{text}_"),
+  }
+}
+
+fn write_user(text: &mut String, user: &User, markup: Markup) {
+  match user.username {
+    Some(ref at) => text.push_str(&escape(markup, &format!("@{at}"))),
     None => {
-      write!(text, r#"<a href="tg://user?id={}">"#, user.id).unwrap();
-      text.push_str(&v_htmlescape::escape(&user.first_name).to_string());
+      let mut name = escape(markup, &user.first_name);
       if let Some(ref last) = user.last_name {
-        write!(text, " {}", v_htmlescape::escape(last)).unwrap();
+        write!(name, " {}", escape(markup, last)).unwrap();
+      }
+      text.push_str(&link(markup, &format!("tg://user?id={}", user.id), &name));
+    },
+  }
+}
+
+/// Where a forwarded message came from, after bot API's `MessageOrigin`,
+/// derived from the legacy `forward_*` fields.
+#[derive(Debug, PartialEq)]
+enum ForwardOrigin<'a> {
+  User(&'a User),
+  /// A user who hides their account, only the name is known.
+  HiddenUser(&'a str),
+  /// An anonymous group admin, posting as the group.
+  Chat(&'a Chat),
+  Channel {
+    chat: &'a Chat,
+    message_id: i32,
+    signature: Option<&'a str>,
+  },
+}
+
+impl<'a> ForwardOrigin<'a> {
+  fn of(msg: &'a Message) -> Option<Self> {
+    if let Some(ref from) = msg.forward_from {
+      Some(Self::User(from))
+    } else if let Some(ref chat) = msg.forward_from_chat {
+      Some(match msg.forward_from_message_id {
+        Some(message_id) => Self::Channel {
+          chat,
+          message_id,
+          signature: msg.forward_signature.as_deref(),
+        },
+        None => Self::Chat(chat),
+      })
+    } else {
+      msg.forward_sender_name.as_deref().map(Self::HiddenUser)
+    }
+  }
+}
+
+fn chat_title(chat: &Chat, markup: Markup) -> String {
+  escape(markup, chat.title.as_deref().unwrap_or("unknown"))
+}
+
+/// The `[template] forward` line of `msg`, `None` if it wasn't forwarded.
+fn forward_line(msg: &Message, template: &str, markup: Markup) -> Option<String> {
+  let origin = ForwardOrigin::of(msg)?;
+  let mut text = String::new();
+  match origin {
+    ForwardOrigin::User(from) => write_user(&mut text, from, markup),
+    ForwardOrigin::HiddenUser(name) => {
+      text.push_str(&escape(markup, &format!("{name} (hidden account)")));
+    },
+    ForwardOrigin::Chat(chat) => text.push_str(&chat_title(chat, markup)),
+    ForwardOrigin::Channel {
+      chat,
+      message_id,
+      signature,
+    } => {
+      let title = chat_title(chat, markup);
+      text.push_str("channel ");
+      let url = if let Some(ref username) = chat.username {
+        format!("https://t.me/{username}/{message_id}")
+      } else {
+        debug!("from_chat.id = {}", chat.id);
+        let id = -(chat.id + 1000000000000);
+        format!("https://t.me/c/{id}/{message_id}")
+      };
+      text.push_str(&link(markup, &url, &title));
+      if let Some(signature) = signature {
+        write!(text, " {}", escape(markup, &format!("({signature})"))).unwrap();
+      }
+    },
+  }
+  Some(expand(template, &[("source", &text)]))
+}
+
+/// `date` in `timezone`, or the server's when `None`.
+fn format_date(date: u64, timezone: Option<Tz>) -> String {
+  let Some(utc) = DateTime::from_timestamp(date as i64, 0) else {
+    return String::new();
+  };
+  match timezone {
+    Some(tz) => utc.with_timezone(&tz).format(DATE_FORMAT).to_string(),
+    None => utc.with_timezone(&Local).format(DATE_FORMAT).to_string(),
+  }
+}
+
+/// Replaces each `{name}` in `template` with its value in `vars` in one
+/// pass, so values are never expanded themselves. Unknown names are kept.
+fn expand(template: &str, vars: &[(&str, &str)]) -> String {
+  let mut text = String::with_capacity(template.len());
+  let mut rest = template;
+  while let Some(start) = rest.find('{') {
+    text.push_str(&rest[..start]);
+    rest = &rest[start..];
+    let value = rest.find('}').and_then(|end| {
+      vars
+        .iter()
+        .find(|(name, _)| *name == &rest[1..end])
+        .map(|(_, value)| (end, value))
+    });
+    match value {
+      Some((end, value)) => {
+        text.push_str(value);
+        rest = &rest[end + 1..];
+      },
+      None => {
+        text.push('{');
+        rest = &rest[1..];
+      },
+    }
+  }
+  text.push_str(rest);
+  text
+}
+
+/// The `[template] header` variables, `user` is markup already.
+fn header_vars(
+  user: &str,
+  date: u64,
+  timezone: Option<Tz>,
+  report: &ReplaceReport,
+) -> [(&'static str, String); 3] {
+  [
+    ("user", user.to_string()),
+    ("date", format_date(date, timezone)),
+    ("link_count", report.replacements.len().to_string()),
+  ]
+}
+
+/// Expands the `[template] header` variables, `user` is markup already.
+fn header(
+  template: &str,
+  user: &str,
+  date: u64,
+  timezone: Option<Tz>,
+  report: &ReplaceReport,
+) -> String {
+  let vars = header_vars(user, date, timezone, report);
+  let vars: Vec<_> = vars
+    .iter()
+    .map(|(name, value)| (*name, value.as_str()))
+    .collect();
+  expand(template, &vars)
+}
+
+/// Writes each changed link as `original → cleaned`, one per line, for
+/// replies that shouldn't repeat the whole message.
+fn write_pairs(text: &mut String, report: &ReplaceReport, markup: Markup) {
+  let mut written: Vec<(&str, &str)> = Vec::new();
+  for replacement in &report.replacements {
+    let pair = (replacement.original.as_str(), replacement.cleaned.as_str());
+    if written.contains(&pair) {
+      continue;
+    }
+    if !written.is_empty() {
+      text.push('\n');
+    }
+    let cleaned = if pair.1.trim().is_empty() {
+      "(removed)"
+    } else {
+      pair.1
+    };
+    write!(
+      text,
+      "{} → {}",
+      escape(markup, pair.0),
+      escape(markup, cleaned)
+    )
+    .unwrap();
+    written.push(pair);
+  }
+  for original in &report.unexpanded {
+    write!(
+      text,
+      "\n{}",
+      escape(markup, &format!("{original} {UNEXPANDED}"))
+    )
+    .unwrap();
+  }
+}
+
+/// Writes each cleaned link once, one per line, for chats that only want the
+/// links.
+fn write_links(text: &mut String, report: &ReplaceReport, markup: Markup) {
+  let mut written: Vec<&str> = Vec::new();
+  for replacement in &report.replacements {
+    let cleaned = replacement.cleaned.trim();
+    if cleaned.is_empty() || written.contains(&cleaned) {
+      continue;
+    }
+    if !written.is_empty() {
+      text.push('\n');
+    }
+    text.push_str(&escape(markup, cleaned));
+    written.push(cleaned);
+  }
+  if written.is_empty() {
+    text.push_str(&italic(markup, &escape(markup, NOTHING_LEFT)));
+  }
+}
+
+/// The cleaned link appearing first in `replaced`.
+fn first_link<'a>(replaced: &str, report: &'a ReplaceReport) -> Option<&'a str> {
+  report
+    .replacements
+    .iter()
+    .map(|replacement| replacement.cleaned.trim())
+    .filter(|cleaned| !cleaned.is_empty())
+    .min_by_key(|cleaned| replaced.find(cleaned).unwrap_or(usize::MAX))
+}
+
+/// Builds the message sent for `msg`, whose text was cleaned to `replaced`.
+///
+/// Reposts carry the whole cleaned text, replies only the changed links and
+/// [`Action::Links`] only the cleaned ones.
+fn cleaned_message(
+  msg: &Message,
+  replaced: &str,
+  report: &ReplaceReport,
+  action: Action,
+  trial: bool,
+  template: &Template,
+  timezone: Option<Tz>,
+) -> SendMessageParams {
+  let markup = template.parse_mode;
+  let mut text = String::with_capacity(128);
+  if action == Action::Reply && !report.replacements.is_empty() {
+    write_pairs(&mut text, report, markup);
+  } else if action == Action::Links {
+    write_links(&mut text, report, markup);
+  } else if replaced.trim().is_empty() {
+    // telegram refuses empty messages, and the reader should know why the
+    // original is gone
+    text.push_str(&italic(markup, &escape(markup, NOTHING_LEFT)));
+  } else {
+    text.push_str(&escape(markup, replaced));
+  }
+
+  if action == Action::Repost {
+    let mut user = String::new();
+    match (&msg.sender_chat, &msg.from) {
+      // anonymous admins and channels post as a chat
+      (Some(chat), _) => user.push_str(&chat_title(chat, markup)),
+      (None, Some(from)) => write_user(&mut user, from, markup),
+      (None, None) => user.push_str("Unknown"),
+    }
+    let header = header(&template.header, &user, msg.date, timezone, report);
+    let forward = forward_line(msg, template.forward(), markup).unwrap_or_default();
+    let vars = header_vars(&user, msg.date, timezone, report);
+    let mut vars: Vec<_> = vars
+      .iter()
+      .map(|(name, value)| (*name, value.as_str()))
+      .collect();
+    vars.extend([
+      ("header", header.as_str()),
+      ("text", &text),
+      ("forward", &forward),
+    ]);
+    // without a forward line, the default layout ends in blank lines
+    text = expand(&template.repost, &vars).trim_end().to_string();
+  }
+
+  if template.domain_summary && report.replacements.len() > 1 {
+    let mut summary = "cleaned: ".to_string();
+    for (i, (site, count)) in report.site_counts().into_iter().enumerate() {
+      if i > 0 {
+        summary.push_str(", ");
       }
-      text.push_str("</a>");
+      write!(summary, "{site} ×{count}").unwrap();
+    }
+    write!(text, "\n\n{}", italic(markup, &escape(markup, &summary))).unwrap();
+  }
+
+  if trial {
+    write!(text, "\n\n{}", italic(markup, &escape(markup, "(trial)"))).unwrap();
+  }
+
+  let preview = template.link_preview;
+  if preview == LinkPreview::FirstLink {
+    if let Some(first) = first_link(replaced, report) {
+      // telegram previews the first link, which this invisible one is
+      text.insert_str(0, &link(markup, first, "\u{200b}"));
+    }
+  }
+
+  let mut send_msg = SendMessageParams::builder()
+    .chat_id(msg.chat.id)
+    .text(text)
+    .parse_mode(parse_mode(markup))
+    .build();
+
+  // in forums, replies and reposts belong to the topic of the original
+  if msg.is_topic_message == Some(true) {
+    send_msg.message_thread_id = msg.message_thread_id;
+  }
+  if preview == LinkPreview::Disabled {
+    send_msg.disable_web_page_preview = Some(true);
+  }
+  send_msg.reply_to_message_id = match action {
+    Action::Repost => msg.reply_to_message.as_ref().map(|i| i.message_id),
+    Action::Reply | Action::Links | Action::Audit | Action::Auto => Some(msg.message_id),
+  };
+  send_msg
+}
+
+/// Whether telegram refused because the forum topic was closed or deleted.
+fn is_topic_gone(err: &frankenstein::Error) -> bool {
+  match err {
+    frankenstein::Error::Api(resp) => {
+      resp.description.contains("TOPIC_CLOSED")
+        || resp.description.contains("TOPIC_DELETED")
+        || resp.description.contains("message thread not found")
+    },
+    _ => false,
+  }
+}
+
+/// Whether telegram refused because the bot lacks rights in the chat, not
+/// just for the message at hand, like one too old to delete.
+fn is_permission_error(err: &frankenstein::Error) -> bool {
+  match err {
+    frankenstein::Error::Api(resp) => {
+      resp.description.contains("not enough rights")
+        || resp.description.contains("CHAT_ADMIN_REQUIRED")
+    },
+    _ => false,
+  }
+}
+
+/// Switches a chat where deleting stopped working to reply mode, telling the
+/// chat and owner about it.
+async fn downgrade(
+  bot: &Bot,
+  config: &Config,
+  chat_id: i64,
+  err: &frankenstein::Error,
+) -> Result<()> {
+  let reason = match err {
+    frankenstein::Error::Api(resp) => resp.description.clone(),
+    err => err.to_string(),
+  };
+  let since = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|since| since.as_secs() as i64)
+    .unwrap_or_default();
+  if !bot.db.add_downgrade(chat_id, since, &reason)? {
+    return Ok(());
+  }
+  warn!(chat_id; "Can't delete messages in {chat_id} ({reason}), switched to reply mode");
+  let text = format!(
+    "I can't delete messages here anymore ({reason}), so I'll reply with cleaned links instead. \
+     After granting me the permission to delete messages, ask the bot owner to /restore {chat_id}."
+  );
+  let mut notify = vec![chat_id];
+  notify.extend(config.owner);
+  for to in notify {
+    let params = SendMessageParams::builder()
+      .chat_id(to)
+      .text(text.clone())
+      .build();
+    let notified = with_retry(&config.retry, "notify of downgrade", || {
+      bot.api.send_message(&params)
+    })
+    .await;
+    if let Err(err) = notified {
+      error!(chat_id; "Failed to notify {to} of downgrade: {err}");
+    }
+  }
+  Ok(())
+}
+
+/// Counts `msg` as cleaned, recording its links for `/stats`. Failing to
+/// is only logged, as the message was handled already.
+fn count_cleaned(bot: &Bot, msg: &Message, report: &ReplaceReport) {
+  bot.stats.cleaned();
+  let domains: Vec<_> = report
+    .replacements
+    .iter()
+    .map(|replacement| {
+      Url::parse(&replacement.original)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_else(|| replacement.site.clone())
+    })
+    .collect();
+  let domains: Vec<_> = domains.iter().map(String::as_str).collect();
+  let user = msg
+    .from
+    .as_ref()
+    .map(|from| (from.id, from.username.as_deref()));
+  if let Err(err) = bot
+    .db
+    .add_replacements(msg.chat.id, user, msg.date, &domains)
+  {
+    error!(chat_id = msg.chat.id; "Failed to record cleaned links: {err:?}");
+  }
+}
+
+/// Records the links of `msg` in an audited chat, leaving it alone.
+fn audit(bot: &Bot, msg: &Message, report: &ReplaceReport) -> Result<()> {
+  let chat_id = msg.chat.id;
+  let links: Vec<_> = report
+    .replacements
+    .iter()
+    .map(|replacement| {
+      info!(
+        chat_id, replacer = replacement.site.as_str();
+        "Audit of {chat_id}: {} -> {}", replacement.original, replacement.cleaned
+      );
+      (
+        replacement.site.as_str(),
+        replacement.original.as_str(),
+        replacement.cleaned.as_str(),
+      )
+    })
+    .collect();
+  bot
+    .db
+    .add_audit(chat_id, msg.message_id, msg.date, &links)
+    .context("Failed to record audit")
+}
+
+/// Deletes `msg` without reposting, for `what` it is.
+async fn delete_unwanted(bot: &Bot, msg: &Message, what: &str) -> Result<()> {
+  let chat_id = msg.chat.id;
+  if bot.config.load().dry_run {
+    info!(chat_id; "Dry run, would delete {what} {} in {chat_id}", msg.message_id);
+    return Ok(());
+  }
+  info!(chat_id; "Deleting {what} in {chat_id}");
+  let params = DeleteMessageParams::builder()
+    .chat_id(chat_id)
+    .message_id(msg.message_id)
+    .build();
+  with_retry(&bot.config.load().retry, "delete message", || {
+    bot
+      .api
+      .delete_message(&params)
+      .instrument(info_span!("delete_message", chat_id))
+  })
+  .await
+  .with_context(|| format!("Failed to delete {what}..."))?;
+  Ok(())
+}
+
+/// Pings the admins of the chat about `msg` scoring `score` as spam,
+/// replying to `reply_to`.
+async fn report_spam(bot: &Bot, msg: &Message, reply_to: i32, score: u32) -> Result<()> {
+  let chat_id = msg.chat.id;
+  if bot.config.load().dry_run {
+    info!(chat_id; "Dry run, would report spam scoring {score} in {chat_id}");
+    return Ok(());
+  }
+  info!(chat_id; "Reporting spam scoring {score} in {chat_id}");
+  let admins = match bot.admins.get(&chat_id) {
+    Some(admins) => admins,
+    None => {
+      let params = GetChatAdministratorsParams::builder()
+        .chat_id(chat_id)
+        .build();
+      let members = bot
+        .api
+        .get_chat_administrators(&params)
+        .await
+        .context("Failed to get chat admins...")?
+        .result;
+      let admins: Vec<_> = members
+        .into_iter()
+        .filter_map(|member| match member {
+          ChatMember::Creator(owner) => Some(owner.user),
+          ChatMember::Administrator(admin) => Some(admin.user),
+          _ => None,
+        })
+        .filter(|admin| !admin.is_bot)
+        .collect();
+      bot.admins.insert(chat_id, admins.clone());
+      admins
     },
+  };
+  let mut text = "Possible spam".to_string();
+  if let Some(from) = &msg.from {
+    text.push_str(" from ");
+    write_user(&mut text, from, Markup::Html);
+  }
+  write!(text, " (score {score})").unwrap();
+  for admin in &admins {
+    text.push(' ');
+    write_user(&mut text, admin, Markup::Html);
   }
+  let mut params = SendMessageParams::builder()
+    .chat_id(chat_id)
+    .text(text)
+    .parse_mode(ParseMode::Html)
+    .reply_to_message_id(reply_to)
+    .build();
+  if msg.is_topic_message == Some(true) {
+    params.message_thread_id = msg.message_thread_id;
+  }
+  with_retry(&bot.config.load().retry, "report spam", || {
+    bot.api.send_message(&params)
+  })
+  .await
+  .context("Failed to report spam...")?;
+  Ok(())
 }
 
-pub(crate) async fn process_update(
+/// HTML reply to a private message `cleaned` to `replaced`.
+/// Sends the rest of a text too long for one message, each part following
+/// `first` without replying to anything.
+async fn send_rest(
   api: &AsyncApi,
-  config: Arc<Config>,
-  update: Update,
+  config: &Config,
+  first: &SendMessageParams,
+  chunks: impl Iterator<Item = String>,
+  what: &str,
 ) -> Result<()> {
-  debug!("Processing update: {}", &update.update_id);
+  let sending = format!("send part of {what}");
+  for (i, chunk) in chunks.enumerate() {
+    let params = SendMessageParams {
+      text: chunk,
+      reply_to_message_id: None,
+      ..first.clone()
+    };
+    with_retry(&config.retry, &sending, || api.send_message(&params))
+      .await
+      .with_context(|| format!("Failed to send part {} of {what}...", i + 2))?;
+  }
+  Ok(())
+}
+
+fn private_reply(cleaned: bool, replaced: &str) -> String {
+  if !cleaned {
+    NOTHING_TO_CLEAN.to_string()
+  } else if replaced.trim().is_empty() {
+    italic(Markup::Html, NOTHING_LEFT)
+  } else {
+    v_htmlescape::escape(replaced).to_string()
+  }
+}
+
+/// Replies to `msg`, sent or forwarded to the bot in a private chat, with
+/// its text or caption cleaned, as `private-chats` allows.
+async fn process_private(bot: &Bot, config: &Config, msg: &Message) -> Result<()> {
+  let chat_id = msg.chat.id;
+  let (text, entities) = match (&msg.text, &msg.caption) {
+    (Some(text), _) => (text, msg.entities.as_deref()),
+    (None, Some(caption)) => (caption, msg.caption_entities.as_deref()),
+    (None, None) => return Ok(()),
+  };
+  let rules = Rules {
+    embeds: &bot.embeds,
+    tracking_params: &config.tracking_params,
+    custom: &config.rules,
+    disabled: &[],
+    plugins: Some(&bot.plugins),
+    shorteners: &config.resolver.shorteners,
+  };
+  let code = code_ranges(text, entities.unwrap_or_default());
+  let (replaced, report) = replace_outside(text, &code, rules, &bot.resolver)
+    .await
+    .context("Failed to replace text")?;
+  let cleaned = !report.replacements.is_empty() && replaced != *text;
+  let reply = private_reply(cleaned, &replaced);
+  if config.dry_run {
+    info!(chat_id; "Dry run, would reply in private chat {chat_id}: {reply}");
+    return Ok(());
+  }
+  let mut chunks = split(&reply, MESSAGE_LIMIT, Markup::Html).into_iter();
+  let mut params = SendMessageParams::builder()
+    .chat_id(chat_id)
+    .text(chunks.next().unwrap_or_default())
+    .parse_mode(ParseMode::Html)
+    .reply_to_message_id(msg.message_id)
+    .build();
+  if config.template.link_preview == LinkPreview::Disabled {
+    params.disable_web_page_preview = Some(true);
+  }
+  with_retry(&config.retry, "reply in private chat", || {
+    bot.api.send_message(&params)
+  })
+  .await
+  .context("Failed to reply in private chat...")?;
+  for chunk in chunks {
+    params.text = chunk;
+    params.reply_to_message_id = None;
+    with_retry(&config.retry, "reply in private chat", || {
+      bot.api.send_message(&params)
+    })
+    .await
+    .context("Failed to reply in private chat...")?;
+  }
+  if cleaned {
+    count_cleaned(bot, msg, &report);
+  }
+  Ok(())
+}
+
+/// Cleans the caption of an album once all of its messages arrived, sending
+/// the album again with the cleaned caption, or replying to it.
+async fn process_album(bot: &Bot, config: &Config, msg: Message) -> Result<()> {
+  let chat_id = msg.chat.id;
+  let username = bot.username_of(chat_id, msg.chat.username.as_deref());
+  let enabled_chats = bot.enabled_chats.read().unwrap().clone();
+  let Some(chat) = config.chat_in(bot.entry, &enabled_chats, chat_id, username) else {
+    return Ok(());
+  };
+  let group = msg.media_group_id.clone().unwrap_or_default();
+  if !bot.albums.add(msg) {
+    return Ok(());
+  }
+  // the rest of the album comes in right after the first message
+  tokio::time::sleep(config.timeouts.album_wait).await;
+  let album = bot.albums.take(chat_id, &group);
+  let Some(captioned) = album.iter().find(|msg| msg.caption.is_some()) else {
+    return Ok(());
+  };
+  let caption = captioned.caption.clone().unwrap_or_default();
+  let rules = Rules {
+    embeds: &bot.embeds,
+    tracking_params: &config.tracking_params,
+    custom: &config.rules,
+    disabled: chat.disabled,
+    plugins: Some(&bot.plugins),
+    shorteners: &config.resolver.shorteners,
+  };
+  let code = code_ranges(
+    &caption,
+    captioned.caption_entities.as_deref().unwrap_or_default(),
+  );
+  let (replaced, report) = replace_outside(&caption, &code, rules, &bot.resolver)
+    .await
+    .context("Failed to replace caption")?;
+  if replaced == caption || report.replacements.is_empty() {
+    return Ok(());
+  }
+  let (action, trial) = chat.current_action();
+  if action == Action::Audit {
+    return audit(bot, captioned, &report);
+  }
+  let mut action = action.resolve(&report);
+  if action == Action::Repost && bot.db.downgrade(chat_id)?.is_some() {
+    action = Action::Reply;
+  }
+  let template = config.template.for_chat(chat.template);
+  let cleaned = |action| {
+    cleaned_message(
+      captioned,
+      &replaced,
+      &report,
+      action,
+      trial,
+      &template,
+      chat.timezone,
+    )
+  };
+  let mut reply = cleaned(action);
+  let silent = (!chat.notifications).then_some(true);
+  let media = match action {
+    Action::Repost => media_group(
+      &album,
+      captioned.message_id,
+      &reply.text,
+      parse_mode(template.parse_mode),
+    ),
+    Action::Reply | Action::Links | Action::Audit | Action::Auto => None,
+  };
+  info!(
+    chat_id;
+    "Replacing album {group} of {} messages in {chat_id} ({action:?})",
+    album.len()
+  );
+  if config.dry_run {
+    info!(chat_id; "Dry run, would send in {chat_id}: {}", reply.text);
+    return Ok(());
+  }
+  let Some(media) = media else {
+    // replies, and albums that can't be sent again as they were
+    if action == Action::Repost {
+      reply = cleaned(Action::Reply);
+    }
+    let mut chunks = split(&reply.text, MESSAGE_LIMIT, template.parse_mode).into_iter();
+    reply.text = chunks.next().unwrap_or_default();
+    reply.disable_notification = silent;
+    with_retry(&config.retry, "reply to album", || {
+      bot.api.send_message(&reply)
+    })
+    .await
+    .context("Failed to reply to album...")?;
+    send_rest(&bot.api, config, &reply, chunks, "album reply").await?;
+    count_cleaned(bot, captioned, &report);
+    return Ok(());
+  };
+  let mut params = SendMediaGroupParams::builder()
+    .chat_id(chat_id)
+    .media(media)
+    .build();
+  params.message_thread_id = reply.message_thread_id;
+  params.reply_to_message_id = reply.reply_to_message_id;
+  params.disable_notification = silent;
+  with_retry(&config.retry, "send album", || {
+    bot
+      .api
+      .send_media_group(&params)
+      .instrument(info_span!("send_media_group", chat_id))
+  })
+  .await
+  .context("Failed to send album...")?;
+  for msg in &album {
+    let params = DeleteMessageParams::builder()
+      .chat_id(chat_id)
+      .message_id(msg.message_id)
+      .build();
+    let deleted = with_retry(&config.retry, "delete message", || {
+      bot.api.delete_message(&params)
+    })
+    .await;
+    match deleted {
+      Err(err) if is_permission_error(&err) => return downgrade(bot, config, chat_id, &err).await,
+      deleted => deleted.context("Failed to delete album message...")?,
+    };
+  }
+  count_cleaned(bot, captioned, &report);
+  Ok(())
+}
+
+#[tracing::instrument(skip_all, fields(update_id = update.update_id))]
+pub(crate) async fn process_update(bot: &Bot, update: Update, received: Instant) -> Result<()> {
+  let update_id = update.update_id;
+  debug!(update_id; "Processing update: {update_id}");
+  bot.stats.update();
+  let api = &*bot.api;
+  let config = bot.config.load_full();
   match update.content {
     UpdateContent::Message(msg) => {
+      bot.seen.record(&msg.chat, msg.date);
       if msg.date < *START_TIME {
         return Ok(());
       }
-      if !config.enabled_chats.contains(&msg.chat.id.to_string()) {
+      if command::handle(bot, &config, &msg).await? || command::handle_opt(bot, &msg).await? {
         return Ok(());
-      };
+      }
+      // asked for explicitly, so opting out doesn't apply
+      if config.private_chats && msg.chat.type_field == ChatType::Private {
+        return process_private(bot, &config, &msg).await;
+      }
 
+      if let Some(from) = &msg.from {
+        if config.is_whitelisted(from.id, from.username.as_deref()) {
+          debug!(update_id; "Leaving message of whitelisted user {}", from.id);
+          return Ok(());
+        }
+        if bot.db.is_opted_out(from.id)? {
+          debug!(update_id; "Leaving message of user {} who opted out", from.id);
+          return Ok(());
+        }
+      }
+      if msg.media_group_id.is_some() {
+        return process_album(bot, &config, msg).await;
+      }
       let text = if let Some(text) = msg.text.clone() {
         text
       } else {
         return Ok(());
       };
-      let replaced = replace_all(&text).await.context("Failed to replace text")?;
-      if replaced == text {
+      let username = bot.username_of(msg.chat.id, msg.chat.username.as_deref());
+      let enabled_chats = bot.enabled_chats.read().unwrap().clone();
+      let Some(chat) = config.chat_in(bot.entry, &enabled_chats, msg.chat.id, username) else {
+        if config.report_missed {
+          // offline only, so short links of chats the bot isn't serving
+          // aren't resolved
+          let rules = Rules {
+            embeds: &bot.embeds,
+            tracking_params: &config.tracking_params,
+            custom: &config.rules,
+            disabled: &[],
+            plugins: None,
+            shorteners: &[],
+          };
+          if clean_offline_with(&text, rules).0 != text {
+            bot.stats.missed(msg.chat.id, Missed::NotEnabled, &text);
+          }
+        }
         return Ok(());
+      };
+      let rules = Rules {
+        embeds: &bot.embeds,
+        tracking_params: &config.tracking_params,
+        custom: &config.rules,
+        disabled: chat.disabled,
+        plugins: Some(&bot.plugins),
+        shorteners: &config.resolver.shorteners,
+      };
+      let code = code_ranges(&text, msg.entities.as_deref().unwrap_or_default());
+      let (replaced, report) = replace_outside(&text, &code, rules, &bot.resolver)
+        .await
+        .context("Failed to replace text")?;
+      bot.stats.latency(Stage::Regex, report.regex_time);
+      if !report.resolve_time.is_zero() {
+        bot.stats.latency(Stage::Resolve, report.resolve_time);
       }
-
-      info!("Replacing message {}", msg.chat.id);
-
-      let mut text = String::with_capacity(128);
-      write!(text, "Send by ").unwrap();
-      match msg.from {
-        Some(user) => write_user(&mut text, &user),
-        None => {
-          write!(text, "Unknown").unwrap();
-        },
+      if chat.current_action().0 == Action::Audit {
+        if replaced != text {
+          audit(bot, &msg, &report)?;
+        }
+        return Ok(());
       }
-
-      writeln!(text, ":\n").unwrap();
-
-      text.push_str(&v_htmlescape::escape(&replaced).to_string());
-
-      if let Some(from) = msg.forward_from {
-        text.push_str("\n\n<i>forwarded from ");
-        write_user(&mut text, &from);
-        text.push_str("</i>");
-      } else if let Some(from_chat) = msg.forward_from_chat {
-        text.push_str("\n\n<i>forwarded from channel ");
-        let title = from_chat
-          .title
-          .map(|title| v_htmlescape::escape(&title).to_string())
-          .unwrap_or_else(|| "unknown".to_string());
-        if let (Some(username), Some(msg_id)) = (from_chat.username, msg.forward_from_message_id) {
-          write!(
-            text,
-            r#"<a href="https://t.me/{username}/{msg_id}">{title}</a>"#,
-          )
-          .unwrap();
-        } else if let Some(msg_id) = msg.forward_from_message_id {
-          debug!("from_chat.id = {}", from_chat.id);
-          let id = -(from_chat.id + 1000000000000);
-          write!(
-            text,
-            r#"<a href="https://t.me/c/{id}/{msg_id}">{title}</a>"#,
-          )
-          .unwrap();
-        } else {
-          text.write_str(&title).unwrap();
+      if chat.delete_ads
+        && matches!(ForwardOrigin::of(&msg), Some(ForwardOrigin::Channel { .. }))
+        && is_shop_ad(&text, &report)
+      {
+        return delete_unwanted(bot, &msg, "shop ad forwarded from a channel").await;
+      }
+      let (mut action, trial) = chat.current_action();
+      let report_score = match bot.posts.escalation(&config.spam, &msg, &text, trial) {
+        Escalation::Clean => None,
+        Escalation::Report(score) => Some(score),
+        Escalation::Delete(score) => {
+          return delete_unwanted(bot, &msg, &format!("spam scoring {score}")).await;
+        },
+      };
+      // Links that couldn't be expanded alone aren't worth a repost
+      if replaced == text || report.replacements.is_empty() {
+        if let Some(score) = report_score {
+          report_spam(bot, &msg, msg.message_id, score).await?;
         }
-        text.push_str("</i>");
-      } else if let Some(ref sender_name) = msg.forward_sender_name {
-        text.push_str("\n\n<i>forwarded from channel ");
-        write!(
-          text,
-          ", forwarded from {}",
-          v_htmlescape::escape(sender_name)
-        )
-        .unwrap();
-        text.push_str("</i>");
-      }
-
-      let mut send_msg = SendMessageParams::builder()
-        .chat_id(msg.chat.id)
-        .text(text)
-        .parse_mode(ParseMode::Html)
-        .build();
+        return Ok(());
+      }
 
-      send_msg.reply_to_message_id = msg.reply_to_message.map(|i| i.message_id);
+      let chat_id = msg.chat.id;
+      action = action.resolve(&report);
+      if action == Action::Repost && bot.db.downgrade(chat_id)?.is_some() {
+        action = Action::Reply;
+      }
+      for replacement in &report.replacements {
+        debug!(
+          update_id, chat_id, replacer = replacement.site.as_str();
+          "Cleaned {} -> {}", replacement.original, replacement.cleaned
+        );
+      }
+      let replacers: Vec<_> = report
+        .site_counts()
+        .into_iter()
+        .map(|(site, _)| site)
+        .collect();
+      info!(
+        update_id, chat_id, replacers = replacers.join(",");
+        "Replacing message {chat_id} ({action:?})"
+      );
 
-      let resp = api
-        .send_message(&send_msg)
-        .await
-        .context("Failed to send message...")?;
+      let template = config.template.for_chat(chat.template);
+      let mut send_msg = cleaned_message(
+        &msg,
+        &replaced,
+        &report,
+        action,
+        trial,
+        &template,
+        chat.timezone,
+      );
+      if !chat.notifications {
+        send_msg.disable_notification = Some(true);
+      }
+      if send_msg.text.trim().is_empty() {
+        warn!(chat_id; "Cleaned message {} in {chat_id} is empty, leaving it", msg.message_id);
+        return Ok(());
+      }
+      if config.dry_run {
+        info!(chat_id; "Dry run, would send in {chat_id}: {}", send_msg.text);
+        if action == Action::Repost {
+          info!(chat_id; "Dry run, would delete message {} in {chat_id}", msg.message_id);
+        }
+        return Ok(());
+      }
+      let mut chunks = split(&send_msg.text, MESSAGE_LIMIT, template.parse_mode).into_iter();
+      send_msg.text = chunks.next().unwrap_or_default();
+      let sending = Instant::now();
+      let sent = with_retry(&config.retry, "send message", || {
+        api
+          .send_message(&send_msg)
+          .instrument(info_span!("send_message", chat_id))
+      })
+      .await;
+      let resp = match sent {
+        Err(err) if config.report_missed && is_permission_error(&err) => {
+          bot
+            .stats
+            .missed(msg.chat.id, Missed::NoPermission, &replaced);
+          return Err(err).context("Failed to send message...");
+        },
+        Err(err) if is_topic_gone(&err) => {
+          warn!(chat_id; "Topic of message {} in {chat_id} is closed, leaving it: {err}", msg.message_id);
+          return Ok(());
+        },
+        resp => resp.context("Failed to send message...")?,
+      };
       debug!("{resp:?}");
+      let sent_id = resp.result.message_id;
+      send_rest(api, &config, &send_msg, chunks, "message")
+        .instrument(info_span!("send_message", chat_id))
+        .await?;
+      bot.stats.latency(Stage::Send, sending.elapsed());
 
-      let resp = api
-        .delete_message(
-          &DeleteMessageParams::builder()
-            .chat_id(msg.chat.id)
-            .message_id(msg.message_id)
-            .build(),
-        )
-        .await
-        .context("Failed to delete message...")?;
-      debug!("{resp:?}",);
+      if action == Action::Repost {
+        let params = DeleteMessageParams::builder()
+          .chat_id(msg.chat.id)
+          .message_id(msg.message_id)
+          .build();
+        let deleting = Instant::now();
+        let deleted = with_retry(&config.retry, "delete message", || {
+          api
+            .delete_message(&params)
+            .instrument(info_span!("delete_message", chat_id))
+        })
+        .await;
+        let resp = match deleted {
+          Err(err) if is_permission_error(&err) => {
+            if config.report_missed {
+              bot.stats.missed(chat_id, Missed::NoPermission, &replaced);
+            }
+            return downgrade(bot, &config, chat_id, &err).await;
+          },
+          resp => resp.context("Failed to delete message...")?,
+        };
+        debug!("{resp:?}",);
+        bot.stats.latency(Stage::Delete, deleting.elapsed());
+      }
+      count_cleaned(bot, &msg, &report);
+      bot.stats.latency(Stage::Total, received.elapsed());
+      if let Some(score) = report_score {
+        report_spam(bot, &msg, sent_id, score).await?;
+      }
 
       Ok(())
     },
+    UpdateContent::MyChatMember(member) => {
+      bot.seen.record(&member.chat, member.date);
+      Ok(())
+    },
     _ => {
       info!("Unsupported message type: {}", MessageType(update.content));
       Ok(())
@@ -162,3 +1084,463 @@ impl Display for MessageType {
     f.write_str(str)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use frankenstein::{Chat, ChatType, Message, SendMessageParams, User};
+  use fuckburl_bot::{
+    config::{Action, ChatTemplate, LinkPreview, Markup, Template},
+    ReplaceReport, Replacement,
+  };
+
+  use super::{
+    cleaned_message, expand, forward_line, header, is_permission_error, is_topic_gone,
+    private_reply, ForwardOrigin, NOTHING_LEFT, NOTHING_TO_CLEAN,
+  };
+
+  fn message() -> Message {
+    let chat = Chat::builder()
+      .id(-1001)
+      .type_field(ChatType::Supergroup)
+      .build();
+    Message::builder()
+      .message_id(1)
+      .date(0u64)
+      .chat(chat)
+      .build()
+  }
+
+  fn channel(username: Option<&str>) -> Option<Box<Chat>> {
+    let mut chat = Chat::builder()
+      .id(-1001234567890)
+      .type_field(ChatType::Channel)
+      .title("News <1>")
+      .build();
+    chat.username = username.map(str::to_string);
+    Some(Box::new(chat))
+  }
+
+  fn forward(msg: &Message) -> String {
+    forward_line(msg, Template::default().forward(), Markup::Html)
+      .map(|line| format!("\n\n{line}"))
+      .unwrap_or_default()
+  }
+
+  #[test]
+  fn not_forwarded() {
+    let msg = message();
+    assert_eq!(None, ForwardOrigin::of(&msg));
+    assert_eq!("", forward(&msg));
+  }
+
+  #[test]
+  fn forward_user() {
+    let mut msg = message();
+    let user = User::builder()
+      .id(42u64)
+      .is_bot(false)
+      .first_name("Alice")
+      .build();
+    msg.forward_from = Some(Box::new(user));
+    assert_eq!(
+      "\n\n<i>forwarded from <a href=\"tg:&#x2f;&#x2f;user?id=42\">Alice</a></i>",
+      forward(&msg)
+    );
+  }
+
+  #[test]
+  fn forward_hidden_user() {
+    let mut msg = message();
+    msg.forward_sender_name = Some("Bob & co".to_string());
+    assert_eq!(
+      Some(ForwardOrigin::HiddenUser("Bob & co")),
+      ForwardOrigin::of(&msg)
+    );
+    assert_eq!(
+      "\n\n<i>forwarded from Bob &amp; co (hidden account)</i>",
+      forward(&msg)
+    );
+  }
+
+  #[test]
+  fn forward_anonymous_admin() {
+    let mut msg = message();
+    let group = Chat::builder()
+      .id(-1009)
+      .type_field(ChatType::Supergroup)
+      .title("Group")
+      .build();
+    msg.forward_from_chat = Some(Box::new(group));
+    assert_eq!("\n\n<i>forwarded from Group</i>", forward(&msg));
+  }
+
+  #[test]
+  fn forward_channel() {
+    let mut msg = message();
+    msg.forward_from_chat = channel(Some("news"));
+    msg.forward_from_message_id = Some(7);
+    msg.forward_signature = Some("Carol".to_string());
+    assert_eq!(
+      "\n\n<i>forwarded from channel <a href=\"https:&#x2f;&#x2f;t.me&#x2f;news&#x2f;7\">News &lt;1&gt;</a> (Carol)</i>",
+      forward(&msg)
+    );
+
+    msg.forward_from_chat = channel(None);
+    msg.forward_signature = None;
+    assert_eq!(
+      "\n\n<i>forwarded from channel <a href=\"https:&#x2f;&#x2f;t.me&#x2f;c&#x2f;1234567890&#x2f;7\">News &lt;1&gt;</a></i>",
+      forward(&msg)
+    );
+  }
+
+  fn user(username: Option<&str>) -> User {
+    let mut user = User::builder()
+      .id(42u64)
+      .is_bot(false)
+      .first_name("Alice <3")
+      .build();
+    user.last_name = Some("Smith".to_string());
+    user.username = username.map(str::to_string);
+    user
+  }
+
+  fn report(sites: &[&str]) -> ReplaceReport {
+    ReplaceReport {
+      replacements: sites
+        .iter()
+        .map(|site| Replacement {
+          site: site.to_string(),
+          original: format!("https://{site}.example/post?from=tg"),
+          cleaned: format!("https://{site}.example/post"),
+          expanded: false,
+        })
+        .collect(),
+      ..Default::default()
+    }
+  }
+
+  const CLEANED: &str = "look https://www.bilibili.com/video/BV1xx & https://x.com/a/status/1";
+
+  /// Fields of the sent message that depend on the original, then its text.
+  fn render(msg: &Message, action: Action, trial: bool, sites: &[&str]) -> String {
+    render_in(Markup::Html, msg, action, trial, sites)
+  }
+
+  /// [`render`] with templates in `markup`.
+  fn render_in(
+    markup: Markup,
+    msg: &Message,
+    action: Action,
+    trial: bool,
+    sites: &[&str],
+  ) -> String {
+    let SendMessageParams {
+      chat_id,
+      message_thread_id,
+      reply_to_message_id,
+      parse_mode,
+      text,
+      ..
+    } = cleaned_message(
+      msg,
+      CLEANED,
+      &report(sites),
+      action,
+      trial,
+      &Template {
+        parse_mode: markup,
+        ..Default::default()
+      },
+      None,
+    );
+    format!(
+      "chat_id: {chat_id:?}\nmessage_thread_id: {message_thread_id:?}\n\
+       reply_to_message_id: {reply_to_message_id:?}\nparse_mode: {parse_mode:?}\n---\n{text}"
+    )
+  }
+
+  fn from(username: Option<&str>) -> Message {
+    let mut msg = message();
+    msg.from = Some(Box::new(user(username)));
+    msg
+  }
+
+  /// Snapshots the repost of `msg` as `name` in HTML, and as `name_markdown`
+  /// in MarkdownV2.
+  fn snapshot_reposts(name: &str, msg: &Message) {
+    insta::assert_snapshot!(name, render(msg, Action::Repost, false, &["bilibili"]));
+    insta::assert_snapshot!(
+      format!("{name}_markdown"),
+      render_in(
+        Markup::MarkdownV2,
+        msg,
+        Action::Repost,
+        false,
+        &["bilibili"]
+      )
+    );
+  }
+
+  #[test]
+  fn render_repost() {
+    snapshot_reposts("render_repost", &from(Some("alice")));
+  }
+
+  #[test]
+  fn render_username_less() {
+    snapshot_reposts("render_username_less", &from(None));
+  }
+
+  #[test]
+  fn render_unknown_sender() {
+    snapshot_reposts("render_unknown_sender", &message());
+  }
+
+  #[test]
+  fn render_forwarded() {
+    let mut msg = from(Some("alice"));
+    msg.forward_from = Some(Box::new(user(None)));
+    snapshot_reposts("render_forwarded_user", &msg);
+
+    let mut msg = from(Some("alice"));
+    msg.forward_from_chat = channel(Some("news"));
+    msg.forward_from_message_id = Some(7);
+    msg.forward_signature = Some("Carol".to_string());
+    snapshot_reposts("render_forwarded_channel", &msg);
+
+    let mut msg = from(Some("alice"));
+    msg.forward_sender_name = Some("Bob".to_string());
+    snapshot_reposts("render_forwarded_hidden", &msg);
+  }
+
+  #[test]
+  fn render_anonymous_admin() {
+    let mut msg = message();
+    let mut bot = User::builder()
+      .id(1087968824u64)
+      .is_bot(true)
+      .first_name("Group")
+      .build();
+    bot.username = Some("GroupAnonymousBot".to_string());
+    msg.from = Some(Box::new(bot));
+    let mut group = *msg.chat.clone();
+    group.title = Some("Group <3".to_string());
+    msg.sender_chat = Some(Box::new(group));
+    snapshot_reposts("render_anonymous_admin", &msg);
+  }
+
+  #[test]
+  fn render_topic_message() {
+    let mut msg = from(Some("alice"));
+    msg.message_thread_id = Some(99);
+    msg.is_topic_message = Some(true);
+    snapshot_reposts("render_topic_message", &msg);
+  }
+
+  fn api_error(error_code: u64, description: &str) -> frankenstein::Error {
+    frankenstein::Error::Api(frankenstein::ErrorResponse {
+      ok: false,
+      description: description.to_string(),
+      error_code,
+      parameters: None,
+    })
+  }
+
+  #[test]
+  fn topic_gone() {
+    let err = |description: &str| api_error(400, description);
+    assert!(is_topic_gone(&err("Bad Request: TOPIC_CLOSED")));
+    assert!(is_topic_gone(&err("Bad Request: message thread not found")));
+    assert!(!is_topic_gone(&err("Bad Request: message text is empty")));
+  }
+
+  #[test]
+  fn permission_errors() {
+    assert!(is_permission_error(&api_error(
+      400,
+      "Bad Request: not enough rights to send text messages to the chat"
+    )));
+    assert!(is_permission_error(&api_error(
+      400,
+      "Bad Request: CHAT_ADMIN_REQUIRED"
+    )));
+    // only this message is affected
+    assert!(!is_permission_error(&api_error(
+      400,
+      "Bad Request: message can't be deleted"
+    )));
+    assert!(!is_permission_error(&api_error(
+      403,
+      "Forbidden: bot was kicked from the supergroup chat"
+    )));
+  }
+
+  #[test]
+  fn render_reply() {
+    let mut msg = from(Some("alice"));
+    let mut original = message();
+    original.message_id = 0;
+    msg.reply_to_message = Some(Box::new(original));
+    insta::assert_snapshot!(
+      "render_reply_repost",
+      render(&msg, Action::Repost, false, &["bilibili"])
+    );
+    insta::assert_snapshot!(
+      "render_reply_reply",
+      render(&msg, Action::Reply, false, &["bilibili"])
+    );
+  }
+
+  #[test]
+  fn render_reply_with_quote() {
+    // frankenstein 0.24 has no `quote`, so this is a reply to someone else's
+    // text, which a repost must keep replying to
+    let mut msg = from(Some("alice"));
+    let mut original = from(Some("bob"));
+    original.message_id = 0;
+    original.text = Some("what's the link?".to_string());
+    msg.reply_to_message = Some(Box::new(original));
+    snapshot_reposts("render_reply_with_quote", &msg);
+  }
+
+  #[test]
+  fn render_links() {
+    insta::assert_snapshot!(render(
+      &from(Some("alice")),
+      Action::Links,
+      false,
+      &["bilibili", "twitter", "bilibili"]
+    ));
+  }
+
+  #[test]
+  fn nothing_left() {
+    let msg = from(Some("alice"));
+    let mut report = report(&["custom"]);
+    let repost = |report: &ReplaceReport, action| {
+      cleaned_message(
+        &msg,
+        " ",
+        report,
+        action,
+        false,
+        &Template {
+          header: String::new(),
+          ..Default::default()
+        },
+        None,
+      )
+      .text
+    };
+    assert_eq!(
+      format!("\n\n<i>{NOTHING_LEFT}</i>"),
+      repost(&report, Action::Repost)
+    );
+    report.replacements[0].cleaned = String::new();
+    assert_eq!(
+      "https:&#x2f;&#x2f;custom.example&#x2f;post?from=tg → (removed)",
+      repost(&report, Action::Reply)
+    );
+  }
+
+  #[test]
+  fn link_preview() {
+    let msg = from(Some("alice"));
+    let report = report(&["twitter", "bilibili"]);
+    let send = |link_preview| {
+      cleaned_message(
+        &msg,
+        "https://bilibili.example/post then https://twitter.example/post",
+        &report,
+        Action::Repost,
+        false,
+        &Template {
+          link_preview,
+          ..Default::default()
+        },
+        None,
+      )
+    };
+    let first = send(LinkPreview::FirstLink);
+    assert!(
+      first.text.starts_with(
+        "<a href=\"https:&#x2f;&#x2f;bilibili.example&#x2f;post\">\u{200b}</a>Send by"
+      ),
+      "{}",
+      first.text
+    );
+    assert_eq!(None, first.disable_web_page_preview);
+    assert_eq!(
+      Some(true),
+      send(LinkPreview::Disabled).disable_web_page_preview
+    );
+  }
+
+  #[test]
+  fn header_variables() {
+    assert_eq!(
+      "@alice cleaned 2 links at 1970-01-01 08:00",
+      header(
+        "{user} cleaned {link_count} links at {date}",
+        "@alice",
+        0,
+        Some(chrono_tz::Asia::Shanghai),
+        &report(&["bilibili", "twitter"])
+      )
+    );
+  }
+
+  #[test]
+  fn private_replies() {
+    assert_eq!(NOTHING_TO_CLEAN, private_reply(false, "hi"));
+    assert_eq!(format!("<i>{NOTHING_LEFT}</i>"), private_reply(true, " "));
+    assert_eq!("a &amp; b", private_reply(true, "a & b"));
+  }
+
+  #[test]
+  fn expand_once() {
+    assert_eq!(
+      "{date} says hi {unknown}",
+      expand(
+        "{user} says {text} {unknown}",
+        &[("user", "{date}"), ("date", "never"), ("text", "hi")]
+      )
+    );
+    assert_eq!("{user", expand("{user", &[("user", "x")]));
+  }
+
+  #[test]
+  fn repost_template() {
+    let mut msg = from(Some("alice"));
+    msg.forward_sender_name = Some("Bob".to_string());
+    let template = Template::default().for_chat(Some(&ChatTemplate {
+      repost: Some("{user}: {text}\n{forward}".to_string()),
+      forward: Some("(转发自 {source})".to_string()),
+      ..Default::default()
+    }));
+    let send_msg = cleaned_message(
+      &msg,
+      "a & b",
+      &report(&["bilibili"]),
+      Action::Repost,
+      false,
+      &template,
+      None,
+    );
+    assert_eq!(
+      "@alice: a &amp; b\n(转发自 Bob (hidden account))",
+      send_msg.text
+    );
+  }
+
+  #[test]
+  fn render_summary_and_trial() {
+    let msg = from(Some("alice"));
+    insta::assert_snapshot!(render(
+      &msg,
+      Action::Reply,
+      true,
+      &["bilibili", "twitter", "bilibili"]
+    ));
+  }
+}
@@ -0,0 +1,49 @@
+//! C bindings of [`clean_offline`], built with the `ffi` feature.
+//!
+//! Strings are NUL-terminated UTF-8, strings returned by this module must be
+//! released with [`fuckburl_free`].
+
+use std::{
+  ffi::{c_char, CStr, CString},
+  ptr,
+};
+
+use crate::clean_offline;
+
+/// Cleans `text` without network access.
+///
+/// Returns the cleaned text, or null if `text` is null or not valid UTF-8.
+/// If `replaced` isn't null, the number of cleaned links is written to it.
+///
+/// # Safety
+///
+/// `text` must be null or point to a NUL-terminated string, `replaced` must be
+/// null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn fuckburl_clean(text: *const c_char, replaced: *mut usize) -> *mut c_char {
+  if text.is_null() {
+    return ptr::null_mut();
+  }
+  let Ok(text) = CStr::from_ptr(text).to_str() else {
+    return ptr::null_mut();
+  };
+  let (cleaned, report) = clean_offline(text);
+  if !replaced.is_null() {
+    *replaced = report.replacements.len();
+  }
+  // Input had no NUL in it, neither does the output.
+  CString::new(cleaned).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+/// Releases a string returned by this library, null is ignored.
+///
+/// # Safety
+///
+/// `text` must be null or a string returned by [`fuckburl_clean`] that wasn't
+/// released yet.
+#[no_mangle]
+pub unsafe extern "C" fn fuckburl_free(text: *mut c_char) {
+  if !text.is_null() {
+    drop(CString::from_raw(text));
+  }
+}
@@ -0,0 +1,95 @@
+//! Polling liveness, served by `/healthz`.
+
+use std::{
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+  },
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+
+pub(crate) fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|now| now.as_secs())
+    .unwrap_or_default()
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Health {
+  /// Unix time of the last successful `getUpdates`, 0 before the first.
+  last_poll: AtomicU64,
+  /// Error of the last `getUpdates`, `None` if it succeeded.
+  last_error: Mutex<Option<String>>,
+}
+
+impl Health {
+  pub fn polled(&self) {
+    self.last_poll.store(now(), Ordering::Release);
+    *self.last_error.lock().unwrap() = None;
+  }
+
+  /// Unix time of the last successful `getUpdates`.
+  pub fn last_poll(&self) -> Option<u64> {
+    Some(self.last_poll.load(Ordering::Acquire)).filter(|at| *at != 0)
+  }
+
+  pub fn poll_failed(&self, err: String) {
+    *self.last_error.lock().unwrap() = Some(err);
+  }
+
+  /// Returns whether polling succeeded within `stale_after`, counting from
+  /// `start` before the first poll, and the details.
+  pub fn check(&self, start: u64, stale_after: Duration) -> (bool, Value) {
+    self.check_at(now(), start, stale_after)
+  }
+
+  fn check_at(&self, now: u64, start: u64, stale_after: Duration) -> (bool, Value) {
+    let last_poll = self.last_poll.load(Ordering::Acquire);
+    let since = now.saturating_sub(if last_poll == 0 { start } else { last_poll });
+    let error = self.last_error.lock().unwrap().clone();
+    let healthy = since <= stale_after.as_secs();
+    let status = match (healthy, &error) {
+      (false, _) => "stale",
+      (true, Some(_)) => "degraded",
+      (true, None) => "ok",
+    };
+    (
+      healthy,
+      json!({
+        "status": status,
+        "last_get_updates": (last_poll != 0).then_some(last_poll),
+        "seconds_since_get_updates": since,
+        "telegram": match error {
+          Some(err) => json!({ "connected": false, "error": err }),
+          None => json!({ "connected": last_poll != 0 }),
+        },
+      }),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::Health;
+
+  #[test]
+  fn stale() {
+    let health = Health::default();
+    let stale_after = Duration::from_secs(60);
+    let (healthy, body) = health.check_at(130, 100, stale_after);
+    assert!(healthy);
+    assert_eq!(body["last_get_updates"], serde_json::Value::Null);
+    assert!(!health.check_at(200, 100, stale_after).0);
+
+    health.poll_failed("dns error".to_string());
+    let (healthy, body) = health.check_at(130, 100, stale_after);
+    assert!(healthy);
+    assert_eq!(body["status"], "degraded");
+    assert_eq!(body["telegram"]["error"], "dns error");
+  }
+}
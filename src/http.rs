@@ -0,0 +1,259 @@
+//! HTTP endpoints, served when `[health] listen` is set.
+
+use std::{convert::Infallible, future::Future, net::SocketAddr, sync::Arc};
+
+use anyhow::{Context, Result};
+use fuckburl_bot::{
+  config::Config,
+  replacer::{replacer_patterns, CustomAction},
+};
+use hyper::{
+  header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+  server::conn::AddrStream,
+  service::{make_service_fn, service_fn},
+  Body, Method, Request, Response, Server, StatusCode,
+};
+use log::{error, info};
+use serde_json::json;
+
+use crate::{health::now, reload::Reloader, Bot, START_TIME};
+
+fn json(status: StatusCode, body: &serde_json::Value) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .header(CONTENT_TYPE, "application/json")
+    .body(Body::from(body.to_string()))
+    .unwrap()
+}
+
+/// Health of the first bot, with every bot under `bots` when there're more.
+fn health(bots: &[Arc<Bot>]) -> (bool, serde_json::Value) {
+  let stale_after = bots[0].config.load().health.stale_after;
+  let (mut healthy, mut body) = bots[0].health.check(*START_TIME, stale_after);
+  if bots.len() > 1 {
+    let mut each = serde_json::Map::new();
+    for bot in bots {
+      let (bot_healthy, bot_body) = bot.health.check(*START_TIME, stale_after);
+      healthy &= bot_healthy;
+      each.insert(bot.username.clone(), bot_body);
+    }
+    body["bots"] = each.into();
+  }
+  let stats = &bots[0].stats;
+  body["uptime_seconds"] = now().saturating_sub(*START_TIME).into();
+  body["updates"] = stats.updates().into();
+  body["last_cleaned"] = stats.last_cleaned().into();
+  (healthy, body)
+}
+
+/// Replacers and `[[rules]]` of `config`, with the `[[chats]]` disabling
+/// each.
+fn rules(config: &Config) -> serde_json::Value {
+  let disabled_in = |name: &str| {
+    config
+      .chats
+      .iter()
+      .filter(|chat| chat.disable.iter().any(|disabled| disabled == name))
+      .map(|chat| chat.id.clone())
+      .collect::<Vec<_>>()
+  };
+  let replacers: Vec<_> = replacer_patterns()
+    .map(|(name, pattern)| {
+      json!({
+        "name": name,
+        "pattern": pattern,
+        "disabled_in": disabled_in(name),
+      })
+    })
+    .collect();
+  let custom: Vec<_> = config
+    .rules
+    .iter()
+    .map(|rule| {
+      let mut body = json!({
+        "name": rule.name,
+        "pattern": rule.regex.as_str(),
+        "disabled_in": disabled_in(&rule.name),
+      });
+      match &rule.action {
+        CustomAction::Replace(template) => body["replace"] = template.as_str().into(),
+        CustomAction::Keep(keys) => body["keep"] = keys.clone().into(),
+        CustomAction::Command { program, args, .. } => {
+          body["command"] = program.as_str().into();
+          body["args"] = args.clone().into();
+        },
+      }
+      body
+    })
+    .collect();
+  json!({
+    "replacers": replacers,
+    "rules": custom,
+    "tracking_params": config.tracking_params,
+  })
+}
+
+/// Whether `req` from `peer` may see and reload rules, which needs `token`
+/// as a bearer token when set, or a request from this host otherwise.
+fn authorized(token: Option<&str>, peer: SocketAddr, req: &Request<Body>) -> bool {
+  match token {
+    Some(token) => req
+      .headers()
+      .get(AUTHORIZATION)
+      .and_then(|value| value.to_str().ok())
+      .and_then(|value| value.strip_prefix("Bearer "))
+      .is_some_and(|given| given == token),
+    None => peer.ip().is_loopback(),
+  }
+}
+
+fn route(
+  bots: &[Arc<Bot>],
+  reloader: &Reloader,
+  peer: SocketAddr,
+  req: &Request<Body>,
+) -> Response<Body> {
+  let path = req.uri().path();
+  if path.starts_with("/rules")
+    && !authorized(bots[0].config.load().health.token.as_deref(), peer, req)
+  {
+    return Response::builder()
+      .status(StatusCode::UNAUTHORIZED)
+      .header(WWW_AUTHENTICATE, "Bearer")
+      .body(Body::empty())
+      .unwrap();
+  }
+  match (req.method(), path) {
+    (&Method::GET, "/healthz") => {
+      let (healthy, body) = health(bots);
+      let status = if healthy {
+        StatusCode::OK
+      } else {
+        StatusCode::SERVICE_UNAVAILABLE
+      };
+      json(status, &body)
+    },
+    (&Method::GET, "/metrics") => json(
+      StatusCode::OK,
+      &json!({ "latency": bots[0].stats.latency_json() }),
+    ),
+    (&Method::GET, "/rules") => json(StatusCode::OK, &rules(&bots[0].config.load())),
+    (&Method::POST, "/rules/reload") => match reloader.reload() {
+      Ok(changes) => {
+        let mut body = rules(&bots[0].config.load());
+        body["changes"] = changes.into();
+        json(StatusCode::OK, &body)
+      },
+      Err(err) => {
+        error!("Failed to reload config, keeping the current one: {err:?}");
+        json(
+          StatusCode::UNPROCESSABLE_ENTITY,
+          &json!({ "error": format!("{err:#}") }),
+        )
+      },
+    },
+    _ => Response::builder()
+      .status(StatusCode::NOT_FOUND)
+      .body(Body::empty())
+      .unwrap(),
+  }
+}
+
+/// Binds `addr`, the returned future serves until the process exits.
+pub(crate) fn serve(
+  addr: SocketAddr,
+  bots: Vec<Arc<Bot>>,
+  reloader: Arc<Reloader>,
+) -> Result<impl Future<Output = ()>> {
+  let bots: Arc<[Arc<Bot>]> = bots.into();
+  let make = make_service_fn(move |conn: &AddrStream| {
+    let bots = Arc::clone(&bots);
+    let reloader = Arc::clone(&reloader);
+    let peer = conn.remote_addr();
+    async move {
+      Ok::<_, Infallible>(service_fn(move |req| {
+        let resp = route(&bots, &reloader, peer, &req);
+        async move { Ok::<_, Infallible>(resp) }
+      }))
+    }
+  });
+  let server = Server::try_bind(&addr)
+    .with_context(|| format!("Failed to listen on {addr}"))?
+    .serve(make);
+  info!("Serving health endpoint on http://{addr}/healthz and rules on http://{addr}/rules");
+  Ok(async move {
+    if let Err(err) = server.await {
+      error!("Health endpoint stopped: {err}");
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use fuckburl_bot::config::Config;
+  use hyper::{header::AUTHORIZATION, Body, Request};
+  use serde_json::json;
+
+  use super::{authorized, rules};
+
+  #[test]
+  fn rules_auth() {
+    let local = "127.0.0.1:4000".parse().unwrap();
+    let remote = "203.0.113.7:4000".parse().unwrap();
+    let request = |auth: Option<&str>| {
+      let mut req = Request::get("/rules");
+      if let Some(auth) = auth {
+        req = req.header(AUTHORIZATION, auth);
+      }
+      req.body(Body::empty()).unwrap()
+    };
+    assert!(authorized(None, local, &request(None)));
+    assert!(!authorized(None, remote, &request(Some("Bearer secret"))));
+    assert!(authorized(
+      Some("secret"),
+      remote,
+      &request(Some("Bearer secret"))
+    ));
+    assert!(!authorized(Some("secret"), local, &request(None)));
+    assert!(!authorized(
+      Some("secret"),
+      remote,
+      &request(Some("Bearer other"))
+    ));
+    assert!(!authorized(
+      Some("secret"),
+      remote,
+      &request(Some("secret"))
+    ));
+  }
+
+  #[test]
+  fn rules_of_config() {
+    let (config, _) = Config::from_toml(
+      "telegram-token = \"123:abc\"\ntracking-params = [\"from\"]\n\
+       [[chats]]\nid = \"-1\"\ndisable = [\"tracking\", \"youtube\"]\n\
+       [[rules]]\nname = \"youtube\"\npattern = 'https://youtu\\.be/\\S+'\nkeep = [\"t\"]\n",
+    )
+    .unwrap();
+    let rules = rules(&config);
+    // the catch-all comes last, whichever replacers are built
+    let (tracking, others) = rules["replacers"].as_array().unwrap().split_last().unwrap();
+    assert_eq!(
+      json!({ "name": "tracking", "pattern": "https?://", "disabled_in": ["-1"] }),
+      *tracking
+    );
+    assert!(others
+      .iter()
+      .all(|replacer| replacer["disabled_in"] == json!([])));
+    assert_eq!(
+      json!([{
+        "name": "youtube",
+        "pattern": r"https://youtu\.be/\S+",
+        "disabled_in": ["-1"],
+        "keep": ["t"],
+      }]),
+      rules["rules"]
+    );
+    assert_eq!(json!(["from"]), rules["tracking_params"]);
+  }
+}
@@ -0,0 +1,84 @@
+//! One JSON object per log line, with the record's key-values as fields.
+
+use chrono::{Local, SecondsFormat};
+use log::{
+  kv::{self, Key, Value, VisitSource},
+  Record,
+};
+use log4rs::encode::{Encode, Write};
+use serde_json::{Map, Number};
+
+#[derive(Debug)]
+pub(crate) struct JsonEncoder;
+
+struct Fields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+  fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+    let value = if let Some(n) = value.to_i64() {
+      serde_json::Value::Number(n.into())
+    } else if let Some(n) = value.to_u64() {
+      serde_json::Value::Number(n.into())
+    } else if let Some(n) = value.to_f64().and_then(Number::from_f64) {
+      serde_json::Value::Number(n)
+    } else if let Some(b) = value.to_bool() {
+      serde_json::Value::Bool(b)
+    } else {
+      serde_json::Value::String(value.to_string())
+    };
+    self.0.insert(key.to_string(), value);
+    Ok(())
+  }
+}
+
+fn to_json(record: &Record) -> anyhow::Result<Map<String, serde_json::Value>> {
+  let mut map = Map::new();
+  map.insert(
+    "time".to_string(),
+    Local::now()
+      .to_rfc3339_opts(SecondsFormat::Millis, false)
+      .into(),
+  );
+  map.insert("level".to_string(), record.level().as_str().into());
+  map.insert("target".to_string(), record.target().into());
+  map.insert("message".to_string(), record.args().to_string().into());
+  record.key_values().visit(&mut Fields(&mut map))?;
+  Ok(map)
+}
+
+impl Encode for JsonEncoder {
+  fn encode(&self, w: &mut dyn Write, record: &Record) -> anyhow::Result<()> {
+    serde_json::to_writer(&mut *w, &to_json(record)?)?;
+    w.write_all(b"\n")?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use log::{Level, Record};
+
+  use super::to_json;
+
+  #[test]
+  fn fields() {
+    let kvs = [("chat_id", -1001i64)];
+    let replacer = [("replacer", "bilibili")];
+    let record = Record::builder()
+      .level(Level::Info)
+      .target("fuckburl_bot")
+      .args(format_args!("hello"))
+      .key_values(&kvs)
+      .build();
+    let json = to_json(&record).unwrap();
+    assert_eq!(json["level"], "INFO");
+    assert_eq!(json["message"], "hello");
+    assert_eq!(json["chat_id"], -1001);
+
+    let record = Record::builder()
+      .args(format_args!(""))
+      .key_values(&replacer)
+      .build();
+    assert_eq!(to_json(&record).unwrap()["replacer"], "bilibili");
+  }
+}
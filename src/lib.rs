@@ -0,0 +1,21 @@
+//! Link cleaning rules of fuckburl-bot, usable without running the bot.
+
+#[macro_use]
+extern crate lazy_static;
+
+pub mod ads;
+pub mod bilibili;
+pub mod cache;
+pub mod config;
+pub mod embed;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod plugin;
+#[cfg(feature = "python")]
+mod python;
+pub mod replacer;
+pub mod resolver;
+
+pub use replacer::{
+  clean_offline, clean_offline_with, replace_all, ReplaceReport, Replacement, Rules,
+};
@@ -1,73 +1,163 @@
 #[macro_use]
 extern crate lazy_static;
 
+mod album;
+mod check;
+mod command;
+mod db;
+mod entities;
 mod event;
-mod replacer;
+mod health;
+mod http;
+mod json_log;
+mod maintenance;
+mod order;
+mod reload;
+mod repl;
+mod reporting;
+mod retry;
+mod seen;
+mod setup;
+mod spam;
+mod split;
+mod stats;
+mod summary;
+mod telemetry;
 
 use async_stream::stream;
 use futures::pin_mut;
-use futures_util::stream::StreamExt;
-use log::{debug, error, info, LevelFilter, trace};
+use futures_util::stream::{select_all, Stream, StreamExt};
+use log::{debug, error, info, trace, warn, LevelFilter};
 use log4rs::{
-  append::console::ConsoleAppender,
+  append::{
+    console::ConsoleAppender,
+    rolling_file::{
+      policy::compound::{
+        roll::fixed_window::FixedWindowRoller, trigger::size::SizeTrigger, CompoundPolicy,
+      },
+      RollingFileAppender,
+    },
+  },
   config::{Appender, Root},
-  encode::pattern::PatternEncoder,
+  encode::{pattern::PatternEncoder, Encode},
+};
+use reqwest::Client;
+use tokio::{
+  sync::Semaphore,
+  task::{JoinError, JoinSet},
 };
-use reqwest::{Client, Proxy};
-use serde::Deserialize;
+use tracing::{info_span, Instrument};
 
 use std::{
+  collections::HashMap,
   fs::{self, File},
-  io::{BufReader, BufWriter, Read, Write},
-  path::PathBuf,
+  io::{self, BufReader, BufWriter, IsTerminal, Read, Write},
+  iter,
+  path::{Path, PathBuf},
   process,
-  sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc,
-  },
-  time::{Duration, SystemTime, UNIX_EPOCH},
+  sync::{Arc, RwLock},
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{bail, Context, Result};
-use clap::{Parser, ValueHint};
+use arc_swap::ArcSwap;
+use clap::{Parser, Subcommand, ValueHint};
 use clap_verbosity_flag::{LogLevel, Verbosity};
-use frankenstein::{AllowedUpdate, AsyncApi, AsyncTelegramApi, GetUpdatesParams};
+use frankenstein::{
+  AllowedUpdate, AsyncApi, AsyncTelegramApi, GetChatParams, GetUpdatesParams, Update, User,
+};
 
-use crate::event::process_update;
+use fuckburl_bot::{
+  cache::Cache,
+  config::{Config, LogFormat, Logging},
+  embed::{self, Embeds},
+  plugin::Plugins,
+  resolver::Resolver,
+};
+
+use crate::{
+  album::Albums,
+  db::Db,
+  event::process_update,
+  health::Health,
+  json_log::JsonEncoder,
+  order::ChatQueues,
+  reload::Reloader,
+  seen::SeenChats,
+  spam::Posts,
+  stats::{Stage, Stats},
+};
 
 #[derive(Parser, Debug)]
 struct Cli {
   #[arg(short = 'c', long, value_name = "DIR")]
   #[arg(value_hint = ValueHint::FilePath)]
   config_file: Option<PathBuf>,
+  /// Run the interactive setup wizard and write the config file
+  #[arg(long)]
+  setup: bool,
+  /// Validate the config file and exit, failing on any problem
+  #[arg(long)]
+  check_config: bool,
+  /// With --check-config, also check the token with telegram
+  #[arg(long, requires = "check_config")]
+  online: bool,
+  /// Replace the enabled chats stored in the database with enabled-chats and
+  /// [[chats]] of the config, which otherwise only seed them
+  #[arg(long)]
+  resync_chats: bool,
   #[clap(flatten)]
   verbose: Verbosity<DefaultLevel>,
+  #[command(subcommand)]
+  command: Option<Command>,
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all(deserialize = "kebab-case"))]
-struct Config {
-  telegram_token: String,
-  #[serde(default = "Default::default")]
-  enabled_chats: Vec<String>,
-  proxy: Option<String>,
-  #[serde(default = "Default::default")]
-  time: Time,
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Clean pasted text with annotations, reloading rules when their file changes
+  Repl {
+    /// File with `[[rules]]`, defaults to the config file
+    #[arg(long, value_name = "FILE")]
+    #[arg(value_hint = ValueHint::FilePath)]
+    rules: Option<PathBuf>,
+  },
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all(deserialize = "kebab-case"))]
-struct Time {
-  fetch_delay: u64,
-  failed_delay: u64,
+const DEFAULT_CONFIG: &str = include_str!("config.example.toml");
+
+/// State shared by update tasks.
+pub(crate) struct Bot {
+  pub api: Arc<AsyncApi>,
+  pub username: String,
+  /// Index in `[[bots]]`, `None` for the bot of `telegram-token`.
+  pub entry: Option<usize>,
+  /// `@username` chat entries resolved with `getChat` at startup, by chat id.
+  pub usernames: HashMap<i64, String>,
+  /// Stored in the database, seeded from `enabled-chats` on first run.
+  pub enabled_chats: RwLock<Vec<String>>,
+  pub config: Arc<ArcSwap<Config>>,
+  pub embeds: Arc<Embeds>,
+  pub resolver: Arc<Resolver>,
+  pub plugins: Arc<Plugins>,
+  pub seen: Arc<SeenChats>,
+  pub stats: Arc<Stats>,
+  pub posts: Arc<Posts>,
+  pub admins: Arc<Cache<i64, Vec<User>>>,
+  /// Albums waiting for the rest of their messages.
+  pub albums: Albums,
+  pub db: Arc<Db>,
+  pub health: Health,
 }
 
-impl Default for Time {
-  fn default() -> Self {
-    Self {
-      fetch_delay: 1000,
-      failed_delay: 5000,
-    }
+impl Bot {
+  /// Number of the bot in the database, 0 for the bot of `telegram-token`.
+  pub fn number(&self) -> i64 {
+    self.entry.map_or(0, |i| i as i64 + 1)
+  }
+
+  /// Username of chat `id`, from the update or resolved at startup.
+  pub fn username_of<'a>(&'a self, id: i64, username: Option<&'a str>) -> Option<&'a str> {
+    username.or_else(|| self.usernames.get(&id).map(String::as_str))
   }
 }
 
@@ -75,104 +165,343 @@ lazy_static! {
   static ref START_TIME: u64 = {
     let start = SystemTime::now();
     let since_the_epoch = start
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards");
+      .duration_since(UNIX_EPOCH)
+      .expect("Time went backwards");
     since_the_epoch.as_secs()
   };
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
   let args = Cli::parse();
-  init_logger(args.verbose.log_level_filter());
+  // the runtime is needed before the config is properly loaded, so this is
+  // a best effort peek, falling back to a single thread
+  let worker_threads = config_path(args.config_file.clone())
+    .ok()
+    .and_then(|path| fs::read_to_string(path).ok())
+    .and_then(|str| Config::from_toml(&str).ok())
+    .and_then(|(config, _)| config.runtime.worker_threads)
+    .filter(|threads| *threads > 0);
+  let mut runtime = match worker_threads {
+    Some(threads) => {
+      let mut runtime = tokio::runtime::Builder::new_multi_thread();
+      runtime.worker_threads(threads);
+      runtime
+    },
+    None => tokio::runtime::Builder::new_current_thread(),
+  };
+  runtime
+    .enable_all()
+    .build()
+    .context("Failed to start the async runtime")?
+    .block_on(run(args, worker_threads))
+}
+
+async fn run(args: Cli, worker_threads: Option<usize>) -> Result<()> {
+  let verbosity = args.verbose.log_level_filter();
+  let logger = init_logger(verbosity);
   info!("Start at: {:?}", *START_TIME);
+  match worker_threads {
+    Some(threads) => info!("Running on {threads} worker threads"),
+    None => debug!("Running on the current thread"),
+  }
   debug!("{args:?}");
-  let config = init_config(args.config_file).context("Failed to init config file")?;
-  let config = Arc::new(config);
-  debug!("{config:?}");
-
-  let mut cli = Client::builder();
-  if let Some(proxy) = &config.proxy {
-    let proxy =
-      Proxy::all(proxy.clone()).with_context(|| format!("Failed to set \"{proxy}\" as proxy"))?;
-    cli = cli.proxy(proxy);
+  let config_path = config_path(args.config_file)?;
+  if let Some(Command::Repl { rules }) = args.command {
+    return repl::run(&rules.unwrap_or(config_path)).await;
   }
-  let cli = cli.build()?;
-
-  let tg_api = AsyncApi::builder()
-    .api_url(format!(
-      "{}{}",
-      frankenstein::BASE_API_URL,
-      &*config.telegram_token,
-    ))
+  if args.check_config {
+    return check::run(&config_path, args.online).await;
+  }
+  if args.setup
+    || (!config_path.exists()
+      && io::stdin().is_terminal()
+      && setup::confirm("No config found, run setup wizard?", true)?)
+  {
+    setup::run(&config_path).await.context("Setup failed")?;
+  }
+  let config = init_config(&config_path).context("Failed to init config file")?;
+  logger.set_config(
+    logger_config(verbosity, &config.logging).context("Failed to set up file logging")?,
+  );
+  let config = Arc::new(ArcSwap::from_pointee(config));
+  let initial = config.load_full();
+  debug!("{initial:?}");
+  for unknown in check::unknown_replacers(&initial) {
+    warn!("{unknown}");
+  }
+
+  let _sentry = reporting::init(&initial.sentry);
+  let _telemetry = telemetry::init(&initial.tracing)?;
+  let db_path = initial
+    .database
+    .clone()
+    .unwrap_or_else(|| config_path.with_file_name("fuckburl.db"));
+  let db = Db::open(&db_path)?;
+
+  let cli = check::http_client(&initial)?;
+
+  let tokens =
+    iter::once(&initial.telegram_token).chain(initial.bots.iter().map(|bot| &bot.telegram_token));
+  let mut apis = Vec::new();
+  for token in tokens {
+    apis.push(connect(&cli, token).await?);
+  }
+
+  let embeds = Arc::new(initial.embeds());
+  tokio::spawn(embed::probe_loop(
+    cli.clone(),
+    Arc::clone(&embeds),
+    Arc::clone(&apis[0].0),
+    Arc::clone(&config),
+  ));
+  let resolver = Arc::new(Resolver::new(
+    check::resolver_client(&initial)?,
+    initial.timeouts.resolve,
+    initial.resolver.host_interval,
+    initial.resolver.max_redirects,
+  ));
+  resolver.set_shorteners(&initial.resolver.shorteners);
+  resolver.set_headers(&initial.resolver.headers, initial.resolver.private);
+  resolver.set_cache_limits(initial.cache.limits());
+  let plugins = Arc::new(Plugins::load(
+    initial.plugins.as_deref(),
+    Arc::clone(&resolver),
+  )?);
+  if !plugins.is_empty() {
+    info!("Loaded plugins {:?}", plugins.names());
+  }
+  let admins = Arc::new(event::admin_cache(initial.cache.limits()));
+  let reloader = Arc::new(Reloader {
+    path: config_path,
+    config: Arc::clone(&config),
+    embeds: Arc::clone(&embeds),
+    resolver: Arc::clone(&resolver),
+    admins: Arc::clone(&admins),
+    api: Arc::clone(&apis[0].0),
+  });
+  tokio::spawn(reload::watch_config(Arc::clone(&reloader)));
+
+  let seen = Arc::new(SeenChats::default());
+  let stats = Arc::new(Stats::default());
+  let posts = Arc::new(Posts::default());
+  let db = Arc::new(db);
+  let mut bots = Vec::new();
+  for (i, (api, username)) in apis.into_iter().enumerate() {
+    let entry = i.checked_sub(1);
+    let number = i as i64;
+    let chats: Vec<_> = initial
+      .chats_of(entry)
+      .map(|chat| chat.id.clone())
+      .collect();
+    if db.seed_enabled_chats(
+      number,
+      initial.enabled_chats_of(entry),
+      &chats,
+      args.resync_chats,
+    )? {
+      info!("Stored enabled chats of @{username} from the config");
+    }
+    let enabled_chats = db.enabled_chats(number)?;
+    let usernames = resolve_usernames(&api, &initial, entry, &enabled_chats).await;
+    bots.push(Arc::new(Bot {
+      api,
+      username,
+      entry,
+      usernames,
+      enabled_chats: RwLock::new(enabled_chats),
+      config: Arc::clone(&config),
+      embeds: Arc::clone(&embeds),
+      resolver: Arc::clone(&resolver),
+      plugins: Arc::clone(&plugins),
+      seen: Arc::clone(&seen),
+      stats: Arc::clone(&stats),
+      posts: Arc::clone(&posts),
+      admins: Arc::clone(&admins),
+      albums: Albums::default(),
+      db: Arc::clone(&db),
+      health: Health::default(),
+    }));
+  }
+  if let Some(addr) = initial.health.listen {
+    tokio::spawn(http::serve(addr, bots.clone(), reloader)?);
+  }
+  tokio::spawn(maintenance::maintenance_loop(Arc::clone(&bots[0])));
+  for bot in &bots {
+    tokio::spawn(summary::summary_loop(Arc::clone(bot)));
+  }
+
+  let mut stream = select_all(bots.iter().map(|bot| Box::pin(updates(Arc::clone(bot)))));
+  let shutdown = shutdown_signal();
+  pin_mut!(shutdown);
+
+  let permits = Arc::new(Semaphore::new(initial.updates.concurrency.max(1)));
+  let mut tasks = JoinSet::new();
+  let mut queues = ChatQueues::default();
+  loop {
+    let limit = {
+      let updates = &config.load().updates;
+      updates.concurrency.max(1) + updates.queue
+    };
+    tokio::select! {
+      Some((bot, value)) = stream.next(), if tasks.len() < limit => {
+        let permits = Arc::clone(&permits);
+        let received = Instant::now();
+        let mut turn = queues.enqueue(bot.number(), &value);
+        tasks.spawn(reporting::with_update(value, move |update| async move {
+          // before taking a permit, which the update it waits for may need
+          turn.wait().await;
+          let _permit = permits.acquire().await;
+          bot.stats.latency(Stage::Queue, received.elapsed());
+          let update_id = update.update_id;
+          if let Err(err) = process_update(&bot, update, received).await {
+            error!(update_id; "Error during processing update: {err}");
+            reporting::capture(&err);
+          };
+        }));
+      },
+      Some(result) = tasks.join_next() => log_join_error(result),
+      _ = &mut shutdown => break,
+    }
+  }
+
+  let config = config.load();
+  info!(
+    "Shutting down, waiting up to {} for {} in-flight updates",
+    humantime::format_duration(config.timeouts.shutdown_grace),
+    tasks.len()
+  );
+  let drain = async {
+    while let Some(result) = tasks.join_next().await {
+      log_join_error(result);
+    }
+  };
+  if tokio::time::timeout(config.timeouts.shutdown_grace, drain)
+    .await
+    .is_err()
+  {
+    warn!("Shutdown grace elapsed, aborting {} updates", tasks.len());
+  }
+
+  Ok(())
+}
+
+/// Creates the api of `token`, checking it and getting the bot username
+/// with `getMe`.
+async fn connect(cli: &Client, token: &str) -> Result<(Arc<AsyncApi>, String)> {
+  let api = AsyncApi::builder()
+    .api_url(format!("{}{}", frankenstein::BASE_API_URL, token))
     .client(cli.clone())
     .build();
-  let tg_api = Arc::new(tg_api);
-  let me = tg_api
+  let me = api
     .get_me()
     .await
     .context("Failed to get telegram bot self info")?;
-  info!(
-    "Current tg bot: {}",
-    me.result
-      .username
-      .context("Failed to get username for bot, maybe token is invalid")?
-  );
-
-  let update_seq = AtomicU32::new(0);
+  let username = me
+    .result
+    .username
+    .context("Failed to get username for bot, maybe token is invalid")?;
+  info!("Current tg bot: {username}");
+  Ok((Arc::new(api), username))
+}
 
-  fn update_params(offset: u32) -> GetUpdatesParams {
-    GetUpdatesParams::builder()
-      .allowed_updates(vec![AllowedUpdate::Message])
-      .offset(offset)
-      .limit(500u32)
-      .build()
+/// Looks up the `@username` entries in `enabled_chats` and in the
+/// `[[chats]]` of `bots[entry]`, so they match even in updates without the
+/// username.
+async fn resolve_usernames(
+  api: &AsyncApi,
+  config: &Config,
+  entry: Option<usize>,
+  enabled_chats: &[String],
+) -> HashMap<i64, String> {
+  let names = enabled_chats
+    .iter()
+    .chain(config.chats_of(entry).map(|chat| &chat.id))
+    .filter(|name| name.starts_with('@'));
+  let mut usernames = HashMap::new();
+  for name in names {
+    let params = GetChatParams::builder().chat_id(name.clone()).build();
+    match api.get_chat(&params).await {
+      Ok(chat) => {
+        info!("{name} is chat {}", chat.result.id);
+        usernames.insert(chat.result.id, name[1..].to_string());
+      },
+      Err(err) => warn!("Failed to look up chat {name}, matching it by username only: {err}"),
+    }
   }
+  usernames
+}
 
-  let stream = {
-    let tg_api = Arc::clone(&tg_api);
-    let config = Arc::clone(&config);
-    stream! {
-      loop {
-        let result = tg_api.get_updates(&update_params(update_seq.load(Ordering::Acquire))).await;
-        let updates = match result {
-          Ok(msg) => msg.result,
-          Err(err) => {
-            error!(
-              "Failed to get updates, retry after {}ms: {:?}",
-              config.time.failed_delay,
-              err.to_string()
-            );
-            tokio::time::sleep(Duration::from_millis(config.time.failed_delay)).await;
-            continue;
-          },
-        };
-        if let Some(last) = updates.iter().last() {
-          let new_id = last.update_id + 1;
-          update_seq.store(new_id, Ordering::Release);
-        }
-        for update in updates.into_iter() {
-          yield update;
-        }
-        trace!("Yield updates..");
-        tokio::time::sleep(Duration::from_millis(config.time.fetch_delay)).await;
+fn update_params(offset: u32, long_poll: Duration) -> GetUpdatesParams {
+  GetUpdatesParams::builder()
+    .allowed_updates(vec![AllowedUpdate::Message, AllowedUpdate::MyChatMember])
+    .offset(offset)
+    .limit(500u32)
+    .timeout(long_poll.as_secs() as u32)
+    .build()
+}
+
+/// Polls updates of `bot` until dropped.
+fn updates(bot: Arc<Bot>) -> impl Stream<Item = (Arc<Bot>, Update)> {
+  stream! {
+    let mut offset = 0;
+    loop {
+      let config = bot.config.load_full();
+      let params = update_params(offset, config.timeouts.long_poll);
+      let result = bot
+        .api
+        .get_updates(&params)
+        .instrument(info_span!("get_updates", bot = bot.username.as_str(), offset = params.offset))
+        .await;
+      let updates = match result {
+        Ok(msg) => {
+          bot.health.polled();
+          msg.result
+        },
+        Err(err) => {
+          bot.health.poll_failed(err.to_string());
+          error!(
+            "Failed to get updates of @{}, retry after {}: {:?}",
+            bot.username,
+            humantime::format_duration(config.timeouts.failed_delay),
+            err.to_string()
+          );
+          tokio::time::sleep(config.timeouts.failed_delay).await;
+          continue;
+        },
+      };
+      if let Some(last) = updates.iter().last() {
+        offset = last.update_id + 1;
       }
+      for update in updates.into_iter() {
+        yield (Arc::clone(&bot), update);
+      }
+      trace!("Yield updates..");
+      tokio::time::sleep(config.timeouts.fetch_delay).await;
     }
-  };
-
-  pin_mut!(stream);
+  }
+}
 
-  while let Some(value) = stream.next().await {
-    let tg_api = Arc::clone(&tg_api);
-    let config = Arc::clone(&config);
-    tokio::spawn(async move {
-      if let Err(err) = process_update(&tg_api, config, value).await {
-        error!("Error during processing update: {err}")
-      };
-    });
+fn log_join_error(result: Result<(), JoinError>) {
+  if let Err(err) = result {
+    error!("Update task failed: {err}");
   }
+}
 
-  Ok(())
+/// Resolves on ctrl-c, or SIGTERM on unix.
+async fn shutdown_signal() {
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut term = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+      _ = tokio::signal::ctrl_c() => {},
+      _ = term.recv() => {},
+    }
+  }
+  #[cfg(not(unix))]
+  {
+    let _ = tokio::signal::ctrl_c().await;
+  }
 }
 
 #[cfg(debug_assertions)]
@@ -190,20 +519,46 @@ impl LogLevel for DebugLevel {
   }
 }
 
-fn init_logger(verbosity: LevelFilter) {
+/// Starts logging to the console, file logging is added once the config is
+/// read.
+fn init_logger(verbosity: LevelFilter) -> log4rs::Handle {
+  let config = logger_config(verbosity, &Logging::default()).unwrap();
+  log4rs::init_config(config).unwrap()
+}
+
+fn logger_config(verbosity: LevelFilter, logging: &Logging) -> Result<log4rs::Config> {
   const PATTERN: &str = "{d(%m-%d %H:%M)} {h({l:.1})} - {h({m})}{n}";
-  let stdout = ConsoleAppender::builder()
-    .encoder(Box::new(PatternEncoder::new(PATTERN)))
-    .build();
-  let config = log4rs::Config::builder()
-    .appender(Appender::builder().build("stdout", Box::new(stdout)))
-    .build(Root::builder().appender("stdout").build(verbosity))
-    .unwrap();
-  log4rs::init_config(config).unwrap();
+  const FILE_PATTERN: &str = "{d(%Y-%m-%d %H:%M:%S)} {l:.1} - {m}{n}";
+  let encoder = |pattern| -> Box<dyn Encode> {
+    match logging.format {
+      LogFormat::Text => Box::new(PatternEncoder::new(pattern)),
+      LogFormat::Json => Box::new(JsonEncoder),
+    }
+  };
+  let stdout = ConsoleAppender::builder().encoder(encoder(PATTERN)).build();
+  let mut config =
+    log4rs::Config::builder().appender(Appender::builder().build("stdout", Box::new(stdout)));
+  let mut root = Root::builder().appender("stdout");
+  if let Some(file) = &logging.file {
+    let roller = FixedWindowRoller::builder()
+      .build(&format!("{}.{{}}", file.to_string_lossy()), logging.keep)
+      .context("Invalid log file name")?;
+    let policy = CompoundPolicy::new(
+      Box::new(SizeTrigger::new(logging.max_size.as_u64())),
+      Box::new(roller),
+    );
+    let appender = RollingFileAppender::builder()
+      .encoder(encoder(FILE_PATTERN))
+      .build(file, Box::new(policy))
+      .with_context(|| format!("Failed to open log file: {}", file.to_string_lossy()))?;
+    config = config.appender(Appender::builder().build("file", Box::new(appender)));
+    root = root.appender("file");
+  }
+  Ok(config.build(root.build(verbosity))?)
 }
 
-fn init_config(path: Option<PathBuf>) -> Result<Config> {
-  let path = if let Some(dir) = path {
+fn config_path(path: Option<PathBuf>) -> Result<PathBuf> {
+  Ok(if let Some(dir) = path {
     dir
   } else if cfg!(debug_assertions) {
     std::env::current_dir()
@@ -214,13 +569,15 @@ fn init_config(path: Option<PathBuf>) -> Result<Config> {
     std::env::current_dir()
       .context("Failed to get current dir")?
       .join("config.toml")
-  };
+  })
+}
 
+fn init_config(path: &Path) -> Result<Config> {
   info!("Initializing config file...");
 
   if path.exists() && path.is_file() {
     info!("Reading config from {}...", &path.to_string_lossy());
-    let file = File::open(&path).context("Failed to")?;
+    let file = File::open(path).context("Failed to")?;
     let mut buf_reader = BufReader::new(file);
     let mut config_str = String::new();
     buf_reader
@@ -231,33 +588,36 @@ fn init_config(path: Option<PathBuf>) -> Result<Config> {
           &path.to_string_lossy()
         )
       })?;
-    let config: Config = toml::from_str(&config_str)
+    let (config, issues) = Config::from_toml(&config_str)
       .with_context(|| format!("Failed to parse config file: {}", &path.to_string_lossy()))?;
+    for issue in issues {
+      warn!("{}: {issue}", path.to_string_lossy());
+    }
     Ok(config)
   } else if !path.exists() {
     if let Some(parent) = path.parent() {
       fs::create_dir_all(parent)
         .with_context(|| format!("Failed to create folder: {}", parent.to_string_lossy()))?;
     };
-    let config = File::create(&path).with_context(|| {
+    let config = File::create(path).with_context(|| {
       format!(
         "Failed to create default config: {}",
         &path.to_string_lossy()
       )
     })?;
-    const DEFAULT_CONFIG: &[u8] = include_bytes!("config.example.toml");
-
     {
       let mut buf_writer = BufWriter::new(config);
-      buf_writer.write_all(DEFAULT_CONFIG).with_context(|| {
-        format!(
-          "Failed to write default config to: {}",
-          &path.to_string_lossy()
-        )
-      })?;
+      buf_writer
+        .write_all(DEFAULT_CONFIG.as_bytes())
+        .with_context(|| {
+          format!(
+            "Failed to write default config to: {}",
+            &path.to_string_lossy()
+          )
+        })?;
     }
     info!("Default config writed to {}", &path.to_string_lossy());
-    info!("Please take a look and configure bot, exiting...");
+    info!("Please take a look and configure bot, or run with --setup, exiting...");
     process::exit(0)
   } else {
     bail!("Path is not a file: {}", path.to_string_lossy())
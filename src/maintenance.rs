@@ -0,0 +1,56 @@
+//! Periodic upkeep of long-running instances.
+
+use std::{fs, sync::Arc, time::Duration};
+
+use bytesize::ByteSize;
+use log::{error, info};
+
+use crate::{health::now, Bot};
+
+/// Used while maintenance is disabled, to notice it being enabled again.
+const DISABLED_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Resident memory of the process, only known on linux.
+fn memory() -> Option<ByteSize> {
+  let status = fs::read_to_string("/proc/self/status").ok()?;
+  let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+  let kib = line.split_whitespace().nth(1)?.parse().ok()?;
+  Some(ByteSize::kib(kib))
+}
+
+/// Prunes what the bot keeps in memory and vacuums the database every
+/// `timeouts.maintenance-interval`.
+pub(crate) async fn maintenance_loop(bot: Arc<Bot>) {
+  loop {
+    let interval = bot.config.load().timeouts.maintenance_interval;
+    if interval.is_zero() {
+      tokio::time::sleep(DISABLED_INTERVAL).await;
+      continue;
+    }
+    tokio::time::sleep(interval).await;
+
+    let config = bot.config.load();
+    let hosts = bot.resolver.prune();
+    bot.admins.prune();
+    let users = bot.posts.prune(now(), config.spam.repeat_window);
+    let replacements = bot
+      .db
+      .prune_replacements(now().saturating_sub(config.stats.keep.as_secs()))
+      .unwrap_or_else(|err| {
+        error!("{err:?}");
+        0
+      });
+    let database = match bot.db.vacuum() {
+      Ok(size) => ByteSize::b(size).to_string(),
+      Err(err) => {
+        error!("{err:?}");
+        "unknown".to_string()
+      },
+    };
+    let memory = memory().map_or_else(|| "unknown".to_string(), |memory| memory.to_string());
+    info!(
+      "Maintenance done, tracking {hosts} resolved hosts and {users} posting users, \
+       pruned {replacements} recorded links, database {database}, memory {memory}"
+    );
+  }
+}
@@ -0,0 +1,129 @@
+//! Keeping updates of a chat in order, as cleaning one message can take
+//! longer than the next when it resolves short links.
+
+use std::collections::HashMap;
+
+use frankenstein::{Update, UpdateContent};
+use futures::future::{FutureExt, Shared};
+use tokio::sync::oneshot;
+
+/// Chats kept before forgetting the finished ones.
+const PRUNE_AT: usize = 256;
+
+/// Where an update waits for the one before it in its chat, and lets the
+/// next one go when dropped, finished or not.
+#[derive(Debug)]
+pub(crate) struct Turn {
+  previous: Option<Shared<oneshot::Receiver<()>>>,
+  _done: oneshot::Sender<()>,
+}
+
+impl Turn {
+  /// Waits until the update before this one finished.
+  pub async fn wait(&mut self) {
+    if let Some(previous) = self.previous.take() {
+      // cancelled, as by a panic, frees this turn as well
+      let _ = previous.await;
+    }
+  }
+}
+
+/// The latest update of each chat, by bot number and chat id, only used
+/// by the loop spawning updates.
+#[derive(Debug, Default)]
+pub(crate) struct ChatQueues {
+  last: HashMap<(i64, i64), Shared<oneshot::Receiver<()>>>,
+}
+
+impl ChatQueues {
+  /// The turn of the update after all those queued in its chat before,
+  /// taken in the order updates are received.
+  pub fn enqueue(&mut self, bot: i64, update: &Update) -> Turn {
+    let (done, finished) = oneshot::channel();
+    let Some(chat) = chat_of(update) else {
+      return Turn {
+        previous: None,
+        _done: done,
+      };
+    };
+    if self.last.len() >= PRUNE_AT {
+      // done, or dropped unfinished, once its sender is gone
+      self
+        .last
+        .retain(|_, finished| finished.clone().now_or_never().is_none());
+    }
+    let previous = self.last.insert((bot, chat), finished.shared());
+    Turn {
+      previous,
+      _done: done,
+    }
+  }
+}
+
+/// Chat of the messages kept in order, albums are left out as their first
+/// message waits for the others.
+fn chat_of(update: &Update) -> Option<i64> {
+  match &update.content {
+    UpdateContent::Message(msg) if msg.media_group_id.is_none() => Some(msg.chat.id),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+  };
+
+  use frankenstein::{Chat, ChatType, Message, Update, UpdateContent};
+
+  use super::{ChatQueues, PRUNE_AT};
+
+  fn update(update_id: u32, chat_id: i64) -> Update {
+    let chat = Chat::builder()
+      .id(chat_id)
+      .type_field(ChatType::Supergroup)
+      .build();
+    let msg = Message::builder()
+      .message_id(update_id as i32)
+      .date(0u64)
+      .chat(chat)
+      .build();
+    Update {
+      update_id,
+      content: UpdateContent::Message(msg),
+    }
+  }
+
+  #[tokio::test]
+  async fn chats_in_order() {
+    let mut queues = ChatQueues::default();
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let mut tasks = Vec::new();
+    // the first update of chat -1 is the slowest, the other chat doesn't wait
+    for (update_id, chat_id, delay) in [(1, -1, 50), (2, -1, 0), (3, -2, 0)] {
+      let mut turn = queues.enqueue(0, &update(update_id, chat_id));
+      let order = Arc::clone(&order);
+      tasks.push(tokio::spawn(async move {
+        turn.wait().await;
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+        order.lock().unwrap().push(update_id);
+      }));
+    }
+    for task in tasks {
+      task.await.unwrap();
+    }
+    assert_eq!(vec![3, 1, 2], *order.lock().unwrap());
+  }
+
+  #[test]
+  fn prunes_finished_chats() {
+    let mut queues = ChatQueues::default();
+    for chat_id in 0..=PRUNE_AT as i64 {
+      drop(queues.enqueue(0, &update(1, -chat_id)));
+    }
+    let _waiting = queues.enqueue(0, &update(1, 1));
+    assert_eq!(2, queues.last.len());
+  }
+}
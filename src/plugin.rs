@@ -0,0 +1,322 @@
+//! Replacers compiled to WASM, loaded from the `plugins` directory when built
+//! with the `plugins` feature.
+//!
+//! A plugin exports its `memory`, `alloc(len: i32) -> i32` making room for
+//! `len` bytes, and `clean(ptr: i32, len: i32) -> i64` taking the UTF-8 text
+//! at `ptr` and returning where the cleaned text is, as `ptr << 32 | len`.
+//! It may import `env.resolve(ptr: i32, len: i32) -> i64`, resolving the short
+//! link at `ptr` over network and returning the url the same way, or 0.
+
+use std::{fs, path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+#[cfg(not(feature = "plugins"))]
+use log::warn;
+
+use crate::{replacer::ReplaceReport, resolver::Resolver};
+
+/// Names of the plugins in `dir`, the stems of its `.wasm` files.
+pub fn names(dir: &Path) -> Result<Vec<String>> {
+  let entries = fs::read_dir(dir)
+    .with_context(|| format!("Failed to read plugins from {}", dir.to_string_lossy()))?;
+  let mut names = Vec::new();
+  for entry in entries {
+    let path = entry?.path();
+    if path.extension().is_some_and(|ext| ext == "wasm") {
+      if let Some(stem) = path.file_stem() {
+        names.push(stem.to_string_lossy().into_owned());
+      }
+    }
+  }
+  names.sort();
+  Ok(names)
+}
+
+/// The loaded plugins, none without the `plugins` feature.
+#[derive(Default)]
+pub struct Plugins {
+  #[cfg(feature = "plugins")]
+  runtime: Option<wasm::Runtime>,
+}
+
+impl std::fmt::Debug for Plugins {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_list().entries(self.names()).finish()
+  }
+}
+
+impl Plugins {
+  /// Compiles the plugins in `dir`, whose `env.resolve` goes through
+  /// `resolver`.
+  pub fn load(dir: Option<&Path>, resolver: Arc<Resolver>) -> Result<Self> {
+    let Some(dir) = dir else {
+      return Ok(Self::default());
+    };
+    #[cfg(feature = "plugins")]
+    {
+      let runtime = wasm::Runtime::load(dir, resolver)?;
+      Ok(Self {
+        runtime: Some(runtime),
+      })
+    }
+    #[cfg(not(feature = "plugins"))]
+    {
+      let _ = resolver;
+      if !names(dir)?.is_empty() {
+        warn!("plugins is set, but the bot is built without the `plugins` feature");
+      }
+      Ok(Self::default())
+    }
+  }
+
+  pub fn names(&self) -> Vec<&str> {
+    #[cfg(feature = "plugins")]
+    if let Some(runtime) = &self.runtime {
+      return runtime.names().collect();
+    }
+    Vec::new()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.names().is_empty()
+  }
+
+  /// Runs every plugin not `disabled` on `text` in turn. A failing plugin
+  /// keeps the text as it was.
+  pub async fn clean(
+    &self,
+    text: String,
+    disabled: &[String],
+    report: &mut ReplaceReport,
+  ) -> String {
+    #[cfg(feature = "plugins")]
+    if let Some(runtime) = &self.runtime {
+      return runtime.clean(text, disabled, report).await;
+    }
+    let _ = (disabled, report);
+    text
+  }
+}
+
+#[cfg(feature = "plugins")]
+mod wasm {
+  use std::{path::Path, sync::Arc};
+
+  use anyhow::{anyhow, Context, Result};
+  use log::warn;
+  use tokio::runtime::Handle;
+  use wasmtime::{
+    AsContext, AsContextMut, Caller, Engine, Linker, Memory, Module, Store, TypedFunc,
+  };
+
+  use crate::{replacer::ReplaceReport, resolver::Resolver};
+
+  /// Instructions a plugin may run on a text, so a looping one doesn't hang
+  /// its update.
+  const FUEL: u64 = 1_000_000_000;
+
+  pub(super) struct Runtime {
+    engine: Engine,
+    plugins: Vec<(String, Module)>,
+    resolver: Arc<Resolver>,
+  }
+
+  impl Runtime {
+    pub fn load(dir: &Path, resolver: Arc<Resolver>) -> Result<Self> {
+      let mut config = wasmtime::Config::new();
+      config.consume_fuel(true);
+      let engine = Engine::new(&config).map_err(|err| anyhow!("{err}"))?;
+      let mut plugins = Vec::new();
+      for name in super::names(dir)? {
+        let path = dir.join(format!("{name}.wasm"));
+        let module = Module::from_file(&engine, &path)
+          .map_err(|err| anyhow!("{err:?}"))
+          .with_context(|| format!("Failed to compile plugin {}", path.to_string_lossy()))?;
+        plugins.push((name, module));
+      }
+      Ok(Self {
+        engine,
+        plugins,
+        resolver,
+      })
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+      self.plugins.iter().map(|(name, _)| name.as_str())
+    }
+
+    pub async fn clean(
+      &self,
+      mut text: String,
+      disabled: &[String],
+      report: &mut ReplaceReport,
+    ) -> String {
+      for (name, module) in &self.plugins {
+        if disabled.contains(name) {
+          continue;
+        }
+        let (engine, module) = (self.engine.clone(), module.clone());
+        let resolver = Arc::clone(&self.resolver);
+        let input = text.clone();
+        let cleaned =
+          tokio::task::spawn_blocking(move || run(&engine, &module, resolver, &input)).await;
+        match cleaned {
+          Ok(Ok(cleaned)) => {
+            report.record_rewrite(name, &text, &cleaned);
+            text = cleaned;
+          },
+          Ok(Err(err)) => warn!("Plugin {name} failed, keeping the text as is: {err:#}"),
+          Err(err) => warn!("Plugin {name} panicked, keeping the text as is: {err}"),
+        }
+      }
+      text
+    }
+  }
+
+  fn pack(ptr: i32, len: usize) -> i64 {
+    ((ptr as u32 as i64) << 32) | len as u32 as i64
+  }
+
+  fn read(store: impl AsContext, memory: Memory, packed: i64) -> Result<String> {
+    let (ptr, len) = ((packed >> 32) as u32 as usize, packed as u32 as usize);
+    let bytes = memory
+      .data(&store)
+      .get(ptr..ptr + len)
+      .context("Plugin returned text out of its memory")?;
+    String::from_utf8(bytes.to_vec()).context("Plugin returned invalid UTF-8")
+  }
+
+  fn write(
+    mut store: impl AsContextMut,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    str: &str,
+  ) -> Result<i64> {
+    let ptr = alloc
+      .call(&mut store, str.len() as i32)
+      .map_err(|err| anyhow!("{err:?}"))?;
+    memory
+      .write(&mut store, ptr as u32 as usize, str.as_bytes())
+      .context("Plugin allocated out of its memory")?;
+    Ok(pack(ptr, str.len()))
+  }
+
+  /// The memory and `alloc` of the plugin calling back.
+  fn exports(caller: &mut Caller<'_, Arc<Resolver>>) -> Result<(Memory, TypedFunc<i32, i32>)> {
+    let memory = caller
+      .get_export("memory")
+      .and_then(|export| export.into_memory())
+      .context("Plugin exports no memory")?;
+    let alloc = caller
+      .get_export("alloc")
+      .and_then(|export| export.into_func())
+      .context("Plugin exports no alloc")?
+      .typed(&caller)
+      .map_err(|err| anyhow!("{err}"))?;
+    Ok((memory, alloc))
+  }
+
+  /// `env.resolve`, blocking the thread the plugin runs on.
+  fn resolve(mut caller: Caller<'_, Arc<Resolver>>, ptr: i32, len: i32) -> Result<i64> {
+    let (memory, alloc) = exports(&mut caller)?;
+    let link = read(&caller, memory, pack(ptr, len as u32 as usize))?;
+    let resolver = Arc::clone(caller.data());
+    match Handle::current().block_on(resolver.resolve(&link)) {
+      Ok(Some(url)) => write(&mut caller, memory, alloc, url.as_str()),
+      Ok(None) => Ok(0),
+      Err(err) => {
+        warn!("Plugin failed to resolve {link}: {err:#}");
+        Ok(0)
+      },
+    }
+  }
+
+  fn run(engine: &Engine, module: &Module, resolver: Arc<Resolver>, text: &str) -> Result<String> {
+    let mut store = Store::new(engine, resolver);
+    store.set_fuel(FUEL).map_err(|err| anyhow!("{err}"))?;
+    let mut linker = Linker::new(engine);
+    linker
+      .func_wrap(
+        "env",
+        "resolve",
+        |caller: Caller<'_, Arc<Resolver>>, ptr: i32, len: i32| {
+          resolve(caller, ptr, len).map_err(|err| wasmtime::Error::msg(format!("{err:#}")))
+        },
+      )
+      .map_err(|err| anyhow!("{err}"))?;
+    let instance = linker
+      .instantiate(&mut store, module)
+      .map_err(|err| anyhow!("{err:?}"))
+      .context("Failed to instantiate plugin")?;
+    let memory = instance
+      .get_memory(&mut store, "memory")
+      .context("Plugin exports no memory")?;
+    let alloc = instance
+      .get_typed_func::<i32, i32>(&mut store, "alloc")
+      .map_err(|err| anyhow!("{err}"))?;
+    let clean = instance
+      .get_typed_func::<(i32, i32), i64>(&mut store, "clean")
+      .map_err(|err| anyhow!("{err}"))?;
+    let input = write(&mut store, memory, alloc, text)?;
+    let output = clean
+      .call(&mut store, ((input >> 32) as i32, text.len() as i32))
+      .map_err(|err| anyhow!("{err:?}"))?;
+    read(&store, memory, output)
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use std::sync::Arc;
+
+    use wasmtime::{Config, Engine, Module};
+
+    use super::run;
+    use crate::resolver::Resolver;
+
+    /// Cuts the text at its first `?`.
+    const CUT_QUERY: &str = r#"
+      (module
+        (memory (export "memory") 1)
+        (global $next (mut i32) (i32.const 1024))
+        (func (export "alloc") (param $len i32) (result i32)
+          (local $ptr i32)
+          (local.set $ptr (global.get $next))
+          (global.set $next (i32.add (global.get $next) (local.get $len)))
+          (local.get $ptr))
+        (func (export "clean") (param $ptr i32) (param $len i32) (result i64)
+          (local $i i32)
+          (block $done
+            (loop $scan
+              (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+              (br_if $done
+                (i32.eq (i32.load8_u (i32.add (local.get $ptr) (local.get $i))) (i32.const 63)))
+              (local.set $i (i32.add (local.get $i) (i32.const 1)))
+              (br $scan)))
+          (i64.or
+            (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+            (i64.extend_i32_u (local.get $i)))))
+    "#;
+
+    #[test]
+    fn runs_plugins() {
+      let mut config = Config::new();
+      config.consume_fuel(true);
+      let engine = Engine::new(&config).unwrap();
+      let module = Module::new(&engine, CUT_QUERY).unwrap();
+      let resolver = Arc::new(Resolver::default());
+      assert_eq!(
+        "https://example.com/a",
+        run(&engine, &module, resolver, "https://example.com/a?si=1").unwrap()
+      );
+
+      let looping = Module::new(
+        &engine,
+        r#"(module (memory (export "memory") 1)
+             (func (export "alloc") (param i32) (result i32) (i32.const 0))
+             (func (export "clean") (param i32 i32) (result i64) (loop (br 0)) (i64.const 0)))"#,
+      )
+      .unwrap();
+      assert!(run(&engine, &looping, Arc::new(Resolver::default()), "x").is_err());
+    }
+  }
+}
@@ -0,0 +1,122 @@
+//! Python module `fuckburl`, built with the `python` feature.
+//!
+//! ```python
+//! import fuckburl
+//!
+//! fuckburl.clean("https://www.bilibili.com/video/BV114514/?spm_id_from=333.788")
+//! cleaner = fuckburl.Cleaner.from_config("config.toml")
+//! text, replacements = cleaner.clean_report(text)
+//! ```
+
+// Triggered by the `#[pymethods]` expansion.
+#![allow(clippy::useless_conversion)]
+
+use std::fs;
+
+use pyo3::{exceptions::PyValueError, prelude::*};
+
+use crate::{
+  clean_offline, clean_offline_with,
+  config::{Embed, RulesFile},
+  embed::Embeds,
+  replacer::{default_tracking_params, CustomRule},
+  ReplaceReport, Rules,
+};
+
+/// `(site, original, cleaned, expanded)` of each cleaned link.
+type PyReport = Vec<(String, String, String, bool)>;
+
+fn to_py(report: ReplaceReport) -> PyReport {
+  report
+    .replacements
+    .into_iter()
+    .map(|r| (r.site, r.original, r.cleaned, r.expanded))
+    .collect()
+}
+
+/// Cleans `text` with the default rules, short links are left as is.
+#[pyfunction]
+fn clean(text: &str) -> String {
+  clean_offline(text).0
+}
+
+/// Like `clean`, also returns the cleaned links.
+#[pyfunction]
+fn clean_report(text: &str) -> (String, PyReport) {
+  let (text, report) = clean_offline(text);
+  (text, to_py(report))
+}
+
+/// Cleaning rules loaded once, reusable for many texts.
+#[pyclass]
+struct Cleaner {
+  embeds: Embeds,
+  tracking_params: Vec<String>,
+  custom: Vec<CustomRule>,
+}
+
+#[pymethods]
+impl Cleaner {
+  #[new]
+  #[pyo3(signature = (tracking_params = None, twitter = None, tiktok = None, instagram = None))]
+  fn new(
+    tracking_params: Option<Vec<String>>,
+    twitter: Option<String>,
+    tiktok: Option<String>,
+    instagram: Option<String>,
+  ) -> Self {
+    let default = Embed::default();
+    Self {
+      embeds: Embeds::new([
+        twitter.unwrap_or(default.twitter),
+        tiktok.unwrap_or(default.tiktok),
+        instagram.unwrap_or(default.instagram),
+      ]),
+      tracking_params: tracking_params.unwrap_or_else(default_tracking_params),
+      custom: Vec::new(),
+    }
+  }
+
+  /// Loads rules from a bot `config.toml`, malformed `[[rules]]` are skipped.
+  #[staticmethod]
+  fn from_config(path: &str) -> PyResult<Self> {
+    let str = fs::read_to_string(path)?;
+    let (file, _) = RulesFile::from_toml(&str)
+      .map_err(|err| PyValueError::new_err(format!("Failed to parse {path}: {err}")))?;
+    Ok(Self {
+      embeds: file.embeds(),
+      tracking_params: file.tracking_params,
+      custom: file.rules,
+    })
+  }
+
+  fn clean(&self, text: &str) -> String {
+    self.clean_report(text).0
+  }
+
+  fn clean_report(&self, text: &str) -> (String, PyReport) {
+    let (text, report) = clean_offline_with(text, self.rules());
+    (text, to_py(report))
+  }
+}
+
+impl Cleaner {
+  fn rules(&self) -> Rules<'_> {
+    Rules {
+      embeds: &self.embeds,
+      tracking_params: &self.tracking_params,
+      custom: &self.custom,
+      disabled: &[],
+      plugins: None,
+      shorteners: &[],
+    }
+  }
+}
+
+#[pymodule]
+fn fuckburl(m: &Bound<'_, PyModule>) -> PyResult<()> {
+  m.add_function(wrap_pyfunction!(clean, m)?)?;
+  m.add_function(wrap_pyfunction!(clean_report, m)?)?;
+  m.add_class::<Cleaner>()?;
+  Ok(())
+}
@@ -0,0 +1,409 @@
+use std::{
+  fs,
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use arc_swap::ArcSwap;
+use frankenstein::{AsyncApi, AsyncTelegramApi, SendMessageParams, User};
+use fuckburl_bot::{
+  cache::Cache,
+  config::{Action, Config, Summary},
+  embed::Embeds,
+  resolver::Resolver,
+};
+use log::{debug, error, info, warn};
+
+/// Used while reloading is disabled, to notice it being enabled again.
+const DISABLED_INTERVAL: Duration = Duration::from_secs(60);
+
+fn modified(path: &Path) -> Result<SystemTime> {
+  fs::metadata(path)
+    .and_then(|meta| meta.modified())
+    .with_context(|| format!("Failed to stat {}", path.to_string_lossy()))
+}
+
+/// What a config reload swaps the config of and applies it to.
+pub(crate) struct Reloader {
+  pub path: PathBuf,
+  pub config: Arc<ArcSwap<Config>>,
+  pub embeds: Arc<Embeds>,
+  pub resolver: Arc<Resolver>,
+  pub admins: Arc<Cache<i64, Vec<User>>>,
+  /// Notifies the owner of what changed.
+  pub api: Arc<AsyncApi>,
+}
+
+impl Reloader {
+  /// Reads the config file again, swapping it in if valid, returns what
+  /// changed.
+  pub fn reload(&self) -> Result<Vec<String>> {
+    let old = self.config.load_full();
+    let new = reload(&self.path, &old)?;
+    let changes = changes(&old, &new);
+    self.embeds.set_domains(new.embed_domains());
+    self.resolver.set_options(
+      new.timeouts.resolve,
+      new.resolver.host_interval,
+      new.resolver.max_redirects,
+    );
+    self.resolver.set_shorteners(&new.resolver.shorteners);
+    self
+      .resolver
+      .set_headers(&new.resolver.headers, new.resolver.private);
+    self.resolver.set_cache_limits(new.cache.limits());
+    self.admins.set_limits(new.cache.limits());
+    let owner = new.owner;
+    self.config.store(Arc::new(new));
+    if changes.is_empty() {
+      info!(
+        "Reloaded config from {}, nothing changed",
+        self.path.to_string_lossy()
+      );
+      return Ok(changes);
+    }
+    info!(
+      "Reloaded config from {} with {} changes",
+      self.path.to_string_lossy(),
+      changes.len()
+    );
+    for change in &changes {
+      info!("Config change: {change}");
+    }
+    if let Some(owner) = owner {
+      let params = SendMessageParams::builder()
+        .chat_id(owner)
+        .text(format!("Config reloaded:\n- {}", changes.join("\n- ")))
+        .build();
+      let api = Arc::clone(&self.api);
+      tokio::spawn(async move {
+        if let Err(err) = api.send_message(&params).await {
+          error!("Failed to notify the owner of config changes: {err}");
+        }
+      });
+    }
+    Ok(changes)
+  }
+}
+
+/// Watches the config file by modification time, swapping in every valid
+/// change.
+pub(crate) async fn watch_config(reloader: Arc<Reloader>) {
+  let path = &reloader.path;
+  let mut last = modified(path).ok();
+  loop {
+    let interval = reloader.config.load().timeouts.reload_interval;
+    if interval.is_zero() {
+      tokio::time::sleep(DISABLED_INTERVAL).await;
+      continue;
+    }
+    tokio::time::sleep(interval).await;
+
+    let current = match modified(path) {
+      Ok(current) => current,
+      Err(err) => {
+        debug!("{err:?}");
+        continue;
+      },
+    };
+    if last == Some(current) {
+      continue;
+    }
+    last = Some(current);
+
+    if let Err(err) = reloader.reload() {
+      error!("Failed to reload config, keeping the current one: {err:?}");
+    }
+  }
+}
+
+fn action(action: Action) -> String {
+  format!("{action:?}").to_lowercase()
+}
+
+/// `added` and `removed` changes of the items only in `new` or `old`.
+fn list_changes<T: PartialEq + std::fmt::Display>(
+  changes: &mut Vec<String>,
+  what: &str,
+  old: &[T],
+  new: &[T],
+) {
+  for item in new.iter().filter(|item| !old.contains(item)) {
+    changes.push(format!("{what} {item} added"));
+  }
+  for item in old.iter().filter(|item| !new.contains(item)) {
+    changes.push(format!("{what} {item} removed"));
+  }
+}
+
+/// What reloading `new` over `old` changes in chats, replacers, rules and
+/// timeouts, one line each.
+fn changes(old: &Config, new: &Config) -> Vec<String> {
+  let mut changes = Vec::new();
+  if new.action != old.action {
+    changes.push(format!(
+      "action: {} → {}",
+      action(old.action),
+      action(new.action)
+    ));
+  }
+  if new.dry_run != old.dry_run {
+    changes.push(format!("dry-run: {}", new.dry_run));
+  }
+  if new.notifications != old.notifications {
+    changes.push(format!("notifications: {}", new.notifications));
+  }
+  if new.stats.summary != old.stats.summary {
+    changes.push(format!(
+      "summary: {} → {}",
+      old.stats.summary, new.stats.summary
+    ));
+  }
+  if new.private_chats != old.private_chats {
+    changes.push(format!("private-chats: {}", new.private_chats));
+  }
+  if new.enable_all != old.enable_all {
+    changes.push(format!("enable-all: {}", new.enable_all));
+  }
+  list_changes(
+    &mut changes,
+    "disabled chat",
+    &old.disabled_chats,
+    &new.disabled_chats,
+  );
+  let ids = |config: &Config| {
+    config
+      .chats
+      .iter()
+      .map(|chat| chat.id.clone())
+      .collect::<Vec<_>>()
+  };
+  list_changes(&mut changes, "chat", &ids(old), &ids(new));
+  for chat in &new.chats {
+    let Some(before) = old
+      .chats
+      .iter()
+      .find(|before| before.id == chat.id && before.bot == chat.bot)
+    else {
+      continue;
+    };
+    let id = &chat.id;
+    if chat.action != before.action {
+      let or_global =
+        |config: &Config, chat_action: Option<Action>| action(chat_action.unwrap_or(config.action));
+      changes.push(format!(
+        "chat {id} action: {} → {}",
+        or_global(old, before.action),
+        or_global(new, chat.action)
+      ));
+    }
+    for name in chat
+      .disable
+      .iter()
+      .filter(|name| !before.disable.contains(name))
+    {
+      changes.push(format!("chat {id}: {name} disabled"));
+    }
+    for name in before
+      .disable
+      .iter()
+      .filter(|name| !chat.disable.contains(name))
+    {
+      changes.push(format!("chat {id}: {name} enabled"));
+    }
+    if chat.delete_ads != before.delete_ads {
+      changes.push(format!("chat {id} delete-ads: {}", chat.delete_ads));
+    }
+    if chat.notifications != before.notifications {
+      changes.push(format!("chat {id} notifications: {:?}", chat.notifications));
+    }
+    if chat.summary != before.summary {
+      let or_global = |config: &Config, summary: Option<Summary>| {
+        summary.unwrap_or(config.stats.summary).to_string()
+      };
+      changes.push(format!(
+        "chat {id} summary: {} → {}",
+        or_global(old, before.summary),
+        or_global(new, chat.summary)
+      ));
+    }
+    if chat.template != before.template {
+      changes.push(format!("chat {id} template changed"));
+    }
+    if chat.timezone != before.timezone {
+      changes.push(format!("chat {id} timezone: {:?}", chat.timezone));
+    }
+  }
+  let rules = |config: &Config| {
+    config
+      .rules
+      .iter()
+      .map(|rule| rule.name.clone())
+      .collect::<Vec<_>>()
+  };
+  list_changes(&mut changes, "rule", &rules(old), &rules(new));
+  for rule in &new.rules {
+    let changed = old
+      .rules
+      .iter()
+      .any(|before| before.name == rule.name && before.regex.as_str() != rule.regex.as_str());
+    if changed {
+      changes.push(format!(
+        "rule {} pattern: {}",
+        rule.name,
+        rule.regex.as_str()
+      ));
+    }
+  }
+  list_changes(
+    &mut changes,
+    "tracking param",
+    &old.tracking_params,
+    &new.tracking_params,
+  );
+  let (before, after) = (&old.timeouts, &new.timeouts);
+  let timeouts = [
+    ("fetch-delay", before.fetch_delay, after.fetch_delay),
+    ("failed-delay", before.failed_delay, after.failed_delay),
+    ("long-poll", before.long_poll, after.long_poll),
+    ("resolve", before.resolve, after.resolve),
+    (
+      "shutdown-grace",
+      before.shutdown_grace,
+      after.shutdown_grace,
+    ),
+    (
+      "reload-interval",
+      before.reload_interval,
+      after.reload_interval,
+    ),
+    (
+      "maintenance-interval",
+      before.maintenance_interval,
+      after.maintenance_interval,
+    ),
+    ("album-wait", before.album_wait, after.album_wait),
+  ];
+  for (name, before, after) in timeouts {
+    if before != after {
+      changes.push(format!(
+        "timeouts.{name}: {} → {}",
+        humantime::format_duration(before),
+        humantime::format_duration(after)
+      ));
+    }
+  }
+  changes
+}
+
+fn reload(path: &Path, old: &Config) -> Result<Config> {
+  let str = fs::read_to_string(path)
+    .with_context(|| format!("Failed to read config file: {}", path.to_string_lossy()))?;
+  let (new, issues) = Config::from_toml(&str)
+    .with_context(|| format!("Failed to parse config file: {}", path.to_string_lossy()))?;
+  for issue in issues {
+    warn!("{}: {issue}", path.to_string_lossy());
+  }
+  if new.telegram_token != old.telegram_token {
+    warn!("telegram-token changed, restart to apply it");
+  }
+  let tokens = |config: &Config| {
+    config
+      .bots
+      .iter()
+      .map(|bot| bot.telegram_token.clone())
+      .collect::<Vec<_>>()
+  };
+  if tokens(&new) != tokens(old) {
+    warn!("[[bots]] tokens changed, restart to apply them");
+  }
+  let enabled = |config: &Config| {
+    let mut enabled = vec![config.enabled_chats.clone()];
+    enabled.extend(config.bots.iter().map(|bot| bot.enabled_chats.clone()));
+    enabled
+  };
+  if enabled(&new) != enabled(old) {
+    warn!(
+      "enabled-chats changed, it only seeds the database, restart with --resync-chats to apply it"
+    );
+  }
+  if new.chats.iter().any(|chat| {
+    !old
+      .chats
+      .iter()
+      .any(|before| before.id == chat.id && before.bot == chat.bot)
+  }) {
+    warn!("[[chats]] added, they're enabled on restart, or right away with /enable");
+  }
+  if new.proxy != old.proxy {
+    warn!("proxy changed, restart to apply it");
+  }
+  if (new.resolver.private, &new.resolver.proxy) != (old.resolver.private, &old.resolver.proxy) {
+    warn!("resolver.private or resolver.proxy changed, restart to apply it");
+  }
+  if new.database != old.database {
+    warn!("database changed, restart to apply it");
+  }
+  if new.plugins != old.plugins {
+    warn!("plugins changed, restart to apply it");
+  }
+  if new.runtime != old.runtime {
+    warn!("[runtime] changed, restart to apply it");
+  }
+  if new.logging != old.logging {
+    warn!("[logging] changed, restart to apply it");
+  }
+  if new.sentry != old.sentry {
+    warn!("[sentry] changed, restart to apply it");
+  }
+  if new.health.listen != old.health.listen {
+    warn!("health.listen changed, restart to apply it");
+  }
+  if new.tracing != old.tracing {
+    warn!("[tracing] changed, restart to apply it");
+  }
+  Ok(new)
+}
+
+#[cfg(test)]
+mod tests {
+  use fuckburl_bot::config::Config;
+
+  use super::changes;
+
+  fn config(toml: &str) -> Config {
+    Config::from_toml(&format!("telegram-token = \"123:abc\"\n{toml}"))
+      .unwrap()
+      .0
+  }
+
+  #[test]
+  fn summary() {
+    let old = config(
+      "tracking-params = [\"from\"]\n[timeouts]\nfetch-delay = \"1s\"\n\
+       [[chats]]\nid = \"-1\"\ndisable = [\"bilibili\"]\n[[chats]]\nid = \"-2\"\n",
+    );
+    let new = config(
+      "action = \"reply\"\ntracking-params = [\"from\", \"spm\"]\n\
+       [timeouts]\nfetch-delay = \"2s\"\n\
+       [[chats]]\nid = \"-1\"\naction = \"links\"\ndisable = [\"twitter\"]\n\
+       [[chats]]\nid = \"-3\"\n",
+    );
+    assert_eq!(
+      vec![
+        "action: repost → reply",
+        "chat -3 added",
+        "chat -2 removed",
+        "chat -1 action: repost → links",
+        "chat -1: twitter disabled",
+        "chat -1: bilibili enabled",
+        "tracking param spm added",
+        "timeouts.fetch-delay: 1s → 2s",
+      ],
+      changes(&old, &new)
+    );
+    assert!(changes(&old, &old).is_empty());
+  }
+}
@@ -0,0 +1,106 @@
+//! `repl` subcommand, for trying out `[[rules]]` while writing them.
+
+use std::{
+  fs,
+  io::{self, BufRead, Write},
+  path::Path,
+  time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use fuckburl_bot::{
+  config::RulesFile, embed::Embeds, replace_all, resolver::Resolver, ReplaceReport, Rules,
+};
+
+struct Loaded {
+  file: RulesFile,
+  embeds: Embeds,
+  modified: Option<SystemTime>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+  fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn load(path: &Path) -> Result<Loaded> {
+  let modified = modified(path);
+  let str = fs::read_to_string(path)
+    .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?;
+  let (file, issues) = RulesFile::from_toml(&str)
+    .with_context(|| format!("Failed to parse {}", path.to_string_lossy()))?;
+  for issue in issues {
+    println!("{issue}");
+  }
+  println!(
+    "Loaded {} custom rules from {}",
+    file.rules.len(),
+    path.to_string_lossy()
+  );
+  Ok(Loaded {
+    embeds: file.embeds(),
+    file,
+    modified,
+  })
+}
+
+fn annotate(report: &ReplaceReport) {
+  if report.replacements.is_empty() {
+    println!("  (nothing cleaned)");
+  }
+  for replacement in &report.replacements {
+    let expanded = if replacement.expanded {
+      ", expanded"
+    } else {
+      ""
+    };
+    println!(
+      "  [{}{expanded}] {} -> {}",
+      replacement.site, replacement.original, replacement.cleaned
+    );
+  }
+}
+
+/// Reads lines from stdin and prints them cleaned with the rules of
+/// `path`, which is reloaded before any input once it changes.
+pub(crate) async fn run(path: &Path) -> Result<()> {
+  let mut loaded = load(path)?;
+  let resolver = Resolver::default();
+  println!("Paste text to clean, ctrl-d to exit.");
+  let mut line = String::new();
+  loop {
+    print!("> ");
+    io::stdout().flush()?;
+    line.clear();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+      println!();
+      return Ok(());
+    }
+    let text = line.trim_end_matches(['\r', '\n']);
+    if text.trim().is_empty() {
+      continue;
+    }
+
+    if modified(path) != loaded.modified {
+      match load(path) {
+        Ok(new) => loaded = new,
+        Err(err) => println!("{err:?}\nKeeping the previous rules"),
+      }
+    }
+
+    let rules = Rules {
+      embeds: &loaded.embeds,
+      tracking_params: &loaded.file.tracking_params,
+      custom: &loaded.file.rules,
+      disabled: &[],
+      plugins: None,
+      shorteners: &[],
+    };
+    match replace_all(text, rules, &resolver).await {
+      Ok((cleaned, report)) => {
+        println!("{cleaned}");
+        annotate(&report);
+      },
+      Err(err) => println!("{err:?}"),
+    }
+  }
+}
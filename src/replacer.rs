@@ -1,81 +1,896 @@
 use std::{
   borrow::{Borrow, Cow},
+  ops::Range,
+  process::Stdio,
   str::FromStr,
+  time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
-use fancy_regex::Regex;
-use log::error;
+use anyhow::{bail, Context, Result};
+use fancy_regex::{Captures, Regex};
+use futures::future::join_all;
+use log::{error, warn};
 use reqwest::Url;
+use tokio::io::AsyncWriteExt;
+use tracing::{info_span, Instrument};
 
+#[cfg(any(feature = "twitter", feature = "tiktok", feature = "instagram"))]
+use crate::embed::Service;
+use crate::{config::Shortener, embed::Embeds, plugin::Plugins, resolver::Resolver};
+
+lazy_static! {
+  static ref URL_REGEX: regex::Regex = regex::Regex::new(r#"https?://[^\s<>"]+"#).unwrap();
+  static ref DEFAULT_TRACKING: Vec<String> = default_tracking_params();
+  /// Cheap checks for what each replacer looks for, in the order of
+  /// [`PREFILTERS`], so most messages skip the fancy regexes.
+  static ref PREFILTER: regex::RegexSet =
+    regex::RegexSet::new(PREFILTERS.iter().map(|(_, pattern)| pattern)).unwrap();
+}
+
+#[cfg(feature = "bilibili")]
 lazy_static! {
   static ref BSHORT_REGEX: Regex =
-    Regex::new(r"((https?://|(?<![a-zA-Z]{1})|^)?b23.tv/[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*").unwrap();
+    Regex::new(r"((https?://|(?<![a-zA-Z]{1})|^)?b23.tv/[0-9a-zA-Z]+/?)\??(?:&?[^=&\s]*=[^=&\s]*)*").unwrap();
   static ref BVIDEO_REGEX: Regex = Regex::new(
-    r"(?P<url>(https?://|(?<![a-zA-Z]{1})|^)(www\.)?bilibili.com/video/[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*"
+    r"(?P<url>(https?://|(?<![a-zA-Z]{1})|^)(www\.|m\.)?bilibili.com/video/[0-9a-zA-Z]+/?)\??(?:&?[^=&\s]*=[^=&\s]*)*"
   )
   .unwrap();
   static ref BARTICLE_REGEX: Regex = Regex::new(
-    r"(https?://|(?<![a-zA-Z]{1})|^)(www\.)?bilibili.com/read/mobile/(?P<cvid>[0-9]+)\??(?:&?[^=&]*=[^=&]*)*"
+    r"(https?://|(?<![a-zA-Z]{1})|^)(www\.)?bilibili.com/read/mobile/(?P<cvid>[0-9]+)\??(?:&?[^=&\s]*=[^=&\s]*)*"
+  )
+  .unwrap();
+  static ref BSPACE_REGEX: Regex = Regex::new(
+    r"(https?://|(?<![a-zA-Z]{1})|^)space\.bilibili\.com/[0-9]+(/[a-zA-Z]+)*/?\??(?:&?[^=&\s]*=[^=&\s]*)*"
+  )
+  .unwrap();
+  /// Where b23.tv links lead to, rather than an app download page.
+  static ref BSHORT_TARGET_REGEX: Regex = Regex::new(
+    r"^https?://((www\.|m\.)?bilibili\.com/[a-z]+/[0-9a-zA-Z]|(space|live)\.bilibili\.com/[0-9]+)"
+  ).unwrap();
+}
+
+#[cfg(feature = "xiaohongshu")]
+lazy_static! {
+  static ref XIAOHONGSHU_REGEX: Regex = Regex::new(
+    r"((https?://|(?<![a-zA-Z]{1})|^)xhslink.com/[0-9a-zA-Z]+/?)\??(?:&?[^=&\s]*=[^=&\s]*)*"
+  ).unwrap();
+  /// Notes shared from the web app, as `xiaohongshu.com/explore/<id>?xsec_token=...`.
+  static ref XIAOHONGSHU_NOTE_REGEX: Regex = Regex::new(
+    r"https?://(www\.)?xiaohongshu\.com/(explore|discovery/item)/[0-9a-f]+\??(?:&?[^=&\s]*=[^=&\s]*)*"
+  ).unwrap();
+  /// Where xhslink.com links lead to, rather than a login or app landing page.
+  static ref XIAOHONGSHU_TARGET_REGEX: Regex = Regex::new(
+    r"^https?://(www\.)?xiaohongshu\.com/(explore|discovery/item)/[0-9a-f]+"
+  ).unwrap();
+}
+
+#[cfg(feature = "twitter")]
+lazy_static! {
+  static ref TWITTER_REGEX: Regex = Regex::new(
+    r"(https?://|(?<![a-zA-Z]{1})|^)(www\.|c\.|mobile\.)?(vx|fx|fixup)?(twitter|x)\.com(?P<path>/[a-zA-Z0-9_]+/status/[0-9]+)\??(?:&?[^=&\s]*=[^=&\s]*)*"
+  )
+  .unwrap();
+  static ref TWITTER_SHORT_REGEX: Regex = Regex::new(
+    r"((https?://|(?<![a-zA-Z]{1})|^)t\.co/[0-9a-zA-Z]+/?)\??(?:&?[^=&\s]*=[^=&\s]*)*"
+  ).unwrap();
+}
+
+#[cfg(feature = "tiktok")]
+lazy_static! {
+  static ref TIKTOK_REGEX: Regex = Regex::new(
+    r"(https?://|(?<![a-zA-Z]{1})|^)(www\.|m\.)?(vx)?tiktok\.com(?P<path>/@[a-zA-Z0-9_.]+/video/[0-9]+)\??(?:&?[^=&\s]*=[^=&\s]*)*"
   )
   .unwrap();
+}
+
+#[cfg(feature = "instagram")]
+lazy_static! {
+  static ref INSTAGRAM_REGEX: Regex = Regex::new(
+    r"(https?://|(?<![a-zA-Z]{1})|^)(www\.)?(dd)?instagram\.com(?P<path>/(p|reel|reels)/[a-zA-Z0-9_-]+/?)\??(?:&?[^=&\s]*=[^=&\s]*)*"
+  )
+  .unwrap();
+}
+
+#[cfg(feature = "amazon")]
+lazy_static! {
   static ref AMAZON_REGEX: Regex = Regex::new(
-    r"(?P<domain>(https?://|(?<![a-zA-Z]{1})|^)(www\.)?amazon\.(com|co(\.[a-zA-Z]+)?)/)[a-zA-Z0-9%-]+/(?P<path>dp/[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*"
+    r"(?P<domain>(https?://|(?<![a-zA-Z]{1})|^)(www\.)?amazon\.(com|co(\.[a-zA-Z]+)?)/)[a-zA-Z0-9%-]+/(?P<path>dp/[0-9a-zA-Z]+/?)\??(?:&?[^=&\s]*=[^=&\s]*)*"
   ).unwrap();
   static ref AMAZON_SEARCH_REGEX: Regex = Regex::new(
-    r"(?P<domain>(https?://|(?<![a-zA-Z]{1})|^)(www\.)?amazon\.(com|co(\.[a-zA-Z]+)?)/s)(?P<keyword>\?k=[a-zA-Z0-9%+-]+)(?:&?[^=&]*=[^=&]*)*"
+    r"(?P<domain>(https?://|(?<![a-zA-Z]{1})|^)(www\.)?amazon\.(com|co(\.[a-zA-Z]+)?)/s)(?P<keyword>\?k=[a-zA-Z0-9%+-]+)(?:&?[^=&\s]*=[^=&\s]*)*"
   )
   .unwrap();
-  static ref TWITTER_REGEX: Regex = Regex::new(
-    r"(https?://|(?<![a-zA-Z]{1})|^)(www|c\.)?(vx)?twitter\.com(?P<path>/[a-zA-Z0-9_]+/status/[0-9]+)\??(?:&?[^=&]*=[^=&]*)*"
+}
+
+#[cfg(feature = "cn-ecommerce")]
+lazy_static! {
+  static ref JD_REGEX: Regex = Regex::new(
+    r"(?P<url>(https?://|(?<![a-zA-Z]{1})|^)item\.(m\.)?jd\.com/product/[0-9]+\.html)\??(?:&?[^=&\s]*=[^=&\s]*)*"
   )
   .unwrap();
-  static ref WEIXIN_REGEX: Regex = Regex::new(
-    r"(https?://|(?<![a-zA-Z]{1})|^)mp\.weixin\.qq\.com/s\??(?:&?[^=&]*=[^=&]*)*"
+  static ref SMZDM_SHORT_REGEX: Regex = Regex::new(
+    r"((https?://|(?<![a-zA-Z.]{1})|^)([a-z]+\.)?zdm\.cn/[0-9a-zA-Z]+/?)\??(?:&?[^=&\s]*=[^=&\s]*)*"
+  ).unwrap();
+  static ref WEIDIAN_SHORT_REGEX: Regex = Regex::new(
+    r"((https?://|(?<![a-zA-Z]{1})|^)k\.weidian\.com/[0-9a-zA-Z_-]+/?)\??(?:&?[^=&\s]*=[^=&\s]*)*"
+  ).unwrap();
+  static ref MEITUAN_SHORT_REGEX: Regex = Regex::new(
+    r"((https?://|(?<![a-zA-Z.]{1})|^)(dpurl\.cn|i\.meituan\.com/s)/[0-9a-zA-Z_-]+/?)\??(?:&?[^=&\s]*=[^=&\s]*)*"
+  ).unwrap();
+}
+
+#[cfg(feature = "cn-social")]
+lazy_static! {
+  static ref WEIXIN_REGEX: Regex =
+    Regex::new(r"(https?://|(?<![a-zA-Z]{1})|^)mp\.weixin\.qq\.com/s\??(?:&?[^=&\s]*=[^=&\s]*)*")
+      .unwrap();
+  static ref DOUBAN_SHORT_REGEX: Regex = Regex::new(
+    r"((https?://|(?<![a-zA-Z]{1})|^)douc\.cc/[0-9a-zA-Z]+/?)\??(?:&?[^=&\s]*=[^=&\s]*)*"
   )
   .unwrap();
-  static ref JD_REGEX: Regex = Regex::new(
-    r"(?P<url>(https?://|(?<![a-zA-Z]{1})|^)item\.(m\.)?jd\.com/product/[0-9]+\.html)\??(?:&?[^=&]*=[^=&]*)*"
+}
+
+#[cfg(feature = "media")]
+lazy_static! {
+  static ref STEAM_REGEX: Regex = Regex::new(
+    r"(https?://|(?<![a-zA-Z]{1})|^)store\.steampowered\.com/app/(?P<id>[0-9]+)(?P<slug>/[^/?#\s]+)?/?\??(?:&?[^=&\s]*=[^=&\s]*)*"
   )
   .unwrap();
-  static ref XIAOHONGSHU_REGEX: Regex = Regex::new(
-    r"((https?://|(?<![a-zA-Z]{1})|^)xhslink.com/[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*"
+  static ref TWITCH_CLIP_REGEX: Regex = Regex::new(
+    r"(https?://|(?<![a-zA-Z]{1})|^)(clips\.twitch\.tv|(www\.|m\.)?twitch\.tv(/[a-zA-Z0-9_]+)?/clip)/(?!embed\b)(?P<slug>[a-zA-Z0-9_-]+)/?\??(?:&?[^=&\s]*=[^=&\s]*)*"
+  )
+  .unwrap();
+  static ref TWITCH_VIDEO_REGEX: Regex = Regex::new(
+    r"(https?://|(?<![a-zA-Z]{1})|^)(www\.|m\.)?twitch\.tv/videos/[0-9]+/?\??(?:&?[^=&\s]*=[^=&\s]*)*"
+  )
+  .unwrap();
+  static ref PIXIV_LEGACY_REGEX: Regex = Regex::new(
+    r"(https?://|(?<![a-zA-Z]{1})|^)(www\.|touch\.)?pixiv\.net/member_illust\.php\?(?:[^=&\s]*=[^=&\s]*&)*?illust_id=(?P<id>[0-9]+)(?:&[^=&\s]*=[^=&\s]*)*"
+  )
+  .unwrap();
+  static ref PIXIV_REGEX: Regex = Regex::new(
+    r"(https?://|(?<![a-zA-Z]{1})|^)(www\.)?pixiv\.net(?P<lang>/[a-z]{2})?/artworks/(?P<id>[0-9]+)/?\??(?:&?[^=&\s]*=[^=&\s]*)*"
+  )
+  .unwrap();
+  static ref SOUNDCLOUD_SHORT_REGEX: Regex = Regex::new(
+    r"((https?://|(?<![a-zA-Z]{1})|^)on\.soundcloud\.com/[0-9a-zA-Z]+/?)\??(?:&?[^=&\s]*=[^=&\s]*)*"
   ).unwrap();
-  static ref TWITTER_SHORT_REGEX: Regex = Regex::new(
-    r"((https?://|(?<![a-zA-Z]{1})|^)t\.co/[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*"
+  static ref STEAM_SHORT_REGEX: Regex = Regex::new(
+    r"((https?://|(?<![a-zA-Z]{1})|^)s\.team/[a-z]+/[0-9a-zA-Z-]+/?)\??(?:&?[^=&\s]*=[^=&\s]*)*"
   ).unwrap();
 }
 
-pub async fn replace_all(text: &str) -> Result<String> {
-  let mut new = text.to_string();
-  new = replace_bshort(&new)
-    .await
-    .context("Failed to replace short url")?;
-  new = replace_xiaohongshu(&new)
-    .await
-    .context("Failed to replace xiaohongshu url")?;
-  new = replace_twitter_short(&new)
-    .await
-    .context("Failed to replace twitter short url")?;
-  replace_btrack(&mut new);
-  new = replace_barticle(&new);
-  new = replace_twitter(&new);
-  new = replace_amazon(&new);
-  new = replace_amazon_search(&new);
-  new = replace_weixin(&new);
-  new = replace_jd(&new);
-  Ok(new)
-}
-
-fn replace_twitter(url: &str) -> String {
-  TWITTER_REGEX
-    .replace(url, "https://c.vxtwitter.com$path")
+#[cfg(feature = "blogs")]
+lazy_static! {
+  /// Email click redirectors of substack newsletters.
+  static ref SUBSTACK_REDIRECT_REGEX: Regex = Regex::new(
+    r"(https?://|(?<![a-zA-Z]{1})|^)substack\.com/redirect/[0-9a-zA-Z/_.-]+\??(?:&?[^=&\s]*=[^=&\s]*)*"
+  ).unwrap();
+  static ref LINKEDIN_SHORT_REGEX: Regex = Regex::new(
+    r"((https?://|(?<![a-zA-Z]{1})|^)lnkd\.in/[0-9a-zA-Z_-]+/?)\??(?:&?[^=&\s]*=[^=&\s]*)*"
+  ).unwrap();
+}
+
+#[cfg(feature = "shopping")]
+lazy_static! {
+  static ref SHEIN_SHORT_REGEX: Regex = Regex::new(
+    r"(https?://|(?<![a-zA-Z]{1})|^)(shein\.top/[0-9a-zA-Z]+/?|api-shein\.shein\.com/h5/sharejump[0-9a-zA-Z/_.-]*)\??(?:&?[^=&\s]*=[^=&\s]*)*"
+  ).unwrap();
+  static ref SHEIN_PRODUCT_REGEX: regex::Regex =
+    regex::Regex::new(r"-p-\d+(-cat-\d+)?\.html$").unwrap();
+}
+
+/// Names of the compiled-in replacers, with what their links contain.
+const PREFILTERS: &[(&str, &str)] = &[
+  #[cfg(feature = "bilibili")]
+  ("bilibili", r"b23\.tv/|bilibili\.com/"),
+  #[cfg(feature = "xiaohongshu")]
+  ("xiaohongshu", r"xhslink\.com/|xiaohongshu\.com/"),
+  #[cfg(feature = "twitter")]
+  ("twitter", r"t\.co/|(twitter|x)\.com/"),
+  #[cfg(feature = "tiktok")]
+  ("tiktok", r"tiktok\.com/"),
+  #[cfg(feature = "instagram")]
+  ("instagram", r"instagram\.com/"),
+  #[cfg(feature = "amazon")]
+  ("amazon", r"amazon\.co"),
+  #[cfg(feature = "cn-social")]
+  ("weixin", r"mp\.weixin\.qq\.com/s"),
+  #[cfg(feature = "cn-ecommerce")]
+  ("jd", r"jd\.com/product/"),
+  #[cfg(feature = "media")]
+  ("steam", r"store\.steampowered\.com/app/|s\.team/"),
+  #[cfg(feature = "media")]
+  ("twitch", r"twitch\.tv/"),
+  #[cfg(feature = "media")]
+  (
+    "pixiv",
+    r"pixiv\.net/(member_illust\.php|([a-z]{2}/)?artworks/)",
+  ),
+  #[cfg(feature = "blogs")]
+  ("medium", r"medium\.com/|/p/[0-9a-f]{8,12}\b"),
+  #[cfg(feature = "blogs")]
+  ("substack", r"substack\.com/"),
+  #[cfg(feature = "blogs")]
+  ("linkedin", r"lnkd\.in/|linkedin\.com/"),
+  #[cfg(feature = "media")]
+  ("imdb", r"imdb\.com/"),
+  #[cfg(feature = "media")]
+  ("soundcloud", r"soundcloud\.com/"),
+  #[cfg(feature = "media")]
+  ("apple", r"(music|apps)\.apple\.com/"),
+  #[cfg(feature = "media")]
+  ("netflix", r"netflix\.com/"),
+  #[cfg(feature = "shopping")]
+  ("shein", r"shein\.(com|top)/"),
+  #[cfg(feature = "shopping")]
+  ("booking", r"booking\.com/"),
+  #[cfg(feature = "shopping")]
+  ("etsy", r"etsy\.com/"),
+  #[cfg(feature = "cn-ecommerce")]
+  ("smzdm", r"smzdm\.com/|zdm\.cn/"),
+  #[cfg(feature = "cn-social")]
+  ("douban", r"douban\.com/|douc\.cc/"),
+  #[cfg(feature = "cn-social")]
+  ("coolapk", r"coolapk\.com/"),
+  #[cfg(feature = "cn-ecommerce")]
+  ("weidian", r"weidian\.com/"),
+  #[cfg(feature = "cn-ecommerce")]
+  ("meituan", r"dianping\.com/|meituan\.com/|dpurl\.cn/"),
+  ("tracking", r"https?://"),
+];
+
+/// Which built-in replacers could match a text.
+struct Prefilter(regex::SetMatches);
+
+impl Prefilter {
+  fn of(text: &str) -> Self {
+    Self(PREFILTER.matches(text))
+  }
+
+  fn any(&self) -> bool {
+    self.0.matched_any()
+  }
+
+  fn may_match(&self, name: &str) -> bool {
+    PREFILTERS
+      .iter()
+      .position(|(known, _)| *known == name)
+      .is_none_or(|i| self.0.matched(i))
+  }
+}
+
+/// Each replacer and the pattern a text must match for it to run.
+pub fn replacer_patterns() -> impl Iterator<Item = (&'static str, &'static str)> {
+  PREFILTERS.iter().copied()
+}
+
+/// A single link rewritten by one of the replacers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replacement {
+  /// Site the link belongs to, e.g. `bilibili`, or name of the custom rule.
+  pub site: String,
+  pub original: String,
+  pub cleaned: String,
+  /// `true` if the link was a short link resolved over network.
+  pub expanded: bool,
+}
+
+/// Everything `replace_all` changed in a text.
+#[derive(Debug, Clone, Default)]
+pub struct ReplaceReport {
+  pub replacements: Vec<Replacement>,
+  /// Short links kept as is because the shortener refused resolving them.
+  pub unexpanded: Vec<String>,
+  /// Spent resolving short links, zero without any.
+  pub resolve_time: Duration,
+  /// Spent matching and rewriting, without resolving.
+  pub regex_time: Duration,
+}
+
+impl ReplaceReport {
+  /// Adds what cleaning another part of the same text did.
+  pub fn extend(&mut self, other: ReplaceReport) {
+    self.replacements.extend(other.replacements);
+    self.unexpanded.extend(other.unexpanded);
+    self.resolve_time += other.resolve_time;
+    self.regex_time += other.regex_time;
+  }
+
+  fn record(&mut self, site: &str, original: &str, cleaned: &str, expanded: bool) {
+    if original == cleaned {
+      return;
+    }
+    self.replacements.push(Replacement {
+      site: site.to_string(),
+      original: original.to_string(),
+      cleaned: cleaned.to_string(),
+      expanded,
+    });
+  }
+
+  /// Records what `site` changed rewriting `original` as a whole, link by
+  /// link if it kept them in place.
+  #[cfg(feature = "plugins")]
+  pub(crate) fn record_rewrite(&mut self, site: &str, original: &str, cleaned: &str) {
+    let before: Vec<_> = URL_REGEX.find_iter(original).collect();
+    let after: Vec<_> = URL_REGEX.find_iter(cleaned).collect();
+    if before.len() != after.len() {
+      self.record(site, original, cleaned, false);
+      return;
+    }
+    for (before, after) in before.iter().zip(&after) {
+      self.record(site, before.as_str(), after.as_str(), false);
+    }
+  }
+
+  /// Keeps the short link `original` noted with [`UNEXPANDED`].
+  fn keep_unexpanded(
+    &mut self,
+    original: &str,
+    replaces: &mut Vec<(Range<usize>, String)>,
+    range: Range<usize>,
+  ) {
+    self.unexpanded.push(original.to_string());
+    replaces.push((range, format!("{original} {UNEXPANDED}")));
+  }
+
+  /// Whether every link still leads to the same page, having only lost
+  /// params, as opposed to being expanded or rewritten.
+  pub fn only_params_stripped(&self) -> bool {
+    self.replacements.iter().all(|replacement| {
+      let (Ok(original), Ok(cleaned)) = (
+        Url::parse(&replacement.original),
+        Url::parse(replacement.cleaned.trim()),
+      ) else {
+        return false;
+      };
+      !replacement.expanded
+        && original.host() == cleaned.host()
+        && original.path() == cleaned.path()
+    })
+  }
+
+  /// Number of cleaned links per site, in order of first appearance.
+  pub fn site_counts(&self) -> Vec<(&str, usize)> {
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for replacement in &self.replacements {
+      match counts
+        .iter_mut()
+        .find(|(site, _)| *site == replacement.site)
+      {
+        Some((_, count)) => *count += 1,
+        None => counts.push((&replacement.site, 1)),
+      }
+    }
+    counts
+  }
+}
+
+/// Appended to short links that couldn't be expanded.
+pub const UNEXPANDED: &str = "(couldn't expand)";
+
+/// Tracking params removed from every url unless configured otherwise.
+pub const DEFAULT_TRACKING_PARAMS: &[&str] = &[
+  "utm_*", "gclid", "fbclid", "mc_cid", "ref_src", "igshid", "spm", "xd_co_f",
+];
+
+/// [`DEFAULT_TRACKING_PARAMS`] as owned strings.
+pub fn default_tracking_params() -> Vec<String> {
+  DEFAULT_TRACKING_PARAMS
+    .iter()
+    .map(|key| key.to_string())
+    .collect()
+}
+
+/// Settings for a cleaning run.
+#[derive(Debug, Clone, Copy)]
+pub struct Rules<'a> {
+  pub embeds: &'a Embeds,
+  /// Query keys removed from every url, a trailing `*` matches keys by prefix.
+  pub tracking_params: &'a [String],
+  /// User defined rules, applied after the built-in replacers.
+  pub custom: &'a [CustomRule],
+  /// Names of replacers to skip, see [`REPLACERS`], of custom rules or of
+  /// plugins.
+  pub disabled: &'a [String],
+  /// Applied last, only by [`replace_all`].
+  pub plugins: Option<&'a Plugins>,
+  /// Shorteners without a replacer of their own, only resolved by
+  /// [`replace_all`].
+  pub shorteners: &'a [Shortener],
+}
+
+/// A rule from the `[[rules]]` config.
+#[derive(Debug)]
+pub struct CustomRule {
+  pub name: String,
+  pub regex: Regex,
+  pub action: CustomAction,
+}
+
+#[derive(Debug)]
+pub enum CustomAction {
+  /// Replace matches by expanding the template, e.g. `$url`.
+  Replace(String),
+  /// Keep only these params in query of the matched url.
+  Keep(Vec<String>),
+  /// Replace matches by what `program` prints given them on stdin, only
+  /// applied by [`replace_all`], after the other rules.
+  Command {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+  },
+}
+
+/// Names of all replacers, the same as [`Replacement::site`] they report.
+pub const REPLACERS: &[&str] = &[
+  #[cfg(feature = "bilibili")]
+  "bilibili",
+  #[cfg(feature = "xiaohongshu")]
+  "xiaohongshu",
+  #[cfg(feature = "twitter")]
+  "twitter",
+  #[cfg(feature = "tiktok")]
+  "tiktok",
+  #[cfg(feature = "instagram")]
+  "instagram",
+  #[cfg(feature = "amazon")]
+  "amazon",
+  #[cfg(feature = "cn-social")]
+  "weixin",
+  #[cfg(feature = "cn-ecommerce")]
+  "jd",
+  #[cfg(feature = "media")]
+  "steam",
+  #[cfg(feature = "media")]
+  "twitch",
+  #[cfg(feature = "media")]
+  "pixiv",
+  #[cfg(feature = "blogs")]
+  "medium",
+  #[cfg(feature = "blogs")]
+  "substack",
+  #[cfg(feature = "blogs")]
+  "linkedin",
+  #[cfg(feature = "media")]
+  "imdb",
+  #[cfg(feature = "media")]
+  "soundcloud",
+  #[cfg(feature = "media")]
+  "apple",
+  #[cfg(feature = "media")]
+  "netflix",
+  #[cfg(feature = "shopping")]
+  "shein",
+  #[cfg(feature = "shopping")]
+  "booking",
+  #[cfg(feature = "shopping")]
+  "etsy",
+  #[cfg(feature = "cn-ecommerce")]
+  "smzdm",
+  #[cfg(feature = "cn-social")]
+  "douban",
+  #[cfg(feature = "cn-social")]
+  "coolapk",
+  #[cfg(feature = "cn-ecommerce")]
+  "weidian",
+  #[cfg(feature = "cn-ecommerce")]
+  "meituan",
+  "shortener",
+  "tracking",
+];
+
+impl Rules<'_> {
+  fn is_enabled(&self, replacer: &str) -> bool {
+    !self.disabled.iter().any(|name| name == replacer)
+  }
+}
+
+/// Cleans all links in `text`, resolving short links over network with
+/// `resolver`.
+#[tracing::instrument(skip_all)]
+pub async fn replace_all(
+  text: &str,
+  rules: Rules<'_>,
+  resolver: &Resolver,
+) -> Result<(String, ReplaceReport)> {
+  let started = Instant::now();
+  let mut report = ReplaceReport::default();
+  let prefilter = Prefilter::of(text);
+  if !prefilter.any() && rules.custom.is_empty() && rules.plugins.is_none_or(Plugins::is_empty) {
+    report.regex_time = started.elapsed();
+    return Ok((text.to_string(), report));
+  }
+  let sites: Vec<_> = SHORT_LINK_SITES
+    .iter()
+    .filter(|site| rules.is_enabled(site.name) && prefilter.may_match(site.name))
+    .collect();
+  let shorteners = if rules.is_enabled("shortener") {
+    rules.shorteners
+  } else {
+    &[]
+  };
+  let mut new = replace_short_links(text, &sites, shorteners, resolver, &mut report).await?;
+  new = replace_offline(new, rules, &mut report);
+  new = replace_commands(new, rules, &mut report).await;
+  if let Some(plugins) = rules.plugins {
+    new = plugins.clean(new, rules.disabled, &mut report).await;
+  }
+  report.regex_time = started.elapsed().saturating_sub(report.resolve_time);
+  Ok((new, report))
+}
+
+/// Applies the custom rules running a command, see [`CustomAction::Command`].
+async fn replace_commands(mut new: String, rules: Rules<'_>, report: &mut ReplaceReport) -> String {
+  for rule in rules.custom {
+    let CustomAction::Command {
+      program,
+      args,
+      timeout,
+    } = &rule.action
+    else {
+      continue;
+    };
+    if !rules.is_enabled(&rule.name) {
+      continue;
+    }
+    let matches: Vec<_> = rule
+      .regex
+      .find_iter(&new)
+      .filter_map(|i| i.ok().map(|i| (i.range(), i.as_str().to_string())))
+      .collect();
+    let mut replaces = Vec::new();
+    for (range, matched) in matches {
+      let span = info_span!("replacer", name = rule.name.as_str());
+      match run_command(program, args, *timeout, &matched)
+        .instrument(span)
+        .await
+      {
+        Ok(cleaned) => {
+          report.record(&rule.name, &matched, &cleaned, false);
+          replaces.push((range, cleaned));
+        },
+        Err(err) => warn!("Keeping {matched} as is: {err:#}"),
+      }
+    }
+    new = splice(&new, replaces);
+  }
+  new
+}
+
+/// Output of `program` given `input` on stdin, without the trailing newline.
+async fn run_command(
+  program: &str,
+  args: &[String],
+  timeout: Duration,
+  input: &str,
+) -> Result<String> {
+  let mut child = tokio::process::Command::new(program)
+    .args(args)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null())
+    .kill_on_drop(true)
+    .spawn()
+    .with_context(|| format!("Failed to run {program}"))?;
+  let mut stdin = child.stdin.take().context("No stdin")?;
+  let output = tokio::time::timeout(timeout, async {
+    stdin.write_all(input.as_bytes()).await?;
+    drop(stdin);
+    child.wait_with_output().await
+  })
+  .await
+  .with_context(|| format!("{program} timed out"))?
+  .with_context(|| format!("Failed to run {program}"))?;
+  if !output.status.success() {
+    bail!("{program} exited with {}", output.status);
+  }
+  let stdout =
+    String::from_utf8(output.stdout).with_context(|| format!("{program} printed invalid UTF-8"))?;
+  let cleaned = stdout.strip_suffix('\n').unwrap_or(&stdout);
+  if cleaned.is_empty() {
+    bail!("{program} printed nothing");
+  }
+  Ok(cleaned.to_string())
+}
+
+/// Cleans links in `text` with the rules that don't need network, short links
+/// are left as is.
+pub fn clean_offline(text: &str) -> (String, ReplaceReport) {
+  let embeds = Embeds::default();
+  let rules = Rules {
+    embeds: &embeds,
+    tracking_params: &DEFAULT_TRACKING,
+    custom: &[],
+    disabled: &[],
+    plugins: None,
+    shorteners: &[],
+  };
+  clean_offline_with(text, rules)
+}
+
+/// [`clean_offline`] with custom rules.
+pub fn clean_offline_with(text: &str, rules: Rules<'_>) -> (String, ReplaceReport) {
+  let mut report = ReplaceReport::default();
+  let new = replace_offline(text.to_string(), rules, &mut report);
+  (new, report)
+}
+
+fn replace_offline(mut new: String, rules: Rules<'_>, report: &mut ReplaceReport) -> String {
+  let prefilter = Prefilter::of(&new);
+  let builtin = |name| rules.is_enabled(name) && prefilter.may_match(name);
+  #[cfg(feature = "bilibili")]
+  if builtin("bilibili") {
+    let _span = info_span!("replacer", name = "bilibili").entered();
+    replace_btrack(&mut new, report);
+    new = replace_barticle(&new, report);
+    new = replace_bspace(&new, report);
+  }
+  #[cfg(feature = "xiaohongshu")]
+  if builtin("xiaohongshu") {
+    let _span = info_span!("replacer", name = "xiaohongshu").entered();
+    new = replace_remove_pairs(
+      &XIAOHONGSHU_NOTE_REGEX,
+      &new,
+      |key| ["xsec_token", "xsec_source", "app_platform"].contains(&key),
+      "xiaohongshu",
+      report,
+    );
+  }
+  #[cfg(feature = "twitter")]
+  if builtin("twitter") {
+    let _span = info_span!("replacer", name = "twitter").entered();
+    new = replace_twitter(
+      &new,
+      rules.embeds.domain(Service::Twitter).as_deref(),
+      report,
+    );
+  }
+  #[cfg(feature = "tiktok")]
+  if builtin("tiktok") {
+    let _span = info_span!("replacer", name = "tiktok").entered();
+    new = replace_tiktok(
+      &new,
+      rules.embeds.domain(Service::TikTok).as_deref(),
+      report,
+    );
+  }
+  #[cfg(feature = "instagram")]
+  if builtin("instagram") {
+    let _span = info_span!("replacer", name = "instagram").entered();
+    new = replace_instagram(
+      &new,
+      rules.embeds.domain(Service::Instagram).as_deref(),
+      report,
+    );
+  }
+  #[cfg(feature = "amazon")]
+  if builtin("amazon") {
+    let _span = info_span!("replacer", name = "amazon").entered();
+    new = replace_amazon(&new, report);
+    new = replace_amazon_search(&new, report);
+  }
+  #[cfg(feature = "cn-social")]
+  if builtin("weixin") {
+    let _span = info_span!("replacer", name = "weixin").entered();
+    new = replace_weixin(&new, report);
+  }
+  #[cfg(feature = "cn-ecommerce")]
+  if builtin("jd") {
+    let _span = info_span!("replacer", name = "jd").entered();
+    new = replace_jd(&new, report);
+  }
+  #[cfg(feature = "media")]
+  if builtin("steam") {
+    let _span = info_span!("replacer", name = "steam").entered();
+    new = replace_steam(&new, report);
+  }
+  #[cfg(feature = "media")]
+  if builtin("twitch") {
+    let _span = info_span!("replacer", name = "twitch").entered();
+    new = replace_twitch_clip(&new, report);
+    new = replace_keep_pairs(
+      &TWITCH_VIDEO_REGEX,
+      &new,
+      Cow::Borrowed(&["t"]),
+      "twitch",
+      report,
+    );
+  }
+  #[cfg(feature = "media")]
+  if builtin("pixiv") {
+    let _span = info_span!("replacer", name = "pixiv").entered();
+    new = replace_pixiv(&new, report);
+  }
+  #[cfg(feature = "blogs")]
+  if builtin("medium") {
+    let _span = info_span!("replacer", name = "medium").entered();
+    new = replace_medium(&new, report);
+  }
+  #[cfg(feature = "blogs")]
+  if builtin("substack") {
+    let _span = info_span!("replacer", name = "substack").entered();
+    new = replace_substack(&new, report);
+  }
+  #[cfg(feature = "blogs")]
+  if builtin("linkedin") {
+    let _span = info_span!("replacer", name = "linkedin").entered();
+    new = replace_linkedin(&new, report);
+  }
+  #[cfg(feature = "media")]
+  if builtin("imdb") {
+    let _span = info_span!("replacer", name = "imdb").entered();
+    new = replace_imdb(&new, report);
+  }
+  #[cfg(feature = "media")]
+  if builtin("soundcloud") {
+    let _span = info_span!("replacer", name = "soundcloud").entered();
+    new = replace_soundcloud(&new, report);
+  }
+  #[cfg(feature = "media")]
+  if builtin("apple") {
+    let _span = info_span!("replacer", name = "apple").entered();
+    new = replace_apple(&new, report);
+  }
+  #[cfg(feature = "media")]
+  if builtin("netflix") {
+    let _span = info_span!("replacer", name = "netflix").entered();
+    new = replace_netflix(&new, report);
+  }
+  #[cfg(feature = "shopping")]
+  if builtin("shein") {
+    let _span = info_span!("replacer", name = "shein").entered();
+    new = replace_shein(&new, report);
+  }
+  #[cfg(feature = "shopping")]
+  if builtin("booking") {
+    let _span = info_span!("replacer", name = "booking").entered();
+    new = replace_booking(&new, report);
+  }
+  #[cfg(feature = "shopping")]
+  if builtin("etsy") {
+    let _span = info_span!("replacer", name = "etsy").entered();
+    new = replace_etsy(&new, report);
+  }
+  #[cfg(feature = "cn-ecommerce")]
+  if builtin("smzdm") {
+    let _span = info_span!("replacer", name = "smzdm").entered();
+    new = replace_smzdm(&new, report);
+  }
+  #[cfg(feature = "cn-social")]
+  if builtin("douban") {
+    let _span = info_span!("replacer", name = "douban").entered();
+    new = replace_douban(&new, report);
+  }
+  #[cfg(feature = "cn-social")]
+  if builtin("coolapk") {
+    let _span = info_span!("replacer", name = "coolapk").entered();
+    new = replace_coolapk(&new, report);
+  }
+  #[cfg(feature = "cn-ecommerce")]
+  if builtin("weidian") {
+    let _span = info_span!("replacer", name = "weidian").entered();
+    new = replace_weidian(&new, report);
+  }
+  #[cfg(feature = "cn-ecommerce")]
+  if builtin("meituan") {
+    let _span = info_span!("replacer", name = "meituan").entered();
+    new = replace_meituan(&new, report);
+  }
+  for rule in rules.custom {
+    if !rules.is_enabled(&rule.name) {
+      continue;
+    }
+    let _span = info_span!("replacer", name = rule.name.as_str()).entered();
+    new = match rule.action {
+      CustomAction::Replace(ref template) => {
+        replace_regex(&rule.regex, &new, template, &rule.name, report)
+      },
+      CustomAction::Keep(ref keys) => {
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        replace_keep_pairs(&rule.regex, &new, Cow::Owned(keys), &rule.name, report)
+      },
+      CustomAction::Command { .. } => continue,
+    };
+  }
+  if builtin("tracking") {
+    let _span = info_span!("replacer", name = "tracking").entered();
+    new = replace_tracking(&new, rules.tracking_params, report);
+  }
+  new
+}
+
+/// Replaces every match of `regex` by expanding `template`, recording each change.
+fn replace_regex(
+  regex: &Regex,
+  text: &str,
+  template: &str,
+  site: &str,
+  report: &mut ReplaceReport,
+) -> String {
+  regex
+    .replace_all(text, |caps: &Captures| {
+      let mut new = String::new();
+      caps.expand(template, &mut new);
+      report.record(site, &caps[0], &new, false);
+      new
+    })
     .into()
 }
 
-fn replace_weixin(text: &str) -> String {
-  let mut new_str = text.to_string();
-  for i in WEIXIN_REGEX.find_iter(text) {
+/// Rebuilds `text` with the given ranges substituted, ranges must be sorted
+/// and not overlapping.
+fn splice(text: &str, replaces: Vec<(Range<usize>, String)>) -> String {
+  let mut new_str = String::with_capacity(text.len());
+  let mut last = 0;
+  for (range, str) in replaces {
+    new_str.push_str(&text[last..range.start]);
+    new_str.push_str(&str);
+    last = range.end;
+  }
+  new_str.push_str(&text[last..]);
+  new_str
+}
+
+#[cfg(feature = "twitter")]
+fn replace_twitter(url: &str, embed: Option<&str>, report: &mut ReplaceReport) -> String {
+  let domain = embed.unwrap_or("x.com");
+  replace_regex(
+    &TWITTER_REGEX,
+    url,
+    &format!("https://{domain}$path"),
+    "twitter",
+    report,
+  )
+}
+
+#[cfg(feature = "tiktok")]
+fn replace_tiktok(url: &str, embed: Option<&str>, report: &mut ReplaceReport) -> String {
+  let domain = embed.unwrap_or("www.tiktok.com");
+  replace_regex(
+    &TIKTOK_REGEX,
+    url,
+    &format!("https://{domain}$path"),
+    "tiktok",
+    report,
+  )
+}
+
+#[cfg(feature = "instagram")]
+fn replace_instagram(url: &str, embed: Option<&str>, report: &mut ReplaceReport) -> String {
+  let domain = embed.unwrap_or("www.instagram.com");
+  replace_regex(
+    &INSTAGRAM_REGEX,
+    url,
+    &format!("https://{domain}$path"),
+    "instagram",
+    report,
+  )
+}
+
+/// Parses a matched link, and whether it came without a scheme, as links
+/// pasted that way come out with one.
+fn parse_link(link: &str) -> Option<(Url, bool)> {
+  match Url::from_str(link) {
+    Ok(url) => Some((url, false)),
+    Err(_) => Url::from_str(&format!("https://{link}"))
+      .ok()
+      .map(|url| (url, true)),
+  }
+}
+
+/// Replaces every match of `regex` by the matched url with only `keys` kept in query.
+fn replace_keep_pairs(
+  regex: &Regex,
+  text: &str,
+  keys: Cow<[&str]>,
+  site: &str,
+  report: &mut ReplaceReport,
+) -> String {
+  let mut replaces = Vec::new();
+  for i in regex.find_iter(text) {
     let i = match i {
       Ok(i) => i,
       Err(err) => {
@@ -83,38 +898,651 @@ fn replace_weixin(text: &str) -> String {
         continue;
       },
     };
-    let mut url = if let Ok(url) = Url::from_str(i.as_str()) {
-      url
-    } else {
+    let Some((mut url, bare)) = parse_link(i.as_str()) else {
       continue;
     };
-    const KEYS: Cow<[&str]> = Cow::Borrowed(&["__biz", "mid", "idx", "sn"]);
-    url.keep_pairs_only_in(KEYS);
-    new_str.replace_range(i.range(), url.to_string().as_str());
+    let query = url.query().map(str::to_string);
+    url.keep_pairs_only_in(keys.clone());
+    if bare && url.query() == query.as_deref() {
+      continue;
+    }
+    report.record(site, i.as_str(), url.as_str(), false);
+    replaces.push((i.range(), url.to_string()));
   }
-  new_str
+  splice(text, replaces)
+}
+
+/// Replaces every match of `regex` by the matched url without the params
+/// `is_param`.
+#[cfg(feature = "xiaohongshu")]
+fn replace_remove_pairs(
+  regex: &Regex,
+  text: &str,
+  is_param: fn(&str) -> bool,
+  site: &str,
+  report: &mut ReplaceReport,
+) -> String {
+  let mut replaces = Vec::new();
+  for i in regex.find_iter(text) {
+    let i = match i {
+      Ok(i) => i,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let Some((mut url, _)) = parse_link(i.as_str()) else {
+      continue;
+    };
+    if !url.query_pairs().any(|(k, _)| is_param(&k)) {
+      continue;
+    }
+    url.remove_pairs_if_key(is_param);
+    report.record(site, i.as_str(), url.as_str(), false);
+    replaces.push((i.range(), url.to_string()));
+  }
+  splice(text, replaces)
 }
 
-fn replace_jd(url: &str) -> String {
-  JD_REGEX.replace_all(url, "$url").into()
+#[cfg(feature = "cn-social")]
+fn replace_weixin(text: &str, report: &mut ReplaceReport) -> String {
+  const KEYS: Cow<[&str]> = Cow::Borrowed(&["__biz", "mid", "idx", "sn"]);
+  replace_keep_pairs(&WEIXIN_REGEX, text, KEYS, "weixin", report)
 }
 
-fn replace_amazon(url: &str) -> String {
-  AMAZON_REGEX.replace_all(url, "$domain$path").into()
+#[cfg(feature = "bilibili")]
+fn replace_bspace(text: &str, report: &mut ReplaceReport) -> String {
+  const KEYS: Cow<[&str]> = Cow::Borrowed(&["tid"]);
+  replace_keep_pairs(&BSPACE_REGEX, text, KEYS, "bilibili", report)
 }
 
-fn replace_amazon_search(url: &str) -> String {
-  AMAZON_SEARCH_REGEX
-    .replace_all(url, "$domain$keyword")
-    .into()
+#[cfg(feature = "cn-ecommerce")]
+fn replace_jd(url: &str, report: &mut ReplaceReport) -> String {
+  replace_regex(&JD_REGEX, url, "$url", "jd", report)
+}
+
+/// Keeps only the id and slug of store pages, like `/app/620/Portal_2/`.
+#[cfg(feature = "media")]
+fn replace_steam(url: &str, report: &mut ReplaceReport) -> String {
+  replace_regex(
+    &STEAM_REGEX,
+    url,
+    "https://store.steampowered.com/app/$id$slug/",
+    "steam",
+    report,
+  )
+}
+
+/// Rewrites clips, also those shared from channels or the mobile site, as
+/// `clips.twitch.tv/<slug>`.
+#[cfg(feature = "media")]
+fn replace_twitch_clip(url: &str, report: &mut ReplaceReport) -> String {
+  replace_regex(
+    &TWITCH_CLIP_REGEX,
+    url,
+    "https://clips.twitch.tv/$slug",
+    "twitch",
+    report,
+  )
+}
+
+/// Rewrites legacy `member_illust.php` links as `/artworks/<id>`, and drops
+/// the params of those.
+#[cfg(feature = "media")]
+fn replace_pixiv(url: &str, report: &mut ReplaceReport) -> String {
+  let url = replace_regex(
+    &PIXIV_LEGACY_REGEX,
+    url,
+    "https://www.pixiv.net/artworks/$id",
+    "pixiv",
+    report,
+  );
+  replace_regex(
+    &PIXIV_REGEX,
+    &url,
+    "https://www.pixiv.net$lang/artworks/$id",
+    "pixiv",
+    report,
+  )
+}
+
+#[cfg(feature = "amazon")]
+fn replace_amazon(url: &str, report: &mut ReplaceReport) -> String {
+  replace_regex(&AMAZON_REGEX, url, "$domain$path", "amazon", report)
+}
+
+#[cfg(feature = "amazon")]
+fn replace_amazon_search(url: &str, report: &mut ReplaceReport) -> String {
+  replace_regex(
+    &AMAZON_SEARCH_REGEX,
+    url,
+    "$domain$keyword",
+    "amazon",
+    report,
+  )
+}
+
+fn is_tracking_param(key: &str, params: &[String]) -> bool {
+  params.iter().any(|param| match param.strip_suffix('*') {
+    Some(prefix) => key.starts_with(prefix),
+    None => key == param,
+  })
+}
+
+/// Removes tracking params from the query and fragment of any url, as a
+/// catch-all after the site specific replacers.
+fn replace_tracking(text: &str, params: &[String], report: &mut ReplaceReport) -> String {
+  let mut replaces = Vec::new();
+  for i in URL_REGEX.find_iter(text) {
+    // Punctuation right after a link is more likely part of the sentence.
+    let str = i
+      .as_str()
+      .trim_end_matches(['.', ',', '!', '?', ';', ':', ')']);
+    let Ok(mut url) = Url::from_str(str) else {
+      continue;
+    };
+    let parsed = url.to_string();
+    // queries without tracking params aren't encoded again
+    if url
+      .query_pairs()
+      .any(|(k, _)| is_tracking_param(&k, params))
+    {
+      url.remove_pairs_if_key(|k| is_tracking_param(k, params));
+    }
+    url.remove_fragment_pairs_if_key(|k| is_tracking_param(k, params));
+    if url.as_str() == parsed {
+      continue;
+    }
+    report.record("tracking", str, url.as_str(), false);
+    replaces.push((i.start()..i.start() + str.len(), url.to_string()));
+  }
+  splice(text, replaces)
+}
+
+/// Params of medium articles telling who shared them.
+#[cfg(feature = "blogs")]
+const MEDIUM_PARAMS: &[&str] = &["source", "sk", "gi"];
+
+/// Whether `url` is a medium article, also of publications on their own
+/// domain, which share `/p/<id>` links.
+#[cfg(feature = "blogs")]
+fn is_medium(url: &Url) -> bool {
+  let host = url.host_str().unwrap_or_default();
+  if host == "medium.com" || host.ends_with(".medium.com") {
+    return true;
+  }
+  url
+    .path()
+    .strip_prefix("/p/")
+    .is_some_and(|id| (8..=12).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Cleans the urls `applies` to with `clean`, as `site`.
+#[cfg(any(
+  feature = "cn-ecommerce",
+  feature = "cn-social",
+  feature = "media",
+  feature = "blogs",
+  feature = "shopping"
+))]
+fn clean_urls(
+  text: &str,
+  site: &str,
+  applies: fn(&Url) -> bool,
+  clean: impl Fn(&mut Url),
+  report: &mut ReplaceReport,
+) -> String {
+  let mut replaces = Vec::new();
+  for i in URL_REGEX.find_iter(text) {
+    let str = i
+      .as_str()
+      .trim_end_matches(['.', ',', '!', '?', ';', ':', ')']);
+    let Ok(mut url) = Url::from_str(str) else {
+      continue;
+    };
+    if !applies(&url) {
+      continue;
+    }
+    let parsed = url.to_string();
+    clean(&mut url);
+    if url.as_str() == parsed {
+      continue;
+    }
+    report.record(site, str, url.as_str(), false);
+    replaces.push((i.start()..i.start() + str.len(), url.to_string()));
+  }
+  splice(text, replaces)
+}
+
+/// Removes the params `is_param` from the urls `applies` to, as `site`.
+#[cfg(any(
+  feature = "cn-ecommerce",
+  feature = "cn-social",
+  feature = "media",
+  feature = "blogs",
+  feature = "shopping"
+))]
+fn remove_params(
+  text: &str,
+  site: &str,
+  applies: fn(&Url) -> bool,
+  is_param: fn(&str) -> bool,
+  report: &mut ReplaceReport,
+) -> String {
+  clean_urls(
+    text,
+    site,
+    applies,
+    |url| {
+      if url.query_pairs().any(|(k, _)| is_param(&k)) {
+        url.remove_pairs_if_key(is_param);
+      }
+    },
+    report,
+  )
+}
+
+#[cfg(feature = "blogs")]
+fn replace_medium(text: &str, report: &mut ReplaceReport) -> String {
+  remove_params(
+    text,
+    "medium",
+    is_medium,
+    |key| MEDIUM_PARAMS.contains(&key),
+    report,
+  )
+}
+
+/// Whether `url` is a post of a substack newsletter.
+#[cfg(feature = "blogs")]
+fn is_substack_post(url: &Url) -> bool {
+  url
+    .host_str()
+    .is_some_and(|host| host.ends_with(".substack.com"))
+    && url.path().starts_with("/p/")
+}
+
+/// Params of substack posts for referrals and tracking.
+#[cfg(feature = "blogs")]
+fn is_substack_param(key: &str) -> bool {
+  key == "r" || key == "triedRedirect" || key.starts_with("utm_")
+}
+
+#[cfg(feature = "blogs")]
+fn replace_substack(text: &str, report: &mut ReplaceReport) -> String {
+  remove_params(
+    text,
+    "substack",
+    is_substack_post,
+    is_substack_param,
+    report,
+  )
+}
+
+/// Whether `url` is a linkedin post or article.
+#[cfg(feature = "blogs")]
+fn is_linkedin_post(url: &Url) -> bool {
+  let host = url.host_str().unwrap_or_default();
+  (host == "linkedin.com" || host.ends_with(".linkedin.com"))
+    && ["/posts/", "/pulse/", "/feed/update/"]
+      .iter()
+      .any(|prefix| url.path().starts_with(prefix))
+}
+
+/// Params of linkedin links telling who shared them.
+#[cfg(feature = "blogs")]
+fn is_linkedin_param(key: &str) -> bool {
+  ["trk", "trackingId", "lipi"].contains(&key)
+}
+
+#[cfg(feature = "blogs")]
+fn replace_linkedin(text: &str, report: &mut ReplaceReport) -> String {
+  remove_params(
+    text,
+    "linkedin",
+    is_linkedin_post,
+    is_linkedin_param,
+    report,
+  )
+}
+
+/// Whether `url` is an imdb title, name or list.
+#[cfg(feature = "media")]
+fn is_imdb(url: &Url) -> bool {
+  matches!(
+    url.host_str(),
+    Some("imdb.com" | "www.imdb.com" | "m.imdb.com")
+  ) && ["/title/", "/name/", "/list/"]
+    .iter()
+    .any(|prefix| url.path().starts_with(prefix))
+}
+
+/// Drops `ref_` from imdb links, whether a param or the last path segment
+/// as in `/title/tt0111161/ref_=nv_sr_1`.
+#[cfg(feature = "media")]
+fn replace_imdb(text: &str, report: &mut ReplaceReport) -> String {
+  clean_urls(
+    text,
+    "imdb",
+    is_imdb,
+    |url| {
+      if let Some(path) = url
+        .path()
+        .rsplit_once('/')
+        .and_then(|(path, last)| last.starts_with("ref_").then(|| format!("{path}/")))
+      {
+        url.set_path(&path);
+      }
+      if url.query_pairs().any(|(k, _)| k == "ref_") {
+        url.remove_pairs_if_key(|key| key == "ref_");
+      }
+    },
+    report,
+  )
+}
+
+/// Whether `url` is a soundcloud track, or any other page of it.
+#[cfg(feature = "media")]
+fn is_soundcloud(url: &Url) -> bool {
+  matches!(
+    url.host_str(),
+    Some("soundcloud.com" | "www.soundcloud.com" | "m.soundcloud.com")
+  )
+}
+
+/// Params soundcloud adds to shared tracks.
+#[cfg(feature = "media")]
+fn is_soundcloud_param(key: &str) -> bool {
+  key == "si" || key == "ref" || key.starts_with("utm_")
+}
+
+#[cfg(feature = "media")]
+fn replace_soundcloud(text: &str, report: &mut ReplaceReport) -> String {
+  remove_params(
+    text,
+    "soundcloud",
+    is_soundcloud,
+    is_soundcloud_param,
+    report,
+  )
+}
+
+/// Whether `url` is on apple music or the app store.
+#[cfg(feature = "media")]
+fn is_apple(url: &Url) -> bool {
+  matches!(url.host_str(), Some("music.apple.com" | "apps.apple.com"))
+}
+
+/// Campaign and affiliate params of apple links, `i` picking the track of
+/// an album is kept.
+#[cfg(feature = "media")]
+fn is_apple_param(key: &str) -> bool {
+  ["ct", "itsct", "itscg", "ls", "at"].contains(&key)
+}
+
+#[cfg(feature = "media")]
+fn replace_apple(text: &str, report: &mut ReplaceReport) -> String {
+  remove_params(text, "apple", is_apple, is_apple_param, report)
+}
+
+/// Whether `url` is a netflix title or video, with or without a region
+/// as in `/jp/title/80100172`.
+#[cfg(feature = "media")]
+fn is_netflix_title(url: &Url) -> bool {
+  matches!(url.host_str(), Some("netflix.com" | "www.netflix.com"))
+    && url
+      .path_segments()
+      .is_some_and(|mut segments| segments.any(|segment| segment == "title" || segment == "watch"))
+}
+
+/// Params of netflix links holding the recommendations of the sharer.
+#[cfg(feature = "media")]
+fn is_netflix_param(key: &str) -> bool {
+  ["trkid", "tctx", "s"].contains(&key)
+}
+
+#[cfg(feature = "media")]
+fn replace_netflix(text: &str, report: &mut ReplaceReport) -> String {
+  remove_params(text, "netflix", is_netflix_title, is_netflix_param, report)
+}
+
+/// Whether `url` is on shein.
+#[cfg(feature = "shopping")]
+fn is_shein(url: &Url) -> bool {
+  url
+    .host_str()
+    .is_some_and(|host| host == "shein.com" || host.ends_with(".shein.com"))
+}
+
+/// Params of shein links telling who shared them, and affiliate ids.
+#[cfg(feature = "shopping")]
+fn is_shein_param(key: &str) -> bool {
+  key == "url_from" || key == "share_from" || key.starts_with("aff_")
+}
+
+/// Unwraps shein share redirects holding their target, and drops every
+/// param of product pages, which only need their id.
+#[cfg(feature = "shopping")]
+fn clean_shein_link(url: &mut Url) {
+  if url.host_str() == Some("api-shein.shein.com") && url.path().starts_with("/h5/sharejump") {
+    let target = url
+      .query_pairs()
+      .filter_map(|(_, value)| Url::parse(&value).ok())
+      .find(is_shein);
+    if let Some(target) = target {
+      *url = target;
+    }
+  }
+  if SHEIN_PRODUCT_REGEX.is_match(url.path()) {
+    url.set_query(None);
+  } else if url.query_pairs().any(|(k, _)| is_shein_param(&k)) {
+    url.remove_pairs_if_key(is_shein_param);
+  }
+}
+
+#[cfg(feature = "shopping")]
+fn replace_shein(text: &str, report: &mut ReplaceReport) -> String {
+  clean_urls(text, "shein", is_shein, clean_shein_link, report)
+}
+
+/// Whether `url` is a hotel on booking.com.
+#[cfg(feature = "shopping")]
+fn is_booking_hotel(url: &Url) -> bool {
+  matches!(url.host_str(), Some("booking.com" | "www.booking.com"))
+    && url.path().starts_with("/hotel/")
+}
+
+/// Params of booking.com hotels for affiliates, sessions and the search
+/// they were found in, the dates and guests are kept.
+#[cfg(feature = "shopping")]
+const BOOKING_PARAMS: [&str; 11] = [
+  "aid",
+  "label",
+  "sid",
+  "dist",
+  "sb_price_type",
+  "srpvid",
+  "srepoch",
+  "ucfs",
+  "hpos",
+  "hapos",
+  "keep_landing",
+];
+
+#[cfg(feature = "shopping")]
+fn replace_booking(text: &str, report: &mut ReplaceReport) -> String {
+  remove_params(
+    text,
+    "booking",
+    is_booking_hotel,
+    |key| BOOKING_PARAMS.contains(&key),
+    report,
+  )
+}
+
+/// Whether `url` is an etsy listing, with or without a region as in
+/// `/uk/listing/<id>/<slug>`.
+#[cfg(feature = "shopping")]
+fn is_etsy_listing(url: &Url) -> bool {
+  matches!(url.host_str(), Some("etsy.com" | "www.etsy.com"))
+    && url
+      .path_segments()
+      .is_some_and(|mut segments| segments.any(|segment| segment == "listing"))
+}
+
+/// Keeps only the id and slug of etsy listings, their params are all
+/// clicks and referrals.
+#[cfg(feature = "shopping")]
+fn replace_etsy(text: &str, report: &mut ReplaceReport) -> String {
+  clean_urls(
+    text,
+    "etsy",
+    is_etsy_listing,
+    |url| url.set_query(None),
+    report,
+  )
+}
+
+/// Whether `url` is a post or deal on smzdm, as in `post.smzdm.com/p/<id>/`.
+#[cfg(feature = "cn-ecommerce")]
+fn is_smzdm_post(url: &Url) -> bool {
+  url
+    .host_str()
+    .is_some_and(|host| host == "smzdm.com" || host.ends_with(".smzdm.com"))
+    && url.path().starts_with("/p/")
+}
+
+/// Drops every param of smzdm posts, they're only `send_by`,
+/// `invite_code` and other referrals.
+#[cfg(feature = "cn-ecommerce")]
+fn clean_smzdm_link(url: &mut Url) {
+  if is_smzdm_post(url) {
+    url.set_query(None);
+    url.set_fragment(None);
+  }
+}
+
+#[cfg(feature = "cn-ecommerce")]
+fn replace_smzdm(text: &str, report: &mut ReplaceReport) -> String {
+  clean_urls(text, "smzdm", is_smzdm_post, clean_smzdm_link, report)
+}
+
+/// Whether `url` is on douban, as its movies, books and groups are each
+/// on their own subdomain.
+#[cfg(feature = "cn-social")]
+fn is_douban(url: &Url) -> bool {
+  url
+    .host_str()
+    .is_some_and(|host| host == "douban.com" || host.ends_with(".douban.com"))
+}
+
+/// Params of douban links shared from its app.
+#[cfg(feature = "cn-social")]
+fn is_douban_param(key: &str) -> bool {
+  ["_i", "dt_dapp", "from"].contains(&key)
+}
+
+#[cfg(feature = "cn-social")]
+fn replace_douban(text: &str, report: &mut ReplaceReport) -> String {
+  remove_params(text, "douban", is_douban, is_douban_param, report)
+}
+
+/// Whether `url` is a feed or an app on coolapk.
+#[cfg(feature = "cn-social")]
+fn is_coolapk_page(url: &Url) -> bool {
+  url
+    .host_str()
+    .is_some_and(|host| host == "coolapk.com" || host.ends_with(".coolapk.com"))
+    && ["/feed/", "/apk/"]
+      .iter()
+      .any(|prefix| url.path().starts_with(prefix))
+}
+
+/// Keeps only the path of coolapk feeds and apps, `shareKey` telling who
+/// shared them.
+#[cfg(feature = "cn-social")]
+fn replace_coolapk(text: &str, report: &mut ReplaceReport) -> String {
+  clean_urls(
+    text,
+    "coolapk",
+    is_coolapk_page,
+    |url| url.set_query(None),
+    report,
+  )
+}
+
+/// Whether `url` is an item on weidian.
+#[cfg(feature = "cn-ecommerce")]
+fn is_weidian_item(url: &Url) -> bool {
+  url
+    .host_str()
+    .is_some_and(|host| host == "weidian.com" || host.ends_with(".weidian.com"))
+    && url.path() == "/item.html"
+}
+
+/// Keeps only `itemID` of weidian items, dropping `wfr`, `share_relation`,
+/// `spider_token` and the like.
+#[cfg(feature = "cn-ecommerce")]
+fn clean_weidian_link(url: &mut Url) {
+  if is_weidian_item(url) {
+    url.keep_pairs_only_in(Cow::Borrowed(&["itemID"]));
+  }
+}
+
+#[cfg(feature = "cn-ecommerce")]
+fn replace_weidian(text: &str, report: &mut ReplaceReport) -> String {
+  clean_urls(text, "weidian", is_weidian_item, clean_weidian_link, report)
+}
+
+/// Whether `url` is on meituan or dianping.
+#[cfg(feature = "cn-ecommerce")]
+fn is_meituan(url: &Url) -> bool {
+  url.host_str().is_some_and(|host| {
+    ["meituan.com", "dianping.com"]
+      .iter()
+      .any(|domain| host == *domain || host.ends_with(&format!(".{domain}")))
+  })
+}
+
+/// Params of meituan and dianping shares, their tokens telling who shared
+/// them.
+#[cfg(feature = "cn-ecommerce")]
+fn is_meituan_param(key: &str) -> bool {
+  key.starts_with("utm_") || key == "cevent" || key.to_ascii_lowercase().ends_with("token")
 }
 
+#[cfg(feature = "cn-ecommerce")]
+fn replace_meituan(text: &str, report: &mut ReplaceReport) -> String {
+  remove_params(text, "meituan", is_meituan, is_meituan_param, report)
+}
+
+/// Cleans where a `lnkd.in` link led, skipping the warning page linkedin
+/// shows before leaving it.
+#[cfg(feature = "blogs")]
+fn clean_linkedin_link(url: &mut Url) {
+  if url.path() == "/safety/go" {
+    let target = url
+      .query_pairs()
+      .find(|(key, _)| key == "url")
+      .and_then(|(_, target)| Url::parse(&target).ok());
+    if let Some(target) = target {
+      *url = target;
+    }
+  }
+  url.remove_pairs_if_key(is_linkedin_param);
+}
+
+#[cfg(feature = "bilibili")]
 fn trim_bili_link(url: &mut Url) {
   const KEYS: Cow<[&str]> = Cow::Borrowed(&["p", "t"]);
   url.keep_pairs_only_in(KEYS);
+  if url.host_str() == Some("m.bilibili.com") {
+    url.set_host(Some("www.bilibili.com")).unwrap();
+  }
 }
 
-fn replace_btrack(text: &mut String) {
+#[cfg(feature = "bilibili")]
+fn replace_btrack(text: &mut String, report: &mut ReplaceReport) {
   let mut replaces = Vec::new();
   for i in BVIDEO_REGEX.find_iter(text) {
     let i = match i {
@@ -128,77 +1556,310 @@ fn replace_btrack(text: &mut String) {
       continue;
     };
     trim_bili_link(&mut url);
+    report.record("bilibili", i.as_str(), url.as_str(), false);
     replaces.push((i.range(), url.to_string()));
   }
-  for (range, str) in replaces {
-    text.replace_range(range, str.as_str());
-  }
+  *text = splice(text, replaces);
 }
 
-async fn replace_bshort(str: &str) -> Result<String> {
-  let mut new_str = str.to_string();
-  let matches: Vec<_> = BSHORT_REGEX.find_iter(str).collect();
-  for x in matches.iter() {
-    let x = match x {
-      Ok(x) => x,
-      Err(err) => {
-        error!("Failed to find_iter: {err}");
-        continue;
-      },
-    };
-    let mut url = get_redirect_url(x.as_str()).await?;
-    trim_bili_link(&mut url);
-    new_str.replace_range(x.range(), url.to_string().as_str());
+/// Short links of a site, resolved over network.
+struct ShortLinkSite {
+  name: &'static str,
+  regex: &'static Regex,
+  /// Where links should lead to, see [`resolved_as_expected`].
+  expected: Option<&'static Regex>,
+  clean: fn(&mut Url),
+  context: &'static str,
+}
+
+/// What a short link was found as.
+#[derive(Clone, Copy)]
+enum ShortLink<'a> {
+  Site(&'a ShortLinkSite),
+  Generic(&'a Shortener),
+}
+
+impl ShortLink<'_> {
+  fn name(self) -> &'static str {
+    match self {
+      ShortLink::Site(site) => site.name,
+      ShortLink::Generic(_) => "shortener",
+    }
+  }
+
+  fn context(self) -> &'static str {
+    match self {
+      ShortLink::Site(site) => site.context,
+      ShortLink::Generic(_) => "Failed to resolve short url",
+    }
+  }
+
+  fn expected(self) -> Option<&'static Regex> {
+    match self {
+      ShortLink::Site(site) => site.expected,
+      ShortLink::Generic(_) => None,
+    }
+  }
+
+  /// Cleans where the link leads, a generic one keeps only the params of
+  /// [`Shortener::keep`].
+  fn clean(self, url: &mut Url) {
+    match self {
+      ShortLink::Site(site) => (site.clean)(url),
+      ShortLink::Generic(Shortener {
+        keep: Some(keep), ..
+      }) => url.keep_pairs_only_in(Cow::Owned(keep.iter().map(String::as_str).collect())),
+      ShortLink::Generic(_) => {},
+    }
   }
-  Ok(new_str)
 }
 
-async fn replace_xiaohongshu(str: &str) -> Result<String> {
-  let mut new_str = str.to_string();
-  let matches: Vec<_> = XIAOHONGSHU_REGEX.find_iter(str).collect();
-  for x in matches.iter() {
-    let x = match x {
-      Ok(x) => x,
-      Err(err) => {
-        error!("Failed to find_iter: {err}");
-        continue;
-      },
+/// Links in `str` of `shorteners`, those of `www.` as well.
+fn generic_short_links<'a, 'b>(
+  str: &'b str,
+  shorteners: &'a [Shortener],
+) -> Vec<(ShortLink<'a>, Range<usize>, &'b str)> {
+  let mut links = Vec::new();
+  if shorteners.is_empty() {
+    return links;
+  }
+  for i in URL_REGEX.find_iter(str) {
+    let link = i
+      .as_str()
+      .trim_end_matches(['.', ',', '!', '?', ';', ':', ')']);
+    let Ok(url) = Url::from_str(link) else {
+      continue;
     };
-    let mut url = get_redirect_url(x.as_str()).await?;
-    url.set_query(None);
-    new_str.replace_range(x.range(), url.to_string().as_str());
+    let host = url.host_str().unwrap_or_default();
+    let host = host.strip_prefix("www.").unwrap_or(host);
+    if url.path().len() <= 1 {
+      continue;
+    }
+    if let Some(shortener) = shorteners.iter().find(|shortener| shortener.domain == host) {
+      links.push((
+        ShortLink::Generic(shortener),
+        i.start()..i.start() + link.len(),
+        link,
+      ));
+    }
   }
-  Ok(new_str)
+  links
+}
+
+lazy_static! {
+  static ref SHORT_LINK_SITES: Vec<ShortLinkSite> = Vec::from([
+    #[cfg(feature = "bilibili")]
+    ShortLinkSite {
+      name: "bilibili",
+      regex: &BSHORT_REGEX,
+      expected: Some(&BSHORT_TARGET_REGEX),
+      clean: trim_bili_link,
+      context: "Failed to replace short url",
+    },
+    #[cfg(feature = "xiaohongshu")]
+    ShortLinkSite {
+      name: "xiaohongshu",
+      regex: &XIAOHONGSHU_REGEX,
+      expected: Some(&XIAOHONGSHU_TARGET_REGEX),
+      clean: |url| url.set_query(None),
+      context: "Failed to replace xiaohongshu url",
+    },
+    #[cfg(feature = "twitter")]
+    ShortLinkSite {
+      name: "twitter",
+      regex: &TWITTER_SHORT_REGEX,
+      expected: None,
+      clean: |_| {},
+      context: "Failed to replace twitter short url",
+    },
+    #[cfg(feature = "media")]
+    ShortLinkSite {
+      name: "steam",
+      regex: &STEAM_SHORT_REGEX,
+      expected: None,
+      // store pages are cleaned by `replace_steam` afterwards
+      clean: |url| url.set_query(None),
+      context: "Failed to replace steam short url",
+    },
+    #[cfg(feature = "blogs")]
+    ShortLinkSite {
+      name: "substack",
+      regex: &SUBSTACK_REDIRECT_REGEX,
+      expected: None,
+      // leads anywhere, which the other replacers clean afterwards
+      clean: |url| url.remove_pairs_if_key(is_substack_param),
+      context: "Failed to unwrap substack redirect",
+    },
+    #[cfg(feature = "blogs")]
+    ShortLinkSite {
+      name: "linkedin",
+      regex: &LINKEDIN_SHORT_REGEX,
+      expected: None,
+      clean: clean_linkedin_link,
+      context: "Failed to replace linkedin short url",
+    },
+    #[cfg(feature = "media")]
+    ShortLinkSite {
+      name: "soundcloud",
+      regex: &SOUNDCLOUD_SHORT_REGEX,
+      expected: None,
+      clean: |url| url.remove_pairs_if_key(is_soundcloud_param),
+      context: "Failed to replace soundcloud short url",
+    },
+    #[cfg(feature = "shopping")]
+    ShortLinkSite {
+      name: "shein",
+      regex: &SHEIN_SHORT_REGEX,
+      expected: None,
+      clean: clean_shein_link,
+      context: "Failed to replace shein share link",
+    },
+    #[cfg(feature = "cn-ecommerce")]
+    ShortLinkSite {
+      name: "smzdm",
+      regex: &SMZDM_SHORT_REGEX,
+      expected: None,
+      clean: clean_smzdm_link,
+      context: "Failed to replace smzdm short url",
+    },
+    #[cfg(feature = "cn-social")]
+    ShortLinkSite {
+      name: "douban",
+      regex: &DOUBAN_SHORT_REGEX,
+      expected: None,
+      clean: |url| url.remove_pairs_if_key(is_douban_param),
+      context: "Failed to replace douban short url",
+    },
+    #[cfg(feature = "cn-ecommerce")]
+    ShortLinkSite {
+      name: "weidian",
+      regex: &WEIDIAN_SHORT_REGEX,
+      expected: None,
+      clean: clean_weidian_link,
+      context: "Failed to replace weidian short url",
+    },
+    #[cfg(feature = "cn-ecommerce")]
+    ShortLinkSite {
+      name: "meituan",
+      regex: &MEITUAN_SHORT_REGEX,
+      expected: None,
+      clean: |url| url.remove_pairs_if_key(is_meituan_param),
+      context: "Failed to replace meituan short url",
+    },
+  ]);
+}
+
+/// What became of a short link.
+enum Resolved {
+  Url(Url),
+  /// The shortener refused, noted with [`UNEXPANDED`].
+  Refused,
+  /// Resolving failed or timed out, the link is kept as is.
+  Failed,
 }
 
-async fn replace_twitter_short(str: &str) -> Result<String> {
-  let mut new_str = str.to_string();
-  let matches: Vec<_> = TWITTER_SHORT_REGEX.find_iter(str).collect();
-  for x in matches.iter() {
-    let x = match x {
-      Ok(x) => x,
+/// Resolves the short links of `sites` in `str` concurrently, they never
+/// overlap, and replaces them all in one pass. Links that failed resolving,
+/// as on timeouts, are left as they are.
+async fn replace_short_links(
+  str: &str,
+  sites: &[&ShortLinkSite],
+  shorteners: &[Shortener],
+  resolver: &Resolver,
+  report: &mut ReplaceReport,
+) -> Result<String> {
+  let mut links = Vec::new();
+  for site in sites {
+    for x in site.regex.find_iter(str) {
+      match x {
+        Ok(x) => links.push((ShortLink::Site(site), x.range(), x.as_str())),
+        Err(err) => error!("Failed to find_iter: {err}"),
+      }
+    }
+  }
+  links.extend(generic_short_links(str, shorteners));
+  links.sort_by_key(|(_, range, _)| range.start);
+  // links of sites come first, so a shortener they cover as well is dropped
+  links.dedup_by(|(_, next, _), (_, kept, _)| next.start < kept.end);
+  // the same link may be pasted several times, resolve it once
+  let mut unique: Vec<(ShortLink, &str)> = Vec::new();
+  let indices: Vec<_> = links
+    .iter()
+    .map(|(site, _, link)| {
+      unique
+        .iter()
+        .position(|(_, known)| known == link)
+        .unwrap_or_else(|| {
+          unique.push((*site, link));
+          unique.len() - 1
+        })
+    })
+    .collect();
+  let resolving = Instant::now();
+  let resolved = join_all(unique.iter().map(|(site, link)| {
+    resolver
+      .resolve(link)
+      .instrument(info_span!("replacer", name = site.name()))
+  }))
+  .await;
+  if !unique.is_empty() {
+    report.resolve_time = resolving.elapsed();
+  }
+  // a slow or unreachable shortener only keeps its own links as they are
+  let resolved: Vec<_> = resolved
+    .into_iter()
+    .zip(&unique)
+    .map(|(url, (site, link))| match url.context(site.context()) {
+      Ok(Some(url)) => Resolved::Url(url),
+      Ok(None) => Resolved::Refused,
       Err(err) => {
-        error!("Failed to find_iter: {err}");
+        warn!("Keeping {link} as is: {err:#}");
+        Resolved::Failed
+      },
+    })
+    .collect();
+
+  let mut replaces = Vec::new();
+  for ((site, range, link), i) in links.into_iter().zip(indices) {
+    let mut url = match &resolved[i] {
+      Resolved::Url(url) => url.clone(),
+      Resolved::Refused => {
+        report.keep_unexpanded(link, &mut replaces, range);
         continue;
       },
+      Resolved::Failed => continue,
     };
-    let url = get_redirect_url(x.as_str()).await?;
-    new_str.replace_range(x.range(), url.to_string().as_str());
+    if let Some(expected) = site.expected() {
+      if !resolved_as_expected(expected, link, &url) {
+        continue;
+      }
+    }
+    site.clean(&mut url);
+    report.record(site.name(), link, url.as_str(), true);
+    replaces.push((range, url.to_string()));
   }
-  Ok(new_str)
+  Ok(splice(str, replaces))
 }
 
-fn replace_barticle(str: &str) -> String {
-  BARTICLE_REGEX
-    .replace_all(str, "https://www.bilibili.com/read/cv$cvid")
-    .into()
+/// Whether `original` resolved to content matching `expected`, shorteners
+/// may send datacenter IPs to app install pages instead.
+fn resolved_as_expected(expected: &Regex, original: &str, url: &Url) -> bool {
+  if expected.is_match(url.as_str()).unwrap_or(false) {
+    return true;
+  }
+  warn!("{original} resolved to unexpected {url}, keeping it as is");
+  false
 }
 
-async fn get_redirect_url(url: &str) -> Result<Url> {
-  let resp = reqwest::get(url)
-    .await
-    .with_context(|| format!("Failed to get url {url}"))?;
-  Ok(resp.url().clone())
+#[cfg(feature = "bilibili")]
+fn replace_barticle(str: &str, report: &mut ReplaceReport) -> String {
+  replace_regex(
+    &BARTICLE_REGEX,
+    str,
+    "https://www.bilibili.com/read/cv$cvid",
+    "bilibili",
+    report,
+  )
 }
 
 trait RemovePairsIf {
@@ -212,8 +1873,15 @@ trait RemovePairsIf {
   where
     Self: Sized,
   {
-    self.remove_pairs_if_key(|k| !vec.contains(&k.borrow()));
+    self.remove_pairs_if_key(|k| !vec.contains(&k));
   }
+
+  /// [`remove_pairs_if_key`](Self::remove_pairs_if_key) on a fragment of
+  /// pairs, like `#utm_source=x`, keeping the other pairs as they were.
+  fn remove_fragment_pairs_if_key<P>(&mut self, predicate: P)
+  where
+    Self: Sized,
+    P: Fn(&str) -> bool;
 }
 
 impl RemovePairsIf for Url {
@@ -233,105 +1901,928 @@ impl RemovePairsIf for Url {
 
     self.set_query(match &*ser.finish() {
       "" => None,
-      query => Some(query),
+      query => Some(query),
+    });
+  }
+
+  fn remove_fragment_pairs_if_key<P>(&mut self, predicate: P)
+  where
+    Self: Sized,
+    P: Fn(&str) -> bool,
+  {
+    // anchors like `#comments` aren't pairs
+    let Some(fragment) = self.fragment().filter(|fragment| fragment.contains('=')) else {
+      return;
+    };
+    let kept = fragment
+      .split('&')
+      .filter(|pair| {
+        let key = pair.split_once('=').map_or(*pair, |(key, _)| key);
+        let key = form_urlencoded::parse(key.as_bytes())
+          .next()
+          .map(|(key, _)| key)
+          .unwrap_or_default();
+        !predicate(&key)
+      })
+      .collect::<Vec<_>>()
+      .join("&");
+    self.set_fragment(match &*kept {
+      "" => None,
+      fragment => Some(fragment),
     });
   }
 }
 
+// tests of a family of replacers only run when it's built
 #[cfg(test)]
 mod tests {
   use super::*;
 
+  /// The short link site `name`, wherever its family puts it.
+  #[cfg(any(feature = "bilibili", feature = "xiaohongshu", feature = "twitter"))]
+  fn short_link_site(name: &str) -> &'static ShortLinkSite {
+    SHORT_LINK_SITES
+      .iter()
+      .find(|site| site.name == name)
+      .unwrap()
+  }
+
   #[tokio::test]
+  #[cfg(feature = "bilibili")]
   async fn remove_all() {
     let mut text = "https://www.bilibili.com/video/BV1Hg411T7fT/?spm_id_from=333.788.recommend_more_video.1&vd_source=425ad7d352481d80617a03327da07da0".to_string();
-    replace_btrack(&mut text);
+    replace_btrack(&mut text, &mut ReplaceReport::default());
     assert_eq!("https://www.bilibili.com/video/BV1Hg411T7fT/", text);
   }
 
   #[test]
+  #[cfg(feature = "bilibili")]
   fn keep_certain_params() {
     {
       let mut text =
         "https://www.bilibili.com/video/BV114514/?t=123&p=1&spm=1.2212.22321".to_string();
-      replace_btrack(&mut text);
+      replace_btrack(&mut text, &mut ReplaceReport::default());
       assert_eq!("https://www.bilibili.com/video/BV114514/?t=123&p=1", text);
     }
     {
       let mut text = "https://www.bilibili.com/video/BV114514/?t=123&spm=1.2212.22321".to_string();
-      replace_btrack(&mut text);
+      replace_btrack(&mut text, &mut ReplaceReport::default());
       assert_eq!("https://www.bilibili.com/video/BV114514/?t=123", text);
     }
   }
 
+  #[test]
+  #[cfg(feature = "bilibili")]
+  fn mobile_bilibili() {
+    let mut text =
+      "https://m.bilibili.com/video/BV1Hg411T7fT?p=2&share_source=copy_web&vd_source=425ad7d3"
+        .to_string();
+    replace_btrack(&mut text, &mut ReplaceReport::default());
+    assert_eq!("https://www.bilibili.com/video/BV1Hg411T7fT?p=2", text);
+  }
+
+  #[test]
+  #[cfg(all(feature = "amazon", feature = "bilibili"))]
+  fn disabled_replacers() {
+    let embeds = Embeds::default();
+    let disabled = ["amazon".to_string()];
+    let rules = Rules {
+      embeds: &embeds,
+      tracking_params: &DEFAULT_TRACKING,
+      custom: &[],
+      disabled: &disabled,
+      plugins: None,
+      shorteners: &[],
+    };
+    let text = "https://www.amazon.com/Redragon-S101/dp/B00NLZUM36/ref=sr_1_1?keywords=gaming https://www.bilibili.com/video/BV114514/?spm=1";
+    let (new, report) = clean_offline_with(text, rules);
+    assert_eq!(
+      "https://www.amazon.com/Redragon-S101/dp/B00NLZUM36/ref=sr_1_1?keywords=gaming https://www.bilibili.com/video/BV114514/",
+      new
+    );
+    assert_eq!(vec![("bilibili", 1)], report.site_counts());
+  }
+
+  #[test]
+  fn custom_rules() {
+    let embeds = Embeds::default();
+    let custom = [
+      CustomRule {
+        name: "example".to_string(),
+        regex: Regex::new(r"(?P<url>https://example\.com/item/[0-9]+)\?\S*").unwrap(),
+        action: CustomAction::Replace("$url".to_string()),
+      },
+      CustomRule {
+        name: "shop".to_string(),
+        regex: Regex::new(r"https://shop\.example\.com/\S*").unwrap(),
+        action: CustomAction::Keep(vec!["id".to_string()]),
+      },
+    ];
+    let rules = Rules {
+      embeds: &embeds,
+      tracking_params: &DEFAULT_TRACKING,
+      custom: &custom,
+      disabled: &[],
+      plugins: None,
+      shorteners: &[],
+    };
+    let (new, report) = clean_offline_with(
+      "https://example.com/item/42?from=share https://shop.example.com/p?id=7&aff=me",
+      rules,
+    );
+    assert_eq!(
+      "https://example.com/item/42 https://shop.example.com/p?id=7",
+      new
+    );
+    assert_eq!(vec![("example", 1), ("shop", 1)], report.site_counts());
+  }
+
+  #[tokio::test]
+  async fn command_rules() {
+    let embeds = Embeds::default();
+    let command = |name: &str, program: &str, args: &[&str]| CustomRule {
+      name: name.to_string(),
+      regex: Regex::new(r"https://example\.com/\S*").unwrap(),
+      action: CustomAction::Command {
+        program: program.to_string(),
+        args: args.iter().map(|arg| arg.to_string()).collect(),
+        timeout: Duration::from_millis(500),
+      },
+    };
+    let custom = [
+      command("cut", "sed", &["s/?.*//"]),
+      command("slow", "sleep", &["5"]),
+    ];
+    let rules = Rules {
+      embeds: &embeds,
+      tracking_params: &DEFAULT_TRACKING,
+      custom: &custom,
+      disabled: &[],
+      plugins: None,
+      shorteners: &[],
+    };
+    let text = "see https://example.com/a?from=share";
+    assert_eq!(text, clean_offline_with(text, rules).0);
+    let (new, report) = replace_all(text, rules, &Resolver::default())
+      .await
+      .unwrap();
+    assert_eq!("see https://example.com/a", new);
+    assert_eq!(vec![("cut", 1)], report.site_counts());
+  }
+
+  #[test]
+  #[cfg(all(feature = "bilibili", feature = "cn-ecommerce"))]
+  fn report_site_counts() {
+    let mut report = ReplaceReport::default();
+    let mut text = "https://www.bilibili.com/video/BV114514/?spm=1 https://www.bilibili.com/video/BV1919810/?t=1&spm=2".to_string();
+    replace_btrack(&mut text, &mut report);
+    let text = replace_jd(
+      &format!("{text} https://item.jd.com/product/100026923531.html?utm_source=iosapp"),
+      &mut report,
+    );
+    assert_eq!(
+      "https://www.bilibili.com/video/BV114514/ https://www.bilibili.com/video/BV1919810/?t=1 https://item.jd.com/product/100026923531.html",
+      text
+    );
+    assert_eq!(vec![("bilibili", 2), ("jd", 1)], report.site_counts());
+  }
+
+  #[test]
+  #[cfg(feature = "bilibili")]
+  fn report_only_params_stripped() {
+    let mut report = ReplaceReport::default();
+    replace_btrack(
+      &mut "https://www.bilibili.com/video/BV114514/?spm=1".to_string(),
+      &mut report,
+    );
+    assert!(report.only_params_stripped());
+    report.record(
+      "bilibili",
+      "https://b23.tv/abc",
+      "https://www.bilibili.com/video/BV1/",
+      true,
+    );
+    assert!(!report.only_params_stripped());
+
+    let mut report = ReplaceReport::default();
+    report.record(
+      "twitter",
+      "https://x.com/a/status/1?s=20",
+      "https://fxtwitter.com/a/status/1",
+      false,
+    );
+    assert!(!report.only_params_stripped());
+  }
+
+  #[test]
+  #[cfg(feature = "bilibili")]
+  fn clean_offline_test() {
+    let (text, report) = clean_offline(
+      "see https://b23.tv/lBI8Ov3 and https://www.bilibili.com/video/BV114514/?spm=1.2212.22321",
+    );
+    assert_eq!(
+      "see https://b23.tv/lBI8Ov3 and https://www.bilibili.com/video/BV114514/",
+      text
+    );
+    assert_eq!(1, report.replacements.len());
+    assert!(!report.replacements[0].expanded);
+  }
+
   #[tokio::test]
+  #[cfg(feature = "bilibili")]
   async fn bshort() {
     let text = "https://b23.tv/lBI8Ov3".to_string();
-    let result = replace_bshort(&text).await.unwrap();
+    let result = replace_short_links(
+      &text,
+      &[short_link_site("bilibili")],
+      &[],
+      &Resolver::default(),
+      &mut ReplaceReport::default(),
+    )
+    .await
+    .unwrap();
     assert_eq!("https://www.bilibili.com/video/BV1se4y177g9/?t=100", result);
   }
 
+  #[tokio::test]
+  async fn duplicate_short_links() {
+    use std::sync::{
+      atomic::{AtomicUsize, Ordering},
+      Arc,
+    };
+
+    use hyper::{header::LOCATION, Body, Response, StatusCode};
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let addr = crate::resolver::serve({
+      let requests = Arc::clone(&requests);
+      move |req: hyper::Request<Body>| {
+        let resp = if req.uri().path() == "/s" {
+          requests.fetch_add(1, Ordering::Relaxed);
+          Response::builder()
+            .status(StatusCode::FOUND)
+            .header(LOCATION, "/long")
+        } else {
+          Response::builder()
+        };
+        async move { resp.body(Body::empty()).unwrap() }
+      }
+    });
+    let base = format!("http://{addr}");
+
+    let site = ShortLinkSite {
+      name: "test",
+      regex: Box::leak(Box::new(
+        Regex::new(r"http://127\.0\.0\.1:[0-9]+/s").unwrap(),
+      )),
+      expected: None,
+      clean: |_| {},
+      context: "Failed to replace test url",
+    };
+    let text = format!("{base}/s and {base}/s, again {base}/s");
+    let mut report = ReplaceReport::default();
+    let result = replace_short_links(&text, &[&site], &[], &Resolver::default(), &mut report)
+      .await
+      .unwrap();
+    assert_eq!(
+      format!("{base}/long and {base}/long, again {base}/long"),
+      result
+    );
+    assert_eq!(1, requests.load(Ordering::Relaxed));
+    assert_eq!(3, report.replacements.len());
+  }
+
+  #[tokio::test]
+  async fn short_link_timeout() {
+    use std::time::Duration;
+
+    use hyper::{Body, Response};
+    use reqwest::Client;
+
+    let addr = crate::resolver::serve(|_| async {
+      tokio::time::sleep(Duration::from_secs(5)).await;
+      Response::new(Body::empty())
+    });
+    let link = format!("http://{addr}/s");
+
+    let site = ShortLinkSite {
+      name: "test",
+      regex: Box::leak(Box::new(
+        Regex::new(r"http://127\.0\.0\.1:[0-9]+/s").unwrap(),
+      )),
+      expected: None,
+      clean: |_| {},
+      context: "Failed to replace test url",
+    };
+    let resolver = Resolver::new(Client::new(), Duration::from_millis(50), Duration::ZERO, 5);
+    let text = format!("slow {link}");
+    let mut report = ReplaceReport::default();
+    let result = replace_short_links(&text, &[&site], &[], &resolver, &mut report)
+      .await
+      .unwrap();
+    assert_eq!(text, result);
+    assert!(report.replacements.is_empty());
+  }
+
+  #[tokio::test]
+  async fn generic_shorteners() {
+    use hyper::{header::LOCATION, Body, Response, StatusCode};
+
+    let addr = crate::resolver::serve(|req: hyper::Request<Body>| async move {
+      let resp = if req.uri().path() == "/s" {
+        Response::builder()
+          .status(StatusCode::FOUND)
+          .header(LOCATION, "/long?id=1&utm_source=share")
+      } else {
+        Response::builder()
+      };
+      resp.body(Body::empty()).unwrap()
+    });
+    let base = format!("http://{addr}");
+
+    let shorteners = [Shortener {
+      domain: "127.0.0.1".to_string(),
+      keep: Some(vec!["id".to_string()]),
+    }];
+    let text = format!("{base}/s. and {base}/");
+    let mut report = ReplaceReport::default();
+    let result = replace_short_links(&text, &[], &shorteners, &Resolver::default(), &mut report)
+      .await
+      .unwrap();
+    assert_eq!(format!("{base}/long?id=1. and {base}/"), result);
+    assert_eq!("shortener", report.replacements[0].site);
+  }
+
+  #[test]
+  #[cfg(all(feature = "bilibili", feature = "xiaohongshu"))]
+  fn resolved_targets() {
+    let expected =
+      |regex: &Regex, url: &str| resolved_as_expected(regex, "short", &Url::parse(url).unwrap());
+    assert!(expected(
+      &BSHORT_TARGET_REGEX,
+      "https://www.bilibili.com/video/BV1se4y177g9/?t=100"
+    ));
+    assert!(expected(
+      &BSHORT_TARGET_REGEX,
+      "https://space.bilibili.com/21967971"
+    ));
+    assert!(!expected(
+      &BSHORT_TARGET_REGEX,
+      "https://d.bilibili.com/download_app.html"
+    ));
+    assert!(expected(
+      &XIAOHONGSHU_TARGET_REGEX,
+      "https://www.xiaohongshu.com/explore/6460b865000000000703a98b?share_from_user_hidden=true"
+    ));
+    assert!(!expected(
+      &XIAOHONGSHU_TARGET_REGEX,
+      "https://www.xiaohongshu.com/website-login/captcha?redirectPath=x"
+    ));
+  }
+
   #[test]
+  #[cfg(feature = "amazon")]
   fn amazon() {
     assert_eq!(
       "https://www.amazon.com/dp/B00NLZUM36/",
-      replace_amazon("https://www.amazon.com/Redragon-S101-Keyboard-Ergonomic-Programmable/dp/B00NLZUM36/ref=sr_1_1?keywords=gaming+keyboard&pd_rd_r=89c237af-e7f2-4af6-b9c4&pd_rd_w=0aaaD&pd_rd_wg=KZWal&pf_rd_p=112312321&pf_rd_r=1233&qid=234231231&qu=eyJxc2MiOinFzcCI6IjYuMjAifQ%3D%3D&sr=8-1"),
+      replace_amazon("https://www.amazon.com/Redragon-S101-Keyboard-Ergonomic-Programmable/dp/B00NLZUM36/ref=sr_1_1?keywords=gaming+keyboard&pd_rd_r=89c237af-e7f2-4af6-b9c4&pd_rd_w=0aaaD&pd_rd_wg=KZWal&pf_rd_p=112312321&pf_rd_r=1233&qid=234231231&qu=eyJxc2MiOinFzcCI6IjYuMjAifQ%3D%3D&sr=8-1", &mut ReplaceReport::default()),
     );
     assert_eq!(
       "https://www.amazon.co.jp/dp/B00NLZUM36/",
-      replace_amazon("https://www.amazon.co.jp/Redragon-S101-Keyboard-Ergonomic-Programmable/dp/B00NLZUM36/ref=sr_1_1?keywords=gaming+keyboard&pd_rd_r=89c237af-e7f2-4af6-b9c4&pd_rd_w=0aaaD&pd_rd_wg=KZWal&pf_rd_p=112312321&pf_rd_r=1233&qid=234231231&qu=eyJxc2MiOinFzcCI6IjYuMjAifQ%3D%3D&sr=8-1"),
+      replace_amazon("https://www.amazon.co.jp/Redragon-S101-Keyboard-Ergonomic-Programmable/dp/B00NLZUM36/ref=sr_1_1?keywords=gaming+keyboard&pd_rd_r=89c237af-e7f2-4af6-b9c4&pd_rd_w=0aaaD&pd_rd_wg=KZWal&pf_rd_p=112312321&pf_rd_r=1233&qid=234231231&qu=eyJxc2MiOinFzcCI6IjYuMjAifQ%3D%3D&sr=8-1", &mut ReplaceReport::default()),
     );
   }
 
   #[test]
+  #[cfg(feature = "amazon")]
   fn amazon_search() {
     assert_eq!(
       "https://www.amazon.com/s?k=%E4%BD%A0%E5%A5%BD%26+%2B",
-      replace_amazon_search("https://www.amazon.com/s?k=%E4%BD%A0%E5%A5%BD%26+%2B&crid=1SHSKHE0RZCED&sprefix=%E4%BD%A0%E5%A5%BD%26+%2B%2Caps%2C1307&ref=nb_sb_noss_2")
+      replace_amazon_search("https://www.amazon.com/s?k=%E4%BD%A0%E5%A5%BD%26+%2B&crid=1SHSKHE0RZCED&sprefix=%E4%BD%A0%E5%A5%BD%26+%2B%2Caps%2C1307&ref=nb_sb_noss_2", &mut ReplaceReport::default())
     )
   }
 
   #[test]
+  #[cfg(feature = "bilibili")]
   fn replace_barticle_test() {
     assert_eq!(
       "https://www.bilibili.com/read/cv19172625",
-      replace_barticle("https://www.bilibili.com/read/mobile/19172625?xxx=114514&asdfasdf=32394239ADSAD-12312aASDASD")
+      replace_barticle("https://www.bilibili.com/read/mobile/19172625?xxx=114514&asdfasdf=32394239ADSAD-12312aASDASD", &mut ReplaceReport::default())
     )
   }
 
   #[test]
+  #[cfg(feature = "bilibili")]
+  fn replace_bspace_test() {
+    assert_eq!(
+      "https://space.bilibili.com/2/",
+      replace_bspace(
+        "https://space.bilibili.com/2/?spm_id_from=333.1007.0.0",
+        &mut ReplaceReport::default()
+      )
+    );
+    assert_eq!(
+      "https://space.bilibili.com/2/video?tid=36",
+      replace_bspace(
+        "https://space.bilibili.com/2/video?tid=36&spm_id_from=333.1007.0.0&vd_source=425ad7d3",
+        &mut ReplaceReport::default()
+      )
+    )
+  }
+
+  #[test]
+  #[cfg(all(feature = "bilibili", feature = "twitter", feature = "cn-ecommerce"))]
+  fn prefilter() {
+    let plain = Prefilter::of("no links in here, just x.co and twitter");
+    assert!(!plain.any());
+    let bili = Prefilter::of("b23.tv/abc");
+    assert!(bili.may_match("bilibili"));
+    assert!(!bili.may_match("twitter"));
+    assert!(!bili.may_match("tracking"));
+    let tracking = Prefilter::of("https://example.com/?utm_source=tg");
+    assert!(tracking.may_match("tracking"));
+    assert!(!tracking.may_match("jd"));
+    assert!(plain.may_match("not built in"));
+  }
+
+  #[test]
+  fn replace_tracking_test() {
+    assert_eq!(
+      "read https://example.com/post?id=3, then https://example.org/.",
+      replace_tracking(
+        "read https://example.com/post?utm_source=tg&id=3&fbclid=IwAR0, then https://example.org/?utm_medium=social.",
+        &DEFAULT_TRACKING,
+        &mut ReplaceReport::default()
+      )
+    );
+    assert_eq!(
+      "https://example.com/?q=a%20b",
+      replace_tracking(
+        "https://example.com/?q=a%20b",
+        &DEFAULT_TRACKING,
+        &mut ReplaceReport::default()
+      )
+    );
+    assert_eq!(
+      "https://example.com/a https://example.com/b#top https://example.com/c?q=a%20b#:~:text=x",
+      replace_tracking(
+        "https://example.com/a#utm_source=tg&xd_co_f=1 https://example.com/b#top \
+         https://example.com/c?q=a%20b#:~:text=x&utm_medium=social",
+        &DEFAULT_TRACKING,
+        &mut ReplaceReport::default()
+      )
+    );
+  }
+
+  #[test]
+  #[cfg(feature = "twitter")]
   fn replace_twitter_test() {
     assert_eq!(
       "https://c.vxtwitter.com/Penny_0571/status/1587323246506528769",
       replace_twitter(
-        "https://twitter.com/Penny_0571/status/1587323246506528769?s=20&t=0Mzx3uLKTD-kygDQmaXvFq"
+        "https://twitter.com/Penny_0571/status/1587323246506528769?s=20&t=0Mzx3uLKTD-kygDQmaXvFq",
+        Some("c.vxtwitter.com"),
+        &mut ReplaceReport::default(),
+      )
+    );
+    assert_eq!(
+      "https://x.com/Penny_0571/status/1587323246506528769",
+      replace_twitter(
+        "https://twitter.com/Penny_0571/status/1587323246506528769?s=20&t=0Mzx3uLKTD-kygDQmaXvFq",
+        None,
+        &mut ReplaceReport::default(),
+      )
+    );
+    assert_eq!(
+      "https://fxtwitter.com/Penny_0571/status/1587323246506528769",
+      replace_twitter(
+        "https://x.com/Penny_0571/status/1587323246506528769?s=20&t=0Mzx3uLKTD-kygDQmaXvFq",
+        Some("fxtwitter.com"),
+        &mut ReplaceReport::default(),
+      )
+    );
+    assert_eq!(
+      "https://c.vxtwitter.com/Penny_0571/status/1587323246506528769",
+      replace_twitter(
+        "https://fixupx.com/Penny_0571/status/1587323246506528769?s=20",
+        Some("c.vxtwitter.com"),
+        &mut ReplaceReport::default(),
+      )
+    )
+  }
+
+  #[test]
+  #[cfg(feature = "tiktok")]
+  fn replace_tiktok_test() {
+    assert_eq!(
+      "https://vxtiktok.com/@scout2015/video/6718335390845095173",
+      replace_tiktok(
+        "https://www.tiktok.com/@scout2015/video/6718335390845095173?is_from_webapp=1&sender_device=pc",
+        Some("vxtiktok.com"),
+        &mut ReplaceReport::default(),
+      )
+    );
+    assert_eq!(
+      "https://www.tiktok.com/@scout2015/video/6718335390845095173",
+      replace_tiktok(
+        "https://www.tiktok.com/@scout2015/video/6718335390845095173?is_from_webapp=1&sender_device=pc",
+        None,
+        &mut ReplaceReport::default(),
+      )
+    )
+  }
+
+  #[test]
+  #[cfg(feature = "instagram")]
+  fn replace_instagram_test() {
+    assert_eq!(
+      "https://ddinstagram.com/p/CqJz1xLr9Zk/",
+      replace_instagram(
+        "https://www.instagram.com/p/CqJz1xLr9Zk/?igshid=MDJmNzVkMjY=",
+        Some("ddinstagram.com"),
+        &mut ReplaceReport::default(),
+      )
+    );
+    assert_eq!(
+      "https://www.instagram.com/reel/CqJz1xLr9Zk/",
+      replace_instagram(
+        "https://www.instagram.com/reel/CqJz1xLr9Zk/?igshid=MDJmNzVkMjY=",
+        None,
+        &mut ReplaceReport::default(),
       )
     )
   }
 
   #[test]
+  #[cfg(feature = "cn-social")]
   fn replace_weixin_test() {
     let text = "https://mp.weixin.qq.com/s?__biz=MzIzzMwNjc1NzU==&mid=2650309&idx=114514&sn=2fd9d2a3b0b544a6da&chksm=e8de3b77dfa9b2612b676b21f34a75a79994bfcd4a4#rd";
     assert_eq!(
       "https://mp.weixin.qq.com/s?__biz=MzIzzMwNjc1NzU%3D%3D&mid=2650309&idx=114514&sn=2fd9d2a3b0b544a6da#rd",
       replace_weixin(
-        text
+        text, &mut ReplaceReport::default()
       )
     )
   }
 
   #[test]
+  #[cfg(feature = "cn-ecommerce")]
   fn replace_jd_test() {
     assert_eq!(
       "https://item.m.jd.com/product/100026923531.html",
-      replace_jd("https://item.m.jd.com/product/100026923531.html?&utm_source=iosapp&utm_medium=appshare&utm_campaign=114514&utm_term=CopyURL&ad_od=share&gx=T2nEPztRx6NTRa30RpDCM")
+      replace_jd("https://item.m.jd.com/product/100026923531.html?&utm_source=iosapp&utm_medium=appshare&utm_campaign=114514&utm_term=CopyURL&ad_od=share&gx=T2nEPztRx6NTRa30RpDCM", &mut ReplaceReport::default())
+    )
+  }
+
+  #[test]
+  #[cfg(feature = "media")]
+  fn replace_steam_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://store.steampowered.com/app/620/Portal_2/ and https://store.steampowered.com/app/400/",
+      replace_steam(
+        "https://store.steampowered.com/app/620/Portal_2/?curator_clanid=123&snr=1_5_9__205&utm_source=x and store.steampowered.com/app/400?snr=1",
+        &mut report
+      )
+    );
+    assert_eq!(2, report.replacements.len());
+    let clean = "https://store.steampowered.com/app/620/Portal_2/";
+    assert_eq!(clean, replace_steam(clean, &mut ReplaceReport::default()));
+    assert!(STEAM_SHORT_REGEX.is_match("https://s.team/a/620").unwrap());
+  }
+
+  #[test]
+  #[cfg(feature = "media")]
+  fn replace_twitch_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://clips.twitch.tv/FunnyClip-abc_1 https://clips.twitch.tv/Other https://clips.twitch.tv/embed?clip=x",
+      replace_twitch_clip(
+        "https://www.twitch.tv/streamer/clip/FunnyClip-abc_1?filter=clips&range=7d&tt_medium=clips_api&tt_content=url \
+         https://m.twitch.tv/clip/Other?tt_medium=redt https://clips.twitch.tv/embed?clip=x",
+        &mut report
+      )
+    );
+    assert_eq!(2, report.replacements.len());
+    let (text, _) = clean_offline(
+      "https://www.twitch.tv/videos/123456?t=1h2m3s&tt_medium=mobile_web_share&tt_content=vod",
+    );
+    assert_eq!("https://www.twitch.tv/videos/123456?t=1h2m3s", text);
+    let (text, _) = clean_offline("vod twitch.tv/videos/123456?tt_medium=mobile_web_share&t=5m");
+    assert_eq!("vod https://twitch.tv/videos/123456?t=5m", text);
+  }
+
+  #[test]
+  #[cfg(feature = "media")]
+  fn replace_pixiv_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://www.pixiv.net/artworks/12345 https://www.pixiv.net/en/artworks/678",
+      replace_pixiv(
+        "https://www.pixiv.net/member_illust.php?mode=medium&illust_id=12345&ref=share \
+         https://pixiv.net/en/artworks/678?utm_source=share&utm_medium=android",
+        &mut report
+      )
+    );
+    assert_eq!(2, report.replacements.len());
+    let clean = "https://www.pixiv.net/artworks/12345";
+    assert_eq!(clean, replace_pixiv(clean, &mut ReplaceReport::default()));
+  }
+
+  #[test]
+  #[cfg(feature = "blogs")]
+  fn replace_medium_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://medium.com/@alice/a-post-4f3c2a1b9d8e?lang=en, https://blog.example.com/p/4f3c2a1b9d8e \
+       https://example.com/p/about?source=x",
+      replace_medium(
+        "https://medium.com/@alice/a-post-4f3c2a1b9d8e?source=friends_link&sk=abc123&lang=en, \
+         https://blog.example.com/p/4f3c2a1b9d8e?gi=5d2b1e \
+         https://example.com/p/about?source=x",
+        &mut report
+      )
+    );
+    assert_eq!(2, report.replacements.len());
+  }
+
+  #[test]
+  #[cfg(feature = "blogs")]
+  fn replace_substack_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://alice.substack.com/p/a-post https://example.com/p/x?r=1",
+      replace_substack(
+        "https://alice.substack.com/p/a-post?r=2abc&utm_campaign=post&utm_medium=web&triedRedirect=true \
+         https://example.com/p/x?r=1",
+        &mut report
+      )
+    );
+    assert_eq!(1, report.replacements.len());
+    assert!(SUBSTACK_REDIRECT_REGEX
+      .is_match("https://substack.com/redirect/2/eyJlIjoiaHR0cHM6Ly9leGFtcGxlLmNvbSJ9.abc-_?j=eyJ1")
+      .unwrap());
+  }
+
+  #[test]
+  #[cfg(feature = "blogs")]
+  fn replace_linkedin_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://www.linkedin.com/posts/alice_rust-activity-7100-abcd https://www.linkedin.com/jobs/view/1?trk=x",
+      replace_linkedin(
+        "https://www.linkedin.com/posts/alice_rust-activity-7100-abcd?trk=public_post&lipi=urn%3Ali \
+         https://www.linkedin.com/jobs/view/1?trk=x",
+        &mut report
+      )
+    );
+    assert_eq!(1, report.replacements.len());
+
+    let mut url = Url::parse(
+      "https://www.linkedin.com/safety/go?url=https%3A%2F%2Fexample.com%2Fa%3Fid%3D1&trk=flagship",
     )
+    .unwrap();
+    clean_linkedin_link(&mut url);
+    assert_eq!("https://example.com/a?id=1", url.as_str());
+  }
+
+  #[test]
+  #[cfg(feature = "media")]
+  fn replace_imdb_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://www.imdb.com/title/tt0111161/ https://m.imdb.com/name/nm0000151/ \
+       https://www.imdb.com/title/tt0903747/episodes/?season=2 https://www.imdb.com/chart/top/?ref_=nv",
+      replace_imdb(
+        "https://www.imdb.com/title/tt0111161/?ref_=nv_sr_srsg_0 https://m.imdb.com/name/nm0000151/ref_=tt_cl_t_1 \
+         https://www.imdb.com/title/tt0903747/episodes/?season=2&ref_=tt_eps https://www.imdb.com/chart/top/?ref_=nv",
+        &mut report
+      )
+    );
+    assert_eq!(3, report.replacements.len());
+  }
+
+  #[test]
+  #[cfg(feature = "media")]
+  fn replace_soundcloud_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://soundcloud.com/artist/track?in=artist%2Fsets%2Falbum https://example.com/?si=1",
+      replace_soundcloud(
+        "https://soundcloud.com/artist/track?in=artist/sets/album&si=5f2c&utm_source=clipboard&utm_medium=text&ref=clipboard \
+         https://example.com/?si=1",
+        &mut report
+      )
+    );
+    assert_eq!(1, report.replacements.len());
+    assert!(SOUNDCLOUD_SHORT_REGEX
+      .is_match("https://on.soundcloud.com/AbC12")
+      .unwrap());
+  }
+
+  #[test]
+  #[cfg(feature = "media")]
+  fn replace_apple_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://music.apple.com/us/album/song/1440857781?i=1440857796 \
+       https://apps.apple.com/us/app/example/id284882215 https://www.apple.com/?ls=1",
+      replace_apple(
+        "https://music.apple.com/us/album/song/1440857781?i=1440857796&ls=1&at=1000lHKX&ct=share \
+         https://apps.apple.com/us/app/example/id284882215?itsct=apps_box&itscg=30200 https://www.apple.com/?ls=1",
+        &mut report
+      )
+    );
+    assert_eq!(2, report.replacements.len());
+  }
+
+  #[test]
+  #[cfg(feature = "media")]
+  fn replace_netflix_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://www.netflix.com/title/80100172 https://www.netflix.com/jp/watch/80117401?t=120 \
+       https://www.netflix.com/browse?s=a",
+      replace_netflix(
+        "https://www.netflix.com/title/80100172?s=a&trkid=13747225 \
+         https://www.netflix.com/jp/watch/80117401?t=120&tctx=0%2C0%2Cabc https://www.netflix.com/browse?s=a",
+        &mut report
+      )
+    );
+    assert_eq!(2, report.replacements.len());
+  }
+
+  #[test]
+  #[cfg(feature = "shopping")]
+  fn replace_shein_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://us.shein.com/Floral-Dress-p-12345678.html https://us.shein.com/Floral-Dress-p-12345678.html \
+       https://us.shein.com/Dresses-c-1727.html?page=2",
+      replace_shein(
+        "https://us.shein.com/Floral-Dress-p-12345678.html?url_from=GM_share&share_from=app&aff_id=1 \
+         https://api-shein.shein.com/h5/sharejump/appjump?link=https%3A%2F%2Fus.shein.com%2FFloral-Dress-p-12345678.html%3Furl_from%3Dx&lang=us \
+         https://us.shein.com/Dresses-c-1727.html?page=2&share_from=app",
+        &mut report
+      )
+    );
+    assert_eq!(3, report.replacements.len());
+    assert!(SHEIN_SHORT_REGEX
+      .is_match("https://shein.top/ab12cd")
+      .unwrap());
+  }
+
+  #[test]
+  #[cfg(feature = "shopping")]
+  fn replace_booking_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://www.booking.com/hotel/jp/example.html?checkin=2024-05-01&checkout=2024-05-03&group_adults=2&no_rooms=1 \
+       https://www.booking.com/searchresults.html?aid=304142",
+      replace_booking(
+        "https://www.booking.com/hotel/jp/example.html?aid=304142&label=gen173nr-1FCAEoggI46AdIM1gEaHWIAQGYATG4ARfIAQzYAQHoAQH4AQKIAgGoAgO4AqKr&sid=0d1f\
+         &checkin=2024-05-01&checkout=2024-05-03&dist=0&group_adults=2&no_rooms=1&sb_price_type=total&srpvid=9a1b \
+         https://www.booking.com/searchresults.html?aid=304142",
+        &mut report
+      )
+    );
+    assert_eq!(1, report.replacements.len());
+  }
+
+  #[test]
+  #[cfg(feature = "shopping")]
+  fn replace_etsy_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://www.etsy.com/listing/1234567890/handmade-mug https://www.etsy.com/uk/listing/42/a-print \
+       https://www.etsy.com/shop/Example?ref=simple-shop-header-name",
+      replace_etsy(
+        "https://www.etsy.com/listing/1234567890/handmade-mug?click_key=ab12&click_sum=3f4e&ref=hp_rv-1&ga_order=most_relevant&organic_search_click=1 \
+         https://www.etsy.com/uk/listing/42/a-print?ref=shop_home_active_1 \
+         https://www.etsy.com/shop/Example?ref=simple-shop-header-name",
+        &mut report
+      )
+    );
+    assert_eq!(2, report.replacements.len());
+  }
+
+  #[test]
+  #[cfg(feature = "cn-ecommerce")]
+  fn replace_smzdm_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://post.smzdm.com/p/a5kl3x2p/ https://www.smzdm.com/p/123456789/ https://www.smzdm.com/fenlei/?send_by=1",
+      replace_smzdm(
+        "https://post.smzdm.com/p/a5kl3x2p/?send_by=3716913905&invite_code=zdmfhmc&zhhtm_source=share \
+         https://www.smzdm.com/p/123456789/?send_by=1#hfeeds https://www.smzdm.com/fenlei/?send_by=1",
+        &mut report
+      )
+    );
+    assert_eq!(2, report.replacements.len());
+    assert!(SMZDM_SHORT_REGEX.is_match("https://zdm.cn/aBc12").unwrap());
+  }
+
+  #[test]
+  #[cfg(feature = "cn-social")]
+  fn replace_douban_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://movie.douban.com/subject/1292052/ https://www.douban.com/group/topic/123/?start=100",
+      replace_douban(
+        "https://movie.douban.com/subject/1292052/?_i=7421529abcdefg&dt_dapp=1&from=singlemessage \
+         https://www.douban.com/group/topic/123/?start=100&_i=1",
+        &mut report
+      )
+    );
+    assert_eq!(2, report.replacements.len());
+    assert!(DOUBAN_SHORT_REGEX
+      .is_match("https://douc.cc/2aBcD3")
+      .unwrap());
+  }
+
+  #[test]
+  #[cfg(feature = "cn-social")]
+  fn replace_coolapk_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://www.coolapk.com/feed/51234567 https://www.coolapk.com/apk/com.coolapk.market \
+       https://www.coolapk.com/u/123?shareKey=x",
+      replace_coolapk(
+        "https://www.coolapk.com/feed/51234567?shareKey=ZjU1Y2FhNGM2MWJjNjUwNzlkNDk~&shareUid=1&shareFrom=com.coolapk.market_13.0.1 \
+         https://www.coolapk.com/apk/com.coolapk.market?s=1 https://www.coolapk.com/u/123?shareKey=x",
+        &mut report
+      )
+    );
+    assert_eq!(2, report.replacements.len());
+  }
+
+  #[test]
+  #[cfg(feature = "cn-ecommerce")]
+  fn replace_weidian_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://weidian.com/item.html?itemID=4412345678 https://shop123.v.weidian.com/?wfr=c",
+      replace_weidian(
+        "https://weidian.com/item.html?itemID=4412345678&wfr=wx&share_relation=abc_1_2&spider_token=1f2e&distributorId=9 \
+         https://shop123.v.weidian.com/?wfr=c",
+        &mut report
+      )
+    );
+    assert_eq!(1, report.replacements.len());
+    assert!(WEIDIAN_SHORT_REGEX
+      .is_match("https://k.weidian.com/a1B2c3")
+      .unwrap());
+  }
+
+  #[test]
+  #[cfg(feature = "cn-ecommerce")]
+  fn replace_meituan_test() {
+    let mut report = ReplaceReport::default();
+    assert_eq!(
+      "https://m.dianping.com/shopshare/k5Xa1?msource=Appshare2021 https://www.meituan.com/meishi/123456/",
+      replace_meituan(
+        "https://m.dianping.com/shopshare/k5Xa1?msource=Appshare2021&utm_source=shop_share&cevent=xxx&shareToken=ab12 \
+         https://www.meituan.com/meishi/123456/?token=cd34",
+        &mut report
+      )
+    );
+    assert_eq!(2, report.replacements.len());
+    assert!(MEITUAN_SHORT_REGEX
+      .is_match("https://dpurl.cn/aBc12")
+      .unwrap());
+    assert!(MEITUAN_SHORT_REGEX
+      .is_match("https://i.meituan.com/s/aBc12")
+      .unwrap());
+  }
+
+  #[test]
+  #[cfg(feature = "xiaohongshu")]
+  fn xiaohongshu_notes() {
+    let (text, report) = clean_offline(
+      "https://www.xiaohongshu.com/explore/6460b865000000000703a98b?xsec_token=ABcd12-_=&xsec_source=pc_share&app_platform=ios \
+       https://www.xiaohongshu.com/user/profile/5a1b?xsec_token=x",
+    );
+    assert_eq!(
+      "https://www.xiaohongshu.com/explore/6460b865000000000703a98b \
+       https://www.xiaohongshu.com/user/profile/5a1b?xsec_token=x",
+      text
+    );
+    assert_eq!(1, report.replacements.len());
   }
 
   #[tokio::test]
+  #[cfg(feature = "xiaohongshu")]
   async fn replace_xiaohongshu_test() {
     let text = "http://xhslink.com/8yMk6p".to_string();
-    let result = replace_xiaohongshu(&text).await.unwrap();
+    let result = replace_short_links(
+      &text,
+      &[short_link_site("xiaohongshu")],
+      &[],
+      &Resolver::default(),
+      &mut ReplaceReport::default(),
+    )
+    .await
+    .unwrap();
     assert_eq!(
       "https://www.xiaohongshu.com/explore/6460b865000000000703a98b",
       result
@@ -339,9 +2830,18 @@ mod tests {
   }
 
   #[tokio::test]
+  #[cfg(feature = "twitter")]
   async fn replace_twitter_short_test() {
     let text = "https://t.co/jqpeEFD8Nz".to_string();
-    let result = replace_twitter_short(&text).await.unwrap();
+    let result = replace_short_links(
+      &text,
+      &[short_link_site("twitter")],
+      &[],
+      &Resolver::default(),
+      &mut ReplaceReport::default(),
+    )
+    .await
+    .unwrap();
     assert_eq!("https://yazawazi.moe/", result)
   }
 }
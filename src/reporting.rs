@@ -0,0 +1,72 @@
+//! Error reporting to Sentry, no-ops without the `sentry` feature.
+
+use std::future::Future;
+
+use frankenstein::Update;
+use fuckburl_bot::config::Sentry;
+#[cfg(not(feature = "sentry"))]
+use log::warn;
+
+/// Keeps the client alive, events are flushed when dropped.
+pub(crate) struct Guard {
+  #[cfg(feature = "sentry")]
+  _client: Option<sentry::ClientInitGuard>,
+}
+
+pub(crate) fn init(config: &Sentry) -> Guard {
+  #[cfg(feature = "sentry")]
+  {
+    let client = config.dsn.as_deref().map(|dsn| {
+      let mut options = sentry::ClientOptions::new();
+      options.release = sentry::release_name!();
+      options.environment = config.environment.clone().map(Into::into);
+      sentry::init((dsn, options))
+    });
+    Guard { _client: client }
+  }
+  #[cfg(not(feature = "sentry"))]
+  {
+    if config.dsn.is_some() {
+      warn!("sentry.dsn is set, but the bot is built without the `sentry` feature");
+    }
+    Guard {}
+  }
+}
+
+/// Runs `process(update)` with the update attached to errors and panics
+/// reported from it.
+pub(crate) async fn with_update<F, Fut>(update: Update, process: F) -> Fut::Output
+where
+  F: FnOnce(Update) -> Fut,
+  Fut: Future,
+{
+  #[cfg(feature = "sentry")]
+  {
+    use sentry::{Hub, SentryFutureExt};
+
+    let hub = std::sync::Arc::new(Hub::new_from_top(Hub::current()));
+    if hub.client().is_some() {
+      let content = serde_json::to_value(&update)
+        .ok()
+        .and_then(|value| match value {
+          serde_json::Value::Object(map) => Some(map.into_iter().collect()),
+          _ => None,
+        })
+        .unwrap_or_default();
+      hub.configure_scope(|scope| {
+        scope.set_tag("update_id", update.update_id);
+        scope.set_context("update", sentry::protocol::Context::Other(content));
+      });
+    }
+    process(update).bind_hub(hub).await
+  }
+  #[cfg(not(feature = "sentry"))]
+  process(update).await
+}
+
+pub(crate) fn capture(err: &anyhow::Error) {
+  #[cfg(feature = "sentry")]
+  sentry::integrations::anyhow::capture_anyhow(err);
+  #[cfg(not(feature = "sentry"))]
+  let _ = err;
+}
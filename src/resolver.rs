@@ -0,0 +1,620 @@
+//! Resolving short links over network.
+
+use std::{
+  collections::HashMap,
+  sync::{Mutex, RwLock},
+  time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use reqwest::{
+  header::{COOKIE, LOCATION, REFERER},
+  redirect::Policy,
+  Client, ClientBuilder, RequestBuilder, Response, StatusCode, Url,
+};
+use tokio::time::Instant;
+
+use crate::{
+  cache::{Cache, CacheLimits},
+  config::{ResolverHeaders, Shortener},
+};
+
+/// Most of a page read when resolving with `GET`.
+pub const BODY_CAP: usize = 64 * 1024;
+/// How long a resolved short link is reused before resolving it again.
+const RESOLVED_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Refusals of a host within [`BREAKER_WINDOW`] that stop resolving its
+/// links for [`BREAKER_COOLDOWN`].
+const BREAKER_REFUSALS: usize = 3;
+const BREAKER_WINDOW: Duration = Duration::from_secs(60);
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(5 * 60);
+/// Hosts whose redirects are followed past the original shortener.
+const SHORTENERS: &[&str] = &[
+  "b23.tv",
+  "xhslink.com",
+  "t.co",
+  "bit.ly",
+  "tinyurl.com",
+  "vm.tiktok.com",
+  "vt.tiktok.com",
+  "goo.gl",
+];
+
+/// Follows short links to where they redirect, keeping at least
+/// `host_interval` between requests to the same host so a message full of
+/// short links doesn't get the bot rate limited.
+///
+/// Redirects are followed by hand, so `client` shouldn't follow them, see
+/// [`client_builder`]. Following stops at the first host that is neither
+/// the original one nor another shortener.
+#[derive(Debug)]
+pub struct Resolver {
+  client: Client,
+  timeout: RwLock<Duration>,
+  host_interval: RwLock<Duration>,
+  max_redirects: RwLock<usize>,
+  /// Hosts of `resolver.shorteners`, followed like [`SHORTENERS`].
+  shorteners: RwLock<Vec<String>>,
+  headers: RwLock<ResolverHeaders>,
+  /// `resolver.private`, dropping configured cookies and referers.
+  private: RwLock<bool>,
+  /// Earliest time of the next request to each host, pruned once passed.
+  next: Mutex<HashMap<String, Instant>>,
+  /// Times each host answered 403 or 429.
+  rejections: Mutex<HashMap<String, u64>>,
+  /// Recent refusals of each host, see [`BREAKER_REFUSALS`].
+  breakers: Mutex<HashMap<String, Breaker>>,
+  resolved: Cache<String, Url>,
+}
+
+/// Refusals of a host, and until when it isn't asked again.
+#[derive(Debug, Default)]
+struct Breaker {
+  refusals: Vec<Instant>,
+  open_until: Option<Instant>,
+}
+
+impl Default for Resolver {
+  fn default() -> Self {
+    Self::new(
+      client_builder().build().unwrap(),
+      Duration::from_secs(10),
+      Duration::from_millis(200),
+      crate::config::Resolver::default().max_redirects,
+    )
+  }
+}
+
+impl Resolver {
+  pub fn new(
+    client: Client,
+    timeout: Duration,
+    host_interval: Duration,
+    max_redirects: usize,
+  ) -> Self {
+    Self {
+      client,
+      timeout: RwLock::new(timeout),
+      host_interval: RwLock::new(host_interval),
+      max_redirects: RwLock::new(max_redirects),
+      shorteners: RwLock::new(Vec::new()),
+      headers: RwLock::new(ResolverHeaders::default()),
+      private: RwLock::new(false),
+      next: Mutex::new(HashMap::new()),
+      rejections: Mutex::new(HashMap::new()),
+      breakers: Mutex::new(HashMap::new()),
+      resolved: Cache::new(
+        "resolved short links",
+        crate::config::Cache::default().limits(),
+        Some(RESOLVED_TTL),
+        |short, url| short.len() + url.as_str().len(),
+      ),
+    }
+  }
+
+  /// Applies reloaded `timeouts.resolve`, `resolver.host-interval` and
+  /// `resolver.max-redirects`.
+  pub fn set_options(&self, timeout: Duration, host_interval: Duration, max_redirects: usize) {
+    *self.timeout.write().unwrap() = timeout;
+    *self.host_interval.write().unwrap() = host_interval;
+    *self.max_redirects.write().unwrap() = max_redirects;
+  }
+
+  /// Applies reloaded `resolver.shorteners`.
+  pub fn set_shorteners(&self, shorteners: &[Shortener]) {
+    *self.shorteners.write().unwrap() = shorteners
+      .iter()
+      .map(|shortener| shortener.domain.clone())
+      .collect();
+  }
+
+  /// Applies reloaded `[resolver.headers]` and `resolver.private`, whose
+  /// client is only built on start, see [`private_client_builder`].
+  pub fn set_headers(&self, headers: &ResolverHeaders, private: bool) {
+    *self.headers.write().unwrap() = headers.clone();
+    *self.private.write().unwrap() = private;
+  }
+
+  /// Whether redirects to `host` are followed, as it's another shortener
+  /// rather than the site itself.
+  fn is_shortener(&self, host: &str) -> bool {
+    let configured = self.shorteners.read().unwrap();
+    SHORTENERS
+      .iter()
+      .copied()
+      .chain(configured.iter().map(String::as_str))
+      .any(|shortener| host == shortener || host.ends_with(&format!(".{shortener}")))
+  }
+
+  /// Returns when a request to `host` may be sent, reserving the slot.
+  fn reserve(&self, host: &str, now: Instant) -> Instant {
+    let interval = *self.host_interval.read().unwrap();
+    let mut next = self.next.lock().unwrap();
+    next.retain(|_, at| *at > now);
+    let slot = next.get(host).map_or(now, |at| (*at).max(now));
+    next.insert(host.to_string(), slot + interval);
+    slot
+  }
+
+  /// Applies reloaded `[cache]` limits.
+  pub fn set_cache_limits(&self, limits: CacheLimits) {
+    self.resolved.set_limits(limits);
+  }
+
+  /// Short links resolved recently, reused until they expire.
+  pub fn cache(&self) -> &Cache<String, Url> {
+    &self.resolved
+  }
+
+  /// Whether `host` refused too often lately to be asked again.
+  fn is_open(&self, host: &str, now: Instant) -> bool {
+    let breakers = self.breakers.lock().unwrap();
+    breakers
+      .get(host)
+      .and_then(|breaker| breaker.open_until)
+      .is_some_and(|until| now < until)
+  }
+
+  /// Notes a refusal of `host`, opening its breaker after
+  /// [`BREAKER_REFUSALS`] within [`BREAKER_WINDOW`].
+  fn trip(&self, host: &str, now: Instant) {
+    let mut breakers = self.breakers.lock().unwrap();
+    let breaker = breakers.entry(host.to_string()).or_default();
+    breaker
+      .refusals
+      .retain(|at| now.duration_since(*at) < BREAKER_WINDOW);
+    breaker.refusals.push(now);
+    if breaker.refusals.len() >= BREAKER_REFUSALS {
+      warn!(
+        "{host} refused {} times within {}, leaving its links for {}",
+        breaker.refusals.len(),
+        humantime::format_duration(BREAKER_WINDOW),
+        humantime::format_duration(BREAKER_COOLDOWN)
+      );
+      breaker.refusals.clear();
+      breaker.open_until = Some(now + BREAKER_COOLDOWN);
+    }
+  }
+
+  /// Forgets hosts whose next slot passed and expired short links, returns
+  /// how many hosts are left.
+  pub fn prune(&self) -> usize {
+    self.resolved.prune();
+    let now = Instant::now();
+    self.breakers.lock().unwrap().retain(|_, breaker| {
+      breaker
+        .refusals
+        .retain(|at| now.duration_since(*at) < BREAKER_WINDOW);
+      !breaker.refusals.is_empty() || breaker.open_until.is_some_and(|until| now < until)
+    });
+    let mut next = self.next.lock().unwrap();
+    next.retain(|_, at| *at > now);
+    next.len()
+  }
+
+  /// Hosts that refused resolving and how often, most often first.
+  pub fn rejections(&self) -> Vec<(String, u64)> {
+    let mut rejections: Vec<_> = self
+      .rejections
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|(host, count)| (host.clone(), *count))
+      .collect();
+    rejections.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    rejections
+  }
+
+  /// Waits for the slot of `host`, then sends `request`.
+  async fn send(&self, host: &str, request: RequestBuilder) -> reqwest::Result<Response> {
+    let slot = self.reserve(host, Instant::now());
+    tokio::time::sleep_until(slot).await;
+    let timeout = *self.timeout.read().unwrap();
+    let mut headers = self.headers.read().unwrap().of(host);
+    if *self.private.read().unwrap() {
+      headers.remove(COOKIE);
+      headers.remove(REFERER);
+    }
+    request.headers(headers).timeout(timeout).send().await
+  }
+
+  /// Returns the url `url` redirects to, or `None` when the
+  /// shortener refused with 403 or 429, as with challenge pages and rate
+  /// limits, or refused too often lately.
+  ///
+  /// Tries `HEAD` first, falling back to `GET` for shorteners that don't
+  /// support it, which reads at most [`BODY_CAP`] of the page. Resolved links are
+  /// cached for a day.
+  #[tracing::instrument(skip(self))]
+  pub async fn resolve(&self, url: &str) -> Result<Option<Url>> {
+    if let Some(resolved) = self.resolved.get(&url.to_string()) {
+      return Ok(Some(resolved));
+    }
+    let resolved = self.fetch(url).await?;
+    if let Some(resolved) = &resolved {
+      self.resolved.insert(url.to_string(), resolved.clone());
+    }
+    Ok(resolved)
+  }
+
+  async fn fetch(&self, url: &str) -> Result<Option<Url>> {
+    let mut current = Url::parse(url)
+      .or_else(|_| Url::parse(&format!("https://{url}")))
+      .with_context(|| format!("Failed to parse url {url}"))?;
+    let origin = current.host_str().unwrap_or_default().to_string();
+    let max_redirects = *self.max_redirects.read().unwrap();
+    let mut visited = vec![current.clone()];
+    // once a shortener rejected HEAD, keep using GET along its redirects
+    let mut get = false;
+    loop {
+      let location = match self.hop(&current, &mut get).await? {
+        Hop::Refused => return Ok(None),
+        Hop::Done => return Ok(Some(current)),
+        Hop::Redirect(location) => location,
+      };
+      let next = current
+        .join(&location)
+        .with_context(|| format!("Bad redirect from {current} to {location}"))?;
+      if visited.contains(&next) {
+        bail!("Redirect loop from {url} at {next}");
+      }
+      if visited.len() > max_redirects {
+        bail!("{url} redirected more than {max_redirects} times");
+      }
+      let host = next.host_str().unwrap_or_default();
+      // the rest is up to the site, which may bounce through login walls
+      if host != origin && !self.is_shortener(host) {
+        return Ok(Some(next));
+      }
+      visited.push(next.clone());
+      current = next;
+    }
+  }
+
+  /// Requests `url` once, with `HEAD` unless `get`, which is set when `HEAD`
+  /// isn't supported or fails to send.
+  async fn hop(&self, url: &Url, get: &mut bool) -> Result<Hop> {
+    let host = url.host_str().unwrap_or_default().to_string();
+    if self.is_open(&host, Instant::now()) {
+      debug!("{host} refused too often lately, leaving {url}");
+      return Ok(Hop::Refused);
+    }
+    if !*get {
+      match self.send(&host, self.client.head(url.clone())).await {
+        Ok(resp) if is_refusal(resp.status()) => return Ok(self.refused(host, url, resp.status())),
+        Ok(resp)
+          if resp.status() == StatusCode::METHOD_NOT_ALLOWED
+            || resp.status() == StatusCode::NOT_IMPLEMENTED =>
+        {
+          debug!("HEAD {url} answered {}, trying GET", resp.status())
+        },
+        Ok(resp) => return Ok(Hop::of(&resp)),
+        Err(err) => debug!("HEAD {url} failed, trying GET: {err}"),
+      }
+      *get = true;
+    }
+    let mut resp = self
+      .send(&host, self.client.get(url.clone()))
+      .await
+      .with_context(|| format!("Failed to get url {url}"))?;
+    let status = resp.status();
+    if is_refusal(status) {
+      return Ok(self.refused(host, url, status));
+    }
+    let hop = Hop::of(&resp);
+    // drain small pages so the connection is reused, give up on larger ones
+    let mut read = 0;
+    while read < BODY_CAP {
+      match resp.chunk().await {
+        Ok(Some(chunk)) => read += chunk.len(),
+        _ => break,
+      }
+    }
+    Ok(hop)
+  }
+
+  /// Counts `host` refusing to resolve `url` with `status`.
+  fn refused(&self, host: String, url: &Url, status: StatusCode) -> Hop {
+    warn!("{host} refused resolving {url} with {status}");
+    self.trip(&host, Instant::now());
+    *self.rejections.lock().unwrap().entry(host).or_default() += 1;
+    Hop::Refused
+  }
+}
+
+/// Whether `status` refuses the bot, as challenge pages and rate limits do,
+/// so there's no point in asking again.
+fn is_refusal(status: StatusCode) -> bool {
+  status == StatusCode::FORBIDDEN || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Outcome of a single request while resolving.
+enum Hop {
+  Redirect(String),
+  Done,
+  Refused,
+}
+
+impl Hop {
+  fn of(resp: &Response) -> Self {
+    match resp.headers().get(LOCATION) {
+      Some(location) if resp.status().is_redirection() => {
+        Hop::Redirect(String::from_utf8_lossy(location.as_bytes()).into_owned())
+      },
+      _ => Hop::Done,
+    }
+  }
+}
+
+/// A client that leaves redirects to [`Resolver`].
+pub fn client_builder() -> ClientBuilder {
+  Client::builder().redirect(Policy::none())
+}
+
+/// [`client_builder`] for `resolver.private`, which speaks only HTTP/1.1 as
+/// the settings of HTTP/2 tell clients apart, and opens a new connection
+/// for each request. Cookies are never stored either way.
+pub fn private_client_builder() -> ClientBuilder {
+  client_builder()
+    .referer(false)
+    .http1_only()
+    .pool_max_idle_per_host(0)
+}
+
+/// Serves `handler` on a free local port for tests, returns its address.
+#[cfg(test)]
+pub(crate) fn serve<F, Fut>(handler: F) -> std::net::SocketAddr
+where
+  F: Fn(hyper::Request<hyper::Body>) -> Fut + Clone + Send + Sync + 'static,
+  Fut: std::future::Future<Output = hyper::Response<hyper::Body>> + Send + 'static,
+{
+  use std::convert::Infallible;
+
+  use hyper::{
+    service::{make_service_fn, service_fn},
+    Server,
+  };
+
+  let make = make_service_fn(move |_| {
+    let handler = handler.clone();
+    async move {
+      Ok::<_, Infallible>(service_fn(move |req| {
+        let resp = handler(req);
+        async move { Ok::<_, Infallible>(resp.await) }
+      }))
+    }
+  });
+  let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+  let addr = server.local_addr();
+  tokio::spawn(server);
+  addr
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+  };
+
+  use hyper::{header::LOCATION, Body, Method, Request, Response, StatusCode};
+  use reqwest::Client;
+  use tokio::time::Instant;
+
+  use super::{client_builder, serve, Resolver};
+  use crate::config::ResolverHeaders;
+
+  #[test]
+  fn host_interval() {
+    let interval = Duration::from_millis(200);
+    let resolver = Resolver::new(Client::new(), Duration::from_secs(10), interval, 5);
+    let now = Instant::now();
+    assert_eq!(now, resolver.reserve("b23.tv", now));
+    assert_eq!(now + interval, resolver.reserve("b23.tv", now));
+    assert_eq!(now + interval * 2, resolver.reserve("b23.tv", now));
+    assert_eq!(now, resolver.reserve("t.co", now));
+
+    let later = now + Duration::from_secs(1);
+    assert_eq!(later, resolver.reserve("b23.tv", later));
+    assert_eq!(1, resolver.next.lock().unwrap().len());
+  }
+
+  #[tokio::test]
+  async fn rejected() {
+    let requests = Arc::new(Mutex::new(0));
+    let addr = serve({
+      let requests = Arc::clone(&requests);
+      move |_| {
+        *requests.lock().unwrap() += 1;
+        async {
+          Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .body(Body::empty())
+            .unwrap()
+        }
+      }
+    });
+    let url = format!("http://{addr}/abc");
+
+    let resolver = Resolver::default();
+    assert_eq!(None, resolver.resolve(&url).await.unwrap());
+    assert_eq!(vec![("127.0.0.1".to_string(), 1)], resolver.rejections());
+    // refusing HEAD isn't asked again with GET
+    assert_eq!(1, *requests.lock().unwrap());
+
+    // the host is left alone after refusing a few times
+    for _ in 1..super::BREAKER_REFUSALS + 2 {
+      assert_eq!(None, resolver.resolve(&url).await.unwrap());
+    }
+    assert_eq!(super::BREAKER_REFUSALS, *requests.lock().unwrap());
+  }
+
+  #[test]
+  fn breaker() {
+    let resolver = Resolver::default();
+    let now = Instant::now();
+    for i in 0..super::BREAKER_REFUSALS as u32 - 1 {
+      resolver.trip("b23.tv", now + super::BREAKER_WINDOW * i);
+    }
+    // spread out refusals don't count
+    let later = now + super::BREAKER_WINDOW * super::BREAKER_REFUSALS as u32;
+    assert!(!resolver.is_open("b23.tv", later));
+    for _ in 0..super::BREAKER_REFUSALS {
+      resolver.trip("b23.tv", later);
+    }
+    assert!(resolver.is_open("b23.tv", later));
+    assert!(!resolver.is_open("t.co", later));
+    assert!(!resolver.is_open("b23.tv", later + super::BREAKER_COOLDOWN));
+  }
+
+  #[tokio::test]
+  async fn head_then_get() {
+    let methods = Arc::new(Mutex::new(Vec::new()));
+    let addr = serve({
+      let methods = Arc::clone(&methods);
+      move |req: Request<Body>| {
+        methods
+          .lock()
+          .unwrap()
+          .push(format!("{} {}", req.method(), req.uri().path()));
+        let resp = match (req.method(), req.uri().path()) {
+          (&Method::HEAD, "/head") => Response::builder()
+            .status(StatusCode::FOUND)
+            .header(LOCATION, "/video"),
+          (&Method::HEAD, "/get") => Response::builder().status(StatusCode::METHOD_NOT_ALLOWED),
+          (_, "/get") => Response::builder()
+            .status(StatusCode::FOUND)
+            .header(LOCATION, "/video"),
+          _ => Response::builder(),
+        };
+        async move { resp.body(Body::from("page")).unwrap() }
+      }
+    });
+    let base = format!("http://{addr}");
+
+    let resolver = Resolver::new(
+      client_builder().build().unwrap(),
+      Duration::from_secs(10),
+      Duration::ZERO,
+      5,
+    );
+    for path in ["/head", "/get"] {
+      let url = resolver
+        .resolve(&format!("{base}{path}"))
+        .await
+        .unwrap()
+        .unwrap();
+      assert_eq!("/video", url.path());
+    }
+    assert_eq!(
+      vec![
+        "HEAD /head",
+        "HEAD /video",
+        "HEAD /get",
+        "GET /get",
+        "GET /video"
+      ],
+      *methods.lock().unwrap()
+    );
+
+    resolver.resolve(&format!("{base}/head")).await.unwrap();
+    assert_eq!(5, methods.lock().unwrap().len());
+    assert_eq!(1, resolver.cache().stats().hits);
+  }
+
+  #[tokio::test]
+  async fn redirects() {
+    let addr = serve(|req: Request<Body>| {
+      let path = req.uri().path().to_string();
+      let location = match path.as_str() {
+        "/away" => "https://www.bilibili.com/video/BV1se4y177g9/".to_string(),
+        "/a" => "/b".to_string(),
+        "/b" => "/a".to_string(),
+        deep => format!("{deep}x"),
+      };
+      async move {
+        Response::builder()
+          .status(StatusCode::FOUND)
+          .header(LOCATION, location)
+          .body(Body::empty())
+          .unwrap()
+      }
+    });
+    let base = format!("http://{addr}");
+
+    let resolver = Resolver::new(
+      client_builder().build().unwrap(),
+      Duration::from_secs(10),
+      Duration::ZERO,
+      5,
+    );
+    // bilibili itself isn't requested
+    let url = resolver.resolve(&format!("{base}/away")).await.unwrap();
+    assert_eq!(
+      "https://www.bilibili.com/video/BV1se4y177g9/",
+      url.unwrap().as_str()
+    );
+    let err = resolver.resolve(&format!("{base}/a")).await.unwrap_err();
+    assert!(err.to_string().contains("loop"), "{err}");
+    let err = resolver.resolve(&format!("{base}/x")).await.unwrap_err();
+    assert!(err.to_string().contains("more than 5"), "{err}");
+  }
+
+  #[tokio::test]
+  async fn headers() {
+    let addr = serve(|req: Request<Body>| {
+      let agent = req.headers()["user-agent"].to_str().unwrap().to_string();
+      let cookie = req.headers().contains_key("cookie");
+      async move {
+        Response::builder()
+          .status(StatusCode::FOUND)
+          .header(
+            LOCATION,
+            format!("https://example.com/{agent}?cookie={cookie}"),
+          )
+          .body(Body::empty())
+          .unwrap()
+      }
+    });
+    let base = format!("http://{addr}");
+
+    let resolver = Resolver::default();
+    let headers: ResolverHeaders = toml::from_str(
+      "user-agent = \"bot\"\ncookie = \"a=1\"\n[\"127.0.0.1\"]\nuser-agent = \"local\"\n",
+    )
+    .unwrap();
+    resolver.set_headers(&headers, false);
+    let url = resolver.resolve(&format!("{base}/s")).await.unwrap();
+    assert_eq!(
+      "https://example.com/local?cookie=true",
+      url.unwrap().as_str()
+    );
+    resolver.set_headers(&headers, true);
+    let url = resolver.resolve(&format!("{base}/private")).await.unwrap();
+    assert_eq!(
+      "https://example.com/local?cookie=false",
+      url.unwrap().as_str()
+    );
+  }
+}
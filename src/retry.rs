@@ -0,0 +1,167 @@
+//! Retrying telegram calls that failed for transient reasons, with
+//! exponential backoff.
+
+use std::{
+  future::Future,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use frankenstein::Error;
+use fuckburl_bot::config::Retry;
+use log::warn;
+
+/// How long to wait before retrying a call failing with `err`, `None` if it
+/// would fail again, like for missing permissions.
+fn retry_after(err: &Error) -> Option<Option<Duration>> {
+  match err {
+    // transport failures are mapped to 500 too
+    Error::Http(err) if err.code == 429 || err.code >= 500 => Some(None),
+    Error::Api(resp) if resp.error_code == 429 => Some(
+      resp
+        .parameters
+        .as_ref()
+        .and_then(|params| params.retry_after)
+        .map(|secs| Duration::from_secs(secs.into())),
+    ),
+    Error::Api(resp) if resp.error_code >= 500 => Some(None),
+    _ => None,
+  }
+}
+
+/// Delay after the `attempt`th failed try, counting from 1, before jitter.
+fn backoff(config: &Retry, attempt: u32) -> Duration {
+  config
+    .base_delay
+    .saturating_mul(2u32.saturating_pow(attempt - 1))
+    .min(config.max_delay)
+}
+
+/// Up to a quarter of `delay`, so calls failing together don't retry
+/// together.
+fn jitter(delay: Duration) -> Duration {
+  let nanos = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|since| since.subsec_nanos())
+    .unwrap_or_default();
+  delay.mul_f64(f64::from(nanos % 1000) / 4000.0)
+}
+
+/// Calls `call` until it succeeds, fails for good or runs out of
+/// `config.attempts`, logging retries of `what`.
+pub(crate) async fn with_retry<T, F, Fut>(
+  config: &Retry,
+  what: &str,
+  mut call: F,
+) -> Result<T, Error>
+where
+  F: FnMut() -> Fut,
+  Fut: Future<Output = Result<T, Error>>,
+{
+  let mut attempt = 1;
+  loop {
+    let err = match call().await {
+      Ok(resp) => return Ok(resp),
+      Err(err) => err,
+    };
+    if attempt >= config.attempts {
+      return Err(err);
+    }
+    let delay = match retry_after(&err) {
+      None => return Err(err),
+      Some(Some(after)) if after > config.max_delay => return Err(err),
+      Some(Some(after)) => after,
+      Some(None) => {
+        let delay = backoff(config, attempt);
+        delay + jitter(delay)
+      },
+    };
+    warn!("Failed to {what} ({err}), retrying in {delay:?}");
+    tokio::time::sleep(delay).await;
+    attempt += 1;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{cell::Cell, time::Duration};
+
+  use frankenstein::{Error, ErrorResponse, HttpError, ResponseParameters};
+  use fuckburl_bot::config::Retry;
+
+  use super::{backoff, retry_after, with_retry};
+
+  fn api_error(error_code: u64, retry_after: Option<u16>) -> Error {
+    Error::Api(ErrorResponse {
+      ok: false,
+      description: "error".to_string(),
+      error_code,
+      parameters: Some(ResponseParameters {
+        migrate_to_chat_id: None,
+        retry_after,
+      }),
+    })
+  }
+
+  #[test]
+  fn transient() {
+    let network = Error::Http(HttpError {
+      code: 500,
+      message: "connection reset".to_string(),
+    });
+    assert_eq!(Some(None), retry_after(&network));
+    assert_eq!(
+      Some(Some(Duration::from_secs(3))),
+      retry_after(&api_error(429, Some(3)))
+    );
+    assert_eq!(Some(None), retry_after(&api_error(502, None)));
+    assert_eq!(None, retry_after(&api_error(403, None)));
+    assert_eq!(None, retry_after(&api_error(400, None)));
+  }
+
+  #[test]
+  fn delays() {
+    let config = Retry {
+      attempts: 5,
+      base_delay: Duration::from_millis(500),
+      max_delay: Duration::from_secs(3),
+    };
+    let delays: Vec<_> = (1..5).map(|attempt| backoff(&config, attempt)).collect();
+    assert_eq!(
+      vec![
+        Duration::from_millis(500),
+        Duration::from_secs(1),
+        Duration::from_secs(2),
+        Duration::from_secs(3),
+      ],
+      delays
+    );
+  }
+
+  #[tokio::test]
+  async fn retries() {
+    let config = Retry {
+      base_delay: Duration::ZERO,
+      ..Default::default()
+    };
+    let calls = Cell::new(0);
+    let result = with_retry(&config, "test", || async {
+      calls.set(calls.get() + 1);
+      if calls.get() < 3 {
+        Err(api_error(500, None))
+      } else {
+        Ok(calls.get())
+      }
+    })
+    .await;
+    assert_eq!(3, result.unwrap());
+
+    calls.set(0);
+    let result: Result<(), _> = with_retry(&config, "test", || async {
+      calls.set(calls.get() + 1);
+      Err(api_error(403, None))
+    })
+    .await;
+    assert!(result.is_err());
+    assert_eq!(1, calls.get());
+  }
+}
@@ -0,0 +1,103 @@
+//! Chats the bot received updates from, for finding chat ids.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use frankenstein::{Chat, ChatType};
+
+/// Oldest chats are forgotten past this.
+const CAPACITY: usize = 200;
+
+#[derive(Debug, Clone)]
+pub(crate) struct SeenChat {
+  pub id: i64,
+  pub title: String,
+  pub username: Option<String>,
+  pub kind: &'static str,
+  /// Unix time of the last update.
+  pub last: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct SeenChats {
+  chats: Mutex<HashMap<i64, SeenChat>>,
+}
+
+pub(crate) fn chat_kind(chat: &Chat) -> &'static str {
+  match chat.type_field {
+    ChatType::Private => "private",
+    ChatType::Group => "group",
+    ChatType::Supergroup => "supergroup",
+    ChatType::Channel => "channel",
+  }
+}
+
+pub(crate) fn chat_title(chat: &Chat) -> String {
+  chat
+    .title
+    .clone()
+    .or_else(|| chat.username.clone())
+    .or_else(|| chat.first_name.clone())
+    .unwrap_or_else(|| "unknown".to_string())
+}
+
+impl SeenChats {
+  pub fn record(&self, chat: &Chat, date: u64) {
+    let mut chats = self.chats.lock().unwrap();
+    if chats.len() >= CAPACITY && !chats.contains_key(&chat.id) {
+      if let Some(oldest) = chats
+        .values()
+        .min_by_key(|seen| seen.last)
+        .map(|seen| seen.id)
+      {
+        chats.remove(&oldest);
+      }
+    }
+    chats.insert(
+      chat.id,
+      SeenChat {
+        id: chat.id,
+        title: chat_title(chat),
+        username: chat.username.clone(),
+        kind: chat_kind(chat),
+        last: date,
+      },
+    );
+  }
+
+  /// Returns every chat, most recent first.
+  pub fn list(&self) -> Vec<SeenChat> {
+    let mut chats: Vec<_> = self.chats.lock().unwrap().values().cloned().collect();
+    chats.sort_by_key(|seen| std::cmp::Reverse(seen.last));
+    chats
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use frankenstein::{Chat, ChatType};
+
+  use super::{SeenChats, CAPACITY};
+
+  fn chat(id: i64) -> Chat {
+    Chat::builder()
+      .id(id)
+      .type_field(ChatType::Supergroup)
+      .title(format!("group {id}"))
+      .build()
+  }
+
+  #[test]
+  fn evicts_oldest() {
+    let seen = SeenChats::default();
+    for id in 0..CAPACITY as i64 {
+      seen.record(&chat(-id), id as u64 + 10);
+    }
+    seen.record(&chat(0), 1000);
+    seen.record(&chat(-1000), 1001);
+    let list = seen.list();
+    assert_eq!(list.len(), CAPACITY);
+    assert_eq!(list[0].id, -1000);
+    assert_eq!(list[1].id, 0);
+    assert!(!list.iter().any(|seen| seen.id == -1));
+  }
+}
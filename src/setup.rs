@@ -0,0 +1,177 @@
+//! Interactive first-run setup.
+
+use std::{
+  fs,
+  io::{self, BufRead, Write},
+  path::Path,
+  time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use frankenstein::{
+  AllowedUpdate, AsyncApi, AsyncTelegramApi, Chat, ChatType, GetUpdatesParams, UpdateContent,
+};
+use reqwest::{Client, Proxy};
+
+use crate::seen::{chat_kind, chat_title};
+
+/// How long chats are collected from incoming updates.
+const LISTEN: Duration = Duration::from_secs(60);
+
+fn prompt(question: &str) -> Result<String> {
+  print!("{question}");
+  io::stdout().flush()?;
+  let mut line = String::new();
+  if io::stdin().lock().read_line(&mut line)? == 0 {
+    bail!("Setup aborted");
+  }
+  Ok(line.trim().to_string())
+}
+
+/// Asks a yes/no question, empty answer is `default`.
+pub(crate) fn confirm(question: &str, default: bool) -> Result<bool> {
+  let hint = if default { "[Y/n]" } else { "[y/N]" };
+  let answer = prompt(&format!("{question} {hint} "))?;
+  Ok(match answer.to_lowercase().as_str() {
+    "" => default,
+    "y" | "yes" => true,
+    _ => false,
+  })
+}
+
+fn describe(chat: &Chat) -> String {
+  format!("{} ({}, {})", chat_title(chat), chat_kind(chat), chat.id)
+}
+
+/// Asks for the token, verifies it, lets the user pick chats from incoming
+/// updates, then writes the config to `path`.
+pub(crate) async fn run(path: &Path) -> Result<()> {
+  if path.exists() && !confirm(&format!("Overwrite {}?", path.to_string_lossy()), false)? {
+    bail!("Setup aborted");
+  }
+
+  let token = prompt("Telegram token, get from @BotFather: ")?;
+  let proxy = prompt("Proxy, e.g. http://localhost:7899 (empty for none): ")?;
+  let mut client = Client::builder();
+  if !proxy.is_empty() {
+    client =
+      client.proxy(Proxy::all(&proxy).with_context(|| format!("Invalid proxy \"{proxy}\""))?);
+  }
+  let api = AsyncApi::builder()
+    .api_url(format!("{}{}", frankenstein::BASE_API_URL, token))
+    .client(client.build()?)
+    .build();
+  let me = api
+    .get_me()
+    .await
+    .context("Failed to get bot info, is the token valid?")?;
+  println!(
+    "Logged in as @{}",
+    me.result.username.unwrap_or(me.result.first_name)
+  );
+
+  println!(
+    "Add the bot to your groups and send a message there, listening for {}s...",
+    LISTEN.as_secs()
+  );
+  let mut chats: Vec<Chat> = Vec::new();
+  let mut offset = 0;
+  let start = Instant::now();
+  while start.elapsed() < LISTEN {
+    let params = GetUpdatesParams::builder()
+      .allowed_updates(vec![AllowedUpdate::Message, AllowedUpdate::MyChatMember])
+      .offset(offset)
+      .timeout(10u32)
+      .build();
+    let updates = api
+      .get_updates(&params)
+      .await
+      .context("Failed to get updates")?;
+    for update in updates.result {
+      offset = update.update_id + 1;
+      let chat = match update.content {
+        UpdateContent::Message(msg) => *msg.chat,
+        UpdateContent::MyChatMember(member) => member.chat,
+        _ => continue,
+      };
+      if chat.type_field == ChatType::Private || chats.iter().any(|c| c.id == chat.id) {
+        continue;
+      }
+      println!("  [{}] {}", chats.len() + 1, describe(&chat));
+      chats.push(chat);
+    }
+  }
+
+  let mut enabled = Vec::new();
+  if chats.is_empty() {
+    println!("No chats seen, add them to enabled-chats later.");
+  } else {
+    let answer = prompt("Enable which chats? Numbers separated by comma, empty for all: ")?;
+    if answer.is_empty() {
+      enabled = chats.iter().map(|chat| chat.id.to_string()).collect();
+    } else {
+      for i in answer.split(',') {
+        let chat = i
+          .trim()
+          .parse::<usize>()
+          .ok()
+          .and_then(|i| chats.get(i.wrapping_sub(1)))
+          .with_context(|| format!("Invalid choice \"{}\"", i.trim()))?;
+        enabled.push(chat.id.to_string());
+      }
+    }
+  }
+
+  let config = render(&token, &proxy, &enabled);
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent)
+      .with_context(|| format!("Failed to create folder: {}", parent.to_string_lossy()))?;
+  }
+  fs::write(path, config)
+    .with_context(|| format!("Failed to write config to: {}", path.to_string_lossy()))?;
+  println!("Config written to {}", path.to_string_lossy());
+  Ok(())
+}
+
+/// Fills the example config with the answers.
+fn render(token: &str, proxy: &str, enabled: &[String]) -> String {
+  let chats: Vec<String> = enabled.iter().map(|id| format!("\"{id}\"")).collect();
+  let mut config = String::new();
+  // only the top-level proxy, `[resolver]` has one of its own
+  let mut proxied = proxy.is_empty();
+  for line in crate::DEFAULT_CONFIG.lines() {
+    if line.starts_with("telegram-token = ") {
+      config.push_str(&format!("telegram-token = \"{token}\""));
+    } else if line.starts_with("enabled-chats = ") {
+      config.push_str(&format!("enabled-chats = [{}]", chats.join(", ")));
+    } else if line.starts_with("# proxy = ") && !proxied {
+      config.push_str(&format!("proxy = \"{proxy}\""));
+      proxied = true;
+    } else {
+      config.push_str(line);
+    }
+    config.push('\n');
+  }
+  config
+}
+
+#[cfg(test)]
+mod tests {
+  use fuckburl_bot::config::Config;
+
+  use super::render;
+
+  #[test]
+  fn rendered_config_parses() {
+    let text = render(
+      "123:abc",
+      "socks5://127.0.0.1:1080",
+      &["-100123".to_string()],
+    );
+    let (config, issues) = Config::from_toml(&text).unwrap();
+    assert!(issues.is_empty());
+    assert_eq!(&*config.telegram_token, "123:abc");
+    assert_eq!(config.enabled_chats, vec!["-100123".to_string()]);
+    assert_eq!(config.proxy.as_deref(), Some("socks5://127.0.0.1:1080"));
+  }
+}
@@ -0,0 +1,131 @@
+//! Spam scoring, escalating from cleaning to reporting or deleting.
+
+use std::{
+  collections::{HashMap, VecDeque},
+  sync::Mutex,
+  time::Duration,
+};
+
+use frankenstein::Message;
+use fuckburl_bot::{
+  ads::{spam_signals, SpamSignals},
+  config::Spam,
+};
+
+/// Pairs of chat and user tracked before the stale ones are dropped.
+const MAX_TRACKED: usize = 10_000;
+
+/// What to do with a message beyond cleaning it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Escalation {
+  Clean,
+  /// Clean, and ping the chat admins.
+  Report(u32),
+  /// Delete without reposting.
+  Delete(u32),
+}
+
+/// Recent messages with links, by chat and user.
+#[derive(Debug, Default)]
+pub(crate) struct Posts {
+  posts: Mutex<HashMap<(i64, u64), VecDeque<u64>>>,
+}
+
+impl Posts {
+  /// Records a message with links by `user` in `chat` at `date`, returns how
+  /// many earlier ones are within `window`.
+  pub fn record(&self, chat: i64, user: u64, date: u64, window: Duration) -> usize {
+    let since = date.saturating_sub(window.as_secs());
+    let mut posts = self.posts.lock().unwrap();
+    if posts.len() >= MAX_TRACKED {
+      drop(posts);
+      self.prune(date, window);
+      posts = self.posts.lock().unwrap();
+    }
+    let dates = posts.entry((chat, user)).or_default();
+    while dates.front().is_some_and(|first| *first < since) {
+      dates.pop_front();
+    }
+    let repeats = dates.len();
+    dates.push_back(date);
+    repeats
+  }
+
+  /// Forgets users without messages since `now` minus `window`, returns how
+  /// many are left.
+  pub fn prune(&self, now: u64, window: Duration) -> usize {
+    let since = now.saturating_sub(window.as_secs());
+    let mut posts = self.posts.lock().unwrap();
+    posts.retain(|_, dates| dates.back().is_some_and(|last| *last >= since));
+    posts.len()
+  }
+
+  /// Scores `msg` with `text` and returns its escalation, recording it for
+  /// repeats if it has links. Nothing is recorded while `[spam]` is disabled.
+  pub fn escalation(&self, config: &Spam, msg: &Message, text: &str, trial: bool) -> Escalation {
+    if config.report_score.is_none() && config.delete_score.is_none() {
+      return Escalation::Clean;
+    }
+    let signals = spam_signals(text);
+    let repeats = match &msg.from {
+      Some(from) if signals.links > 0 => {
+        self.record(msg.chat.id, from.id, msg.date, config.repeat_window)
+      },
+      _ => 0,
+    };
+    escalation(config, score(&signals, repeats), trial)
+  }
+}
+
+/// One point per affiliate param and earlier message, two per link of a spam
+/// shortener.
+pub(crate) fn score(signals: &SpamSignals, repeats: usize) -> u32 {
+  (signals.affiliate_params + signals.spam_shorteners * 2 + repeats) as u32
+}
+
+/// Escalation of a message scoring `score`, never deleting in `trial` chats,
+/// which only get replies.
+pub(crate) fn escalation(config: &Spam, score: u32, trial: bool) -> Escalation {
+  let reached = |threshold: Option<u32>| threshold.is_some_and(|threshold| score >= threshold);
+  if reached(config.delete_score) && !trial {
+    Escalation::Delete(score)
+  } else if reached(config.report_score) || reached(config.delete_score) {
+    Escalation::Report(score)
+  } else {
+    Escalation::Clean
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use fuckburl_bot::config::Spam;
+
+  use super::{escalation, Escalation, Posts};
+
+  #[test]
+  fn repeats() {
+    let posts = Posts::default();
+    let window = Duration::from_secs(60);
+    assert_eq!(0, posts.record(-1, 1, 100, window));
+    assert_eq!(1, posts.record(-1, 1, 130, window));
+    assert_eq!(0, posts.record(-1, 2, 130, window));
+    assert_eq!(1, posts.record(-1, 1, 190, window));
+    assert_eq!(1, posts.prune(191, window));
+  }
+
+  #[test]
+  fn thresholds() {
+    let config = Spam {
+      report_score: Some(3),
+      delete_score: Some(5),
+      ..Default::default()
+    };
+    assert_eq!(Escalation::Clean, escalation(&config, 2, false));
+    assert_eq!(Escalation::Report(3), escalation(&config, 3, false));
+    assert_eq!(Escalation::Delete(5), escalation(&config, 5, false));
+    assert_eq!(Escalation::Report(5), escalation(&config, 5, true));
+    assert_eq!(Escalation::Clean, escalation(&Spam::default(), 9, false));
+  }
+}
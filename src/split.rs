@@ -0,0 +1,236 @@
+//! Splitting HTML and MarkdownV2 messages longer than telegram allows.
+
+use fuckburl_bot::config::Markup;
+
+/// Most UTF-16 units in the text of a message.
+pub(crate) const MESSAGE_LIMIT: usize = 4096;
+
+/// Where a chunk may end, by preference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Break {
+  /// Inside a word, only when there's nothing better.
+  Any,
+  Space,
+  Newline,
+}
+
+/// Splits `text` in `markup` into chunks of at most `limit` UTF-16 units,
+/// preferring line breaks, then spaces, and never breaking inside a tag, an
+/// escape or an element.
+///
+/// Counting markup as text keeps chunks within the limit however telegram
+/// parses them.
+pub(crate) fn split(text: &str, limit: usize, markup: Markup) -> Vec<String> {
+  let mut chunks = Vec::new();
+  let mut rest = text.trim();
+  while rest.encode_utf16().count() > limit {
+    let end = chunk_end(rest, limit, markup);
+    let (chunk, tail) = rest.split_at(end);
+    chunks.push(chunk.trim_end().to_string());
+    rest = tail.trim_start();
+  }
+  if !rest.is_empty() || chunks.is_empty() {
+    chunks.push(rest.to_string());
+  }
+  chunks
+}
+
+/// Tags, escapes and elements of HTML seen so far.
+#[derive(Default)]
+struct Html {
+  in_tag: bool,
+  in_escape: bool,
+  depth: usize,
+}
+
+impl Html {
+  /// Takes `c`, followed by `next`, and tells if it's still inside something.
+  fn step(&mut self, c: char, next: Option<char>) -> bool {
+    match c {
+      '<' => {
+        self.in_tag = true;
+        match next {
+          Some('/') => self.depth = self.depth.saturating_sub(1),
+          _ => self.depth += 1,
+        }
+      },
+      '>' if self.in_tag => self.in_tag = false,
+      '&' if !self.in_tag => self.in_escape = true,
+      ';' if self.in_escape => self.in_escape = false,
+      _ => {},
+    }
+    self.in_tag || self.in_escape || self.depth > 0
+  }
+}
+
+/// Where in a MarkdownV2 link the text is.
+#[derive(Default, PartialEq, Eq)]
+enum Link {
+  #[default]
+  Outside,
+  Label,
+  Url,
+}
+
+/// Escapes, links and open style markers of MarkdownV2 seen so far.
+#[derive(Default)]
+struct Markdown {
+  escaped: bool,
+  /// The second character of a marker is still to come.
+  pending: bool,
+  link: Link,
+  open: Vec<&'static str>,
+}
+
+impl Markdown {
+  /// Takes `c`, followed by `next`, and tells if it's still inside something.
+  fn step(&mut self, c: char, next: Option<char>) -> bool {
+    if self.pending {
+      self.pending = false;
+    } else if self.escaped {
+      self.escaped = false;
+    } else if c == '\\' {
+      self.escaped = true;
+    } else if self.open.last() == Some(&"`") {
+      // nothing but escapes and the closing backtick count in code
+      if c == '`' {
+        self.open.pop();
+      }
+    } else {
+      match (c, next) {
+        ('[', _) if self.link == Link::Outside => self.link = Link::Label,
+        (']', Some('(')) if self.link == Link::Label => {
+          self.link = Link::Url;
+          self.pending = true;
+        },
+        (')', _) if self.link == Link::Url => self.link = Link::Outside,
+        _ if self.link == Link::Url => {},
+        ('_', Some('_')) => self.toggle("__"),
+        ('|', Some('|')) => self.toggle("||"),
+        ('_', _) => self.toggle("_"),
+        ('*', _) => self.toggle("*"),
+        ('~', _) => self.toggle("~"),
+        ('`', _) => self.open.push("`"),
+        _ => {},
+      }
+    }
+    self.escaped || self.pending || self.link != Link::Outside || !self.open.is_empty()
+  }
+
+  /// Opens `marker`, or closes it if it's the last one open.
+  fn toggle(&mut self, marker: &'static str) {
+    if self.open.last() == Some(&marker) {
+      self.open.pop();
+    } else {
+      self.open.push(marker);
+    }
+    self.pending = marker.len() > 1;
+  }
+}
+
+/// Byte offset in `text` to end its first chunk at.
+fn chunk_end(text: &str, limit: usize, markup: Markup) -> usize {
+  let mut best: Option<(Break, usize)> = None;
+  let mut units = 0;
+  let mut html = Html::default();
+  let mut markdown = Markdown::default();
+  let mut prev = '\0';
+  let mut chars = text.char_indices().peekable();
+  while let Some((i, c)) = chars.next() {
+    units += c.len_utf16();
+    if units > limit {
+      break;
+    }
+    let next = chars.peek().map(|&(_, c)| c);
+    let inside = match markup {
+      Markup::Html => html.step(c, next),
+      Markup::MarkdownV2 => markdown.step(c, next),
+    };
+    let end = i + c.len_utf8();
+    if inside {
+      prev = c;
+      continue;
+    }
+    let kind = match c {
+      '\n' => Break::Newline,
+      ' ' => Break::Space,
+      // right after a closing tag or escape
+      '>' | ';' if markup == Markup::Html && prev != ' ' => Break::Space,
+      _ => Break::Any,
+    };
+    if best.is_none_or(|(best, _)| kind >= best) {
+      best = Some((kind, end));
+    }
+    prev = c;
+  }
+  match best {
+    Some((_, end)) => end,
+    // an element longer than the limit, cut it at the limit
+    None => text
+      .char_indices()
+      .scan(0, |units, (i, c)| {
+        *units += c.len_utf16();
+        Some((*units, i))
+      })
+      .find(|(units, _)| *units > limit)
+      .map_or(text.len(), |(_, i)| i),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use fuckburl_bot::config::Markup;
+
+  use super::split;
+
+  fn split_html(text: &str, limit: usize) -> Vec<String> {
+    split(text, limit, Markup::Html)
+  }
+
+  #[test]
+  fn short() {
+    assert_eq!(vec!["hi".to_string()], split_html("hi", 10));
+    assert_eq!(vec![String::new()], split_html("", 10));
+  }
+
+  #[test]
+  fn prefers_lines_and_keeps_markup() {
+    assert_eq!(
+      vec!["one two", "three four"],
+      split_html("one two\nthree four", 12)
+    );
+    assert_eq!(vec!["aaa bbb", "ccc"], split_html("aaa bbb ccc", 8));
+    assert_eq!(
+      vec!["a", "<a href=\"x\">b c</a>", "d&amp;e"],
+      split_html("a <a href=\"x\">b c</a> d&amp;e", 20)
+    );
+  }
+
+  #[test]
+  fn long_words() {
+    let chunks = split_html(&"x".repeat(25), 10);
+    assert_eq!(
+      vec![10, 10, 5],
+      chunks.iter().map(String::len).collect::<Vec<_>>()
+    );
+    let chunks = split_html(&"链接".repeat(6), 5);
+    assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 5));
+    assert_eq!("链接".repeat(6), chunks.concat());
+  }
+
+  #[test]
+  fn markdown() {
+    let split_markdown = |text, limit| split(text, limit, Markup::MarkdownV2);
+    assert_eq!(
+      vec!["a", "[b c](https://x.y/\\)z)", "d\\.e"],
+      split_markdown("a [b c](https://x.y/\\)z) d\\.e", 24)
+    );
+    assert_eq!(
+      vec!["_one two_", "__three__", "||four||"],
+      split_markdown("_one two_ __three__ ||four||", 12)
+    );
+    assert_eq!(vec!["`a_b c`", "d"], split_markdown("`a_b c` d", 8));
+    // never right after a backslash
+    assert_eq!(vec!["ab", "\\ c"], split_markdown("ab\\ c", 3));
+  }
+}
@@ -0,0 +1,276 @@
+//! Counters since start, reported by `/stats`.
+
+use std::{
+  collections::{HashMap, VecDeque},
+  fmt::Write,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+  },
+  time::Duration,
+};
+
+use log::info;
+
+use crate::health::now;
+
+/// Why a message with dirty links was left alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Missed {
+  /// The chat isn't enabled.
+  NotEnabled,
+  /// Sending or deleting was refused by telegram.
+  NoPermission,
+}
+
+/// Every this many missed messages of a chat, one is logged.
+const SAMPLE_EVERY: u64 = 50;
+
+/// Latest durations of each stage kept for percentiles.
+const LATENCY_SAMPLES: usize = 1000;
+
+/// Part of handling a message that's timed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stage {
+  /// Waiting for a free slot after being received.
+  Queue,
+  /// Matching and rewriting links, without resolving.
+  Regex,
+  Resolve,
+  Send,
+  Delete,
+  /// From being received to the cleaned message being done.
+  Total,
+}
+
+impl Stage {
+  const ALL: [Stage; 6] = [
+    Stage::Queue,
+    Stage::Regex,
+    Stage::Resolve,
+    Stage::Send,
+    Stage::Delete,
+    Stage::Total,
+  ];
+
+  fn name(self) -> &'static str {
+    match self {
+      Stage::Queue => "queue",
+      Stage::Regex => "regex",
+      Stage::Resolve => "resolve",
+      Stage::Send => "send",
+      Stage::Delete => "delete",
+      Stage::Total => "total",
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct Timings {
+  count: u64,
+  max: Duration,
+  /// The latest [`LATENCY_SAMPLES`], oldest first.
+  samples: VecDeque<Duration>,
+}
+
+impl Timings {
+  fn record(&mut self, took: Duration) {
+    self.count += 1;
+    self.max = self.max.max(took);
+    if self.samples.len() == LATENCY_SAMPLES {
+      self.samples.pop_front();
+    }
+    self.samples.push_back(took);
+  }
+
+  /// 50th and 95th percentiles of the latest samples.
+  fn percentiles(&self) -> Option<(Duration, Duration)> {
+    let mut sorted: Vec<_> = self.samples.iter().copied().collect();
+    sorted.sort();
+    let at = |p: usize| sorted[(sorted.len() * p / 100).min(sorted.len() - 1)];
+    (!sorted.is_empty()).then(|| (at(50), at(95)))
+  }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+  cleaned: AtomicU64,
+  /// Unix time of the last cleaned message, 0 before the first.
+  last_cleaned: AtomicU64,
+  updates: AtomicU64,
+  /// Missed messages by chat, not enabled and no permission.
+  missed: Mutex<HashMap<i64, [u64; 2]>>,
+  /// By [`Stage`].
+  latency: Mutex<[Timings; 6]>,
+}
+
+impl Stats {
+  pub fn cleaned(&self) {
+    self.cleaned.fetch_add(1, Ordering::Relaxed);
+    self.last_cleaned.store(now(), Ordering::Relaxed);
+  }
+
+  pub fn update(&self) {
+    self.updates.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn updates(&self) -> u64 {
+    self.updates.load(Ordering::Relaxed)
+  }
+
+  pub fn last_cleaned(&self) -> Option<u64> {
+    Some(self.last_cleaned.load(Ordering::Relaxed)).filter(|at| *at != 0)
+  }
+
+  /// Uptime since `start`, updates and when polling and cleaning last
+  /// happened, as of `now`.
+  pub fn render_activity(&self, start: u64, now: u64, last_poll: Option<u64>) -> String {
+    let ago = |at: Option<u64>| match at {
+      Some(at) => format!("{} ago", format_secs(now.saturating_sub(at))),
+      None => "never".to_string(),
+    };
+    format!(
+      "Uptime: {}\nUpdates since start: {}\nLast getUpdates: {}\nLast cleaned: {}",
+      format_secs(now.saturating_sub(start)),
+      self.updates(),
+      ago(last_poll),
+      ago(self.last_cleaned())
+    )
+  }
+
+  pub fn latency(&self, stage: Stage, took: Duration) {
+    self.latency.lock().unwrap()[stage as usize].record(took);
+  }
+
+  /// p50, p95 and max of every stage timed at least once.
+  pub fn render_latency(&self) -> String {
+    let latency = self.latency.lock().unwrap();
+    let mut text = "Latency (p50 / p95 / max):".to_string();
+    let ms = |took: Duration| format!("{}ms", took.as_millis());
+    for stage in Stage::ALL {
+      let timings = &latency[stage as usize];
+      let Some((p50, p95)) = timings.percentiles() else {
+        continue;
+      };
+      write!(
+        text,
+        "\n{}: {} / {} / {} ({})",
+        stage.name(),
+        ms(p50),
+        ms(p95),
+        ms(timings.max),
+        timings.count
+      )
+      .unwrap();
+    }
+    text
+  }
+
+  /// [`Stats::render_latency`] in milliseconds, for `/metrics`.
+  pub fn latency_json(&self) -> serde_json::Value {
+    let latency = self.latency.lock().unwrap();
+    let mut stages = serde_json::Map::new();
+    for stage in Stage::ALL {
+      let timings = &latency[stage as usize];
+      let Some((p50, p95)) = timings.percentiles() else {
+        continue;
+      };
+      let ms = |took: Duration| took.as_secs_f64() * 1000.0;
+      stages.insert(
+        stage.name().to_string(),
+        serde_json::json!({
+          "count": timings.count,
+          "p50_ms": ms(p50),
+          "p95_ms": ms(p95),
+          "max_ms": ms(timings.max),
+        }),
+      );
+    }
+    stages.into()
+  }
+
+  /// Counts a message that would have been cleaned, the first one of each
+  /// chat and then every [`SAMPLE_EVERY`] are logged with `sample`.
+  pub fn missed(&self, chat: i64, reason: Missed, sample: &str) {
+    let count = {
+      let mut missed = self.missed.lock().unwrap();
+      let counts = missed.entry(chat).or_default();
+      counts[reason as usize] += 1;
+      counts[reason as usize]
+    };
+    if count % SAMPLE_EVERY == 1 {
+      info!("Missed cleaning in chat {chat} ({reason:?}, {count} so far): {sample}");
+    }
+  }
+
+  pub fn render(&self) -> String {
+    let mut text = format!("Cleaned messages: {}", self.cleaned.load(Ordering::Relaxed));
+    let missed = self.missed.lock().unwrap();
+    if !missed.is_empty() {
+      let mut chats: Vec<_> = missed.iter().collect();
+      chats.sort_by_key(|(_, counts)| std::cmp::Reverse(counts[0] + counts[1]));
+      text.push_str("\n\nMissed cleaning opportunities:");
+      for (chat, [not_enabled, no_permission]) in chats {
+        write!(text, "\n<code>{chat}</code>").unwrap();
+        if *not_enabled > 0 {
+          write!(text, " {not_enabled} not enabled").unwrap();
+        }
+        if *no_permission > 0 {
+          write!(text, " {no_permission} no permission").unwrap();
+        }
+      }
+    }
+    text
+  }
+}
+
+fn format_secs(secs: u64) -> String {
+  humantime::format_duration(Duration::from_secs(secs)).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::{Missed, Stage, Stats};
+
+  #[test]
+  fn render_latency() {
+    let stats = Stats::default();
+    for ms in 1..=100 {
+      stats.latency(Stage::Send, Duration::from_millis(ms));
+    }
+    stats.latency(Stage::Queue, Duration::ZERO);
+    assert_eq!(
+      stats.render_latency(),
+      "Latency (p50 / p95 / max):\nqueue: 0ms / 0ms / 0ms (1)\nsend: 51ms / 96ms / 100ms (100)"
+    );
+    assert_eq!(100, stats.latency_json()["send"]["count"]);
+  }
+
+  #[test]
+  fn render_activity() {
+    let stats = Stats::default();
+    stats.update();
+    stats.update();
+    assert_eq!(
+      stats.render_activity(100, 3790, Some(3780)),
+      "Uptime: 1h 1m 30s\nUpdates since start: 2\nLast getUpdates: 10s ago\nLast cleaned: never"
+    );
+  }
+
+  #[test]
+  fn render_missed() {
+    let stats = Stats::default();
+    stats.cleaned();
+    stats.missed(-1, Missed::NotEnabled, "");
+    stats.missed(-2, Missed::NoPermission, "");
+    stats.missed(-2, Missed::NotEnabled, "");
+    assert_eq!(
+      stats.render(),
+      "Cleaned messages: 1\n\nMissed cleaning opportunities:\n\
+       <code>-2</code> 1 not enabled 1 no permission\n\
+       <code>-1</code> 1 not enabled"
+    );
+  }
+}
@@ -0,0 +1,153 @@
+//! Weekly summaries of the links cleaned in a chat, posted on the schedule
+//! of `stats.summary`.
+
+use std::{fmt::Write, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use chrono::Utc;
+use frankenstein::{AsyncTelegramApi, ParseMode, SendMessageParams};
+use log::{error, info};
+
+use crate::{db::ReplacementStats, health::now, retry::with_retry, Bot, START_TIME};
+
+/// How often schedules are checked.
+const TICK: Duration = Duration::from_secs(60);
+const WEEK: u64 = 7 * 24 * 60 * 60;
+/// Senders listed on the leaderboard.
+const TOP: usize = 3;
+
+/// The summary of a week of `stats`, `None` if nothing was cleaned.
+pub(crate) fn render(stats: &ReplacementStats) -> Option<String> {
+  if stats.links == 0 {
+    return None;
+  }
+  let links = if stats.links == 1 { "link" } else { "links" };
+  let mut text = format!("This week I cleaned {} {links}.", stats.links);
+  if !stats.offenders.is_empty() {
+    text.push_str("\nTop sharers of dirty links:");
+  }
+  for (i, (id, username, links)) in stats.offenders.iter().enumerate() {
+    let place = i + 1;
+    match username {
+      Some(username) => write!(
+        text,
+        "\n{place}. @{} ({links})",
+        v_htmlescape::escape(username)
+      ),
+      None => write!(
+        text,
+        "\n{place}. <a href=\"tg://user?id={id}\">{id}</a> ({links})"
+      ),
+    }
+    .unwrap();
+  }
+  Some(text)
+}
+
+/// Chat ids of the enabled chats of `bot` and `[[chats]]`, with their
+/// usernames.
+fn chats(bot: &Bot) -> Vec<(i64, Option<&str>)> {
+  let config = bot.config.load();
+  let entries: Vec<_> = bot
+    .enabled_chats
+    .read()
+    .unwrap()
+    .iter()
+    .cloned()
+    .chain(config.chats_of(bot.entry).map(|chat| chat.id.clone()))
+    .collect();
+  let mut chats = Vec::new();
+  for entry in entries {
+    let id = match entry.strip_prefix('@') {
+      Some(name) => bot
+        .usernames
+        .iter()
+        .find(|(_, username)| username.eq_ignore_ascii_case(name))
+        .map(|(id, _)| *id),
+      None => entry.parse().ok(),
+    };
+    if let Some(id) = id.filter(|id| !chats.iter().any(|(chat, _)| chat == id)) {
+      chats.push((id, bot.username_of(id, None)));
+    }
+  }
+  chats
+}
+
+/// Posts the summaries due since the last ones, or since the start for
+/// chats that never got one.
+async fn post_due(bot: &Bot) -> Result<()> {
+  let config = bot.config.load();
+  let enabled_chats = bot.enabled_chats.read().unwrap().clone();
+  for (chat_id, username) in chats(bot) {
+    let Some(chat) = config.chat_in(bot.entry, &enabled_chats, chat_id, username) else {
+      continue;
+    };
+    let Some(due) = chat.summary.last_due(Utc::now(), chat.timezone) else {
+      continue;
+    };
+    if due <= *START_TIME && bot.db.last_summary(chat_id)?.is_none() {
+      continue;
+    }
+    if !bot.db.claim_summary(chat_id, due)? {
+      continue;
+    }
+    let stats = bot
+      .db
+      .replacement_stats(now().saturating_sub(WEEK), Some(chat_id), TOP)?;
+    let Some(text) = render(&stats) else {
+      continue;
+    };
+    if config.dry_run {
+      info!(chat_id; "Dry run, would post summary: {text}");
+      continue;
+    }
+    let params = SendMessageParams::builder()
+      .chat_id(chat_id)
+      .text(text)
+      .parse_mode(ParseMode::Html)
+      .disable_notification(!chat.notifications)
+      .build();
+    // claimed already, so a failing chat is skipped until next week
+    match with_retry(&config.retry, "post summary", || {
+      bot.api.send_message(&params)
+    })
+    .await
+    {
+      Ok(_) => info!(chat_id; "Posted weekly summary"),
+      Err(err) => error!(chat_id; "Failed to post summary: {err}"),
+    }
+  }
+  Ok(())
+}
+
+/// Posts summaries to the chats of `bot` when they're due.
+pub(crate) async fn summary_loop(bot: Arc<Bot>) {
+  loop {
+    tokio::time::sleep(TICK).await;
+    if let Err(err) = post_due(&bot).await {
+      error!("{err:?}");
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::db::ReplacementStats;
+
+  use super::render;
+
+  #[test]
+  fn leaderboard() {
+    assert_eq!(None, render(&ReplacementStats::default()));
+    let stats = ReplacementStats {
+      links: 143,
+      offenders: vec![(1, Some("foo".to_string()), 40), (2, None, 3)],
+      ..Default::default()
+    };
+    assert_eq!(
+      "This week I cleaned 143 links.\nTop sharers of dirty links:\n1. @foo (40)\n\
+       2. <a href=\"tg://user?id=2\">2</a> (3)",
+      render(&stats).unwrap()
+    );
+  }
+}
@@ -0,0 +1,66 @@
+//! Span export over OTLP, no-ops without the `otel` feature.
+
+use anyhow::Result;
+use fuckburl_bot::config::Tracing;
+#[cfg(not(feature = "otel"))]
+use log::warn;
+
+/// Flushes pending spans when dropped.
+pub(crate) struct Guard {
+  #[cfg(feature = "otel")]
+  provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for Guard {
+  fn drop(&mut self) {
+    #[cfg(feature = "otel")]
+    if let Some(provider) = self.provider.take() {
+      if let Err(err) = provider.shutdown() {
+        log::error!("Failed to flush spans: {err}");
+      }
+    }
+  }
+}
+
+pub(crate) fn init(config: &Tracing) -> Result<Guard> {
+  #[cfg(feature = "otel")]
+  {
+    use anyhow::Context;
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let Some(endpoint) = &config.otlp_endpoint else {
+      return Ok(Guard { provider: None });
+    };
+    let exporter = SpanExporter::builder()
+      .with_http()
+      .with_endpoint(endpoint)
+      .build()
+      .context("Failed to create OTLP exporter")?;
+    let provider = SdkTracerProvider::builder()
+      .with_batch_exporter(exporter)
+      .with_resource(
+        Resource::builder()
+          .with_service_name(config.service_name.clone())
+          .build(),
+      )
+      .build();
+    let tracer = provider.tracer("fuckburl-bot");
+    let subscriber =
+      tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber)
+      .context("Failed to install tracing subscriber")?;
+    Ok(Guard {
+      provider: Some(provider),
+    })
+  }
+  #[cfg(not(feature = "otel"))]
+  {
+    if config.otlp_endpoint.is_some() {
+      warn!("tracing.otlp-endpoint is set, but the bot is built without the `otel` feature");
+    }
+    Ok(Guard {})
+  }
+}